@@ -0,0 +1,8 @@
+mov $ff00 &fe00
+mov $48 &fe12
+mov $45 &fe13
+mov $4c &fe14
+mov $4c &fe15
+mov $4f &fe16
+mov $21 &fe17
+hlt
@@ -0,0 +1,12 @@
+mov $0 R1
+mov $1 R2
+mov $a R3
+loop:
+add R1 R2
+mov R2 R1
+mov ACC R2
+dec R3
+mov R3 ACC
+jne $0 &[!loop]
+mov R1 ACC
+hlt
@@ -0,0 +1,14 @@
+mov [!handler] &1002
+mov $5 R1
+loop:
+int $1
+dec R1
+mov R1 ACC
+jne $0 &[!loop]
+mov &0800 ACC
+hlt
+handler:
+mov &0800 R1
+inc R1
+mov R1 &0800
+rti
@@ -0,0 +1,20 @@
+mov [!text] R1
+mov $5 R3
+push:
+mov &R1 R2
+rsf R2 $8
+psh R2
+inc R1
+dec R3
+mov R3 ACC
+jne $0 &[!push]
+mov $0 R4
+pop:
+pop R2
+mov R2 $fe10 R4
+inc R4
+mov R4 ACC
+jne $5 &[!pop]
+hlt
+text:
+.ascii "STACK"
@@ -0,0 +1,122 @@
+; First-fit heap allocator. Append it to a program that ends in hlt and call, with no stack
+; arguments, so after psh $0
+;   alloc  size in R1, returns a pointer in ACC, $0 if no free block is big enough
+;   free   pointer in R1, freeing $0 does nothing
+; Every block starts with a header word holding its size, header included. Free blocks keep the
+; address of the next free block in the word after the header, so blocks are at least 4 bytes
+; and sizes are even. The free list is sorted by address and freed blocks are merged with free
+; neighbours. The heap runs from the heap start word the loader fills in up to !heapend, the
+; first call to alloc turns it into one free block.
+; Locals live in R1-R8, which cal saves and ret restores, and only ACC comes back.
+const heapword = $1018
+const heapend = $f000
+
+alloc:
+mov &[!heapready] ACC
+jne $0 &[!allocstart]
+mov &[!heapword] R2
+inc R2
+and R2 $fffe
+mov ACC R2
+mov [!heapend] R3
+sub R3 R2
+mov ACC R3
+mov R3 $0 R2
+mov $0 R4
+mov R4 $2 R2
+mov R2 &[!freelist]
+mov $1 &[!heapready]
+allocstart:
+; No block is bigger than the heap, which also keeps the rounding below from wrapping
+mov R1 ACC
+jgt [!heapend] &[!allocfail]
+inc R1
+and R1 $fffe
+mov ACC R1
+jne $0 &[!allocsized]
+mov $2 R1
+allocsized:
+add $2 R1
+mov ACC R1
+; R2 holds the address of the link to the block in R3
+mov [!freelist] R2
+allocwalk:
+mov &R2 R3
+mov R3 ACC
+jeq $0 &[!allocfail]
+mov &R3 R4
+mov R4 ACC
+jge R1 &[!allocfit]
+add $2 R3
+mov ACC R2
+jmp &[!allocwalk]
+allocfit:
+; A rest too small to hold a free block stays part of the allocation
+sub R4 R1
+mov ACC R5
+jlt $4 &[!allocwhole]
+add R3 R1
+mov ACC R6
+mov R5 $0 R6
+mov $2 R3 R7
+mov R7 $2 R6
+mov R6 $0 R2
+mov R1 $0 R3
+jmp &[!allocdone]
+allocwhole:
+mov $2 R3 R7
+mov R7 $0 R2
+allocdone:
+add $2 R3
+ret
+allocfail:
+mov $0 ACC
+ret
+
+free:
+mov R1 ACC
+jeq $0 &[!freedone]
+sub R1 $2
+mov ACC R1
+mov &R1 R8
+; Find the first free block after this one, R6 is the one before it or $0
+mov [!freelist] R2
+mov $0 R6
+freewalk:
+mov &R2 R3
+mov R3 ACC
+jeq $0 &[!freeinsert]
+jgt R1 &[!freeinsert]
+mov R3 R6
+add $2 R3
+mov ACC R2
+jmp &[!freewalk]
+freeinsert:
+mov R3 $2 R1
+mov R1 $0 R2
+add R1 R8
+jne R3 &[!freeprev]
+mov &R3 R4
+add R8 R4
+mov ACC R8
+mov R8 $0 R1
+mov $2 R3 R5
+mov R5 $2 R1
+freeprev:
+mov R6 ACC
+jeq $0 &[!freedone]
+mov &R6 R4
+add R6 R4
+jne R1 &[!freedone]
+add R4 R8
+mov ACC R4
+mov R4 $0 R6
+mov $2 R1 R5
+mov R5 $2 R6
+freedone:
+ret
+
+heapready:
+.word $0
+freelist:
+.word $0
@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::container::DebugInfo;
+use crate::cpu::instruction::{self, Instruction};
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub enum Edge {
+    Call(u16, u16),
+    // An indirect call or jump, the target is only known at run time
+    Indirect(u16, u16),
+    // A jump from one routine straight into another
+    TailCall(u16, u16),
+}
+
+// Routines are the program entry at address 0 and every CAL target reachable from it.
+// Each routine is walked over every branch until RET, RTI, HLT, RESET, JMP or an unknown opcode.
+pub fn call_graph(code: &[u8], tail_calls: bool) -> BTreeSet<Edge> {
+    let mut routines = BTreeSet::new();
+    let mut queue = vec![0];
+    while let Some(entry) = queue.pop() {
+        if routines.insert(entry) {
+            queue.extend(
+                walk(code, entry, &BTreeSet::new())
+                    .into_iter()
+                    .filter_map(|edge| match edge {
+                        Edge::Call(_, target) => Some(target),
+                        _ => None,
+                    }),
+            );
+        }
+    }
+
+    let mut edges = BTreeSet::new();
+    for &entry in routines.iter() {
+        for edge in walk(code, entry, &routines) {
+            match edge {
+                Edge::Call(_, target) => {
+                    edges.insert(Edge::Call(entry, target));
+                }
+                Edge::TailCall(_, target) if tail_calls => {
+                    edges.insert(Edge::TailCall(entry, target));
+                }
+                Edge::TailCall(..) => {}
+                Edge::Indirect(_, site) => {
+                    edges.insert(Edge::Indirect(entry, site));
+                }
+            }
+        }
+    }
+    edges
+}
+
+// Jumps that fall through when not taken, all end in the target address
+const CONDITIONAL_JUMPS: [Instruction; 18] = [
+    instruction::JNE_LIT_MEM,
+    instruction::JNE_REG_MEM,
+    instruction::JEQ_LIT_MEM,
+    instruction::JEQ_REG_MEM,
+    instruction::JGT_LIT_MEM,
+    instruction::JGT_REG_MEM,
+    instruction::JLT_LIT_MEM,
+    instruction::JLT_REG_MEM,
+    instruction::JGE_LIT_MEM,
+    instruction::JGE_REG_MEM,
+    instruction::JLE_LIT_MEM,
+    instruction::JLE_REG_MEM,
+    instruction::LOOP_REG_MEM,
+    instruction::JZ_MEM,
+    instruction::JNZ_MEM,
+    instruction::JC_MEM,
+    instruction::JNC_MEM,
+    instruction::JS_MEM,
+];
+
+// Returns the calls and jumps into other routines found from `entry`, keyed by call site
+fn walk(code: &[u8], entry: u16, routines: &BTreeSet<u16>) -> Vec<Edge> {
+    let mut edges = vec![];
+    let mut visited = BTreeSet::new();
+    let mut queue = vec![entry];
+    while let Some(address) = queue.pop() {
+        if !visited.insert(address) {
+            continue;
+        }
+        let instruction = match decode(code, address) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        let next = address + instruction.size;
+        // A JMP doesn't fall through, anything else that isn't a return or a halt does
+        let (target, falls_through) = match instruction.opcode {
+            x if x == instruction::RET.opcode
+                || x == instruction::RET_INT.opcode
+                || x == instruction::HLT.opcode
+                || x == instruction::RESET.opcode =>
+            {
+                continue
+            }
+            x if x == instruction::CAL_LIT.opcode => {
+                edges.push(Edge::Call(address, word(code, address + 1)));
+                (None, true)
+            }
+            x if x == instruction::CAL_REG.opcode => {
+                edges.push(Edge::Indirect(address, address));
+                (None, true)
+            }
+            x if x == instruction::JMP_LIT.opcode => (Some(word(code, address + 1)), false),
+            x if x == instruction::JMP_REG.opcode => {
+                edges.push(Edge::Indirect(address, address));
+                continue;
+            }
+            x if CONDITIONAL_JUMPS.iter().any(|jump| jump.opcode == x) => {
+                (Some(word(code, next - 2)), true)
+            }
+            _ => (None, true),
+        };
+        if let Some(target) = target {
+            if target != entry && routines.contains(&target) {
+                edges.push(Edge::TailCall(address, target));
+            } else {
+                queue.push(target);
+            }
+        }
+        if falls_through {
+            queue.push(next);
+        }
+    }
+    edges
+}
+
+fn decode(code: &[u8], address: u16) -> Option<Instruction> {
+    let opcode = *code.get(address as usize)?;
+    let instruction = instruction::LIST
+        .iter()
+        .find(|instruction| instruction.opcode == opcode)?;
+    if address as usize + instruction.size as usize > code.len() {
+        return None;
+    }
+    Some(*instruction)
+}
+
+fn word(code: &[u8], address: u16) -> u16 {
+    u16::from_be_bytes([code[address as usize], code[address as usize + 1]])
+}
+
+pub fn to_dot(edges: &BTreeSet<Edge>, debug: Option<&DebugInfo>) -> String {
+    let symbols: BTreeMap<u16, &str> = debug
+        .map(|debug| {
+            debug
+                .symbols
+                .iter()
+                .map(|(name, address)| (*address, name.as_str()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let name = |address: &u16| match symbols.get(address) {
+        Some(symbol) => symbol.to_string(),
+        None => format!("{:#06x}", address),
+    };
+
+    let mut res = String::from("digraph calls {\n");
+    if edges.iter().any(|edge| matches!(edge, Edge::Indirect(..))) {
+        res.push_str("    \"indirect\" [shape=box];\n");
+    }
+    for edge in edges {
+        res.push_str(&match edge {
+            Edge::Call(from, to) => format!("    \"{}\" -> \"{}\";\n", name(from), name(to)),
+            Edge::Indirect(from, site) => format!(
+                "    \"{}\" -> \"indirect\" [label=\"{:#06x}\"];\n",
+                name(from),
+                site
+            ),
+            Edge::TailCall(from, to) => format!(
+                "    \"{}\" -> \"{}\" [style=dashed, label=\"tail\"];\n",
+                name(from),
+                name(to)
+            ),
+        });
+    }
+    res.push_str("}\n");
+    res
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::{call_graph, to_dot};
+    use crate::assembler;
+
+    const FIXTURE: &str = "cal [!draw]\ncal [!beep]\nhlt\n\
+                           draw:\nmov $3 R1\nloop:\ncal [!plot]\ndec R1\njne $0 &[!loop]\nret\n\
+                           plot:\ncal R5\nret\n\
+                           beep:\njeq $0 &[!plot]\nret\n";
+
+    fn dot(tail_calls: bool) -> String {
+        let assembly = assembler::assemble(FIXTURE, &assembler::Options::default()).unwrap();
+        let debug = assembly.debug_info("fixture.asm");
+        to_dot(&call_graph(&assembly.bytes, tail_calls), Some(&debug))
+    }
+
+    #[test]
+    fn calls() {
+        assert_eq!(
+            dot(false),
+            "digraph calls {\n    \"indirect\" [shape=box];\n    \"0x0000\" -> \"draw\";\n    \
+             \"0x0000\" -> \"beep\";\n    \"draw\" -> \"plot\";\n    \
+             \"plot\" -> \"indirect\" [label=\"0x0016\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn tail_calls() {
+        assert!(dot(true).contains("    \"beep\" -> \"plot\" [style=dashed, label=\"tail\"];\n"));
+    }
+
+    #[test]
+    fn unconditional_jumps() {
+        // The data after jmp is never walked, so it doesn't read as a call to $1234. The call
+        // is only reached through the loop.
+        let code = "jmp &[!start]\ndata:\ncal [$1234]\nstart:\nmov $2 R1\n\
+                    loop R1 &[!far]\njmp R2\nfar:\ncal [!sub]\nhlt\nsub:\nret\n";
+        let bytes = assembler::compile(code, &assembler::Options::default()).unwrap();
+        let edges: Vec<String> = call_graph(&bytes, false)
+            .iter()
+            .map(|edge| format!("{:?}", edge))
+            .collect();
+        assert_eq!(edges, vec!["Call(0, 20)", "Indirect(0, 14)"]);
+    }
+
+    #[test]
+    fn jumps_by_opcode() {
+        // Both sides of a flag jump are walked, so both calls are found
+        let code = "cmp R1 $0\njz &[!zero]\ncal [$100]\nhlt\nzero:\ncal [$200]\nhlt\n";
+        let bytes = assembler::compile(code, &assembler::Options::default()).unwrap();
+        let edges: Vec<String> = call_graph(&bytes, false)
+            .iter()
+            .map(|edge| format!("{:?}", edge))
+            .collect();
+        assert_eq!(edges, vec!["Call(0, 256)", "Call(0, 512)"]);
+    }
+}
@@ -1,302 +1,1889 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
 
-use formats::{
-    lit, lit_mem, lit_off_reg, lit_reg, mem_reg, no_arg, reg, reg_lit, reg_lit8, reg_mem,
-    reg_ptr_reg, reg_reg,
+use expression::{evaluate, evaluate32, evaluate_runtime};
+use formats::{instruction_of, mov32};
+use parser::{
+    ascii, budget, constant, data, label, meta, opcode, pool, register_alias, runtime_expression,
+    square_bracket_expression, unescape, Line, Type,
 };
-use parser::{label, Type};
 
-use crate::cpu::instruction;
-use crate::cpu::register::get_from_string;
-use crate::parser_combinator::core::{Parser, ParserState};
-use crate::parser_combinator::string::{character, optional_whitespace};
+use crate::container::DebugInfo;
+use crate::cpu::extension::EXTENSION_RANGE;
+use crate::cpu::instruction::{self, Instruction, OperandKind};
+use crate::cpu::register::{self, get_from_string};
+use crate::parser_combinator::core::{ParseError, Parser, ParserState};
+use crate::parser_combinator::string::{comment, optional_whitespace};
 
+mod builder;
+mod expression;
 mod formats;
 mod parser;
+mod peephole;
+mod printer;
 
-pub fn compile(code: &str) -> Vec<u8> {
-    match assembly_parser().parse(code) {
-        Ok(ParserState { result, index }) => {
-            if code.len() != index {
-                panic!("Could not parse from index {}", index);
-            }
-            let mut res = vec![];
-            let mut labels = HashMap::new();
-            let mut current_address = 0;
+pub use builder::{Assembler, AssemblerBuilder};
+pub use expression::Runtime;
 
-            for t in &result {
-                match t {
-                    Type::Label(label) => {
-                        labels.insert(label, current_address);
-                    }
-                    Type::Instruction0 { instruction, .. } => current_address += instruction.size,
-                    Type::Instruction1 { instruction, .. } => current_address += instruction.size,
-                    Type::Instruction2 { instruction, .. } => current_address += instruction.size,
-                    Type::Instruction3 { instruction, .. } => current_address += instruction.size,
-                    _ => panic!("Unexpected instruction on top level: {:?}", t),
-                }
-            }
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    pub wrap_expressions: bool,
+    pub warn_shadowing: bool,
+    // Bytes the program may take, code and pools together
+    pub max_size: Option<u16>,
+    // Runs the peephole pass in `peephole` before addresses are assigned
+    pub optimize: bool,
+}
 
-            for t in &result {
-                res.extend(encode(t, &labels))
-            }
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub symbols: Vec<(String, u16)>,
+    pub lines: Vec<LineInfo>,
+    pub pool: Vec<PoolEntry>,
+    pub regions: Vec<Region>,
+    // Keys and values of the `.meta` directives in source order
+    pub meta: Vec<(String, Vec<u8>)>,
+    // Lines the peephole pass removed and the address they would have started at
+    pub removed: Vec<(u16, u16)>,
+    // Problems that don't stop the assembly, like a redefined register alias with
+    // `warn_shadowing`. The caller decides whether to show them.
+    pub warnings: Vec<Diagnostic>,
+}
 
-            res
+// Code from a `.budget` to the next one or the end, the code before the first budget is a
+// region without one
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Region {
+    pub line: u16,
+    pub start: u16,
+    pub end: u16,
+    pub budget: Option<u16>,
+}
+
+// One emitted instruction, its bytes are `bytes[address..address + instruction.size]`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct LineInfo {
+    pub line: u16,
+    pub address: u16,
+    pub instruction: Instruction,
+}
+
+// A literal placed by `=...`, its bytes are `bytes[address..address + size]`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PoolEntry {
+    pub address: u16,
+    pub size: u16,
+    pub literal: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub line: u16,
+    pub message: String,
+    pub position: Option<Position>,
+}
+
+// The source line of a syntax error and the 1 based column it failed at, in characters
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Position {
+    pub column: u16,
+    pub text: String,
+}
+
+impl Diagnostic {
+    pub fn new(line: u16, message: String) -> Diagnostic {
+        Diagnostic {
+            line,
+            message,
+            position: None,
         }
-        Err(err) => panic!("Could not compile: {}", err.message),
     }
 }
 
-fn encode(t: &Type, labels: &HashMap<&String, u16>) -> Vec<u8> {
-    match t {
-        Type::Instruction0 { instruction } => vec![instruction.opcode],
-        Type::Instruction1 { instruction, arg0 } => {
-            let mut res = vec![instruction.opcode];
-            res.extend(encode(arg0, labels));
-            res
+// Syntax errors show their line with a caret under the column:
+//   line 3, column 11: Expected a term after '+'
+//       mov [$1 +] R1
+//               ^
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.position {
+            None => write!(f, "line {}: {}", self.line, self.message),
+            Some(position) => {
+                // Tabs stay tabs so the caret lines up however they are displayed
+                let indent: String = position
+                    .text
+                    .chars()
+                    .take(position.column as usize - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(
+                    f,
+                    "line {}, column {}: {}\n    {}\n    {}^",
+                    self.line, position.column, self.message, position.text, indent
+                )
+            }
         }
-        Type::Instruction2 {
-            instruction,
-            arg0,
-            arg1,
-        } => {
-            let mut res = vec![instruction.opcode];
-            res.extend(encode(arg0, labels));
-            res.extend(encode(arg1, labels));
-            res
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(Diagnostic::to_string).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Assembly {
+    pub fn debug_info(&self, file: &str) -> DebugInfo {
+        DebugInfo {
+            file: file.to_string(),
+            lines: self
+                .lines
+                .iter()
+                .map(|line| (line.address, line.line))
+                .collect(),
+            symbols: self.symbols.clone(),
         }
-        Type::Instruction3 {
-            instruction,
-            arg0,
-            arg1,
-            arg2,
-        } => {
-            let mut res = vec![instruction.opcode];
-            res.extend(encode(arg0, labels));
-            res.extend(encode(arg1, labels));
-            res.extend(encode(arg2, labels));
-            res
+    }
+
+    // Address, bytes and source of every instruction and pool entry, in address order. Lines the
+    // peephole pass removed have no bytes.
+    pub fn listing(&self, code: &str) -> String {
+        let source: Vec<&str> = code.lines().collect();
+        let text = |line: u16| source[line as usize - 1].trim().to_string();
+        let mut rows: Vec<(u16, u16, u16, String)> = self
+            .lines
+            .iter()
+            .map(|line| {
+                let mut text = text(line.line);
+                // Aliases and rewritten lines show the instruction they were emitted as
+                if text.split_whitespace().next() != Some(line.instruction.mnemonic) {
+                    text = format!("{} => {}", text, line.instruction.syntax());
+                }
+                (line.address, line.line, line.instruction.size, text)
+            })
+            .collect();
+        rows.extend(
+            self.removed
+                .iter()
+                .map(|&(line, address)| (address, line, 0, format!("{} => removed", text(line)))),
+        );
+        rows.sort_by_key(|&(_, line, _, _)| line);
+        let mut rows: Vec<(u16, u16, String)> = rows
+            .into_iter()
+            .map(|(address, _, size, text)| (address, size, text))
+            .collect();
+        rows.extend(
+            self.pool
+                .iter()
+                .map(|entry| (entry.address, entry.size, format!("pool {}", entry.literal))),
+        );
+        rows.sort_by_key(|(address, _, _)| *address);
+        rows.iter()
+            .map(|(address, size, text)| {
+                let bytes: Vec<String> = self.bytes[*address as usize..(*address + *size) as usize]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                format!("{:04x}  {:<24} {}\n", address, bytes.join(" "), text)
+            })
+            .collect()
+    }
+
+    // Total size, every region against its budget and the largest routines, a routine being
+    // the bytes from one label to the next
+    pub fn size_report(&self) -> String {
+        let mut res = format!("code: {} bytes\n", self.bytes.len());
+        for region in &self.regions {
+            res.push_str(&format!(
+                "region at line {}: {:#06x}-{:#06x}, {} bytes",
+                region.line,
+                region.start,
+                region.end,
+                region.end - region.start
+            ));
+            if let Some(budget) = region.budget {
+                res.push_str(&format!(
+                    ", budget {}, {} free",
+                    budget,
+                    budget - (region.end - region.start)
+                ));
+            }
+            res.push('\n');
         }
-        Type::BinaryOperation { .. } => panic!("Not supported yet"),
-        Type::Ignored => panic!("ignored node was left after processing"),
-        Type::HexLiteral(val) => val.to_be_bytes().to_vec(),
-        Type::HexLiteral8(val) => vec![*val],
-        Type::Address(val) => val.to_be_bytes().to_vec(),
-        Type::Variable(name) => labels[name].to_be_bytes().to_vec(),
-        Type::Register(val) => vec![get_from_string(val) as u8],
-        Type::Operator(_) => panic!("Not supported yet"),
-        Type::Label(_) => Vec::with_capacity(0),
+        if !self.symbols.is_empty() {
+            res.push_str("largest routines:\n");
+        }
+        for (name, size) in self.routines().iter().take(10) {
+            res.push_str(&format!("  {:<16} {} bytes\n", name, size));
+        }
+        res
+    }
+
+    // Largest first, ties in address order
+    fn routines(&self) -> Vec<(&str, u16)> {
+        let mut symbols: Vec<&(String, u16)> = self.symbols.iter().collect();
+        symbols.sort_by_key(|(_, address)| *address);
+        let mut routines: Vec<(&str, u16)> = symbols
+            .iter()
+            .enumerate()
+            .map(|(i, (name, address))| {
+                let end = symbols
+                    .get(i + 1)
+                    .map(|(_, next)| *next)
+                    .unwrap_or(self.bytes.len() as u16);
+                (name.as_str(), end - address)
+            })
+            .collect();
+        routines.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        routines
     }
 }
 
-fn assembly_parser<'a>() -> Parser<'a, str, Vec<Type>> {
-    assembly_instruction()
-        .left(optional_whitespace())
-        .left(character('\n'))
-        .one_or_more()
+// Just the bytes, for embedders that have no use for symbols and line info
+pub fn compile(code: &str, options: &Options) -> Result<Vec<u8>, Diagnostics> {
+    assemble(code, options).map(|assembly| assembly.bytes)
 }
 
-fn assembly_instruction<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        label(),
-        mov(),
-        add(),
-        sub(),
-        mul(),
-        lsf(),
-        rsf(),
-        and(),
-        or(),
-        xor(),
-        jeq(),
-        jne(),
-        jgt(),
-        jlt(),
-        jle(),
-        jge(),
-        psh(),
-        pop(),
-        inc(),
-        dec(),
-        not(),
-        cal(),
-        ret(),
-        hlt(),
-    ])
+pub fn assemble(code: &str, options: &Options) -> Result<Assembly, Diagnostics> {
+    assemble_lines(parse(code, builtin())?, options)
 }
 
-fn mov<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("mov", instruction::MOVE_LIT_REG),
-        lit_off_reg("mov", instruction::MOVE_LIT_OFF_REG),
-        reg_reg("mov", instruction::MOVE_REG_REG),
-        lit_mem("mov", instruction::MOVE_LIT_MEM),
-        mem_reg("mov", instruction::MOVE_MEM_REG),
-        reg_ptr_reg("mov", instruction::MOVE_REG_PTR_REG),
-        reg_mem("mov", instruction::MOVE_REG_MEM),
-    ])
+// Label and constant addresses by name
+pub type SymbolTable = BTreeMap<String, u16>;
+
+// Assembles a single instruction that goes at `at` in a program that is already built, for
+// patches and other tools. Labels and constants come from `symbols`. Anything that only makes
+// sense in a whole program, like defining a label or using a pool literal, is an error.
+pub fn assemble_one(src: &str, at: u16, symbols: &SymbolTable) -> Result<Vec<u8>, Diagnostics> {
+    let error = |message: String| Diagnostics(vec![Diagnostic::new(1, message)]);
+    let source = src.strip_suffix('\n').unwrap_or(src);
+    if source.contains('\n') {
+        return Err(error("Expected a single instruction".to_string()));
+    }
+    let mut t = match parse(&format!("{}\n", source), builtin())?.pop() {
+        Some(Line { item: Some(t), .. }) => t,
+        _ => return Err(error("Expected an instruction".to_string())),
+    };
+    match &t {
+        Type::Instruction0 { .. }
+        | Type::Instruction1 { .. }
+        | Type::Instruction2 { .. }
+        | Type::Instruction3 { .. }
+        | Type::Mov32 { .. }
+        | Type::Opcode { .. }
+        | Type::Data { .. }
+        | Type::Ascii { .. } => {}
+        _ => return Err(error(format!("{} needs the whole program", describe(&t)))),
+    }
+    if let Some(literal) = operands(&t).into_iter().find_map(|arg| match arg {
+        Type::PoolLiteral(literal) => Some(literal),
+        _ => None,
+    }) {
+        return Err(error(format!(
+            "{} needs the whole program",
+            printer::pool_literal(literal)
+        )));
+    }
+    let options = Options::default();
+    resolve_aliases(&mut t, &mut HashMap::new()).map_err(error)?;
+    let bytes = encode(&t, symbols, &[], &options).map_err(error)?;
+    if at as usize + bytes.len() > 0x10000 {
+        return Err(error(format!(
+            "{} bytes at {:#06x} run past the end of memory",
+            bytes.len(),
+            at
+        )));
+    }
+    Ok(bytes)
 }
 
-fn add<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("add", instruction::ADD_LIT_REG),
-        reg_reg("add", instruction::ADD_REG_REG),
-    ])
+fn builtin() -> Rc<[Instruction]> {
+    Rc::from(&instruction::LIST[..])
 }
 
-fn sub<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("sub", instruction::SUB_LIT_REG),
-        reg_reg("sub", instruction::SUB_REG_REG),
-        reg_lit("sub", instruction::SUB_REG_LIT),
-    ])
+fn assemble_lines(mut result: Vec<Line>, options: &Options) -> Result<Assembly, Diagnostics> {
+    let mut assembly = Assembly {
+        bytes: vec![],
+        symbols: vec![],
+        lines: vec![],
+        pool: vec![],
+        regions: vec![],
+        meta: vec![],
+        removed: vec![],
+        warnings: vec![],
+    };
+    let removed = if options.optimize {
+        peephole::optimize(&mut result)
+    } else {
+        vec![]
+    };
+    let mut diagnostics = vec![];
+    let mut labels = BTreeMap::new();
+    // Line of every label and constant, they share `labels` once constants are evaluated
+    let mut defined = HashMap::new();
+    let mut constants = vec![];
+    let mut current_address = 0;
+    let mut aliases = HashMap::new();
+    // Literals are placed at every `.pool` and after the code, one group per location
+    let mut pools: Vec<Vec<(Type, u16)>> = vec![];
+    let mut pending: Vec<Type> = vec![];
+    // Bytes reserved for each line while assigning addresses, encoding must emit exactly these
+    let mut sizes = vec![0; result.len()];
+    // The first line that ends past `options.max_size`
+    let mut past_limit = None;
+
+    // Every source line is kept, so its index is its line number
+    for (index, source) in result.iter_mut().enumerate() {
+        let line = index as u16 + 1;
+        let t = match &mut source.item {
+            Some(t) => t,
+            None => {
+                if removed.contains(&line) {
+                    assembly.removed.push((line, current_address));
+                }
+                continue;
+            }
+        };
+        let start = current_address;
+        if let Type::RegisterAlias { name, .. } = t {
+            match aliases.get(name) {
+                Some(Type::Register(previous)) if options.warn_shadowing => {
+                    assembly.warnings.push(Diagnostic::new(
+                        line,
+                        format!("Register alias {} redefined, was {}", name, previous),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if let Err(message) = resolve_aliases(t, &mut aliases) {
+            diagnostics.push(Diagnostic::new(line, message));
+        }
+        // The end of the line as usize, so a program past the end of memory is reported and
+        // doesn't overflow the address
+        let end = match t {
+            Type::Budget(size) => {
+                assembly.regions.push(Region {
+                    line,
+                    start: current_address,
+                    end: current_address,
+                    budget: Some(*size),
+                });
+                start as usize
+            }
+            Type::Opcode { operands, .. } => start as usize + 1 + operands.len(),
+            Type::Meta { key, value } => {
+                match meta_value(&assembly.meta, key, value) {
+                    Ok(value) => assembly.meta.push((key.clone(), value)),
+                    Err(message) => diagnostics.push(Diagnostic::new(line, message)),
+                }
+                start as usize
+            }
+            Type::Data { wide, values } => {
+                start as usize + values.len() * if *wide { 2 } else { 1 }
+            }
+            Type::Ascii { .. } => match encode_ascii(t) {
+                Ok(bytes) => start as usize + bytes.len(),
+                Err(message) => {
+                    diagnostics.push(Diagnostic::new(line, message));
+                    start as usize
+                }
+            },
+            Type::Pool => place_pool(&mut pending, current_address, &mut pools, &mut assembly),
+            Type::Label(label) => {
+                match define(&mut defined, label, line) {
+                    Ok(()) => {
+                        labels.insert(label.clone(), current_address);
+                        assembly.symbols.push((label.clone(), current_address));
+                    }
+                    Err(message) => diagnostics.push(Diagnostic::new(line, message)),
+                }
+                start as usize
+            }
+            Type::Constant { name, value } => {
+                match define(&mut defined, name, line) {
+                    Ok(()) => constants.push((line, name.clone(), (**value).clone())),
+                    Err(message) => diagnostics.push(Diagnostic::new(line, message)),
+                }
+                start as usize
+            }
+            Type::Instruction0 { instruction, .. }
+            | Type::Instruction1 { instruction, .. }
+            | Type::Instruction2 { instruction, .. }
+            | Type::Instruction3 { instruction, .. } => {
+                assembly.lines.push(LineInfo {
+                    line,
+                    address: current_address,
+                    instruction: *instruction,
+                });
+                let end = start as usize + instruction.size as usize;
+                for arg in operands(t) {
+                    if let Type::PoolLiteral(literal) = arg {
+                        let placed = pools.iter().flatten().any(|(l, _)| l == &**literal);
+                        if !placed && !pending.contains(literal) {
+                            pending.push((**literal).clone());
+                        }
+                    }
+                }
+                end
+            }
+            Type::Mov32 { .. } => {
+                let size = instruction::MOVE_LIT_REG.size;
+                for address in [start, start.wrapping_add(size)] {
+                    assembly.lines.push(LineInfo {
+                        line,
+                        address,
+                        instruction: instruction::MOVE_LIT_REG,
+                    });
+                }
+                start as usize + 2 * size as usize
+            }
+            Type::RegisterAlias { .. } => start as usize,
+            _ => panic!("Unexpected instruction on top level: {:?}", t),
+        };
+        current_address = match past_memory(end) {
+            Some(message) => {
+                diagnostics.push(Diagnostic::new(line, message));
+                return Err(Diagnostics(diagnostics));
+            }
+            None => end as u16,
+        };
+        sizes[index] = current_address - start;
+        if past_limit.is_none() && options.max_size.is_some_and(|max| current_address > max) {
+            past_limit = Some(line);
+        }
+    }
+    // Every label is known by now, so constants can be used above their definition
+    for (line, name, value) in constants {
+        match evaluate(&value, &labels, options.wrap_expressions) {
+            Ok(value) => {
+                labels.insert(name, value);
+            }
+            Err(message) => diagnostics.push(Diagnostic::new(line, message)),
+        }
+    }
+    let end = place_pool(&mut pending, current_address, &mut pools, &mut assembly);
+    if let Some(message) = past_memory(end) {
+        diagnostics.push(Diagnostic::new(result.len() as u16, message));
+        return Err(Diagnostics(diagnostics));
+    }
+    let end = end as u16;
+    match options.max_size {
+        Some(max) if end > max => {
+            let mut message = format!(
+                "Program is {} bytes, {} over the limit of {}",
+                end,
+                end - max,
+                max
+            );
+            if let Some((name, address)) = assembly.symbols.iter().find(|(_, a)| *a >= max) {
+                message.push_str(&format!(
+                    ", the first label past it is {} at {:#06x}",
+                    name, address
+                ));
+            }
+            // Only the final pool is past the limit otherwise
+            let line = past_limit.unwrap_or(result.len() as u16);
+            diagnostics.push(Diagnostic::new(line, message));
+        }
+        _ => {}
+    }
+    if assembly.regions.first().map(|region| region.start) != Some(0) {
+        assembly.regions.insert(
+            0,
+            Region {
+                line: 1,
+                start: 0,
+                end: 0,
+                budget: None,
+            },
+        );
+    }
+    let starts: Vec<u16> = assembly.regions.iter().map(|region| region.start).collect();
+    for (i, region) in assembly.regions.iter_mut().enumerate() {
+        region.end = starts.get(i + 1).cloned().unwrap_or(end);
+        let size = region.end - region.start;
+        match region.budget {
+            Some(budget) if size > budget => diagnostics.push(Diagnostic::new(
+                region.line,
+                format!(
+                    "Region is {} bytes, {} over its budget of {}",
+                    size,
+                    size - budget,
+                    budget
+                ),
+            )),
+            _ => {}
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(Diagnostics(diagnostics));
+    }
+
+    let literals: Vec<(Type, u16)> = pools.iter().flatten().cloned().collect();
+    let mut pools = pools.iter();
+    for (index, source) in result.iter().enumerate() {
+        let t = match &source.item {
+            Some(t) => t,
+            None => continue,
+        };
+        let bytes = match t {
+            Type::Pool => encode_pool(pools.next().unwrap(), &labels, options),
+            _ => encode(t, &labels, &literals, options),
+        };
+        let line = index as u16 + 1;
+        match bytes {
+            // Every label after this line would be off, so this is an assembler bug
+            Ok(bytes) if bytes.len() != sizes[index] as usize => diagnostics.push(Diagnostic::new(
+                line,
+                format!(
+                    "Internal error: {} encoded to {} bytes, {} were reserved for it",
+                    describe(t),
+                    bytes.len(),
+                    sizes[index]
+                ),
+            )),
+            Ok(bytes) => assembly.bytes.extend(bytes),
+            Err(message) => diagnostics.push(Diagnostic::new(line, message)),
+        }
+    }
+    // Errors in the final pool are reported on the last line
+    match encode_pool(pools.next().unwrap(), &labels, options) {
+        Ok(bytes) => assembly.bytes.extend(bytes),
+        Err(message) => diagnostics.push(Diagnostic::new(result.len() as u16, message)),
+    }
+
+    if diagnostics.is_empty() {
+        Ok(assembly)
+    } else {
+        Err(Diagnostics(diagnostics))
+    }
 }
 
-fn mul<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("mul", instruction::MUL_LIT_REG),
-        reg_reg("mul", instruction::MUL_REG_REG),
-    ])
+// Rewrites the source in the canonical layout, compiling the result gives the same bytes
+pub fn format(code: &str) -> Result<String, Diagnostics> {
+    Ok(printer::print(&parse(code, builtin())?))
 }
 
-fn lsf<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        reg_lit8("lsf", instruction::LSF_REG_LIT8),
-        reg_reg("lsf", instruction::LSF_REG_REG),
-    ])
+// Each line holds at most one item and a comment and has to be consumed completely, anything
+// left after them is reported on its own line instead of failing the whole file
+fn parse(code: &str, instructions: Rc<[Instruction]>) -> Result<Vec<Line>, Diagnostics> {
+    let mut result = vec![];
+    let mut diagnostics = vec![];
+    let parser = source_line(instructions);
+    for (line, text) in code.split_inclusive('\n').enumerate() {
+        let content = text.strip_suffix('\n').unwrap_or(text);
+        let error = if content.len() == text.len() {
+            Some((
+                "Expected a newline at the end of the file".to_string(),
+                text.len(),
+            ))
+        } else {
+            match parser.parse(content) {
+                Ok(ParserState {
+                    result: line,
+                    index,
+                }) if index == content.len() => {
+                    result.push(line);
+                    None
+                }
+                Ok(ParserState { index, .. }) => Some((
+                    format!("Unexpected trailing characters: '{}'", &content[index..]),
+                    index,
+                )),
+                Err(ParseError { index, .. }) => Some(
+                    parser::bracket_error(content)
+                        .unwrap_or_else(|| ("Could not parse".to_string(), index)),
+                ),
+            }
+        };
+        if let Some((message, index)) = error {
+            diagnostics.push(Diagnostic {
+                line: line as u16 + 1,
+                message,
+                position: Some(Position {
+                    column: column(content, index),
+                    text: content.to_string(),
+                }),
+            });
+        }
+    }
+    if code.is_empty() {
+        diagnostics.push(Diagnostic::new(1, "There is no code".to_string()));
+    }
+
+    if diagnostics.is_empty() {
+        Ok(result)
+    } else {
+        Err(Diagnostics(diagnostics))
+    }
 }
 
-fn rsf<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        reg_lit8("rsf", instruction::RSF_REG_LIT8),
-        reg_reg("rsf", instruction::RSF_REG_REG),
-    ])
+// The 1 based column of a byte index, counting characters so an editor puts the cursor on the
+// same spot
+fn column(content: &str, index: usize) -> u16 {
+    content
+        .char_indices()
+        .take_while(|&(i, _)| i < index)
+        .count() as u16
+        + 1
 }
 
-fn and<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("and", instruction::AND_REG_LIT),
-        reg_reg("and", instruction::AND_REG_REG),
-    ])
+// Labels and constants share one namespace and each name is defined once
+fn define(defined: &mut HashMap<String, u16>, name: &str, line: u16) -> Result<(), String> {
+    match defined.get(name) {
+        Some(previous) => Err(format!("{} is already defined on line {}", name, previous)),
+        None => {
+            defined.insert(name.to_string(), line);
+            Ok(())
+        }
+    }
 }
 
-fn or<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("or", instruction::OR_REG_LIT),
-        reg_reg("or", instruction::OR_REG_REG),
-    ])
+// Aliases apply from the line they are defined on, so this runs in source order
+fn resolve_aliases(t: &mut Type, aliases: &mut HashMap<String, Type>) -> Result<(), String> {
+    match t {
+        Type::RegisterAlias { name, register } => {
+            aliases.insert(name.clone(), (**register).clone());
+        }
+        Type::Instruction1 { arg0, .. } => resolve_aliases(arg0, aliases)?,
+        Type::Instruction2 { arg0, arg1, .. } => {
+            resolve_aliases(arg0, aliases)?;
+            resolve_aliases(arg1, aliases)?;
+        }
+        Type::Instruction3 {
+            arg0, arg1, arg2, ..
+        } => {
+            resolve_aliases(arg0, aliases)?;
+            resolve_aliases(arg1, aliases)?;
+            resolve_aliases(arg2, aliases)?;
+        }
+        Type::Mov32 { high, low, .. } => {
+            resolve_aliases(high, aliases)?;
+            resolve_aliases(low, aliases)?;
+        }
+        Type::Register(name) if !register::LIST.iter().any(|&r| register::name(r) == name) => {
+            *t = aliases
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown register or alias: {}", name))?;
+        }
+        _ => {}
+    }
+    Ok(())
 }
 
-fn xor<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_reg("xor", instruction::XOR_REG_LIT),
-        reg_reg("xor", instruction::XOR_REG_REG),
-    ])
+// Evaluates an expression written as the inside of `[...]`, e.g. `!buffer + $10`
+pub fn evaluate_expression(
+    expression: &str,
+    symbols: &BTreeMap<String, u16>,
+) -> Result<u16, String> {
+    let t = parse_expression(expression, false)?;
+    evaluate(&t, symbols, false)
 }
 
-fn jeq<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_mem("jeq", instruction::JEQ_LIT_MEM),
-        reg_mem("jeq", instruction::JEQ_REG_MEM),
-    ])
+// Like `evaluate_expression` with registers and memory reads as terms, e.g. `byte(ACC) & $3`
+pub fn evaluate_runtime_expression(
+    expression: &str,
+    symbols: &BTreeMap<String, u16>,
+    runtime: &dyn Runtime,
+) -> Result<u16, String> {
+    let t = parse_expression(expression, true)?;
+    evaluate_runtime(&t, symbols, runtime)
 }
 
-fn jne<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_mem("jne", instruction::JNE_LIT_MEM),
-        reg_mem("jne", instruction::JNE_REG_MEM),
-    ])
+fn parse_expression(expression: &str, runtime: bool) -> Result<Type, String> {
+    let input = format!("[{}]", expression);
+    let parser = if runtime {
+        runtime_expression()
+    } else {
+        square_bracket_expression()
+    };
+    match parser.parse(&input) {
+        Ok(ParserState { result, index }) if index == input.len() => Ok(result),
+        Ok(ParserState { index, .. }) | Err(ParseError { index, .. }) => Err(format!(
+            "Could not parse expression {} at index {}",
+            expression,
+            index.max(1) - 1
+        )),
+    }
 }
 
-fn jgt<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_mem("jgt", instruction::JGT_LIT_MEM),
-        reg_mem("jgt", instruction::JGT_REG_MEM),
-    ])
+fn operands(t: &Type) -> Vec<&Type> {
+    match t {
+        Type::Instruction1 { arg0, .. } => vec![arg0],
+        Type::Instruction2 { arg0, arg1, .. } => vec![arg0, arg1],
+        Type::Instruction3 {
+            arg0, arg1, arg2, ..
+        } => vec![arg0, arg1, arg2],
+        _ => vec![],
+    }
 }
 
-fn jlt<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_mem("jlt", instruction::JLT_LIT_MEM),
-        reg_mem("jlt", instruction::JLT_REG_MEM),
-    ])
+// Assigns addresses to the pending literals at `address`, returns the address after the pool
+// The end of the pool, past the end of memory if it doesn't fit. Entries that don't fit wrap
+// around, the caller reports the program as too big.
+fn place_pool(
+    pending: &mut Vec<Type>,
+    address: u16,
+    pools: &mut Vec<Vec<(Type, u16)>>,
+    assembly: &mut Assembly,
+) -> usize {
+    let mut address = address as usize;
+    let mut pool = vec![];
+    for literal in pending.drain(..) {
+        let size = match &literal {
+            // Bad escapes are reported when the pool is encoded
+            Type::StringLiteral(text) => unescape(text).map_or(0, |bytes| bytes.len()) + 1,
+            _ => 2,
+        };
+        assembly.pool.push(PoolEntry {
+            address: address as u16,
+            size: size as u16,
+            literal: printer::pool_literal(&literal),
+        });
+        pool.push((literal, address as u16));
+        address += size;
+    }
+    pools.push(pool);
+    address
 }
 
-fn jle<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_mem("jle", instruction::JLE_LIT_MEM),
-        reg_mem("jle", instruction::JLE_REG_MEM),
-    ])
+// Addresses are 16 bits, so a program can end at 0xffff at the latest
+fn past_memory(end: usize) -> Option<String> {
+    if end > 0xffff {
+        Some(format!(
+            "Program runs past the end of memory, it would end at {:#x}",
+            end
+        ))
+    } else {
+        None
+    }
 }
 
-fn jge<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit_mem("jge", instruction::JGE_LIT_MEM),
-        reg_mem("jge", instruction::JGE_REG_MEM),
-    ])
+// `mov $lit reg` for instructions, their canonical source for anything else
+fn describe(t: &Type) -> String {
+    match t {
+        Type::Instruction0 { instruction }
+        | Type::Instruction1 { instruction, .. }
+        | Type::Instruction2 { instruction, .. }
+        | Type::Instruction3 { instruction, .. } => format!("`{}`", instruction.syntax()),
+        _ => format!("`{}`", printer::item(t)),
+    }
 }
 
-fn psh<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit("psh", instruction::PSH_LIT),
-        reg("psh", instruction::PSH_REG),
-    ])
+// Strings are zero terminated, anything else is a word
+fn encode_pool(
+    pool: &[(Type, u16)],
+    labels: &BTreeMap<String, u16>,
+    options: &Options,
+) -> Result<Vec<u8>, String> {
+    let mut res = vec![];
+    for (literal, _) in pool {
+        match literal {
+            Type::StringLiteral(text) => {
+                res.extend(unescape(text)?);
+                res.push(0);
+            }
+            _ => res.extend(
+                evaluate(literal, labels, options.wrap_expressions)?
+                    .to_be_bytes()
+                    .iter(),
+            ),
+        }
+    }
+    Ok(res)
 }
 
-fn pop<'a>() -> Parser<'a, str, Type> {
-    reg("pop", instruction::POP_REG)
+// Keys and values are stored with a length byte and a NUL after them, see `container::meta_block`
+fn meta_value(meta: &[(String, Vec<u8>)], key: &str, value: &str) -> Result<Vec<u8>, String> {
+    if meta.iter().any(|(other, _)| other == key) {
+        return Err(format!("Metadata key {} is already set", key));
+    }
+    let bytes = unescape(value)?;
+    if key.len() > 0xff || bytes.len() > 0xff {
+        return Err(format!(
+            "Metadata {} is too long, keys and values have at most 255 bytes",
+            key
+        ));
+    }
+    if bytes.contains(&0) {
+        return Err(format!("The value of metadata {} contains a NUL", key));
+    }
+    Ok(bytes)
 }
 
-fn inc<'a>() -> Parser<'a, str, Type> {
-    reg("inc", instruction::INC_REG)
+fn encode_ascii(t: &Type) -> Result<Vec<u8>, String> {
+    let mut res = vec![];
+    if let Type::Ascii {
+        strings,
+        terminated,
+    } = t
+    {
+        for text in strings {
+            res.extend(unescape(text)?);
+        }
+        if *terminated {
+            res.push(0);
+        }
+    }
+    Ok(res)
 }
 
-fn dec<'a>() -> Parser<'a, str, Type> {
-    reg("dec", instruction::DEC_REG)
+fn encode_byte(
+    t: &Type,
+    labels: &BTreeMap<String, u16>,
+    literals: &[(Type, u16)],
+    options: &Options,
+) -> Result<u8, String> {
+    match encode(t, labels, literals, options)?.as_slice() {
+        &[0, low] => Ok(low),
+        _ => Err(format!(
+            "{} does not fit in 8 bits",
+            expression::to_string(t)
+        )),
+    }
 }
 
-fn not<'a>() -> Parser<'a, str, Type> {
-    reg("not", instruction::NOT_REG)
+fn encode(
+    t: &Type,
+    labels: &BTreeMap<String, u16>,
+    literals: &[(Type, u16)],
+    options: &Options,
+) -> Result<Vec<u8>, String> {
+    let res = match t {
+        Type::Instruction0 { instruction }
+        | Type::Instruction1 { instruction, .. }
+        | Type::Instruction2 { instruction, .. }
+        | Type::Instruction3 { instruction, .. } => {
+            let mut res = vec![instruction.opcode];
+            for (arg, &kind) in operands(t).into_iter().zip(instruction.format.operands()) {
+                let bytes = encode(arg, labels, literals, options)?;
+                // Expressions are evaluated to a word, 8-bit operands take its low byte
+                match (kind, bytes.as_slice()) {
+                    (OperandKind::Literal8, &[0, low]) => res.push(low),
+                    (OperandKind::Literal8, &[_, _]) => {
+                        return Err(format!(
+                            "{} does not fit in 8 bits",
+                            expression::to_string(arg)
+                        ))
+                    }
+                    _ => res.extend(bytes),
+                }
+            }
+            res
+        }
+        Type::Opcode { opcode, operands } => {
+            let mut res = vec![];
+            for byte in std::iter::once(opcode.as_ref()).chain(operands) {
+                res.push(encode_byte(byte, labels, literals, options)?);
+            }
+            if !EXTENSION_RANGE.contains(&res[0]) {
+                return Err(format!(
+                    "Opcode ${:x} is outside the extension range ${:x}-${:x}",
+                    res[0],
+                    EXTENSION_RANGE.start(),
+                    EXTENSION_RANGE.end()
+                ));
+            }
+            res
+        }
+        Type::Ascii { .. } => encode_ascii(t)?,
+        Type::Data { wide: true, values } => {
+            let mut res = vec![];
+            for value in values {
+                res.extend(encode(value, labels, literals, options)?);
+            }
+            res
+        }
+        Type::Data {
+            wide: false,
+            values,
+        } => {
+            let mut res = vec![];
+            for value in values {
+                res.push(encode_byte(value, labels, literals, options)?);
+            }
+            res
+        }
+        Type::Mov32 { value, high, low } => {
+            let value = evaluate32(value, labels, options.wrap_expressions)?;
+            let mut res = vec![];
+            for (word, register) in [((value >> 16) as u16, high), (value as u16, low)] {
+                res.push(instruction::MOVE_LIT_REG.opcode);
+                res.extend(word.to_be_bytes());
+                res.extend(encode(register, labels, literals, options)?);
+            }
+            res
+        }
+        Type::BinaryOperation { .. } | Type::Wrap(_) | Type::Variable(_) | Type::Literal32(_) => {
+            evaluate(t, labels, options.wrap_expressions)?
+                .to_be_bytes()
+                .to_vec()
+        }
+        Type::HexLiteral(val) => val.to_be_bytes().to_vec(),
+        Type::HexLiteral8(val) => vec![*val],
+        Type::Address(val) => val.to_be_bytes().to_vec(),
+        Type::FrameOffset { negative, offset } => frame_offset(*negative, offset, labels, options)?
+            .to_be_bytes()
+            .to_vec(),
+        Type::Register(val) => vec![register::number(get_from_string(val))],
+        Type::PoolLiteral(literal) => {
+            let (_, address) = literals.iter().find(|(l, _)| l == &**literal).unwrap();
+            address.to_be_bytes().to_vec()
+        }
+        Type::Operator(_) | Type::StringLiteral(_) | Type::MemoryRead { .. } => {
+            return Err(format!("{} can't be encoded here", describe(t)))
+        }
+        Type::Label(_)
+        | Type::Constant { .. }
+        | Type::RegisterAlias { .. }
+        | Type::Pool
+        | Type::Budget(_)
+        | Type::Meta { .. } => Vec::with_capacity(0),
+    };
+    Ok(res)
 }
 
-fn cal<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        lit("cal", instruction::CAL_LIT),
-        reg("cal", instruction::CAL_REG),
-    ])
+// Two's complement, so an offset reaches $8000 below FP and $7fff above it
+fn frame_offset(
+    negative: bool,
+    offset: &Type,
+    labels: &BTreeMap<String, u16>,
+    options: &Options,
+) -> Result<u16, String> {
+    let value = evaluate(offset, labels, options.wrap_expressions)?;
+    match (negative, value) {
+        (false, 0..=0x7fff) => Ok(value),
+        (true, 0..=0x8000) => Ok(value.wrapping_neg()),
+        _ => Err(format!(
+            "FP {} {} is out of range, offsets go from -$8000 to +$7fff",
+            if negative { "-" } else { "+" },
+            expression::to_string(offset)
+        )),
+    }
 }
 
-fn ret<'a>() -> Parser<'a, str, Type> {
-    no_arg("ret", instruction::RET)
+// An item followed by an optional comment, or a line with at most a comment
+fn source_line<'a>(instructions: Rc<[Instruction]>) -> Parser<'a, str, Line> {
+    let item = assembly_instruction(instructions).left(optional_whitespace());
+    Parser::new(move |input: &str| {
+        let blank = optional_whitespace().parse(input)?.index;
+        let (item, index) = match input[blank..].chars().next() {
+            None | Some(';') => (None, blank),
+            _ => {
+                let state = item.parse(input)?;
+                (Some(state.result), state.index)
+            }
+        };
+        let (comment, index) = match comment().parse_at(input, index) {
+            Ok(state) => (Some(state.result), state.index),
+            Err(_) => (None, index),
+        };
+        Ok(ParserState {
+            index,
+            result: Line { item, comment },
+        })
+    })
 }
 
-fn hlt<'a>() -> Parser<'a, str, Type> {
-    no_arg("hlt", instruction::HLT)
+fn assembly_instruction<'a>(instructions: Rc<[Instruction]>) -> Parser<'a, str, Type> {
+    Parser::one_of(vec![
+        label(),
+        constant(),
+        register_alias(),
+        pool(),
+        budget(),
+        ascii(),
+        data(),
+        opcode(),
+        meta(),
+        mov32(),
+        instruction_of(instructions),
+    ])
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{Diagnostic, Diagnostics, Line, LineInfo, Options, PoolEntry, Position, Type};
+    use crate::cpu::instruction::{Instruction, OperandKind};
+    use crate::cpu::{instruction, register, CPU};
+    use crate::device::memory::Memory;
+
     #[test]
     fn compile() {
         let input = "mov $4200 R1\nmov R1 &AAAA\nmov $1000 R1\nmov &AAAA R2\nadd R1 R2\n";
         assert_eq!(
-            super::compile(input),
+            super::compile(input, &Options::default()).unwrap(),
             vec![
-                0x10, 0x42, 0, 4, 0x12, 4, 0xaa, 0xaa, 0x10, 0x10, 0, 4, 0x13, 0xAA, 0xAA, 6, 0x14,
-                4, 6
+                0x10, 0x42, 0, 2, 0x12, 2, 0xaa, 0xaa, 0x10, 0x10, 0, 2, 0x13, 0xAA, 0xAA, 3, 0x14,
+                2, 3
             ]
         )
     }
 
+    #[test]
+    fn compile_literal_styles() {
+        let input = "mov 16896 R1\nmov %1010 R2\nmov 'A' &[$fe00 + 2]\nadd 0b1 R1\n\
+                     lsf R1 3\nmov =['\\n' * 2] R3\n.opcode 227 %1 'z'\n";
+        assert_eq!(
+            super::compile(input, &Options::default()).unwrap(),
+            super::compile(
+                "mov $4200 R1\nmov $a R2\nmov $41 &[$fe00 + $2]\nadd $1 R1\n\
+                 lsf R1 $3\nmov =[$a * $2] R3\n.opcode $e3 $1 $7a\n",
+                &Options::default()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn constant_expressions() {
+        let input = "mov [$10 * $4 + !end] R1\nmov [$2 + $3 * $4] R2\n\
+                     mov [[$2 + $3] * [$4 - [!end - $c]]] R3\nend:\nhlt\n";
+        let assembly = super::assemble(input, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![0x10, 0x00, 0x4c, 0x02, 0x10, 0x00, 0x0e, 0x03, 0x10, 0x00, 0x14, 0x04, 0xff]
+        );
+        assert_eq!(
+            super::assemble("mov $1 R1\nmov [!end * $2] R2\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 2: Undefined variable: end"
+        );
+    }
+
+    #[test]
+    fn max_size() {
+        let limit = |max_size| Options {
+            max_size: Some(max_size),
+            ..Options::default()
+        };
+        let input = "mov $1 R1\n.ascii \"0123456789\"\nmore:\nmov =\"pooled\" R2\nend:\nhlt\n";
+        assert!(super::assemble(input, &limit(26)).is_ok());
+        assert_eq!(
+            super::assemble(input, &limit(0x10)).unwrap_err(),
+            Diagnostics(vec![Diagnostic::new(
+                4,
+                "Program is 26 bytes, 10 over the limit of 16, the first label past it \
+                 is end at 0x0012"
+                    .to_string()
+            )])
+        );
+        // Only the pool is past the limit
+        assert_eq!(
+            super::assemble(input, &limit(0x14))
+                .unwrap_err()
+                .to_string(),
+            "line 6: Program is 26 bytes, 6 over the limit of 20"
+        );
+    }
+
     #[test]
     fn compile_with_labels() {
         let input = "mov $2345 ACC\nstart:\njeq $4200 &[!start]\n";
         assert_eq!(
-            super::compile(input),
-            vec![0x10, 0x23, 0x45, 0x02, 0x52, 0x42, 0x00, 0x00, 0x04]
+            super::compile(input, &Options::default()).unwrap(),
+            vec![0x10, 0x23, 0x45, 0x01, 0x52, 0x42, 0x00, 0x00, 0x04]
+        )
+    }
+
+    #[test]
+    fn compile_is_reproducible() {
+        let input = "start:\nmov $a R1\nloop:\ndec R1\nmov R1 ACC\njne $0 &[!loop]\ncal [!done]\njeq $0 &[!start]\ndone:\nhlt\n";
+        let golden = vec![
+            0x10, 0x00, 0x0a, 0x02, 0x37, 0x02, 0x11, 0x02, 0x01, 0x50, 0x00, 0x00, 0x00, 0x04,
+            0x19, 0x00, 0x16, 0x52, 0x00, 0x00, 0x00, 0x00, 0xff,
+        ];
+        for _ in 0..100 {
+            assert_eq!(super::compile(input, &Options::default()).unwrap(), golden)
+        }
+    }
+
+    #[test]
+    fn program_past_end_of_memory() {
+        let code = "mov $1 R1\n".repeat(20000);
+        let limited = Options {
+            max_size: Some(0xfe00),
+            ..Options::default()
+        };
+        for options in [Options::default(), limited].iter() {
+            assert_eq!(
+                super::assemble(&code, options).unwrap_err().to_string(),
+                "line 16384: Program runs past the end of memory, it would end at 0x10000"
+            );
+        }
+        assert_eq!(
+            super::assemble(
+                ".word $1 $2\n.pool\n".repeat(0x4000).as_str(),
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 32767: Program runs past the end of memory, it would end at 0x10000"
+        );
+        // Only the final pool is past the end
+        let code = format!("{}mov =$1234 R1\n.word $1\n", "mov $1 R1\n".repeat(16382));
+        assert_eq!(
+            super::assemble(&code, &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 16384: Program runs past the end of memory, it would end at 0x10000"
+        );
+    }
+
+    #[test]
+    fn declaration_order() {
+        // Constants and register aliases emit nothing, so their order can't change the output
+        let declarations = [
+            "const screen = $fe00",
+            "const width = $10",
+            "const last = [!end - $1]",
+            ".regalias counter R3",
+            ".regalias total R4",
+        ];
+        let code = "mov [!width] counter\nloop:\ndec counter\njne $0 &[!loop]\n\
+                    mov [!last] total\nmov $48 &[!screen + !width]\nend:\nhlt\n";
+        let build = |order: &[&str]| {
+            let source = format!("{}\n{}", order.join("\n"), code);
+            let assembly = super::assemble(&source, &Options::default()).unwrap();
+            let debug = assembly.debug_info("prog.asm");
+            (assembly.bytes, debug)
+        };
+        let expected = build(&declarations);
+        for rotation in 0..declarations.len() {
+            let mut order = declarations.to_vec();
+            order.rotate_left(rotation);
+            assert_eq!(build(&order), expected, "{:?}", order);
+            order.reverse();
+            assert_eq!(build(&order), expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn compile_wrapping_expressions() {
+        let wrap = Options {
+            wrap_expressions: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            super::compile("mov [wrap: $ffff + $2] R1\n", &Options::default()).unwrap(),
+            vec![0x10, 0x00, 0x01, 0x02]
+        );
+        assert_eq!(
+            super::compile("mov [$ffff + $2] R1\n", &wrap).unwrap(),
+            vec![0x10, 0x00, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn compile_overflowing_expression() {
+        assert_eq!(
+            super::compile("hlt\nmov [$ffff + $2] R1\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 2: Overflow in [$ffff + $2], use [wrap: ...] or --wrap-expressions to wrap around"
+        );
+    }
+
+    #[test]
+    fn register_aliases() {
+        let input = ".regalias counter R3\n.regalias ptr R7\nmov $5 counter\nmov &ptr counter\n.regalias counter R4\ndec counter\n";
+        assert_eq!(
+            super::compile(input, &Options::default()).unwrap(),
+            vec![0x10, 0x00, 0x05, 0x04, 0x1c, 0x08, 0x04, 0x37, 0x05]
         )
     }
 
+    #[test]
+    fn alias_redefinition_warning() {
+        let code = ".regalias counter R3\n.regalias counter R4\ninc counter\nhlt\n";
+        let assembly = super::assemble(code, &Options::default()).unwrap();
+        assert!(assembly.warnings.is_empty());
+        let options = Options {
+            warn_shadowing: true,
+            ..Options::default()
+        };
+        let assembly = super::assemble(code, &options).unwrap();
+        assert_eq!(
+            assembly
+                .warnings
+                .iter()
+                .map(|warning| warning.to_string())
+                .collect::<Vec<_>>(),
+            vec!["line 2: Register alias counter redefined, was R3"]
+        );
+        assert_eq!(&assembly.bytes[..2], &[0x36, 0x05]);
+    }
+
+    #[test]
+    fn alias_used_before_definition() {
+        assert_eq!(
+            super::compile(
+                "hlt\ninc counter\n.regalias counter R3\n",
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 2: Unknown register or alias: counter"
+        );
+    }
+
+    #[test]
+    fn assemble() {
+        let assembly = super::assemble(
+            "mov $2 R1\nloop:\ndec R1\njne $0 &[!loop]\n",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![0x10, 0x00, 0x02, 0x02, 0x37, 0x02, 0x50, 0x00, 0x00, 0x00, 0x04]
+        );
+        assert_eq!(assembly.symbols, vec![("loop".to_string(), 4)]);
+        assert_eq!(
+            assembly.lines,
+            vec![
+                LineInfo {
+                    line: 1,
+                    address: 0,
+                    instruction: instruction::MOVE_LIT_REG,
+                },
+                LineInfo {
+                    line: 3,
+                    address: 4,
+                    instruction: instruction::DEC_REG,
+                },
+                LineInfo {
+                    line: 4,
+                    address: 6,
+                    instruction: instruction::JNE_LIT_MEM,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let assembly = super::assemble(
+            "; counts down from 2\nmov $2 R1 ; start\n\nloop: ;\ndec R1\n  ; jne $0 &[!loop] hlt: .pool\njne $0 &[!loop];again\n",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![0x10, 0x00, 0x02, 0x02, 0x37, 0x02, 0x50, 0x00, 0x00, 0x00, 0x04]
+        );
+        assert_eq!(assembly.symbols, vec![("loop".to_string(), 4)]);
+        let lines: Vec<u16> = assembly.lines.iter().map(|line| line.line).collect();
+        assert_eq!(lines, vec![2, 5, 7]);
+
+        assert_eq!(
+            super::assemble(".ascii \"a;b\" ; c\n", &Options::default())
+                .unwrap()
+                .bytes,
+            b"a;b".to_vec()
+        );
+        assert_eq!(
+            super::assemble("hlt R1 ; R3\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 5: Unexpected trailing characters: 'R1 ; R3'\n    hlt R1 ; R3\n        ^"
+        );
+    }
+
+    const POOLS: &str ="mov =\"Hi\" R1\nmov =!end R2\n.pool\nmov =\"Hi\" R3\nmov =\"Yo\" R4\nmov =!end R5\nend:\nhlt\n";
+
+    #[test]
+    fn literal_pools() {
+        let assembly = super::assemble(POOLS, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x10, 0x00, 0x08, 0x02, 0x10, 0x00, 0x0b, 0x03, b'H', b'i', 0, 0x00, 0x19, 0x10,
+                0x00, 0x08, 0x04, 0x10, 0x00, 0x1a, 0x05, 0x10, 0x00, 0x0b, 0x06, 0xff, b'Y', b'o',
+                0
+            ]
+        );
+        assert_eq!(assembly.symbols, vec![("end".to_string(), 0x19)]);
+        assert_eq!(
+            assembly.pool,
+            vec![
+                PoolEntry {
+                    address: 0x08,
+                    size: 3,
+                    literal: "=\"Hi\"".to_string(),
+                },
+                PoolEntry {
+                    address: 0x0b,
+                    size: 2,
+                    literal: "=!end".to_string(),
+                },
+                PoolEntry {
+                    address: 0x1a,
+                    size: 3,
+                    literal: "=\"Yo\"".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn listing() {
+        let assembly = super::assemble(POOLS, &Options::default()).unwrap();
+        let listing = assembly.listing(POOLS);
+        let rows: Vec<&str> = listing.lines().collect();
+        assert_eq!(rows.len(), 9);
+        assert_eq!(rows[0], "0000  10 00 08 02              mov =\"Hi\" R1");
+        assert_eq!(rows[2], "0008  48 69 00                 pool =\"Hi\"");
+        assert_eq!(rows[3], "000b  00 19                    pool =!end");
+        assert_eq!(rows[8], "001a  59 6f 00                 pool =\"Yo\"");
+    }
+
+    #[test]
+    fn mov32() {
+        let input = "mov32 $deadbeef R1:R2\n.regalias lo R4\nmov32 [!end * $10000 + $1] R3:lo\n\
+                     end:\nhlt\n";
+        let assembly = super::assemble(input, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x10, 0xde, 0xad, 0x02, 0x10, 0xbe, 0xef, 0x03, 0x10, 0x00, 0x10, 0x04, 0x10, 0x00,
+                0x01, 0x05, 0xff
+            ]
+        );
+        assert_eq!(assembly.symbols, vec![("end".to_string(), 0x10)]);
+        let listing = assembly.listing(input);
+        let rows: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            rows[..2],
+            [
+                "0000  10 de ad 02              mov32 $deadbeef R1:R2 => mov $lit reg",
+                "0004  10 be ef 03              mov32 $deadbeef R1:R2 => mov $lit reg"
+            ]
+        );
+        assert_eq!(
+            super::assemble("mov [$1 + $10000] R1\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1: $10000 does not fit in 16 bits"
+        );
+        assert_eq!(
+            super::assemble("mov32 $100000000 R1:R2\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 1: Could not parse\n    mov32 $100000000 R1:R2\n    ^"
+        );
+    }
+
+    #[test]
+    fn mov32_add() {
+        // R5:R6 = R1:R2 + R3:R4, a sum below the low word means it carried
+        let input = "mov32 $deadbeef R1:R2\nmov32 $1f000 R3:R4\n\
+                     add R2 R4\nmov ACC R6\njge R2 &[!nocarry]\ninc R1\n\
+                     nocarry:\nadd R1 R3\nmov ACC R5\nhlt\n";
+        let assembly = super::assemble(input, &Options::default()).unwrap();
+        let memory = Memory::with_contents(0x100, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R5), 0xdeaf);
+        assert_eq!(cpu.get_register(register::R6), 0xaeef);
+    }
+
+    #[test]
+    fn aliases() {
+        let options = Options::default();
+        for (alias, canonical) in [("halt", "hlt"), ("clr R4", "mov $0 R4")].iter() {
+            assert_eq!(
+                super::compile(&format!("{}\n", alias), &options).unwrap(),
+                super::compile(&format!("{}\n", canonical), &options).unwrap(),
+                "{}",
+                alias
+            );
+        }
+
+        let code = "clr R1\nmov $0 R1\n";
+        let listing = super::assemble(code, &options).unwrap().listing(code);
+        let rows: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            rows[0],
+            "0000  10 00 00 02              clr R1 => mov $lit reg"
+        );
+        assert_eq!(rows[1], "0004  10 00 00 02              mov $0 R1");
+    }
+
+    #[test]
+    fn lit8_operands() {
+        let input = "mov $3 R1\nlsf R1 $2\ninc R1\nrsf R1 [$1 + $1]\nhlt\n";
+        let bytes = super::compile(input, &Options::default()).unwrap();
+        assert_eq!(
+            bytes,
+            vec![0x10, 0x00, 0x03, 0x02, 0x40, 0x02, 0x02, 0x36, 0x02, 0x42, 0x02, 0x02, 0xff]
+        );
+
+        let memory = Memory::with_contents(0x100, &bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 0x3);
+        assert_eq!(cpu.get_register(register::IP), 0x0d);
+
+        assert_eq!(
+            super::assemble("lsf R1 $100\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1: $100 does not fit in 8 bits"
+        );
+    }
+
+    #[test]
+    fn assemble_one() {
+        let mut symbols = super::SymbolTable::new();
+        symbols.insert("loop".to_string(), 0x10);
+        symbols.insert("size".to_string(), 0x4);
+        // Jump targets are absolute, so the address doesn't change the bytes
+        for &at in &[0x0, 0x200] {
+            assert_eq!(
+                super::assemble_one("jne $0 &[!loop]", at, &symbols),
+                Ok(vec![0x50, 0x00, 0x00, 0x00, 0x10])
+            );
+        }
+        assert_eq!(
+            super::assemble_one("lsf R1 [!size * $2]\n", 0, &symbols),
+            Ok(vec![0x40, 0x02, 0x08])
+        );
+
+        let error = |src: &str, at: u16| {
+            super::assemble_one(src, at, &symbols)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("jmp &[!nope]", 0), "line 1: Undefined variable: nope");
+        assert_eq!(
+            error("lsf R1 [!loop * $10]", 0),
+            "line 1: [!loop * $10] does not fit in 8 bits"
+        );
+        assert_eq!(
+            error("hlt\nhlt\n", 0),
+            "line 1: Expected a single instruction"
+        );
+        assert_eq!(error("; nothing", 0), "line 1: Expected an instruction");
+        assert_eq!(
+            error("start:", 0),
+            "line 1: `start:` needs the whole program"
+        );
+        assert_eq!(
+            error("mov =$5 R1", 0),
+            "line 1: =$5 needs the whole program"
+        );
+        assert_eq!(
+            error("mov $1 count", 0),
+            "line 1: Unknown register or alias: count"
+        );
+        assert_eq!(
+            error("mov $1 R1", 0xfffe),
+            "line 1: 4 bytes at 0xfffe run past the end of memory"
+        );
+        assert_eq!(
+            error("hlt R1", 0),
+            "line 1, column 5: Unexpected trailing characters: 'R1'\n    hlt R1\n        ^"
+        );
+    }
+
+    #[test]
+    fn frame_offsets() {
+        let input = "const size = $4\nmov &[FP + [!size * $2]] R1\nmov R1 &[FP - $8000]\n";
+        assert_eq!(
+            super::compile(input, &Options::default()).unwrap(),
+            vec![0x1f, 0x00, 0x08, 0x02, 0x20, 0x02, 0x80, 0x00]
+        );
+        assert_eq!(
+            super::assemble("mov &[FP + $8000] R1\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1: FP + $8000 is out of range, offsets go from -$8000 to +$7fff"
+        );
+        assert_eq!(
+            super::assemble("mov R1 &[FP - $8001]\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1: FP - $8001 is out of range, offsets go from -$8000 to +$7fff"
+        );
+    }
+
+    #[test]
+    fn extension_opcodes() {
+        assert_eq!(
+            super::compile(".opcode $e3 $4 $ff\nhlt\n", &Options::default()).unwrap(),
+            vec![0xe3, 0x04, 0xff, 0xff]
+        );
+        assert_eq!(
+            super::assemble(".opcode $10\n.opcode $e0 $100\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1: Opcode $10 is outside the extension range $e0-$ef\nline 2: $100 does not fit in 8 bits"
+        );
+    }
+
+    const BUDGETED: &str = "cal [!draw]\nhlt\n.budget $10\ndraw:\nmov $1 R1\nmov R1 &800\nret\n\
+                            .budget $8\nbeep:\nmov =\"Hi\" R2\nret\n";
+
+    #[test]
+    fn budgets() {
+        let assembly = super::assemble(BUDGETED, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.size_report(),
+            "code: 21 bytes\n\
+             region at line 1: 0x0000-0x0004, 4 bytes\n\
+             region at line 3: 0x0004-0x000d, 9 bytes, budget 16, 7 free\n\
+             region at line 8: 0x000d-0x0015, 8 bytes, budget 8, 0 free\n\
+             largest routines:\n  draw             9 bytes\n  beep             8 bytes\n"
+        );
+
+        assert_eq!(
+            super::assemble(&BUDGETED.replace("$8", "$6"), &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 8: Region is 8 bytes, 2 over its budget of 6"
+        );
+    }
+
+    #[test]
+    fn meta() {
+        let assembly = super::assemble(
+            ".meta version \"1.2\"\nhlt\n.meta built \"2026\\x2d10\"\n",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(assembly.bytes, vec![0xff]);
+        assert_eq!(
+            assembly.meta,
+            vec![
+                ("version".to_string(), b"1.2".to_vec()),
+                ("built".to_string(), b"2026-10".to_vec())
+            ]
+        );
+        assert_eq!(
+            super::assemble(
+                ".meta version \"1\"\n.meta version \"2\"\n.meta name \"a\\0\"\n",
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 2: Metadata key version is already set\n\
+             line 3: The value of metadata name contains a NUL"
+        );
+        assert_eq!(
+            super::assemble(
+                &format!(".meta name \"{}\"\n", "a".repeat(256)),
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 1: Metadata name is too long, keys and values have at most 255 bytes"
+        );
+    }
+
+    #[test]
+    fn ascii() {
+        assert_eq!(
+            super::compile(
+                ".ascii \"a\\nb\\t\\0\\\\\\\"\\x7f\"\n.ascii \"line1\\n\" \"line2\"\n.asciiz \"z\"\n",
+                &Options::default()
+            ).unwrap(),
+            b"a\nb\t\0\\\"\x7fline1\nline2z\0".to_vec()
+        );
+        assert_eq!(
+            super::assemble(
+                "mov [!end] R1\n.ascii \"\\q\"\n.asciiz \"\\x4\"\nend:\n",
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 2: Invalid escape '\\q' in \"\\q\"\n\
+             line 3: Invalid escape '\\x4' in \"\\x4\", expected two hex digits"
+        );
+
+        let code = "mov [!msg] R4\nloop:\nmov &R4 ACC\nrsf ACC $8\njeq $0 &[!done]\n\
+                    mov R4 R1\nsys $2\ninc R4\njne $0 &[!loop]\ndone:\nhlt\n\
+                    msg:\n.asciiz \"Hi\\t\\x21\\n\"\n";
+        let memory =
+            Memory::with_contents(0x100, &super::compile(code, &Options::default()).unwrap());
+        let mut cpu = CPU::new(Box::new(memory));
+        let output = Rc::new(RefCell::new(vec![]));
+        let written = output.clone();
+        cpu.register_syscall(
+            2,
+            Box::new(move |cpu: &mut CPU| {
+                let address = cpu.get_register(register::R1) as usize;
+                written.borrow_mut().push(cpu.memory().get_u8(address));
+            }),
+        );
+        cpu.run().unwrap();
+        assert_eq!(output.borrow().as_slice(), b"Hi\t!\n");
+    }
+
+    #[test]
+    fn data() {
+        let code = "mov [!msg] R1\nmov [!table + $1] R2\nhlt\n\
+                    table:\n.byte $1, $2 'c' [!table + $10]\n\
+                    .word !msg,[!msg + $1]\ndata8 $ff\ndata16 $abcd\n\
+                    msg:\n.ascii \"Hi\"\n";
+        let assembly = super::assemble(code, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.symbols,
+            vec![("table".to_string(), 0x09), ("msg".to_string(), 0x14)]
+        );
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x10, 0x00, 0x14, 0x02, 0x10, 0x00, 0x0a, 0x03, 0xff, 0x01, 0x02, b'c', 0x19, 0x00,
+                0x14, 0x00, 0x15, 0xff, 0xab, 0xcd, b'H', b'i'
+            ]
+        );
+        assert_eq!(
+            super::assemble("hlt\n.byte $1 $100\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 2: $100 does not fit in 8 bits"
+        );
+        assert_eq!(
+            super::assemble(".word\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 1: Could not parse\n    .word\n    ^"
+        );
+    }
+
+    #[test]
+    fn constants() {
+        // Used before and after the definition, in arithmetic and from another constant
+        let code = "mov $48 &[!screen + !width * $2]\nconst screen = $fe00\n\
+                    const width=%10000\nconst last = [!screen + !width - $1]\n\
+                    mov [!last] R1\nend:\nconst size = [!end]\n";
+        let assembly = super::assemble(code, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![0x09, 0x00, 0x48, 0xfe, 0x20, 0x10, 0xfe, 0x0f, 0x02]
+        );
+        assert_eq!(assembly.symbols, vec![("end".to_string(), 9)]);
+
+        assert_eq!(
+            super::assemble(
+                "const a = $1\nb:\nconst a = $2\nconst b = $3\nb:\nconst c = [!d]\nconst d = $1\n",
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 3: a is already defined on line 1\n\
+             line 4: b is already defined on line 2\n\
+             line 5: b is already defined on line 2\n\
+             line 6: Undefined variable: d"
+        );
+    }
+
+    #[test]
+    fn assemble_reports_every_error() {
+        assert_eq!(
+            super::assemble("mov [!a] R1\nhlt\nmov [!b] R2\n", &Options::default()),
+            Err(Diagnostics(vec![
+                Diagnostic::new(1, "Undefined variable: a".to_string()),
+                Diagnostic::new(3, "Undefined variable: b".to_string()),
+            ]))
+        );
+        assert_eq!(
+            super::assemble("hlt\nmov $1 R1 R2 R3\nhlt R1\nmov\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 2, column 14: Unexpected trailing characters: 'R3'\n\
+             \x20   mov $1 R1 R2 R3\n\
+             \x20                ^\n\
+             line 3, column 5: Unexpected trailing characters: 'R1'\n\
+             \x20   hlt R1\n\
+             \x20       ^\n\
+             line 4, column 1: Could not parse\n\
+             \x20   mov\n\
+             \x20   ^"
+        );
+        assert_eq!(
+            super::assemble("mov [$1 + $2 R1\n\tmov [$1 +] R1\n", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 5: Unclosed '['\n\
+             \x20   mov [$1 + $2 R1\n\
+             \x20       ^\n\
+             line 2, column 10: Expected a term after '+'\n\
+             \x20   \tmov [$1 +] R1\n\
+             \x20   \t        ^"
+        );
+    }
+
+    #[test]
+    fn syntax_error_position() {
+        let code = "start:\nmov $1 R1\nadd R1 [$2 * ]\nhlt\n";
+        assert_eq!(
+            super::compile(code, &Options::default()).unwrap_err(),
+            Diagnostics(vec![Diagnostic {
+                line: 3,
+                message: "Expected a term after '*'".to_string(),
+                position: Some(Position {
+                    column: 12,
+                    text: "add R1 [$2 * ]".to_string(),
+                }),
+            }])
+        );
+        assert_eq!(
+            super::compile("hlt", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 4: Expected a newline at the end of the file\n    hlt\n       ^"
+        );
+    }
+
+    #[test]
+    fn non_ascii_columns() {
+        assert_eq!(
+            super::assemble(
+                "ü:\n.ascii \"héllo\" R1 ; naïve\nmov [!ü +] R1\n",
+                &Options::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "line 2, column 16: Unexpected trailing characters: 'R1 ; naïve'\n\
+             \x20   .ascii \"héllo\" R1 ; naïve\n\
+             \x20                  ^\n\
+             line 3, column 9: Expected a term after '+'\n\
+             \x20   mov [!ü +] R1\n\
+             \x20           ^"
+        );
+        assert_eq!(
+            super::compile("hlt ; ⚠", &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 8: Expected a newline at the end of the file\n    hlt ; ⚠\n           ^"
+        );
+
+        // Multi-byte characters anywhere in valid lines give errors, never a panic
+        for code in [
+            "mov [$1 + !x] R1\nx:\nhlt\n",
+            "mov &[FP - $4] R1\nhlt ; c\n",
+            ".word $1 'a'\n.ascii \"ab\"\n.meta v \"a\"\n",
+        ]
+        .iter()
+        {
+            for (index, _) in code.char_indices() {
+                for c in ["é", "€", "😀"].iter() {
+                    let code = format!("{}{}{}", &code[..index], c, &code[index..]);
+                    let _ = super::assemble(&code, &Options::default());
+                    let _ = super::format(&code);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interrupts() {
+        let input = "mov [!handler] &1006\nint $3\nhlt\nhandler:\nmov $42 &0800\nrti\n";
+        let assembly = super::assemble(input, &Options::default()).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x09, 0x00, 0x09, 0x10, 0x06, 0x00, 0x00, 0x03, 0xff, 0x09, 0x00, 0x42, 0x08, 0x00,
+                0x01
+            ]
+        );
+        assert_eq!(assembly.symbols, vec![("handler".to_string(), 0x09)]);
+
+        let memory = Memory::with_contents(0x2000, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        assert_eq!(cpu.run().unwrap().ip, 0x08);
+        assert_eq!(cpu.memory().get_u16(0x0800), 0x42);
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let mut lines = super::parse("mov $1 R1\nend:\nhlt\n", super::builtin()).unwrap();
+        if let Some(Type::Instruction2 { instruction, .. }) = &mut lines[0].item {
+            // A format that reserves more than its operands encode to
+            *instruction = Instruction {
+                size: 5,
+                ..instruction::MOVE_LIT_REG
+            };
+        }
+        assert_eq!(
+            super::assemble_lines(lines, &Options::default())
+                .unwrap_err()
+                .to_string(),
+            "line 1: Internal error: `mov $lit reg` encoded to 4 bytes, 5 were reserved for it"
+        );
+    }
+
+    #[test]
+    fn sizes_match_every_format() {
+        for instruction in instruction::LIST.iter() {
+            let args: Vec<Box<Type>> = instruction
+                .format
+                .operands()
+                .iter()
+                .map(|kind| {
+                    Box::new(match kind {
+                        OperandKind::Literal => Type::HexLiteral(0x1234),
+                        OperandKind::Literal8 => Type::HexLiteral(0x12),
+                        OperandKind::Register | OperandKind::RegisterIndirect => {
+                            Type::Register("R2".to_string())
+                        }
+                        OperandKind::Address => Type::Address(0x1234),
+                        OperandKind::FrameOffset => Type::FrameOffset {
+                            negative: true,
+                            offset: Box::new(Type::HexLiteral(0x12)),
+                        },
+                    })
+                })
+                .collect();
+            let instruction = *instruction;
+            let t = match args.as_slice() {
+                [] => Type::Instruction0 { instruction },
+                [arg0] => Type::Instruction1 {
+                    instruction,
+                    arg0: arg0.clone(),
+                },
+                [arg0, arg1] => Type::Instruction2 {
+                    instruction,
+                    arg0: arg0.clone(),
+                    arg1: arg1.clone(),
+                },
+                [arg0, arg1, arg2] => Type::Instruction3 {
+                    instruction,
+                    arg0: arg0.clone(),
+                    arg1: arg1.clone(),
+                    arg2: arg2.clone(),
+                },
+                _ => unreachable!(),
+            };
+            let line = Line {
+                item: Some(t),
+                comment: None,
+            };
+            let assembly = super::assemble_lines(vec![line], &Options::default())
+                .unwrap_or_else(|diagnostics| panic!("{}", diagnostics));
+            assert_eq!(
+                assembly.bytes.len(),
+                instruction.size as usize,
+                "{}",
+                instruction.syntax()
+            );
+        }
+    }
+
     #[test]
     fn mov() {
         let input = vec![
@@ -312,7 +1899,13 @@ mod tests {
             "mov $aa R3 R1",
         ];
         for line in input {
-            assert!(super::mov().parse(line).is_ok(), line)
+            assert!(
+                super::assembly_instruction(super::builtin())
+                    .parse(line)
+                    .is_ok(),
+                "{}",
+                line
+            )
         }
     }
 }
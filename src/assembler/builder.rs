@@ -0,0 +1,192 @@
+// An assembler that knows more instructions than the built-in ones, for embedders that register
+// CPU extensions. Custom instructions take an opcode from the extension range and operands of
+// a form some built-in instruction already has, so they are parsed and encoded the same way.
+use std::rc::Rc;
+
+use super::formats::ALIASES;
+use super::{assemble_lines, parse, Assembly, Diagnostics, Options};
+use crate::cpu::extension::EXTENSION_RANGE;
+use crate::cpu::instruction::{self, Instruction, OperandKind};
+
+pub struct AssemblerBuilder {
+    options: Options,
+    instructions: Vec<Instruction>,
+}
+
+impl AssemblerBuilder {
+    pub fn new() -> AssemblerBuilder {
+        AssemblerBuilder {
+            options: Options::default(),
+            instructions: instruction::LIST.to_vec(),
+        }
+    }
+
+    pub fn options(mut self, options: Options) -> AssemblerBuilder {
+        self.options = options;
+        self
+    }
+
+    // `frob reg $lit` is `instruction("frob", &[OperandKind::Register, OperandKind::Literal], op)`
+    pub fn instruction(
+        mut self,
+        mnemonic: &'static str,
+        operands: &[OperandKind],
+        opcode: u8,
+    ) -> Result<AssemblerBuilder, String> {
+        if mnemonic.is_empty() || !mnemonic.chars().all(char::is_alphabetic) {
+            return Err(format!("Mnemonic {:?} has to be made of letters", mnemonic));
+        }
+        if self.instructions.iter().any(|i| i.mnemonic == mnemonic)
+            || ALIASES.iter().any(|alias| alias.name == mnemonic)
+        {
+            return Err(format!("Mnemonic {} is already taken", mnemonic));
+        }
+        if !EXTENSION_RANGE.contains(&opcode) {
+            return Err(format!(
+                "Opcode {:#04x} is outside the extension range {:#04x}..={:#04x}",
+                opcode,
+                EXTENSION_RANGE.start(),
+                EXTENSION_RANGE.end()
+            ));
+        }
+        if let Some(other) = self.instructions.iter().find(|i| i.opcode == opcode) {
+            return Err(format!(
+                "Opcode {:#04x} is already taken by {}",
+                opcode, other.mnemonic
+            ));
+        }
+        let format = instruction::LIST
+            .iter()
+            .map(|i| i.format)
+            .find(|format| format.operands() == operands)
+            .ok_or_else(|| format!("No instruction takes the operands {:?}", operands))?;
+        self.instructions.push(Instruction {
+            mnemonic,
+            opcode,
+            format,
+            size: format.size(),
+            cycles: format.size(),
+            description: "Extension instruction",
+        });
+        Ok(self)
+    }
+
+    pub fn build(self) -> Assembler {
+        Assembler {
+            options: self.options,
+            instructions: Rc::from(self.instructions),
+        }
+    }
+}
+
+impl Default for AssemblerBuilder {
+    fn default() -> AssemblerBuilder {
+        AssemblerBuilder::new()
+    }
+}
+
+pub struct Assembler {
+    options: Options,
+    instructions: Rc<[Instruction]>,
+}
+
+impl Assembler {
+    pub fn assemble(&self, code: &str) -> Result<Assembly, Diagnostics> {
+        assemble_lines(parse(code, self.instructions.clone())?, &self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AssemblerBuilder;
+    use crate::cpu::extension::{CpuView, StepOutcome};
+    use crate::cpu::instruction::OperandKind;
+    use crate::cpu::{register, CPU};
+    use crate::device::memory::Memory;
+
+    #[test]
+    fn custom_instruction() {
+        // frob reg $lit multiplies the register by the literal
+        let assembler = AssemblerBuilder::new()
+            .instruction("frob", &[OperandKind::Register, OperandKind::Literal], 0xe5)
+            .unwrap()
+            .build();
+        let assembly = assembler
+            .assemble("mov $3 R1\nfrob R1 $5\nfrob R1 [$1 + $1]\nhlt\n")
+            .unwrap();
+        assert_eq!(
+            assembly.bytes[4..12],
+            [0xe5, 0x02, 0x00, 0x05, 0xe5, 0x02, 0x00, 0x02]
+        );
+        assert_eq!(
+            assembly
+                .listing("mov $3 R1\nfrob R1 $5\nfrob R1 [$1 + $1]\nhlt\n")
+                .lines()
+                .nth(1),
+            Some("0004  e5 02 00 05              frob R1 $5")
+        );
+
+        let memory = Memory::with_contents(0x100, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        cpu.register_extension(
+            0xe5,
+            Box::new(|cpu: &mut CpuView| {
+                let reg = register::from_byte(cpu.fetch8()).unwrap();
+                let factor = cpu.fetch16();
+                cpu.set_register(reg, cpu.get_register(reg) * factor);
+                StepOutcome::Continue
+            }),
+        )
+        .unwrap();
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 30);
+
+        assert_eq!(
+            assembler.assemble("frob $5 R1\n").unwrap_err().to_string(),
+            "line 1, column 1: Could not parse\n    frob $5 R1\n    ^"
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        let error = |mnemonic, operands: &[OperandKind], opcode| {
+            AssemblerBuilder::new()
+                .instruction("frob", &[OperandKind::Register], 0xe0)
+                .and_then(|builder| builder.instruction(mnemonic, operands, opcode))
+                .err()
+        };
+        assert_eq!(
+            error("mov", &[OperandKind::Register], 0xe1),
+            Some("Mnemonic mov is already taken".to_string())
+        );
+        assert_eq!(
+            error("frob", &[OperandKind::Literal], 0xe1),
+            Some("Mnemonic frob is already taken".to_string())
+        );
+        assert_eq!(
+            error("clr", &[OperandKind::Register], 0xe1),
+            Some("Mnemonic clr is already taken".to_string())
+        );
+        assert_eq!(
+            error("twiddle", &[OperandKind::Register], 0xe0),
+            Some("Opcode 0xe0 is already taken by frob".to_string())
+        );
+        assert_eq!(
+            error("twiddle", &[OperandKind::Register], 0x10),
+            Some("Opcode 0x10 is outside the extension range 0xe0..=0xef".to_string())
+        );
+        assert_eq!(
+            error("twiddle2", &[OperandKind::Register], 0xe1),
+            Some("Mnemonic \"twiddle2\" has to be made of letters".to_string())
+        );
+        assert_eq!(
+            error(
+                "twiddle",
+                &[OperandKind::Address, OperandKind::Address],
+                0xe1
+            ),
+            Some("No instruction takes the operands [Address, Address]".to_string())
+        );
+        assert!(error("twiddle", &[], 0xe1).is_none());
+    }
+}
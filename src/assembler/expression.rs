@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use super::parser::{Operator, Type};
+
+// Folds a bracketed expression into a single word.
+// Overflow is an error unless the expression is written as `[wrap: ...]` or `wrap` is set
+// for the whole program, in which case the result is the low 16 bits.
+pub fn evaluate(t: &Type, labels: &BTreeMap<String, u16>, wrap: bool) -> Result<u16, String> {
+    fold(t, labels, wrap, u16::MAX as u32, None).map(|value| value as u16)
+}
+
+// Like `evaluate` with 32 bit arithmetic and literals, for `mov32`
+pub fn evaluate32(t: &Type, labels: &BTreeMap<String, u16>, wrap: bool) -> Result<u32, String> {
+    fold(t, labels, wrap, u32::MAX, None)
+}
+
+// The registers and memory a runtime expression reads
+pub trait Runtime {
+    fn register(&self, name: &str) -> u16;
+    fn read(&self, address: u16, wide: bool) -> Result<u16, String>;
+}
+
+// Like `evaluate` for expressions that read registers and memory
+pub fn evaluate_runtime(
+    t: &Type,
+    labels: &BTreeMap<String, u16>,
+    runtime: &dyn Runtime,
+) -> Result<u16, String> {
+    fold(t, labels, false, u16::MAX as u32, Some(runtime)).map(|value| value as u16)
+}
+
+// `max` is the largest value of the result's width, all ones
+fn fold(
+    t: &Type,
+    labels: &BTreeMap<String, u16>,
+    wrap: bool,
+    max: u32,
+    runtime: Option<&dyn Runtime>,
+) -> Result<u32, String> {
+    match t {
+        Type::HexLiteral(val) | Type::Address(val) => Ok(*val as u32),
+        Type::Literal32(val) if *val <= max => Ok(*val),
+        Type::Literal32(val) => Err(format!("${:x} does not fit in 16 bits", val)),
+        Type::Variable(name) => labels
+            .get(name)
+            .map(|&address| address as u32)
+            .ok_or_else(|| format!("Undefined variable: {}", name)),
+        Type::Wrap(expression) => fold(expression, labels, true, max, runtime),
+        Type::Register(_) | Type::MemoryRead { .. } if runtime.is_none() => Err(format!(
+            "{} can only be read from a running program",
+            to_string(t)
+        )),
+        Type::Register(name) => Ok(runtime.unwrap().register(name) as u32),
+        Type::MemoryRead { wide, address } => {
+            let address = fold(address, labels, wrap, max, runtime)?;
+            Ok(runtime.unwrap().read(address as u16, *wide)? as u32)
+        }
+        Type::BinaryOperation { op, a, b } => {
+            let a = fold(a, labels, wrap, max, runtime)? as u64;
+            let b = fold(b, labels, wrap, max, runtime)? as u64;
+            let result = match op.as_ref() {
+                Type::Operator(Operator::Plus) => a + b,
+                Type::Operator(Operator::Minus) => a.wrapping_sub(b),
+                Type::Operator(Operator::Star) => a * b,
+                Type::Operator(Operator::Slash) if b == 0 => {
+                    return Err(format!("Division by zero in {}", to_string(t)))
+                }
+                Type::Operator(Operator::Slash) => a / b,
+                Type::Operator(Operator::Ampersand) => a & b,
+                _ => return Err(format!("Unexpected operator: {:?}", op)),
+            };
+            if result > max as u64 && !wrap {
+                Err(format!(
+                    "Overflow in {}, use [wrap: ...] or --wrap-expressions to wrap around",
+                    to_string(t)
+                ))
+            } else {
+                Ok((result & max as u64) as u32)
+            }
+        }
+        _ => Err(format!("Not an expression: {:?}", t)),
+    }
+}
+
+pub fn to_string(t: &Type) -> String {
+    match t {
+        Type::HexLiteral(val) => format!("${:x}", val),
+        Type::Literal32(val) => format!("${:x}", val),
+        Type::Address(val) => format!("&{:x}", val),
+        Type::Variable(name) => format!("!{}", name),
+        Type::Register(name) => name.clone(),
+        Type::MemoryRead { wide, address } => format!(
+            "{}({})",
+            if *wide { "word" } else { "byte" },
+            to_string(address)
+        ),
+        Type::Wrap(expression) => match to_string(expression).strip_prefix('[') {
+            Some(inner) => format!("[wrap: {}", inner),
+            None => format!("[wrap: {}]", to_string(expression)),
+        },
+        Type::FrameOffset { negative, offset } => match offset.as_ref() {
+            Type::HexLiteral(0) => String::from("[FP]"),
+            _ => format!(
+                "[FP {} {}]",
+                if *negative { "-" } else { "+" },
+                to_string(offset)
+            ),
+        },
+        Type::BinaryOperation { op, a, b } => format!(
+            "[{} {} {}]",
+            to_string(a),
+            match op.as_ref() {
+                Type::Operator(Operator::Plus) => "+",
+                Type::Operator(Operator::Minus) => "-",
+                Type::Operator(Operator::Star) => "*",
+                Type::Operator(Operator::Slash) => "/",
+                Type::Operator(Operator::Ampersand) => "&",
+                _ => "?",
+            },
+            to_string(b)
+        ),
+        _ => format!("{:?}", t),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::super::parser::{runtime_expression, square_bracket_expression};
+    use super::{evaluate, evaluate_runtime, Runtime};
+
+    fn eval(input: &str, wrap: bool) -> Result<u16, String> {
+        let mut labels = BTreeMap::new();
+        labels.insert("top".to_string(), 0xfff0);
+        evaluate(
+            &square_bracket_expression().parse(input).unwrap().result,
+            &labels,
+            wrap,
+        )
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("[$2 + $3 * $4]", false), Ok(14));
+        assert_eq!(eval("[!top + $f]", false), Ok(0xffff));
+        assert_eq!(eval("[$10 / $3]", false), Ok(5));
+        assert_eq!(
+            eval("[!nope + $1]", false),
+            Err("Undefined variable: nope".to_string())
+        );
+    }
+
+    #[test]
+    fn ampersand() {
+        // Looser than `+`, so this is $f & $13
+        assert_eq!(eval("[$f & $10 + $3]", false), Ok(3));
+        assert_eq!(eval("[!top & $ff]", false), Ok(0xf0));
+    }
+
+    struct Machine;
+
+    impl Runtime for Machine {
+        fn register(&self, name: &str) -> u16 {
+            name.len() as u16
+        }
+
+        fn read(&self, address: u16, wide: bool) -> Result<u16, String> {
+            if wide {
+                Ok(address * 2)
+            } else {
+                Err(format!("No byte at ${:x}", address))
+            }
+        }
+    }
+
+    #[test]
+    fn runtime_terms() {
+        let labels = BTreeMap::new();
+        let eval = |input: &str| {
+            let t = runtime_expression().parse(input).unwrap().result;
+            evaluate_runtime(&t, &labels, &Machine)
+        };
+        assert_eq!(eval("[word(ACC + $1) & $e]"), Ok(8));
+        assert_eq!(eval("[word( [R1] )]"), Ok(4));
+        assert_eq!(eval("[byte($20)]"), Err("No byte at $20".to_string()));
+        assert!(runtime_expression().parse("[R9]").is_err());
+
+        // Outside the debugger these terms don't parse, and can't be evaluated
+        assert!(square_bracket_expression().parse("[ACC]").is_err());
+        let t = runtime_expression().parse("[ACC]").unwrap().result;
+        assert_eq!(
+            evaluate(&t, &labels, false),
+            Err("ACC can only be read from a running program".to_string())
+        );
+    }
+
+    #[test]
+    fn overflowing_add() {
+        assert_eq!(
+            eval("[$ffff + $2]", false),
+            Err(
+                "Overflow in [$ffff + $2], use [wrap: ...] or --wrap-expressions to wrap around"
+                    .to_string()
+            )
+        );
+        assert_eq!(eval("[$ffff + $2]", true), Ok(1));
+        assert_eq!(eval("[wrap: $ffff + $2]", false), Ok(1));
+        assert_eq!(eval("[wrap: [!top + $20] - $1]", false), Ok(0xf));
+    }
+
+    #[test]
+    fn overflowing_mul() {
+        assert!(eval("[$100 * $100]", false).is_err());
+        assert_eq!(eval("[$100 * $101]", true), Ok(0x100));
+        assert_eq!(eval("[wrap: $8001 * $2]", false), Ok(2));
+    }
+
+    #[test]
+    fn underflowing_sub() {
+        assert!(eval("[$1 - $2]", false).is_err());
+        assert_eq!(eval("[$1 - $2]", true), Ok(0xffff));
+        assert_eq!(eval("[wrap: $0 - $10]", false), Ok(0xfff0));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(
+            eval("[wrap: $1 / $0]", false),
+            Err("Division by zero in [$1 / $0]".to_string())
+        );
+    }
+}
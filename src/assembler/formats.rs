@@ -1,135 +1,336 @@
+use std::rc::Rc;
+
 use super::parser::{
-    address, hex_literal, hex_literal8, register, square_bracket_expression, Type,
+    frame_offset, literal, pool_literal, register, square_bracket_expression, variable,
+    wide_literal, Type,
 };
-use crate::cpu::instruction::Instruction;
-use crate::parser_combinator::core::Parser;
+use crate::cpu::instruction::{Instruction, OperandKind};
+use crate::parser_combinator::core::{ParseError, Parser, ParserState};
 use crate::parser_combinator::string;
 
-pub fn lit_reg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), hex_or_exp(), register())
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Operand {
+    Literal(Type),
+    Address(Type),
+    Register(Type),
+    RegisterIndirect(Type),
+    FrameOffset(Type),
+    Expression(Type),
+    PoolLiteral(Type),
 }
 
-pub fn reg_lit<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), register(), hex_or_exp())
+impl Operand {
+    fn fits(&self, kind: OperandKind) -> bool {
+        matches!(
+            (self, kind),
+            (Operand::Literal(_), OperandKind::Literal)
+                | (Operand::Literal(_), OperandKind::Literal8)
+                | (Operand::Expression(_), OperandKind::Literal)
+                | (Operand::Expression(_), OperandKind::Literal8)
+                | (Operand::PoolLiteral(_), OperandKind::Literal)
+                | (Operand::Address(_), OperandKind::Address)
+                | (Operand::Register(_), OperandKind::Register)
+                | (Operand::RegisterIndirect(_), OperandKind::RegisterIndirect)
+                | (Operand::FrameOffset(_), OperandKind::FrameOffset)
+        )
+    }
+
+    fn into_type(self) -> Type {
+        match self {
+            Operand::Literal(t)
+            | Operand::Address(t)
+            | Operand::Register(t)
+            | Operand::RegisterIndirect(t)
+            | Operand::FrameOffset(t)
+            | Operand::Expression(t)
+            | Operand::PoolLiteral(t) => t,
+        }
+    }
 }
 
-pub fn reg_lit8<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), register(), hex_or_exp())
+#[cfg(test)]
+pub fn instruction<'a>() -> Parser<'a, str, Type> {
+    instruction_of(Rc::from(&crate::cpu::instruction::LIST[..]))
 }
 
-pub fn lit_off_reg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    Parser::interspersed(
-        string::whitespace(),
-        vec![com(command), hex_or_exp(), register(), register()],
-    )
-    .map(move |mut res| {
-        let third = res.remove(3);
-        let second = res.remove(2);
-        let first = res.remove(1);
-        Type::Instruction3 {
-            instruction,
-            arg0: Box::new(first),
-            arg1: Box::new(second),
-            arg2: Box::new(third),
+// Parses a mnemonic followed by any operands and picks the instruction of `instructions` whose
+// operand kinds match. If all of them together fit no instruction, the longest prefix that does is
+// taken, so the rest is left over and reported as trailing characters.
+pub fn instruction_of<'a>(instructions: Rc<[Instruction]>) -> Parser<'a, str, Type> {
+    let mnemonic = string::alphabetic();
+    let next = string::whitespace().right(operand());
+    Parser::new(move |input: &'a str| {
+        let mnemonic = mnemonic.parse(input)?;
+        let mut operands = vec![];
+        let mut ends = vec![mnemonic.index];
+        while let Ok(state) = next.parse_at(input, *ends.last().unwrap()) {
+            operands.push(state.result);
+            ends.push(state.index);
         }
-    })
-}
 
-pub fn reg_reg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), register(), register())
+        let error = match select(&instructions, &mnemonic.result, operands.clone()) {
+            Ok(result) => {
+                return Ok(ParserState {
+                    index: *ends.last().unwrap(),
+                    result,
+                })
+            }
+            Err(error) => error,
+        };
+        (0..operands.len())
+            .rev()
+            .find_map(|count| {
+                select(&instructions, &mnemonic.result, operands[..count].to_vec())
+                    .ok()
+                    .map(|result| ParserState {
+                        index: ends[count],
+                        result,
+                    })
+            })
+            .ok_or_else(|| ParseError::new(error))
+    })
 }
 
-pub fn mem_reg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), address_or_exp(), register())
+pub fn operand<'a>() -> Parser<'a, str, Operand> {
+    let expression = square_bracket_expression();
+    let register = whole_word(register());
+    let alias = whole_word(alias());
+    Parser::one_of(vec![
+        literal().map(Operand::Literal),
+        expression.clone().map(Operand::Expression),
+        pool_literal().map(Operand::PoolLiteral),
+        string::character('&').right(ampersand_operand(
+            expression,
+            register.clone(),
+            alias.clone(),
+        )),
+        register.map(Operand::Register),
+        // Any other word may be a register alias, checked once aliases are known
+        alias.map(Operand::Register),
+    ])
 }
 
-pub fn reg_mem<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), register(), address_or_exp())
+// After `&` a bracket starts an offset from FP or else an address expression. Words are registers or aliases unless
+// they are made only of hex digits, so `&ACC` is a register and `&ACCD` an address.
+fn ampersand_operand<'a>(
+    expression: Parser<'a, str, Type>,
+    register: Parser<'a, str, Type>,
+    alias: Parser<'a, str, Type>,
+) -> Parser<'a, str, Operand> {
+    Parser::one_of(vec![
+        frame_offset().map(Operand::FrameOffset),
+        string::character('[')
+            .peek()
+            .right(expression)
+            .map(Operand::Address),
+        register.map(Operand::RegisterIndirect),
+        alias.map(Operand::RegisterIndirect),
+        whole_word(hexadecimal_address()).map(Operand::Address),
+    ])
 }
 
-pub fn lit_mem<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(instruction, com(command), hex_or_exp(), address_or_exp())
+// `mov32 $deadbeef R1:R2` loads a 32 bit value into a register pair as two `mov $lit reg`, the
+// high word into the first register and the low word into the second
+pub fn mov32<'a>() -> Parser<'a, str, Type> {
+    let value = Parser::one_of(vec![
+        wide_literal(),
+        square_bracket_expression(),
+        variable(),
+    ]);
+    let register = Parser::one_of(vec![whole_word(register()), whole_word(alias())]);
+    Parser::new(move |input: &'a str| {
+        let index = string::literal(String::from("mov32"))
+            .left(string::whitespace())
+            .parse(input)?
+            .index;
+        let value = value.parse_at(input, index)?;
+        let index = string::whitespace().parse_at(input, value.index)?.index;
+        let high = register.parse_at(input, index)?;
+        let index = string::character(':').parse_at(input, high.index)?.index;
+        let low = register.parse_at(input, index)?;
+        Ok(ParserState {
+            index: low.index,
+            result: Type::Mov32 {
+                value: Box::new(value.result),
+                high: Box::new(high.result),
+                low: Box::new(low.result),
+            },
+        })
+    })
 }
 
-pub fn reg_ptr_reg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    instruction2(
-        instruction,
-        com(command),
-        string::character('&').right(register()),
-        register(),
-    )
+// One operand of an alias expansion
+#[derive(Debug, Clone, Copy)]
+pub enum Slot {
+    // The operand written at this position after the alias
+    Operand(usize),
+    Literal(u16),
 }
 
-pub fn lit<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    Parser::interspersed(string::whitespace(), vec![com(command), hex_or_exp()])
-        .map(move |res| to_instruction1(instruction, res))
+// A mnemonic that stands for another one. Without a template the operands are passed on as
+// written, otherwise the template lists the operands of the canonical instruction.
+pub struct Alias {
+    pub name: &'static str,
+    pub mnemonic: &'static str,
+    pub template: Option<&'static [Slot]>,
 }
 
-pub fn reg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    Parser::interspersed(string::whitespace(), vec![com(command), register()])
-        .map(move |res| to_instruction1(instruction, res))
-}
+pub const ALIASES: [Alias; 2] = [
+    Alias {
+        name: "halt",
+        mnemonic: "hlt",
+        template: None,
+    },
+    Alias {
+        name: "clr",
+        mnemonic: "mov",
+        template: Some(&[Slot::Literal(0), Slot::Operand(0)]),
+    },
+];
 
-pub fn no_arg<'a>(command: &str, instruction: Instruction) -> Parser<'a, str, Type> {
-    string::literal(command.to_string()).map(move |_| Type::Instruction0 { instruction })
+fn expand(alias: &Alias, operands: Vec<Operand>) -> Result<Vec<Operand>, String> {
+    let template = match alias.template {
+        Some(template) => template,
+        None => return Ok(operands),
+    };
+    let arity = template
+        .iter()
+        .filter(|slot| matches!(slot, Slot::Operand(_)))
+        .count();
+    if operands.len() != arity {
+        return Err(format!(
+            "Invalid operands for {}, expected {} operand{}",
+            alias.name,
+            arity,
+            if arity == 1 { "" } else { "s" }
+        ));
+    }
+    Ok(template
+        .iter()
+        .map(|slot| match *slot {
+            Slot::Operand(index) => operands[index].clone(),
+            Slot::Literal(value) => Operand::Literal(Type::HexLiteral(value)),
+        })
+        .collect())
 }
 
-fn instruction2<'a>(
-    instruction: Instruction,
-    command: Parser<'a, str, Type>,
-    a: Parser<'a, str, Type>,
-    b: Parser<'a, str, Type>,
-) -> Parser<'a, str, Type> {
-    Parser::interspersed(string::whitespace(), vec![command, a, b])
-        .map(move |res| to_instruction2(instruction, res))
-}
+// Aliases are resolved before the operands are matched against the instruction formats
+fn select(
+    instructions: &[Instruction],
+    mnemonic: &str,
+    operands: Vec<Operand>,
+) -> Result<Type, String> {
+    if let Some(alias) = ALIASES.iter().find(|alias| alias.name == mnemonic) {
+        return select(instructions, alias.mnemonic, expand(alias, operands)?);
+    }
+    let candidates: Vec<&Instruction> = instructions
+        .iter()
+        .filter(|instruction| instruction.mnemonic == mnemonic)
+        .collect();
+    if candidates.is_empty() {
+        return Err(format!("Unknown instruction: {}", mnemonic));
+    }
 
-fn hex_or_exp<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![hex_literal(), square_bracket_expression()])
-}
+    let instruction = candidates.iter().find(|instruction| {
+        let kinds = instruction.format.operands();
+        kinds.len() == operands.len()
+            && operands
+                .iter()
+                .zip(kinds.iter())
+                .all(|(operand, &kind)| operand.fits(kind))
+    });
 
-fn hex8_or_exp<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![hex_literal8(), square_bracket_expression()])
+    match instruction {
+        Some(&&instruction) => Ok(to_instruction(instruction, operands)),
+        None => Err(format!(
+            "Invalid operands for {}, supported forms are:\n{}",
+            mnemonic,
+            candidates
+                .iter()
+                .map(|instruction| format!("\t{}\n", instruction.syntax()))
+                .collect::<String>()
+        )),
+    }
 }
 
-fn address_or_exp<'a>() -> Parser<'a, str, Type> {
-    Parser::one_of(vec![
-        address(),
-        string::character('&').right(square_bracket_expression()),
-    ])
+fn to_instruction(instruction: Instruction, operands: Vec<Operand>) -> Type {
+    let mut args = operands
+        .into_iter()
+        .zip(instruction.format.operands().iter())
+        .map(|(operand, &kind)| match (operand.into_type(), kind) {
+            // Larger literals are left for the encoder to report
+            (Type::HexLiteral(value), OperandKind::Literal8) if value <= 0xff => {
+                Box::new(Type::HexLiteral8(value as u8))
+            }
+            (t, _) => Box::new(t),
+        });
+    match instruction.format.operands().len() {
+        0 => Type::Instruction0 { instruction },
+        1 => Type::Instruction1 {
+            instruction,
+            arg0: args.next().unwrap(),
+        },
+        2 => Type::Instruction2 {
+            instruction,
+            arg0: args.next().unwrap(),
+            arg1: args.next().unwrap(),
+        },
+        _ => Type::Instruction3 {
+            instruction,
+            arg0: args.next().unwrap(),
+            arg1: args.next().unwrap(),
+            arg2: args.next().unwrap(),
+        },
+    }
 }
 
-fn com<'a>(command: &str) -> Parser<'a, str, Type> {
-    string::literal(command.to_string()).map(|_| Type::Ignored)
+// Makes sure that e.g. `&ACCD` is not read as the register ACC followed by garbage
+fn whole_word<'a>(parser: Parser<'a, str, Type>) -> Parser<'a, str, Type> {
+    parser.not_followed_by(Parser::new(|input: &str| match input.chars().next() {
+        Some(c) if c.is_alphanumeric() => Ok(ParserState {
+            index: c.len_utf8(),
+            result: c,
+        }),
+        _ => Err(ParseError::new("Not alphanumeric".to_string())),
+    }))
 }
 
-fn to_instruction1(instruction: Instruction, mut parsed_instruction: Vec<Type>) -> Type {
-    let first = parsed_instruction.remove(1);
-    Type::Instruction1 {
-        instruction,
-        arg0: Box::new(first),
-    }
+// Words made only of hex digits are addresses after `&`, so aliases can't look like one
+fn alias<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let state = string::alphabetic().parse(input)?;
+        if state.result.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::new(format!(
+                "{} is not a register",
+                state.result
+            )));
+        }
+        Ok(ParserState {
+            index: state.index,
+            result: Type::Register(state.result),
+        })
+    })
 }
 
-fn to_instruction2(instruction: Instruction, mut parsed_instruction: Vec<Type>) -> Type {
-    let second = parsed_instruction.remove(2);
-    let first = parsed_instruction.remove(1);
-    Type::Instruction2 {
-        instruction,
-        arg0: Box::new(first),
-        arg1: Box::new(second),
-    }
+fn hexadecimal_address<'a>() -> Parser<'a, str, Type> {
+    string::hexadecimal().map(|hex| {
+        Type::Address(
+            u16::from_str_radix(&hex, 16)
+                .unwrap_or_else(|_| panic!("Couldn't parse hexadecimal: {}", hex)),
+        )
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{Operand, Type};
     use crate::cpu::instruction;
-    use crate::parser_combinator::core::ParserState;
+    use crate::parser_combinator::core::{ParseError, ParserState};
 
     #[test]
     fn lit_reg() {
         assert_eq!(
-            super::lit_reg("mov", instruction::MOVE_LIT_REG).parse("mov $aa12 R1"),
+            super::instruction().parse("mov $aa12 R1"),
             Ok(ParserState {
                 index: 12,
                 result: super::Type::Instruction2 {
@@ -140,7 +341,7 @@ mod tests {
             })
         );
         assert_eq!(
-            super::lit_reg("mov", instruction::MOVE_LIT_REG).parse("mov [$aa12 + !a] R1"),
+            super::instruction().parse("mov [$aa12 + !a] R1"),
             Ok(ParserState {
                 index: 19,
                 result: super::Type::Instruction2 {
@@ -159,7 +360,7 @@ mod tests {
     #[test]
     fn lit_off_reg() {
         assert_eq!(
-            super::lit_off_reg("mov", instruction::MOVE_LIT_OFF_REG).parse("mov $aa12 R3 R1"),
+            super::instruction().parse("mov $aa12 R3 R1"),
             Ok(ParserState {
                 index: 15,
                 result: super::Type::Instruction3 {
@@ -175,7 +376,7 @@ mod tests {
     #[test]
     fn reg_reg() {
         assert_eq!(
-            super::reg_reg("mov", instruction::MOVE_REG_REG).parse("mov R2 R1"),
+            super::instruction().parse("mov R2 R1"),
             Ok(ParserState {
                 index: 9,
                 result: super::Type::Instruction2 {
@@ -190,7 +391,7 @@ mod tests {
     #[test]
     fn mem_reg() {
         assert_eq!(
-            super::mem_reg("mov", instruction::MOVE_MEM_REG).parse("mov &123 R1"),
+            super::instruction().parse("mov &123 R1"),
             Ok(ParserState {
                 index: 11,
                 result: super::Type::Instruction2 {
@@ -201,7 +402,7 @@ mod tests {
             })
         );
         assert_eq!(
-            super::mem_reg("mov", instruction::MOVE_MEM_REG).parse("mov &[$aa12 + !a] R1"),
+            super::instruction().parse("mov &[$aa12 + !a] R1"),
             Ok(ParserState {
                 index: 20,
                 result: super::Type::Instruction2 {
@@ -220,7 +421,7 @@ mod tests {
     #[test]
     fn reg_mem() {
         assert_eq!(
-            super::reg_mem("mov", instruction::MOVE_REG_MEM).parse("mov R1 &123"),
+            super::instruction().parse("mov R1 &123"),
             Ok(ParserState {
                 index: 11,
                 result: super::Type::Instruction2 {
@@ -231,7 +432,7 @@ mod tests {
             })
         );
         assert_eq!(
-            super::reg_mem("mov", instruction::MOVE_REG_MEM).parse("mov R1 &[$aa12 + !a]"),
+            super::instruction().parse("mov R1 &[$aa12 + !a]"),
             Ok(ParserState {
                 index: 20,
                 result: super::Type::Instruction2 {
@@ -250,7 +451,7 @@ mod tests {
     #[test]
     fn lit_mem() {
         assert_eq!(
-            super::lit_mem("mov", instruction::MOVE_LIT_MEM).parse("mov $aa12 &12"),
+            super::instruction().parse("mov $aa12 &12"),
             Ok(ParserState {
                 index: 13,
                 result: super::Type::Instruction2 {
@@ -265,7 +466,7 @@ mod tests {
     #[test]
     fn reg_ptr_reg() {
         assert_eq!(
-            super::reg_ptr_reg("mov", instruction::MOVE_REG_PTR_REG).parse("mov &R2 R1"),
+            super::instruction().parse("mov &R2 R1"),
             Ok(ParserState {
                 index: 10,
                 result: super::Type::Instruction2 {
@@ -277,14 +478,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fp_off() {
+        assert_eq!(
+            super::instruction().parse("mov &[FP + $4] R1"),
+            Ok(ParserState {
+                index: 17,
+                result: super::Type::Instruction2 {
+                    instruction: instruction::MOVE_FP_OFF_REG,
+                    arg0: Box::new(super::Type::FrameOffset {
+                        negative: false,
+                        offset: Box::new(super::Type::HexLiteral(4)),
+                    }),
+                    arg1: Box::new(super::Type::Register("R1".to_string())),
+                },
+            })
+        );
+        assert_eq!(
+            super::instruction().parse("mov R1 &[FP-!x]"),
+            Ok(ParserState {
+                index: 15,
+                result: super::Type::Instruction2 {
+                    instruction: instruction::MOVE_REG_FP_OFF,
+                    arg0: Box::new(super::Type::Register("R1".to_string())),
+                    arg1: Box::new(super::Type::FrameOffset {
+                        negative: true,
+                        offset: Box::new(super::Type::Variable("x".to_string())),
+                    }),
+                },
+            })
+        );
+        // Without FP it is still an address expression
+        assert!(super::instruction().parse("mov &[FP] $1").is_err());
+    }
+
     #[test]
     fn lit() {
         assert_eq!(
-            super::lit("mov", instruction::MOVE_LIT_MEM).parse("mov $aa12"),
+            super::instruction().parse("psh $aa12"),
             Ok(ParserState {
                 index: 9,
                 result: super::Type::Instruction1 {
-                    instruction: instruction::MOVE_LIT_MEM,
+                    instruction: instruction::PSH_LIT,
                     arg0: Box::new(super::Type::HexLiteral(43538)),
                 },
             })
@@ -294,11 +529,11 @@ mod tests {
     #[test]
     fn reg() {
         assert_eq!(
-            super::reg("mov", instruction::MOVE_LIT_MEM).parse("mov R5"),
+            super::instruction().parse("psh R5"),
             Ok(ParserState {
                 index: 6,
                 result: super::Type::Instruction1 {
-                    instruction: instruction::MOVE_LIT_MEM,
+                    instruction: instruction::PSH_REG,
                     arg0: Box::new(super::Type::Register("R5".to_string())),
                 },
             })
@@ -308,13 +543,95 @@ mod tests {
     #[test]
     fn no_arg() {
         assert_eq!(
-            super::no_arg("mov", instruction::MOVE_LIT_MEM).parse("mov"),
+            super::instruction().parse("ret"),
             Ok(ParserState {
                 index: 3,
                 result: super::Type::Instruction0 {
-                    instruction: instruction::MOVE_LIT_MEM,
+                    instruction: instruction::RET,
                 },
             })
         );
     }
+
+    #[test]
+    fn ambiguous_ampersand_operands() {
+        assert_eq!(
+            super::operand().parse("&ACC"),
+            Ok(ParserState {
+                index: 4,
+                result: Operand::RegisterIndirect(Type::Register("ACC".to_string())),
+            })
+        );
+        assert_eq!(
+            super::operand().parse("&ACCD"),
+            Ok(ParserState {
+                index: 5,
+                result: Operand::Address(Type::Address(0xaccd)),
+            })
+        );
+        assert_eq!(
+            super::operand().parse("&12"),
+            Ok(ParserState {
+                index: 3,
+                result: Operand::Address(Type::Address(0x12)),
+            })
+        );
+        assert_eq!(
+            super::instruction()
+                .parse("mov $1 &[!a]")
+                .map(|state| state.result),
+            Ok(Type::Instruction2 {
+                instruction: instruction::MOVE_LIT_MEM,
+                arg0: Box::new(Type::HexLiteral(1)),
+                arg1: Box::new(Type::Variable("a".to_string())),
+            })
+        );
+        assert_eq!(
+            super::instruction()
+                .parse("mov &[!a] R1")
+                .map(|state| state.result),
+            Ok(Type::Instruction2 {
+                instruction: instruction::MOVE_MEM_REG,
+                arg0: Box::new(Type::Variable("a".to_string())),
+                arg1: Box::new(Type::Register("R1".to_string())),
+            })
+        );
+    }
+
+    #[test]
+    fn unsupported_operands() {
+        assert_eq!(
+            super::instruction().parse("psh &12"),
+            Err(ParseError::new(
+                "Invalid operands for psh, supported forms are:\n\tpsh $lit\n\tpsh reg\n"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            super::instruction().parse("jump $12"),
+            Err(ParseError::new("Unknown instruction: jump".to_string()))
+        );
+        assert_eq!(
+            super::instruction().parse("clr"),
+            Err(ParseError::new(
+                "Invalid operands for clr, expected 1 operand".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn extra_operands() {
+        assert_eq!(
+            super::instruction()
+                .parse("mov R1 R2 R3")
+                .map(|state| state.index),
+            Ok(9)
+        );
+        assert_eq!(
+            super::instruction()
+                .parse("hlt R1")
+                .map(|state| state.index),
+            Ok(3)
+        );
+    }
 }
@@ -1,4 +1,5 @@
 use crate::cpu::instruction::Instruction;
+use crate::cpu::register;
 use crate::parser_combinator::core::{ParseError, Parser, ParserState};
 use crate::parser_combinator::string;
 
@@ -7,77 +8,337 @@ pub enum Operator {
     Plus,
     Minus,
     Star,
+    Slash,
+    Ampersand,
 }
 
+const UNCLOSED_BRACKET: &str = "Unclosed '['";
+
+// `[...]` with an optional `wrap:` after the bracket. Terms are literals, variables and nested
+// brackets, `*` and `/` bind tighter than `+` and `-`, which bind tighter than `&`, and all of
+// them associate to the left. Spaces and tabs are optional everywhere inside the brackets.
 pub fn square_bracket_expression<'a>() -> Parser<'a, str, Type> {
-    Parser::new(|input| {
+    bracket(false)
+}
+
+// A bracket expression for the debugger, evaluated against a running program. Its terms can also
+// be register names and memory reads, `word(...)` and `byte(...)`.
+pub fn runtime_expression<'a>() -> Parser<'a, str, Type> {
+    bracket(true)
+}
+
+fn bracket<'a>(runtime: bool) -> Parser<'a, str, Type> {
+    Parser::new(move |input: &str| {
         let mut index = string::character('[').parse(input)?.index;
-        index = string::optional_whitespace().parse_at(input, index)?.index;
+        index = string::optional_blanks().parse_at(input, index)?.index;
 
-        let mut result = vec![];
-        let mut expect_operator = false;
+        let wrap = string::literal(String::from("wrap:"))
+            .parse_at(input, index)
+            .is_ok();
+        if wrap {
+            index = string::optional_blanks()
+                .parse_at(input, index + "wrap:".len())?
+                .index;
+        }
 
-        loop {
-            if expect_operator {
-                match input.chars().nth(index) {
-                    Some(']') => {
-                        index = string::character(']').parse_at(input, index)?.index;
-                        break;
-                    }
-                    None => {
-                        return Err(ParseError {
-                            message: "EOL".to_string(),
-                            index,
-                        })
-                    }
-                    _ => {
-                        let state = operator().parse_at(input, index)?;
-                        index = string::optional_whitespace()
-                            .parse_at(input, state.index)?
-                            .index;
-                        expect_operator = false;
-                        result.push(state.result);
-                    }
-                }
+        let state = conjunction(runtime).parse_at(input, index)?;
+        index = string::optional_blanks()
+            .parse_at(input, state.index)?
+            .index;
+        if !input[index..].starts_with(']') {
+            return Err(unexpected(input, index, runtime));
+        }
+
+        Ok(ParserState {
+            index: index + 1,
+            result: if wrap {
+                Type::Wrap(Box::new(state.result))
             } else {
-                let state =
-                    Parser::one_of(vec![square_bracket_expression(), hex_literal(), variable()])
-                        .parse_at(input, index)?;
-                result.push(state.result);
-                index = string::optional_whitespace()
+                state.result
+            },
+        })
+    })
+}
+
+// `[FP + $4]`, `[FP - [!size * $2]]` or just `[FP]`
+pub fn frame_offset<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let mut index = string::character('[')
+            .left(string::optional_blanks())
+            .left(string::literal(String::from("FP")))
+            .left(string::optional_blanks())
+            .parse(input)?
+            .index;
+        let (negative, offset) = match input[index..].chars().next() {
+            Some(sign @ '+') | Some(sign @ '-') => {
+                index = string::optional_blanks().parse_at(input, index + 1)?.index;
+                let state = sum(false).parse_at(input, index)?;
+                index = string::optional_blanks()
                     .parse_at(input, state.index)?
                     .index;
-                expect_operator = true;
+                (sign == '-', state.result)
             }
+            _ => (false, Type::HexLiteral(0)),
+        };
+        if !input[index..].starts_with(']') {
+            return Err(unexpected(input, index, false));
+        }
+        Ok(ParserState {
+            index: index + 1,
+            result: Type::FrameOffset {
+                negative,
+                offset: Box::new(offset),
+            },
+        })
+    })
+}
+
+fn conjunction<'a>(runtime: bool) -> Parser<'a, str, Type> {
+    sum(runtime).chainl1(operator_of(0), binary_operation)
+}
+
+fn sum<'a>(runtime: bool) -> Parser<'a, str, Type> {
+    product(runtime).chainl1(operator_of(1), binary_operation)
+}
+
+fn product<'a>(runtime: bool) -> Parser<'a, str, Type> {
+    term(runtime).chainl1(operator_of(2), binary_operation)
+}
+
+// Errors in a nested bracket are its own, not those of the other terms
+fn term<'a>(runtime: bool) -> Parser<'a, str, Type> {
+    Parser::new(move |input: &str| {
+        if input.starts_with('[') {
+            bracket(runtime).parse(input)
+        } else if runtime {
+            Parser::one_of(vec![
+                wide_literal(),
+                variable(),
+                memory_read(),
+                register_name(),
+            ])
+            .parse(input)
+        } else {
+            Parser::one_of(vec![wide_literal(), variable()]).parse(input)
         }
+    })
+}
 
+// `word(address)` or `byte(address)`, the address is a runtime expression without the brackets
+fn memory_read<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let wide = Parser::one_of(vec![
+            string::literal(String::from("word")),
+            string::literal(String::from("byte")),
+        ])
+        .map(|function| function == "word")
+        .left(string::optional_blanks())
+        .left(string::character('('))
+        .left(string::optional_blanks())
+        .parse(input)?;
+        let address = conjunction(true).parse_at(input, wide.index)?;
+        let index = string::optional_blanks()
+            .left(string::character(')'))
+            .parse_at(input, address.index)?
+            .index;
         Ok(ParserState {
             index,
-            result: group_binary_operations(result),
+            result: Type::MemoryRead {
+                wide: wide.result,
+                address: Box::new(address.result),
+            },
         })
     })
 }
 
-pub fn hex_literal<'a>() -> Parser<'a, str, Type> {
-    string::character('$')
-        .right(string::hexadecimal())
-        .map(|hex| {
-            Type::HexLiteral(
-                u16::from_str_radix(&hex, 16)
-                    .expect(&format!("Couldn't parse hexadecimal: {}", hex)),
-            )
+// Any register the CPU has, not just the ones instructions can name
+fn register_name<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let length = input
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(input.len());
+        match register::LIST
+            .iter()
+            .map(|&reg| register::name(reg))
+            .find(|&name| name == &input[..length])
+        {
+            Some(name) => Ok(ParserState {
+                index: length,
+                result: Type::Register(name.to_string()),
+            }),
+            None => Err(ParseError::new(format!(
+                "{} is not a register",
+                &input[..length]
+            ))),
+        }
+    })
+}
+
+fn operator_of<'a>(priority: usize) -> Parser<'a, str, Type> {
+    string::optional_blanks()
+        .right(operator())
+        .and_then(move |state| match state.result {
+            Type::Operator(op) if op.priority() == priority => Ok(state),
+            _ => Err(ParseError::new(String::from("Wrong operator"))),
         })
+        .left(string::optional_blanks())
 }
 
-pub fn hex_literal8<'a>() -> Parser<'a, str, Type> {
-    string::character('$')
-        .right(string::hexadecimal())
-        .map(|hex| {
-            Type::HexLiteral8(
-                u8::from_str_radix(&hex, 16)
-                    .expect(&format!("Couldn't parse hexadecimal: {}", hex)),
-            )
+fn binary_operation(a: Type, op: Type, b: Type) -> Type {
+    Type::BinaryOperation {
+        op: Box::new(op),
+        a: Box::new(a),
+        b: Box::new(b),
+    }
+}
+
+// Why the expression stopped before `index`: an operator without a term after it, or anything
+// else where the `]` should be
+fn unexpected(input: &str, index: usize, runtime: bool) -> ParseError {
+    if let Ok(state) = operator()
+        .left(string::optional_blanks())
+        .parse_at(input, index)
+    {
+        match term(runtime).parse_at(input, state.index) {
+            Err(error) if input[state.index..].starts_with('[') => error,
+            _ => ParseError {
+                message: format!("Expected a term after '{}'", &input[index..index + 1]),
+                index,
+            },
+        }
+    } else {
+        ParseError {
+            message: String::from(UNCLOSED_BRACKET),
+            index: 0,
+        }
+    }
+}
+
+// The first bracket expression on a line that doesn't parse and the index to point at, the opening
+// bracket if it isn't closed. Strings and the comment are skipped.
+pub fn bracket_error(line: &str) -> Option<(String, usize)> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut skip_to = 0;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            _ if index < skip_to => {}
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, ';') => break,
+            (None, '[') => match frame_offset()
+                .parse_at(line, index)
+                .or_else(|_| square_bracket_expression().parse_at(line, index))
+            {
+                Ok(state) => skip_to = state.index,
+                Err(error) if error.message.contains('\n') => return None,
+                Err(error) => return Some((error.message, error.index)),
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+pub fn hex_literal<'a>() -> Parser<'a, str, Type> {
+    number(string::character('$').right(string::hexadecimal()), 16)
+}
+
+// `123`
+pub fn decimal_literal<'a>() -> Parser<'a, str, Type> {
+    number(string::digits(10), 10)
+}
+
+// `%1010` or `0b1010`
+pub fn binary_literal<'a>() -> Parser<'a, str, Type> {
+    let prefix = Parser::one_of(vec![
+        string::character('%'),
+        string::literal(String::from("0b")),
+    ]);
+    number(prefix.right(string::digits(2)), 2)
+}
+
+fn number<'a>(digits: Parser<'a, str, String>, radix: u32) -> Parser<'a, str, Type> {
+    Parser::new(move |input: &str| {
+        let state = digits.parse(input)?;
+        match u16::from_str_radix(&state.result, radix) {
+            Ok(value) => Ok(ParserState {
+                index: state.index,
+                result: Type::HexLiteral(value),
+            }),
+            Err(_) => Err(ParseError::new(format!(
+                "{} does not fit in 16 bits",
+                &input[..state.index]
+            ))),
+        }
+    })
+}
+
+// `'A'` is the ASCII code of the character, with the escapes of `string::quoted`
+pub fn char_literal<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let state = string::quoted('\'').parse(input)?;
+        let mut chars = state.result.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => Ok(ParserState {
+                index: state.index,
+                result: Type::HexLiteral(c as u16),
+            }),
+            _ => Err(ParseError::new(format!(
+                "{} is not a single ASCII character",
+                &input[..state.index]
+            ))),
+        }
+    })
+}
+
+// Any literal, binary before decimal so the `0` of `0b` isn't taken for a number
+pub fn literal<'a>() -> Parser<'a, str, Type> {
+    Parser::one_of(vec![
+        hex_literal(),
+        binary_literal(),
+        decimal_literal(),
+        char_literal(),
+    ])
+}
+
+// A literal of up to 32 bits, for `mov32` and the terms of expressions. Values that fit in 16 bits
+// are plain literals, wider ones are only accepted where a 32 bit result is expected.
+pub fn wide_literal<'a>() -> Parser<'a, str, Type> {
+    let digits = Parser::one_of(vec![
+        string::character('$')
+            .right(string::hexadecimal())
+            .map(|digits| (digits, 16)),
+        Parser::one_of(vec![
+            string::character('%'),
+            string::literal(String::from("0b")),
+        ])
+        .right(string::digits(2))
+        .map(|digits| (digits, 2)),
+        string::digits(10).map(|digits| (digits, 10)),
+    ]);
+    let number = Parser::new(move |input: &str| {
+        let state = digits.parse(input)?;
+        let (digits, radix) = &state.result;
+        let result = match u32::from_str_radix(digits, *radix) {
+            Ok(value) if value <= 0xffff => Type::HexLiteral(value as u16),
+            Ok(value) => Type::Literal32(value),
+            Err(_) => {
+                return Err(ParseError::new(format!(
+                    "{} does not fit in 32 bits",
+                    &input[..state.index]
+                )))
+            }
+        };
+        Ok(ParserState {
+            index: state.index,
+            result,
         })
+    });
+    Parser::one_of(vec![number, char_literal()])
 }
 
 fn operator<'a>() -> Parser<'a, str, Type> {
@@ -85,33 +346,26 @@ fn operator<'a>() -> Parser<'a, str, Type> {
         string::character('+'),
         string::character('-'),
         string::character('*'),
+        string::character('/'),
+        string::character('&'),
     ])
     .map(|op| match op.chars().next().unwrap() {
         '+' => Type::Operator(Operator::Plus),
         '-' => Type::Operator(Operator::Minus),
         '*' => Type::Operator(Operator::Star),
+        '/' => Type::Operator(Operator::Slash),
+        '&' => Type::Operator(Operator::Ampersand),
         _ => panic!(),
     })
 }
 
-pub fn address<'a>() -> Parser<'a, str, Type> {
-    string::character('&')
-        .right(string::hexadecimal())
-        .map(|hex| {
-            Type::Address(
-                u16::from_str_radix(&hex, 16)
-                    .expect(&format!("Couldn't parse hexadecimal: {}", hex)),
-            )
-        })
-}
-
 pub fn label<'a>() -> Parser<'a, str, Type> {
     string::alphabetic()
         .left(string::character(':'))
         .map(Type::Label)
 }
 
-fn variable<'a>() -> Parser<'a, str, Type> {
+pub fn variable<'a>() -> Parser<'a, str, Type> {
     string::character('!')
         .right(string::alphabetic())
         .map(Type::Variable)
@@ -123,36 +377,246 @@ impl Operator {
             Operator::Plus => 1,
             Operator::Minus => 1,
             Operator::Star => 2,
+            Operator::Slash => 2,
+            Operator::Ampersand => 0,
         }
     }
 }
 
-fn group_binary_operations<'a>(mut expression: Vec<Type>) -> Type {
-    if expression.len() == 1 {
-        return expression.remove(0);
-    }
+// `.regalias name R1` lets later operands write `name` instead of the register
+pub fn register_alias<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let index = string::literal(String::from(".regalias"))
+            .left(string::whitespace())
+            .parse(input)?
+            .index;
+        let name = string::alphabetic()
+            .left(string::whitespace())
+            .parse_at(input, index)?;
+        let register = register().parse_at(input, name.index)?;
+        Ok(ParserState {
+            index: register.index,
+            result: Type::RegisterAlias {
+                name: name.result,
+                register: Box::new(register.result),
+            },
+        })
+    })
+}
+
+// `const screen = $fe00` names a value for `!screen`, like a label does for an address. The value
+// is a literal or an expression over labels and the constants above it.
+pub fn constant<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let index = string::literal(String::from("const"))
+            .left(string::whitespace())
+            .parse(input)?
+            .index;
+        let name = string::alphabetic()
+            .left(string::optional_blanks())
+            .left(string::character('='))
+            .left(string::optional_blanks())
+            .parse_at(input, index)?;
+        let value = Parser::one_of(vec![literal(), square_bracket_expression(), variable()])
+            .parse_at(input, name.index)?;
+        Ok(ParserState {
+            index: value.index,
+            result: Type::Constant {
+                name: name.result,
+                value: Box::new(value.result),
+            },
+        })
+    })
+}
+
+// `=` places a string or word in the literal pool, the operand is then the address of the entry
+pub fn pool_literal<'a>() -> Parser<'a, str, Type> {
+    string::character('=')
+        .right(Parser::one_of(vec![
+            string_literal(),
+            literal(),
+            variable(),
+            square_bracket_expression(),
+        ]))
+        .map(|literal| Type::PoolLiteral(Box::new(literal)))
+}
 
-    let mut pos = 1;
-    let mut priority = usize::MAX;
-    for i in (1..expression.len()).step_by(2) {
-        match expression[i] {
-            Type::Operator(op) if op.priority() < priority => {
-                pos = i;
-                priority = op.priority();
+// Pending pool literals are emitted here instead of after the code
+pub fn pool<'a>() -> Parser<'a, str, Type> {
+    string::literal(String::from(".pool")).map(|_| Type::Pool)
+}
+
+// `.opcode $e3 $1 $2` emits an extension opcode followed by raw operand bytes
+pub fn opcode<'a>() -> Parser<'a, str, Type> {
+    string::literal(String::from(".opcode"))
+        .right(string::whitespace().right(literal()).one_or_more())
+        .map(|mut bytes| Type::Opcode {
+            opcode: Box::new(bytes.remove(0)),
+            operands: bytes,
+        })
+}
+
+// `.budget $100` limits the code from here to the next budget or the end of the program
+pub fn budget<'a>() -> Parser<'a, str, Type> {
+    string::literal(String::from(".budget"))
+        .right(string::whitespace())
+        .right(literal())
+        .map(|size| match size {
+            Type::HexLiteral(size) => Type::Budget(size),
+            _ => unreachable!(),
+        })
+}
+
+// `.ascii "a" "b"` emits the bytes of its strings, `.asciiz` adds a terminating zero
+pub fn ascii<'a>() -> Parser<'a, str, Type> {
+    let directive = Parser::one_of(vec![
+        string::literal(String::from(".asciiz")).map(|_| true),
+        string::literal(String::from(".ascii")).map(|_| false),
+    ]);
+    let strings = string::whitespace()
+        .right(string_literal())
+        .one_or_more()
+        .map(|strings| {
+            strings
+                .into_iter()
+                .map(|string| match string {
+                    Type::StringLiteral(text) => text,
+                    _ => unreachable!(),
+                })
+                .collect()
+        });
+    Parser::new(move |input: &str| {
+        let terminated = directive.parse(input)?;
+        let strings = strings.parse_at(input, terminated.index)?;
+        Ok(ParserState {
+            index: strings.index,
+            result: Type::Ascii {
+                strings: strings.result,
+                terminated: terminated.result,
+            },
+        })
+    })
+}
+
+// `.meta version "1.2"` adds a key and value to the program's metadata instead of emitting bytes
+pub fn meta<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let index = string::literal(String::from(".meta"))
+            .left(string::whitespace())
+            .parse(input)?
+            .index;
+        let key = string::alphabetic()
+            .left(string::whitespace())
+            .parse_at(input, index)?;
+        let value = string_literal().parse_at(input, key.index)?;
+        Ok(ParserState {
+            index: value.index,
+            result: Type::Meta {
+                key: key.result,
+                value: match value.result {
+                    Type::StringLiteral(text) => text,
+                    _ => unreachable!(),
+                },
+            },
+        })
+    })
+}
+
+// `.byte $1, 'a' [!x]` and `.word $1234 !label` emit their values, separated by commas or
+// spaces. `data8` and `data16` are the same directives.
+pub fn data<'a>() -> Parser<'a, str, Type> {
+    let directive = Parser::one_of(vec![
+        string::literal(String::from(".byte")).map(|_| false),
+        string::literal(String::from("data8")).map(|_| false),
+        string::literal(String::from(".word")).map(|_| true),
+        string::literal(String::from("data16")).map(|_| true),
+    ]);
+    let value = || Parser::one_of(vec![literal(), variable(), square_bracket_expression()]);
+    let separator = Parser::one_of(vec![
+        string::optional_blanks()
+            .right(string::character(','))
+            .left(string::optional_blanks()),
+        string::whitespace(),
+    ]);
+    let first = string::whitespace().right(value());
+    let rest = separator.right(value()).zero_or_more();
+    Parser::new(move |input: &str| {
+        let wide = directive.parse(input)?;
+        let first = first.parse_at(input, wide.index)?;
+        let rest = rest.parse_at(input, first.index)?;
+        let mut values = vec![first.result];
+        values.extend(rest.result);
+        Ok(ParserState {
+            index: rest.index,
+            result: Type::Data {
+                wide: wide.result,
+                values,
+            },
+        })
+    })
+}
+
+// The text is kept as written, escapes are resolved by `unescape` when the bytes are needed
+fn string_literal<'a>() -> Parser<'a, str, Type> {
+    Parser::new(|input: &str| {
+        let index = string::character('"').parse(input)?.index;
+        let mut chars = input[index..].char_indices();
+        while let Some((end, c)) = chars.next() {
+            match c {
+                '"' => {
+                    return Ok(ParserState {
+                        index: index + end + 1,
+                        result: Type::StringLiteral(input[index..index + end].to_string()),
+                    })
+                }
+                '\\' => {
+                    chars.next();
+                }
+                '\n' => break,
+                _ => {}
             }
-            Type::Operator(_) => continue,
-            _ => panic!(),
         }
-    }
-
-    let op = expression.remove(pos);
-    let (left, right) = expression.split_at(pos);
+        Err(ParseError::new("Unterminated string".to_string()))
+    })
+}
 
-    Type::BinaryOperation {
-        op: Box::new(op),
-        a: Box::new(group_binary_operations(left.to_vec())),
-        b: Box::new(group_binary_operations(right.to_vec())),
+// Supports \n, \t, \0, \\, \" and \xNN
+pub fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut res = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            res.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let escape = chars.next();
+        res.push(match escape {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('x') => {
+                let digits: String = chars.clone().take(2).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) if digits.len() == 2 => {
+                        chars.nth(1);
+                        byte
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Invalid escape '\\x{}' in \"{}\", expected two hex digits",
+                            digits, text
+                        ))
+                    }
+                }
+            }
+            Some(c) => return Err(format!("Invalid escape '\\{}' in \"{}\"", c, text)),
+            None => return Err(format!("Unfinished escape in \"{}\"", text)),
+        });
     }
+    Ok(res)
 }
 
 pub fn register<'a>() -> Parser<'a, str, Type> {
@@ -174,6 +638,13 @@ pub fn register<'a>() -> Parser<'a, str, Type> {
     .map(Type::Register)
 }
 
+// A source line, blank and comment only lines have no item
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Line {
+    pub item: Option<Type>,
+    pub comment: Option<String>,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Type {
     Instruction0 {
@@ -199,20 +670,66 @@ pub enum Type {
         a: Box<Type>,
         b: Box<Type>,
     },
-    Ignored,
+    Wrap(Box<Type>),
+    // `word(address)` or `byte(address)` in a runtime expression
+    MemoryRead {
+        wide: bool,
+        address: Box<Type>,
+    },
+    // `[FP + offset]` or `[FP - offset]`, the offset is an expression
+    FrameOffset {
+        negative: bool,
+        offset: Box<Type>,
+    },
     HexLiteral(u16),
     HexLiteral8(u8),
+    Literal32(u32),
     Address(u16),
     Variable(String),
     Register(String),
+    RegisterAlias {
+        name: String,
+        register: Box<Type>,
+    },
     Operator(Operator),
     Label(String),
+    Constant {
+        name: String,
+        value: Box<Type>,
+    },
+    StringLiteral(String),
+    PoolLiteral(Box<Type>),
+    Pool,
+    Budget(u16),
+    Ascii {
+        strings: Vec<String>,
+        terminated: bool,
+    },
+    Opcode {
+        opcode: Box<Type>,
+        operands: Vec<Type>,
+    },
+    // The value is kept as written, like the strings of Ascii
+    Meta {
+        key: String,
+        value: String,
+    },
+    // Bytes or words
+    Data {
+        wide: bool,
+        values: Vec<Type>,
+    },
+    Mov32 {
+        value: Box<Type>,
+        high: Box<Type>,
+        low: Box<Type>,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use super::{Operator, Type};
-    use crate::parser_combinator::core::ParserState;
+    use crate::parser_combinator::core::{ParseError, ParserState};
 
     #[test]
     fn register() {
@@ -225,6 +742,20 @@ mod tests {
         )
     }
 
+    #[test]
+    fn register_alias() {
+        assert_eq!(
+            super::register_alias().parse(".regalias counter R3"),
+            Ok(ParserState {
+                index: 20,
+                result: Type::RegisterAlias {
+                    name: String::from("counter"),
+                    register: Box::new(Type::Register(String::from("R3"))),
+                },
+            })
+        )
+    }
+
     #[test]
     fn hex_literal() {
         assert_eq!(
@@ -236,6 +767,55 @@ mod tests {
         )
     }
 
+    #[test]
+    fn decimal_and_binary_literals() {
+        for (input, value, index) in [
+            ("123", 123, 3),
+            ("65535 R1", 0xffff, 5),
+            ("%1010", 10, 5),
+            ("0b1010", 10, 6),
+            ("0", 0, 1),
+            ("0x10", 0, 1),
+        ] {
+            assert_eq!(
+                super::literal().parse(input),
+                Ok(ParserState {
+                    index,
+                    result: Type::HexLiteral(value),
+                }),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            super::decimal_literal().parse("65536").unwrap_err().message,
+            "65536 does not fit in 16 bits"
+        );
+        assert!(super::binary_literal().parse("%2").is_err());
+        assert!(super::literal().parse("x1").is_err());
+    }
+
+    #[test]
+    fn char_literal() {
+        for (input, value, index) in [("'A'", 0x41, 3), ("'\\n'", 0x0a, 4), ("'\\''", 0x27, 4)] {
+            assert_eq!(
+                super::char_literal().parse(input),
+                Ok(ParserState {
+                    index,
+                    result: Type::HexLiteral(value),
+                }),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            super::char_literal().parse("'AB'").unwrap_err().message,
+            "'AB' is not a single ASCII character"
+        );
+        assert!(super::char_literal().parse("''").is_err());
+        assert!(super::char_literal().parse("'é'").is_err());
+    }
+
     #[test]
     fn variable() {
         assert_eq!(
@@ -247,6 +827,41 @@ mod tests {
         )
     }
 
+    #[test]
+    fn pool_literal() {
+        assert_eq!(
+            super::pool_literal().parse("=\"Hello, world\" R1"),
+            Ok(ParserState {
+                index: 15,
+                result: Type::PoolLiteral(Box::new(Type::StringLiteral(String::from(
+                    "Hello, world"
+                )))),
+            })
+        );
+        assert_eq!(
+            super::pool_literal().parse("=!msg"),
+            Ok(ParserState {
+                index: 5,
+                result: Type::PoolLiteral(Box::new(Type::Variable(String::from("msg")))),
+            })
+        );
+        assert!(super::pool_literal().parse("=\"Hello\nR1").is_err());
+    }
+
+    #[test]
+    fn opcode() {
+        assert_eq!(
+            super::opcode().parse(".opcode $e3 $4 $ff"),
+            Ok(ParserState {
+                index: 18,
+                result: Type::Opcode {
+                    opcode: Box::new(Type::HexLiteral(0xe3)),
+                    operands: vec![Type::HexLiteral(0x4), Type::HexLiteral(0xff)],
+                },
+            })
+        );
+    }
+
     #[test]
     fn label() {
         assert_eq!(
@@ -265,47 +880,90 @@ mod tests {
             Ok(ParserState {
                 index: 26,
                 result: Type::BinaryOperation {
-                    a: Box::new(Type::HexLiteral(43538)),
-                    op: Box::new(Type::Operator(Operator::Plus)),
-                    b: Box::new(Type::BinaryOperation {
-                        a: Box::new(Type::BinaryOperation {
+                    a: Box::new(Type::BinaryOperation {
+                        a: Box::new(Type::HexLiteral(43538)),
+                        op: Box::new(Type::Operator(Operator::Plus)),
+                        b: Box::new(Type::BinaryOperation {
                             a: Box::new(Type::Variable("uu".to_string())),
                             op: Box::new(Type::Operator(Operator::Star)),
                             b: Box::new(Type::Variable("aa".to_string())),
                         }),
-                        op: Box::new(Type::Operator(Operator::Minus)),
-                        b: Box::new(Type::HexLiteral(1)),
                     }),
+                    op: Box::new(Type::Operator(Operator::Minus)),
+                    b: Box::new(Type::HexLiteral(1)),
                 }
             })
         )
     }
 
     #[test]
-    fn group_binary_operations() {
-        assert_eq!(
-            super::group_binary_operations(vec![
-                Type::HexLiteral(43538),
-                Type::Operator(Operator::Plus),
-                Type::Variable("uu".to_string()),
-                Type::Operator(Operator::Star),
-                Type::Variable("aa".to_string()),
-                Type::Operator(Operator::Minus),
-                Type::HexLiteral(1),
-            ]),
-            Type::BinaryOperation {
-                a: Box::new(Type::HexLiteral(43538)),
-                op: Box::new(Type::Operator(Operator::Plus)),
-                b: Box::new(Type::BinaryOperation {
-                    a: Box::new(Type::BinaryOperation {
-                        a: Box::new(Type::Variable("uu".to_string())),
-                        op: Box::new(Type::Operator(Operator::Star)),
-                        b: Box::new(Type::Variable("aa".to_string())),
-                    }),
-                    op: Box::new(Type::Operator(Operator::Minus)),
-                    b: Box::new(Type::HexLiteral(1)),
+    fn square_bracket_expression_spacing() {
+        let expected = super::square_bracket_expression()
+            .parse("[$1 + !a]")
+            .unwrap()
+            .result;
+        for input in ["[$1+!a]", "[ $1 +!a ]", "[$1+ !a]", "[\t$1\t+\t!a\t]"] {
+            assert_eq!(
+                super::square_bracket_expression().parse(input),
+                Ok(ParserState {
+                    index: input.len(),
+                    result: expected.clone(),
                 }),
-            }
-        )
+                "{}",
+                input
+            );
+        }
+        let result = |input| {
+            super::square_bracket_expression()
+                .parse(input)
+                .map(|state| state.result)
+        };
+        assert_eq!(
+            result("[[$1+$2]*[wrap:$3-!b]]"),
+            result("[ [ $1 + $2 ] * [ wrap: $3 - !b ] ]")
+        );
+        // Left to right within a priority
+        assert_eq!(result("[$a-$2-$1]"), result("[[$a - $2] - $1]"));
+    }
+
+    #[test]
+    fn square_bracket_expression_errors() {
+        assert_eq!(
+            super::square_bracket_expression().parse("[$1 + !a"),
+            Err(ParseError {
+                message: "Unclosed '['".to_string(),
+                index: 0,
+            })
+        );
+        assert_eq!(
+            super::square_bracket_expression().parse("[$1 * [!a - $2 R1"),
+            Err(ParseError {
+                message: "Unclosed '['".to_string(),
+                index: 6,
+            })
+        );
+        assert_eq!(
+            super::square_bracket_expression().parse("[$1 +]"),
+            Err(ParseError {
+                message: "Expected a term after '+'".to_string(),
+                index: 4,
+            })
+        );
+        assert_eq!(
+            super::square_bracket_expression().parse("[$1 $2]"),
+            Err(ParseError {
+                message: "Unclosed '['".to_string(),
+                index: 0,
+            })
+        );
+        assert_eq!(
+            super::bracket_error("mov [$1 + $2 R1 ; [fine]"),
+            Some(("Unclosed '['".to_string(), 4))
+        );
+        assert_eq!(
+            super::bracket_error("mov ['[' + [$1 *]] R1"),
+            Some(("Expected a term after '*'".to_string(), 15))
+        );
+        assert_eq!(super::bracket_error(".ascii \"\\\"[\" ; ["), None);
     }
 }
@@ -0,0 +1,189 @@
+use super::parser::{Line, Type};
+use crate::cpu::instruction;
+
+// The patterns `vm compile --optimize` rewrites before addresses are assigned:
+//   mov reg reg of a register to itself   removed
+//   psh reg followed by pop of the same   both removed
+//   psh reg followed by pop other         mov reg other
+//   psh $lit followed by pop reg          mov $lit reg
+//   jmp &[!label] right before label:     removed
+// Pairs only match on consecutive items, blank and comment lines aside. A label between them
+// could be jumped to and anything else, like a `.regalias`, could change what they mean, so
+// either keeps both. Returns the lines that were removed, their comments stay.
+pub fn optimize(lines: &mut [Line]) -> Vec<u16> {
+    let items: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.item.is_some())
+        .map(|(index, _)| index)
+        .collect();
+    let mut removed = vec![];
+    let mut i = 0;
+    while i < items.len() {
+        let index = items[i];
+        let next = items.get(i + 1).and_then(|&next| lines[next].item.as_ref());
+        match rewrite(lines[index].item.as_ref().unwrap(), next) {
+            Rewrite::Keep => i += 1,
+            Rewrite::Remove => {
+                lines[index].item = None;
+                removed.push(index as u16 + 1);
+                i += 1;
+            }
+            Rewrite::RemovePair => {
+                for &index in &items[i..i + 2] {
+                    lines[index].item = None;
+                    removed.push(index as u16 + 1);
+                }
+                i += 2;
+            }
+            Rewrite::Replace(t) => {
+                lines[index].item = Some(t);
+                lines[items[i + 1]].item = None;
+                removed.push(items[i + 1] as u16 + 1);
+                i += 2;
+            }
+        }
+    }
+    removed
+}
+
+enum Rewrite {
+    Keep,
+    Remove,
+    // The item and the one after it
+    RemovePair,
+    // The item becomes this one and the one after it goes
+    Replace(Type),
+}
+
+fn rewrite(t: &Type, next: Option<&Type>) -> Rewrite {
+    let popped = match next {
+        Some(Type::Instruction1 { instruction, arg0 }) if *instruction == instruction::POP_REG => {
+            Some(arg0)
+        }
+        _ => None,
+    };
+    match (t, popped) {
+        (
+            Type::Instruction2 {
+                instruction,
+                arg0,
+                arg1,
+            },
+            _,
+        ) if *instruction == instruction::MOVE_REG_REG && arg0 == arg1 => Rewrite::Remove,
+        (Type::Instruction1 { instruction, arg0 }, Some(popped))
+            if *instruction == instruction::PSH_REG =>
+        {
+            if arg0 == popped {
+                Rewrite::RemovePair
+            } else {
+                Rewrite::Replace(Type::Instruction2 {
+                    instruction: instruction::MOVE_REG_REG,
+                    arg0: arg0.clone(),
+                    arg1: popped.clone(),
+                })
+            }
+        }
+        (Type::Instruction1 { instruction, arg0 }, Some(popped))
+            if *instruction == instruction::PSH_LIT =>
+        {
+            Rewrite::Replace(Type::Instruction2 {
+                instruction: instruction::MOVE_LIT_REG,
+                arg0: arg0.clone(),
+                arg1: popped.clone(),
+            })
+        }
+        (Type::Instruction1 { instruction, arg0 }, _) if *instruction == instruction::JMP_LIT => {
+            match (&**arg0, next) {
+                (Type::Variable(target), Some(Type::Label(label))) if target == label => {
+                    Rewrite::Remove
+                }
+                _ => Rewrite::Keep,
+            }
+        }
+        _ => Rewrite::Keep,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{self, Options};
+
+    fn optimized(code: &str) -> Vec<u8> {
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        assembler::compile(code, &options).unwrap()
+    }
+
+    fn plain(code: &str) -> Vec<u8> {
+        assembler::compile(code, &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn patterns() {
+        let cases = [
+            ("mov $1 R1\nmov R1 R1\nhlt\n", "mov $1 R1\nhlt\n"),
+            ("psh R1\npop R1\nhlt\n", "hlt\n"),
+            (".regalias tmp R4\npsh tmp\npop tmp\nhlt\n", "hlt\n"),
+            ("psh R1\n; to R2\n\npop R2\nhlt\n", "mov R1 R2\nhlt\n"),
+            ("psh [$2 * $3]\npop R3\nhlt\n", "mov $6 R3\nhlt\n"),
+            ("jmp &[!next]\n\nnext:\nhlt\n", "hlt\n"),
+        ];
+        for (code, expected) in cases.iter() {
+            assert_eq!(optimized(code), plain(expected), "{}", code);
+            assert_ne!(plain(code), plain(expected), "{}", code);
+        }
+    }
+
+    #[test]
+    fn labels_between() {
+        // Code may jump to back and reach the pop on its own, a different alias could be meant
+        // by the second tmp and the jmp target isn't the label right after it
+        let cases = [
+            "psh R1\nback:\npop R1\njmp &[!back]\n",
+            ".regalias tmp R2\npsh tmp\n.regalias tmp R3\npop tmp\nhlt\n",
+            "jmp &[!end]\nnext:\nend:\nhlt\n",
+            "mov R1 R2\npsh R1\nhlt\npop R1\n",
+        ];
+        for code in cases.iter() {
+            assert_eq!(optimized(code), plain(code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        let code = "psh R1\npop R1\nloop:\nmov R2 R2\njmp &[!loop]\n";
+        assert_eq!(
+            plain(code),
+            vec![0x17, 0x02, 0x18, 0x02, 0x11, 0x03, 0x03, 0x5c, 0x00, 0x04]
+        );
+        assert_eq!(optimized(code), vec![0x5c, 0x00, 0x00]);
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let assembly = assembler::assemble(code, &options).unwrap();
+        assert_eq!(assembly.symbols, vec![("loop".to_string(), 0)]);
+        assert_eq!(assembly.removed, vec![(1, 0), (2, 0), (4, 0)]);
+        assert_eq!(
+            assembly.listing(code),
+            "0000                           psh R1 => removed\n\
+             0000                           pop R1 => removed\n\
+             0000                           mov R2 R2 => removed\n\
+             0000  5c 00 00                 jmp &[!loop]\n"
+        );
+
+        let code = "mov $1 R1\npsh R1 ; copy\npop R2\nhlt\n";
+        let assembly = assembler::assemble(code, &options).unwrap();
+        assert_eq!(
+            assembly.listing(code),
+            "0000  10 00 01 02              mov $1 R1\n\
+             0004  11 02 03                 psh R1 ; copy => mov reg reg\n\
+             0007                           pop R2 => removed\n\
+             0007  ff                       hlt\n"
+        );
+    }
+}
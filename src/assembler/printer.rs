@@ -0,0 +1,237 @@
+use super::expression;
+use super::parser::{Line, Type};
+use crate::cpu::instruction::OperandKind;
+
+// Canonical layout: labels and directives on their own line, mnemonics padded so operands line
+// up, and single spaces between operands. Blank lines stay, a trailing comment is one space
+// after the item.
+pub fn print(lines: &[Line]) -> String {
+    lines
+        .iter()
+        .map(|line| {
+            let mut res = line.item.as_ref().map(item).unwrap_or_default();
+            if let Some(comment) = &line.comment {
+                if !res.is_empty() {
+                    res.push(' ');
+                }
+                res.push(';');
+                res.push_str(comment.trim_end());
+            }
+            res + "\n"
+        })
+        .collect()
+}
+
+pub fn item(t: &Type) -> String {
+    match t {
+        Type::Label(name) => format!("{}:", name),
+        Type::Constant { name, value } => {
+            format!("const {} = {}", name, expression::to_string(value))
+        }
+        Type::RegisterAlias { name, register } => {
+            format!(
+                ".regalias {} {}",
+                name,
+                operand(register, OperandKind::Register)
+            )
+        }
+        Type::Pool => ".pool".to_string(),
+        Type::Budget(size) => format!(".budget ${:x}", size),
+        Type::Ascii {
+            strings,
+            terminated,
+        } => {
+            let mut res = if *terminated { ".asciiz" } else { ".ascii" }.to_string();
+            for text in strings {
+                res.push_str(&format!(" \"{}\"", text));
+            }
+            res
+        }
+        Type::Meta { key, value } => format!(".meta {} \"{}\"", key, value),
+        Type::Opcode { opcode, operands } => {
+            let mut res = format!(".opcode {}", expression::to_string(opcode));
+            for operand in operands {
+                res.push(' ');
+                res.push_str(&expression::to_string(operand));
+            }
+            res
+        }
+        Type::Data { wide, values } => {
+            let mut res = if *wide { ".word" } else { ".byte" }.to_string();
+            for value in values {
+                res.push(' ');
+                res.push_str(&expression::to_string(value));
+            }
+            res
+        }
+        Type::Mov32 { value, high, low } => format!(
+            "mov32 {} {}:{}",
+            operand(value, OperandKind::Literal),
+            operand(high, OperandKind::Register),
+            operand(low, OperandKind::Register)
+        ),
+        Type::Instruction0 { instruction } => instruction.mnemonic.to_string(),
+        Type::Instruction1 { instruction, arg0 } => {
+            instruction_line(instruction.mnemonic, &[arg0], instruction.format.operands())
+        }
+        Type::Instruction2 {
+            instruction,
+            arg0,
+            arg1,
+        } => instruction_line(
+            instruction.mnemonic,
+            &[arg0, arg1],
+            instruction.format.operands(),
+        ),
+        Type::Instruction3 {
+            instruction,
+            arg0,
+            arg1,
+            arg2,
+        } => instruction_line(
+            instruction.mnemonic,
+            &[arg0, arg1, arg2],
+            instruction.format.operands(),
+        ),
+        _ => expression::to_string(t),
+    }
+}
+
+fn instruction_line(mnemonic: &str, args: &[&Type], kinds: &[OperandKind]) -> String {
+    let operands: Vec<String> = args
+        .iter()
+        .zip(kinds.iter())
+        .map(|(arg, &kind)| operand(arg, kind))
+        .collect();
+    format!("{:<3} {}", mnemonic, operands.join(" "))
+}
+
+fn operand(t: &Type, kind: OperandKind) -> String {
+    match (t, kind) {
+        (Type::Register(name), OperandKind::RegisterIndirect) => format!("&{}", name),
+        (Type::Register(name), _) => name.clone(),
+        (Type::HexLiteral(value), _) => format!("${:x}", value),
+        (Type::HexLiteral8(value), _) => format!("${:x}", value),
+        (Type::Literal32(value), _) => format!("${:x}", value),
+        (Type::Address(value), _) => format!("&{:x}", value),
+        (Type::FrameOffset { .. }, _) => format!("&{}", expression::to_string(t)),
+        (Type::PoolLiteral(literal), _) => pool_literal(literal),
+        (_, OperandKind::Address) => format!("&{}", bracketed(t)),
+        _ => bracketed(t),
+    }
+}
+
+pub fn pool_literal(literal: &Type) -> String {
+    match literal {
+        Type::StringLiteral(text) => format!("=\"{}\"", text),
+        Type::HexLiteral(_) | Type::Variable(_) => format!("={}", expression::to_string(literal)),
+        _ => format!("={}", bracketed(literal)),
+    }
+}
+
+fn bracketed(t: &Type) -> String {
+    let expression = expression::to_string(t);
+    if expression.starts_with('[') {
+        expression
+    } else {
+        format!("[{}]", expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{compile, format, Options};
+    use crate::cpu::instruction;
+
+    const PROGRAMS: [&str; 14] = [
+        "mov $4200 R1\nmov R1 &AAAA\nmov $1000 R1\nmov &AAAA R2\nadd R1 R2\n",
+        "start:\nmov $a R1\nloop:\ndec R1\nmov R1 ACC\njne $0 &[!loop]\ncal [!done]\njeq $0 &[!start]\ndone:\nhlt\n",
+        ".regalias counter R3\n.regalias ptr   R7\nmov   $5 counter\nmov &ptr    counter\ndec counter\nrti\n",
+        "x:\nmov [wrap: $ffff + $2] R1\nmov [[$22 - $2] + !x * $3] &[$333 - $33 * !x]\nmov $aa R3 R1\nlsf R1 $2\nsys $1\n",
+        "mov   =\"Hello, world\" R1\nmov =[!x + $2] R2\n.pool\nx:\nmov =$ff   R3\n",
+        ".opcode $e3  $4 $ff\nhlt\n",
+        ".budget   $10\nmov $1 R1\n.budget $4\nhlt\n",
+        "mov =\"a\\\"b\" R1\n.ascii  \"x\\ty\"   \"\\x41\"\n.asciiz \"\"\n",
+        "; setup\n\nmov   $1 R1;one  \nloop:    ; top\n  ;\nhlt\n",
+        "mov32   $deadbeef R1:R2\nmov32 [!x * $10000] R3:R4\nx:\n",
+        "x:\n.byte $1,$2  'a'\ndata16 !x , [!x + $1]\n",
+        "mov $48 &[!screen + $1]\nconst   screen=$fe00\nconst end = [!x + $2]\nx:\n",
+        "mov &[FP+$4] R1\nmov R1   &[ FP - [!x * $2]]\nmov &[FP] R2\nx:\n",
+        ".meta   version  \"1.2\\n\"\nhlt\n",
+    ];
+
+    #[test]
+    fn canonical_layout() {
+        assert_eq!(
+            format(PROGRAMS[2]).unwrap(),
+            ".regalias counter R3\n.regalias ptr R7\nmov $5 counter\nmov &ptr counter\ndec counter\nrti\n"
+        );
+        assert_eq!(
+            format(PROGRAMS[3]).unwrap(),
+            "x:\nmov [wrap: $ffff + $2] R1\nmov [[$22 - $2] + [!x * $3]] &[$333 - [$33 * !x]]\n\
+             mov $aa R3 R1\nlsf R1 $2\nsys $1\n"
+        );
+        assert_eq!(
+            format(PROGRAMS[4]).unwrap(),
+            "mov =\"Hello, world\" R1\nmov =[!x + $2] R2\n.pool\nx:\nmov =$ff R3\n"
+        );
+        assert_eq!(
+            format(PROGRAMS[8]).unwrap(),
+            "; setup\n\nmov $1 R1 ;one\nloop: ; top\n;\nhlt\n"
+        );
+        assert_eq!(
+            format(PROGRAMS[12]).unwrap(),
+            "mov &[FP + $4] R1\nmov R1 &[FP - [!x * $2]]\nmov &[FP] R2\nx:\n"
+        );
+        assert_eq!(
+            format(PROGRAMS[13]).unwrap(),
+            ".meta version \"1.2\\n\"\nhlt\n"
+        );
+        assert_eq!(
+            format("top:\nloop   R1 &[!top]\nsavectx &800\n").unwrap(),
+            "top:\nloop R1 &[!top]\nsavectx &800\n"
+        );
+    }
+
+    #[test]
+    fn preserves_semantics() {
+        for program in PROGRAMS.iter() {
+            let formatted = format(program).unwrap();
+            assert_eq!(
+                compile(&formatted, &Options::default()).unwrap(),
+                compile(program, &Options::default()).unwrap(),
+                "{}",
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn templates_round_trip() {
+        for i in instruction::LIST.iter() {
+            let line = i
+                .syntax()
+                .replace("$lit8", "$3")
+                .replace("$lit", "$12")
+                .replace("&reg", "&R2")
+                .replace("reg", "R1")
+                .replace("&addr", "&800")
+                + "\n";
+            let formatted = format(&line).unwrap();
+            assert_eq!(
+                compile(&formatted, &Options::default()).unwrap(),
+                compile(&line, &Options::default()).unwrap(),
+                "{}",
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn idempotent() {
+        for program in PROGRAMS.iter() {
+            let formatted = format(program).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted);
+        }
+    }
+}
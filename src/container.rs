@@ -0,0 +1,474 @@
+// Binary container written by `vm compile -g`:
+//
+//   magic "VM16" | version: u8 | section count: u8 | sections...
+//   section: kind: u8 | length: u32 | crc32: u32 | data
+//
+// The metadata section holds the `.meta` keys and values as the guest sees them, see
+// `meta_block`.
+// All numbers are big endian, like everything else in the VM. Sections of unknown kind are
+// skipped when loading, so older loaders keep working with newer containers. Code in versions 1
+// and 2 encodes register operands by their offset into the register file rather than by their
+// number, so those containers are rejected and have to be assembled again.
+use crate::checksum;
+use crate::error::VmError;
+use crate::parser_combinator::byte::{self, literal};
+use crate::parser_combinator::core::{
+    counted, length_prefixed, take, ParseError, Parser, ParserState,
+};
+
+pub const MAGIC: &[u8; 4] = b"VM16";
+pub const VERSION: u8 = 3;
+
+const CODE_SECTION: u8 = 0x01;
+const DEBUG_SECTION: u8 = 0x02;
+const META_SECTION: u8 = 0x03;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Container {
+    pub code: Vec<u8>,
+    pub debug: Option<DebugInfo>,
+    pub meta: Vec<(String, Vec<u8>)>,
+}
+
+// Debug section layout:
+//   file: u16 length + bytes
+//   lines: u16 count + (address: u16, line: u16)*
+//   symbols: u16 count + (address: u16, name: u16 length + bytes)*
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    pub file: String,
+    pub lines: Vec<(u16, u16)>,
+    pub symbols: Vec<(String, u16)>,
+}
+
+impl DebugInfo {
+    pub fn line_for(&self, address: u16) -> Option<u16> {
+        self.lines
+            .iter()
+            .filter(|(start, _)| *start <= address)
+            .max_by_key(|(start, _)| *start)
+            .map(|(_, line)| *line)
+    }
+
+    pub fn location(&self, address: u16) -> Option<String> {
+        self.line_for(address)
+            .map(|line| format!("{}:{}", self.file, line))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res = vec![];
+        write_string(&mut res, &self.file);
+        res.extend((self.lines.len() as u16).to_be_bytes().iter());
+        for (address, line) in &self.lines {
+            res.extend(address.to_be_bytes().iter());
+            res.extend(line.to_be_bytes().iter());
+        }
+        res.extend((self.symbols.len() as u16).to_be_bytes().iter());
+        for (name, address) in &self.symbols {
+            res.extend(address.to_be_bytes().iter());
+            write_string(&mut res, name);
+        }
+        res
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<DebugInfo, String> {
+        let line = byte::u16().pair(byte::u16());
+        let symbol = byte::u16()
+            .pair(string())
+            .map(|(address, name)| (name, address));
+        let debug_info = Parser::new(move |input| {
+            let file = string().parse(input)?;
+            let lines = list(line.clone()).parse_at(input, file.index)?;
+            let symbols = list(symbol.clone()).parse_at(input, lines.index)?;
+            Ok(ParserState {
+                index: symbols.index,
+                result: DebugInfo {
+                    file: file.result,
+                    lines: lines.result,
+                    symbols: symbols.result,
+                },
+            })
+        });
+        debug_info
+            .parse(bytes)
+            .map(|state| state.result)
+            .map_err(truncated)
+    }
+}
+
+impl Container {
+    pub fn is_container(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections = vec![(CODE_SECTION, self.code.clone())];
+        if let Some(debug) = &self.debug {
+            sections.push((DEBUG_SECTION, debug.to_bytes()));
+        }
+        if !self.meta.is_empty() {
+            sections.push((META_SECTION, meta_block(&self.meta)));
+        }
+
+        let mut res = MAGIC.to_vec();
+        res.push(VERSION);
+        res.push(sections.len() as u8);
+        for (kind, data) in sections {
+            res.push(kind);
+            res.extend((data.len() as u32).to_be_bytes().iter());
+            res.extend(checksum::crc32(&data).to_be_bytes().iter());
+            res.extend(data);
+        }
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Container, VmError> {
+        Container::parse(bytes).map_err(VmError::Config)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Container, String> {
+        if !Container::is_container(bytes) {
+            return Err("Not a VM16 container".to_string());
+        }
+        let header = literal(MAGIC).right(byte::u8());
+        let version = header.parse(bytes).map_err(truncated)?;
+        match version.result {
+            VERSION => {}
+            1 | 2 => {
+                return Err(format!(
+                    "Container version {} encodes registers by their offset, assemble it again",
+                    version.result
+                ))
+            }
+            other => return Err(format!("Unsupported container version {}", other)),
+        }
+        let sections = counted(byte::u8().map(usize::from), section())
+            .parse_at(bytes, version.index)
+            .map_err(truncated)?
+            .result;
+
+        let mut code = None;
+        let mut debug = None;
+        let mut meta = vec![];
+        for (kind, checksum, data) in sections {
+            if checksum != checksum::crc32(data) {
+                return Err(format!(
+                    "Checksum mismatch in {} section, the file is corrupted",
+                    section_name(kind)
+                ));
+            }
+            match kind {
+                CODE_SECTION => code = Some(data.to_vec()),
+                DEBUG_SECTION => debug = Some(DebugInfo::from_bytes(data)?),
+                META_SECTION => meta = parse_meta(data)?,
+                _ => {}
+            }
+        }
+
+        Ok(Container {
+            code: code.ok_or_else(|| "Container has no code section".to_string())?,
+            debug,
+            meta,
+        })
+    }
+
+    // What `vm info` shows
+    pub fn info(&self) -> String {
+        let mut res = format!("code: {} bytes\n", self.code.len());
+        match &self.debug {
+            Some(debug) => res.push_str(&format!("debug info: {}\n", debug.file)),
+            None => res.push_str("debug info: none\n"),
+        }
+        if !self.meta.is_empty() {
+            res.push_str("metadata:\n");
+        }
+        for (key, value) in &self.meta {
+            res.push_str(&format!("  {}: {}\n", key, String::from_utf8_lossy(value)));
+        }
+        res
+    }
+}
+
+// Metadata as it is mapped for the guest: every key and value is a length byte, the bytes and a
+// NUL, so PRINT can show a value as it is. A zero word ends the block.
+pub fn meta_block(meta: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut res = vec![];
+    for (key, value) in meta {
+        for bytes in [key.as_bytes(), value] {
+            res.push(bytes.len() as u8);
+            res.extend(bytes);
+            res.push(0);
+        }
+    }
+    res.extend([0, 0]);
+    res
+}
+
+fn parse_meta(block: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let string = || length_prefixed(byte::u8().map(usize::from)).left(literal(&[0]));
+    string()
+        .pair(string())
+        .many_till(literal(&[0, 0]))
+        .parse(block)
+        .map(|state| {
+            state
+                .result
+                .into_iter()
+                .map(|(key, value)| (String::from_utf8_lossy(key).to_string(), value.to_vec()))
+                .collect()
+        })
+        .map_err(|error| format!("Invalid metadata section at byte {}", error.index))
+}
+
+fn section_name(kind: u8) -> String {
+    match kind {
+        CODE_SECTION => "code".to_string(),
+        DEBUG_SECTION => "debug".to_string(),
+        META_SECTION => "metadata".to_string(),
+        _ => format!("unknown ({:#04x})", kind),
+    }
+}
+
+fn write_string(res: &mut Vec<u8>, s: &str) {
+    res.extend((s.len() as u16).to_be_bytes().iter());
+    res.extend(s.as_bytes());
+}
+
+fn string<'a>() -> Parser<'a, [u8], String> {
+    length_prefixed(byte::u16().map(usize::from))
+        .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+}
+
+// A u16 count and that many items
+fn list<'a, O: 'a>(item: Parser<'a, [u8], O>) -> Parser<'a, [u8], Vec<O>> {
+    counted(byte::u16().map(usize::from), item)
+}
+
+// Kind, checksum and data
+type Section<'a> = (u8, u32, &'a [u8]);
+
+fn section<'a>() -> Parser<'a, [u8], Section<'a>> {
+    Parser::new(|input| {
+        let kind = byte::u8().parse(input)?;
+        let length = byte::u32().parse_at(input, kind.index)?;
+        let checksum = byte::u32().parse_at(input, length.index)?;
+        let data = take(length.result as usize).parse_at(input, checksum.index)?;
+        Ok(ParserState {
+            index: data.index,
+            result: (kind.result, checksum.result, data.result),
+        })
+    })
+}
+
+// The parsers above only fail on input that ends too early
+fn truncated(error: ParseError) -> String {
+    format!("Unexpected end of container at byte {}", error.index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Container, DebugInfo};
+    #[cfg(feature = "assembler")]
+    use crate::assembler;
+    use crate::checksum;
+    #[cfg(feature = "assembler")]
+    use crate::inspect;
+    #[cfg(feature = "assembler")]
+    use crate::machine;
+
+    #[test]
+    fn round_trip() {
+        let container = Container {
+            code: vec![0x10, 0x00, 0x01, 0x04, 0xff],
+            meta: vec![],
+            debug: Some(DebugInfo {
+                file: "prog.asm".to_string(),
+                lines: vec![(0, 1), (4, 3)],
+                symbols: vec![("end".to_string(), 4)],
+            }),
+        };
+        assert_eq!(
+            Container::from_bytes(&container.to_bytes()).unwrap(),
+            container
+        );
+
+        let stripped = Container {
+            code: vec![0xff],
+            meta: vec![],
+            debug: None,
+        };
+        assert_eq!(
+            Container::from_bytes(&stripped.to_bytes()).unwrap(),
+            stripped
+        );
+    }
+
+    #[test]
+    fn meta() {
+        let container = Container {
+            code: vec![0xff],
+            meta: vec![
+                ("version".to_string(), b"1.2".to_vec()),
+                ("built".to_string(), b"".to_vec()),
+            ],
+            debug: None,
+        };
+        assert_eq!(
+            super::meta_block(&container.meta),
+            b"\x07version\0\x031.2\0\x05built\0\0\0\0\0".to_vec()
+        );
+        let bytes = container.to_bytes();
+        assert_eq!(Container::from_bytes(&bytes).unwrap(), container);
+        assert_eq!(
+            Container::from_bytes(&bytes).unwrap().info(),
+            "code: 1 bytes\ndebug info: none\nmetadata:\n  version: 1.2\n  built: \n"
+        );
+        assert_eq!(
+            super::parse_meta(b"\x02ab\0\x01c"),
+            Err("Invalid metadata section at byte 6".to_string())
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = Container {
+            code: vec![0x10, 0x00, 0x01, 0x04],
+            meta: vec![],
+            debug: None,
+        }
+        .to_bytes();
+        assert_eq!(
+            Container::from_bytes(&bytes[..bytes.len() - 1]).map_err(|error| error.to_string()),
+            Err("Unexpected end of container at byte 15".to_string())
+        );
+        assert_eq!(
+            Container::from_bytes(&[0xff, 0x00]).map_err(|error| error.to_string()),
+            Err("Not a VM16 container".to_string())
+        );
+        // Cut inside the length of the code section and without a section count
+        assert_eq!(
+            Container::from_bytes(&bytes[..9]).map_err(|error| error.to_string()),
+            Err("Unexpected end of container at byte 7".to_string())
+        );
+        assert_eq!(
+            Container::from_bytes(&bytes[..5]).map_err(|error| error.to_string()),
+            Err("Unexpected end of container at byte 5".to_string())
+        );
+
+        // A symbol name longer than the debug section, the position is within the section
+        let mut debug = vec![0, 1, b'a', 0, 0, 0, 1, 0, 4, 0, 9, b'e', b'n', b'd'];
+        let mut bytes = b"VM16\x03\x01\x02\0\0\0".to_vec();
+        bytes.push(debug.len() as u8);
+        bytes.extend(checksum::crc32(&debug).to_be_bytes().iter());
+        bytes.append(&mut debug);
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err("Unexpected end of container at byte 11".to_string())
+        );
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let container = Container {
+            code: vec![0x10, 0x00, 0x01, 0x04, 0xff],
+            meta: vec![],
+            debug: Some(DebugInfo {
+                file: "prog.asm".to_string(),
+                ..DebugInfo::default()
+            }),
+        };
+        let mut bytes = container.to_bytes();
+        bytes[16] ^= 0x01;
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err("Checksum mismatch in code section, the file is corrupted".to_string())
+        );
+
+        let mut bytes = container.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x80;
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err("Checksum mismatch in debug section, the file is corrupted".to_string())
+        );
+    }
+
+    #[test]
+    fn old_versions() {
+        // Versions 1 and 2 encoded register operands by their offset
+        let bytes = [b'V', b'M', b'1', b'6', 1, 1, 1, 0, 0, 0, 1, 0xff];
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err(
+                "Container version 1 encodes registers by their offset, assemble it again"
+                    .to_string()
+            )
+        );
+        let mut bytes = Container {
+            code: vec![0xff],
+            meta: vec![],
+            debug: None,
+        }
+        .to_bytes();
+        bytes[4] = 2;
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err(
+                "Container version 2 encodes registers by their offset, assemble it again"
+                    .to_string()
+            )
+        );
+        bytes[4] = 4;
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err("Unsupported container version 4".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn debug_info_names_source_line() {
+        let assembly = assembler::assemble(
+            "mov $1 R1\nloop:\ninc R1\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let code = assembly.bytes.clone();
+        let debug = assembly.debug_info("prog.asm");
+        let mut bytes = Container {
+            code,
+            meta: vec![],
+            debug: Some(debug),
+        }
+        .to_bytes();
+        let container = Container::from_bytes(&bytes).unwrap();
+        assert_eq!(container.code, assembly.bytes);
+
+        // The inc on line 3 is at 0x0004, the code section starts at byte 15
+        bytes[15 + 4] = 0xee;
+        assert_eq!(
+            Container::from_bytes(&bytes).map_err(|error| error.to_string()),
+            Err("Checksum mismatch in code section, the file is corrupted".to_string())
+        );
+        // Patched after loading instead, so the fault at the inc names line 3
+        let mut code = container.code.clone();
+        code[4] = 0xee;
+
+        let debug = container.debug.unwrap();
+        assert_eq!(debug.symbols, vec![("loop".to_string(), 4)]);
+        assert_eq!(debug.location(4), Some("prog.asm:3".to_string()));
+        assert_eq!(debug.location(5), Some("prog.asm:3".to_string()));
+        assert_eq!(debug.location(6), Some("prog.asm:4".to_string()));
+
+        let mut cpu = machine::Builder::standard(&code).unwrap().build();
+        let error = cpu.run().unwrap_err();
+        assert_eq!(
+            inspect::fault(&cpu, &error, Some(&debug)),
+            "Illegal opcode 0xee at 0x0004 (prog.asm:3)"
+        );
+        assert_eq!(
+            inspect::fault(&cpu, &error, None),
+            "Illegal opcode 0xee at 0x0004"
+        );
+    }
+}
@@ -1,58 +1,312 @@
-#[cfg(test)]
 use std::collections::HashMap;
+use std::ops::Range;
 
+use extension::{CpuView, Extension, StepOutcome};
+use fault::{CpuError, FaultCause, FaultInfo};
 use register::Register;
+use syscall::Syscall;
 
 use crate::device::memory::Memory;
+use crate::device::port_bus::PortBus;
 use crate::device::Device;
 
+pub mod extension;
+pub mod fault;
 pub mod instruction;
 pub mod register;
+pub mod syscall;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     memory: Box<dyn Device>,
+    ports: PortBus,
     registers: Memory,
     stack_frame_size: u16,
     is_in_interrupt_handler: bool,
+    syscalls: HashMap<u16, Box<dyn Syscall>>,
+    extensions: HashMap<u8, Box<dyn Extension>>,
+    cycles: u64,
+    instructions: u64,
+    cycle_table: [u16; 256],
+    timer: Option<(u64, u16)>,
+    interrupt_stack: Option<u16>,
+    // Pushes may not go below the floor or into the code
+    stack_floor: u16,
+    code_region: Option<Range<u16>>,
+    strict_offsets: bool,
+    // Address of the instruction being executed and the first fault it raised
+    instruction_address: u16,
+    fault: Option<FaultInfo>,
+    // IP and opcode of the last instructions, a ring buffer written at `history_next`
+    history: Vec<Option<(u16, u8)>>,
+    history_next: usize,
+}
+
+// How a program ended with HLT
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct HaltReason {
+    // Address of the HLT
+    pub ip: u16,
+    pub acc: u16,
 }
 
+// Instructions kept by `vm run --history`
+pub const DEFAULT_HISTORY: usize = 256;
+
 const INTERRUPT_VECTOR_ADDRESS: usize = 0x1000;
+// Vectors 0x1000-0x100f, the fault vector follows them. IM only has a bit for each of them.
+const INTERRUPT_VECTORS: u16 = 8;
+// Where a warm reset starts the program, 0 like a cold start if the word is 0 or not in memory
+pub const RESET_VECTOR_ADDRESS: usize = 0x101a;
+const STATE_SIZE: u16 = (register::GENERAL_PURPOSE_LIST.len() as u16 + 3) * 2;
+// Context block written by SAVECTX and read by LOADCTX: every register as a word at its own
+// offset (IP at 0, ACC at 2 ... FLAGS at 30), followed by the stack frame size at 32.
+// The saved IP points after SAVECTX.
+const CONTEXT_SIZE: u16 = register::SIZE + 2;
+// Stands in for an illegal register operand until the fault is taken after the instruction, it
+// reads as zero and absorbs writes
+const SCRATCH_REGISTER: Register = register::SIZE as usize;
 
 impl CPU {
     pub fn new(memory: Box<dyn Device>) -> CPU {
         let mut cpu = CPU {
             memory,
-            registers: Memory::new(register::SIZE),
+            ports: PortBus::new(),
+            registers: Memory::new(register::SIZE + 2),
             stack_frame_size: 0,
             is_in_interrupt_handler: false,
+            syscalls: HashMap::new(),
+            extensions: HashMap::new(),
+            cycles: 0,
+            instructions: 0,
+            cycle_table: [0; 256],
+            timer: None,
+            interrupt_stack: None,
+            stack_floor: 0,
+            code_region: None,
+            strict_offsets: false,
+            instruction_address: 0,
+            fault: None,
+            history: vec![],
+            history_next: 0,
         };
-        cpu.set_register(register::SP, cpu.memory.len() as u16 - 2);
-        cpu.set_register(register::FP, cpu.memory.len() as u16 - 2);
-        cpu.set_register(register::IM, 0xff);
+        for instruction in instruction::LIST.iter() {
+            cpu.cycle_table[instruction.opcode as usize] = instruction.cycles;
+        }
+        // Devices start out in their initial state, so only the CPU itself needs setting up
+        cpu.reset_state();
         cpu
     }
 
-    pub fn run(&mut self) {
-        while !self.step() {}
+    // Restarts the program from address 0, devices are reset in the order they were mapped
+    pub fn reset(&mut self) {
+        self.reset_state();
+        self.memory.reset();
+        self.ports.reset();
+    }
+
+    // Like `reset`, but starts at the reset vector so the program can tell it was rebooted
+    pub fn warm_reset(&mut self) {
+        self.reset();
+        let start = self.reset_vector();
+        self.set_register(register::IP, start);
+    }
+
+    fn reset_vector(&mut self) -> u16 {
+        if RESET_VECTOR_ADDRESS + 2 > self.memory.len() {
+            return 0;
+        }
+        let address = self.memory.get_u16(RESET_VECTOR_ADDRESS);
+        if self.memory.take_fault().is_some() {
+            0
+        } else {
+            address
+        }
+    }
+
+    fn reset_state(&mut self) {
+        self.reset_registers();
+        self.cycles = 0;
+        self.instructions = 0;
+        self.fault = None;
+        self.history.iter_mut().for_each(|entry| *entry = None);
+        self.history_next = 0;
+    }
+
+    // The counters and the history are left alone, a RESET instruction keeps them
+    fn reset_registers(&mut self) {
+        for &reg in register::LIST.iter() {
+            self.set_register(reg, 0);
+        }
+        self.set_register(register::SP, self.memory.len() as u16 - 2);
+        self.set_register(register::FP, self.memory.len() as u16 - 2);
+        self.set_register(register::IM, 0xff);
+        self.stack_frame_size = 0;
+        self.is_in_interrupt_handler = false;
+        self.timer = None;
+    }
+
+    // Runs until HLT or an unhandled fault, `vm run` steps itself to trace and check for Ctrl-C
+    pub fn run(&mut self) -> Result<HaltReason, CpuError> {
+        loop {
+            if let Some(halt) = self.step()? {
+                return Ok(halt);
+            }
+        }
+    }
+
+    pub fn register_syscall(&mut self, number: u16, handler: Box<dyn Syscall>) {
+        self.syscalls.insert(number, handler);
+    }
+
+    // Extensions take one cycle unless set_cycles says otherwise
+    pub fn register_extension(
+        &mut self,
+        opcode: u8,
+        handler: Box<dyn Extension>,
+    ) -> Result<(), String> {
+        if let Some(builtin) = instruction::LIST.iter().find(|i| i.opcode == opcode) {
+            return Err(format!(
+                "Opcode {:#04x} is already defined by {}",
+                opcode, builtin.mnemonic
+            ));
+        }
+        if self.extensions.contains_key(&opcode) {
+            return Err(format!(
+                "Opcode {:#04x} is already defined by an extension",
+                opcode
+            ));
+        }
+        if !extension::EXTENSION_RANGE.contains(&opcode) {
+            return Err(format!(
+                "Opcode {:#04x} is outside the extension range {:#04x}..={:#04x}",
+                opcode,
+                extension::EXTENSION_RANGE.start(),
+                extension::EXTENSION_RANGE.end()
+            ));
+        }
+        self.extensions.insert(opcode, handler);
+        if self.cycle_table[opcode as usize] == 0 {
+            self.cycle_table[opcode as usize] = 1;
+        }
+        Ok(())
+    }
+
+    // Overrides the default timing of a single opcode
+    pub fn set_cycles(&mut self, opcode: u8, cycles: u16) {
+        self.cycle_table[opcode as usize] = cycles;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // Raises `interrupt` after the instruction during which the cycle count reaches `at_cycle`
+    pub fn set_timer(&mut self, at_cycle: u64, interrupt: u16) {
+        self.timer = Some((at_cycle, interrupt));
+    }
+
+    // Handlers run on a separate stack growing down from `top`, so they can't overwrite anything
+    // below the interrupted program's SP. The stack is switched on entering the outermost handler
+    // only, a nested interrupt keeps using the interrupt stack.
+    pub fn set_interrupt_stack(&mut self, top: Option<u16>) {
+        self.interrupt_stack = top;
+    }
+
+    // Devices reached with IN and OUT instead of through memory
+    pub fn set_ports(&mut self, ports: PortBus) {
+        self.ports = ports;
+    }
+
+    // Pushes below `floor` fault as a stack overflow, the default floor 0 only stops SP wrapping
+    pub fn set_stack_floor(&mut self, floor: u16) {
+        self.stack_floor = floor;
+    }
+
+    // Pushes into `code` fault before anything is written, so a runaway SP can't overwrite the
+    // instructions about to run
+    pub fn set_code_region(&mut self, code: Option<Range<u16>>) {
+        self.code_region = code;
+    }
+
+    // Indexed moves wrap around the top of memory, in strict mode crossing it is a memory fault
+    pub fn set_strict_offsets(&mut self, strict: bool) {
+        self.strict_offsets = strict;
+    }
+
+    // Remembers the last `entries` instructions for post-mortem reports, 0 turns it off. Costs
+    // two writes per step while on.
+    pub fn set_history(&mut self, entries: usize) {
+        self.history = vec![None; entries];
+        self.history_next = 0;
+    }
+
+    // IP and opcode of the remembered instructions, oldest first. A faulting instruction is the
+    // last one.
+    pub fn history(&self) -> Vec<(u16, u8)> {
+        let (newer, older) = self.history.split_at(self.history_next);
+        older.iter().chain(newer).flatten().cloned().collect()
+    }
+
+    // Fault description for the host, pushes name the instruction that made them
+    pub fn fault_message(&self, error: &CpuError) -> String {
+        let info = &error.info();
+        let push = match info.cause {
+            FaultCause::StackOverflow | FaultCause::StackIntoCode => {
+                match self.memory.get_u8(info.ip as usize) {
+                    x if x == instruction::PSH_LIT.opcode || x == instruction::PSH_REG.opcode => {
+                        "Push during PSH"
+                    }
+                    x if x == instruction::CAL_LIT.opcode || x == instruction::CAL_REG.opcode => {
+                        "State push during CAL"
+                    }
+                    x if x == instruction::INT.opcode => "State push during INT",
+                    _ => return error.to_string(),
+                }
+            }
+            _ => return error.to_string(),
+        };
+        let problem = match (info.cause, &self.code_region) {
+            (FaultCause::StackIntoCode, Some(code)) => format!(
+                "SP {:#06x} is inside the code region {:#06x}-{:#06x}",
+                info.address,
+                code.start,
+                code.end - 1
+            ),
+            _ => format!(
+                "SP {:#06x} is below the stack floor {:#06x}",
+                info.address,
+                self.stack_floor.max(2)
+            ),
+        };
+        format!("{} at IP={:#06x}: {}", push, info.ip, problem)
     }
 
     #[cfg(test)]
     fn debug_registers(&self) -> HashMap<Register, u16> {
         let mut res = HashMap::new();
         for &reg in register::LIST.iter() {
-            res.insert(reg, self.registers.get_u16(reg as usize));
+            res.insert(reg, self.registers.get_u16(reg));
         }
         res
     }
 
-    fn set_register(&mut self, reg: Register, value: u16) {
+    pub fn memory(&self) -> &dyn Device {
+        self.memory.as_ref()
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: u16) {
         if reg == register::MB {
             self.memory.set_mb(value)
         }
         self.registers.set_u16(reg, value);
     }
 
-    fn get_register(&self, reg: Register) -> u16 {
+    pub fn get_register(&self, reg: Register) -> u16 {
         self.registers.get_u16(reg)
     }
 
@@ -72,64 +326,214 @@ impl CPU {
 
     fn push_to_stack(&mut self, value: u16) {
         let sp = self.get_register(register::SP);
+        if sp < 2 || sp < self.stack_floor {
+            self.raise(FaultCause::StackOverflow, sp);
+            return;
+        }
+        if let Some(code) = &self.code_region {
+            if code.contains(&sp) || code.contains(&(sp + 1)) {
+                self.raise(FaultCause::StackIntoCode, sp);
+                return;
+            }
+        }
         self.memory.set_u16(sp as usize, value);
         self.set_register(register::SP, sp - 2);
         self.stack_frame_size += 2;
     }
 
+    // The stack starts at the end of memory, taking `bytes` from above `sp` must not pass it
+    fn underflows(&mut self, sp: u16, bytes: u16) -> bool {
+        let underflows = sp as usize + bytes as usize > self.memory.len().saturating_sub(2);
+        if underflows {
+            self.raise(FaultCause::StackUnderflow, sp);
+        }
+        underflows
+    }
+
     fn pop_from_stack(&mut self) -> u16 {
-        let new_sp_address = self.get_register(register::SP) + 2;
+        let sp = self.get_register(register::SP);
+        if self.underflows(sp, 2) {
+            return 0;
+        }
+        let new_sp_address = sp + 2;
         self.set_register(register::SP, new_sp_address);
-        self.stack_frame_size -= 2;
+        self.stack_frame_size = self.stack_frame_size.saturating_sub(2);
         self.memory.get_u16(new_sp_address as usize)
     }
 
+    // `base` plus the offset in `reg`, None after raising a fault in strict mode
+    fn offset_address(&mut self, base: u16, reg: Register) -> Option<u16> {
+        let offset = self.get_register(reg);
+        match base.checked_add(offset) {
+            None if self.strict_offsets => {
+                self.raise(FaultCause::MemoryFault, base);
+                None
+            }
+            _ => Some(base.wrapping_add(offset)),
+        }
+    }
+
+    // FP plus a two's complement `offset`. Leaving the address space either way is a fault in
+    // strict mode, like `offset_address`.
+    fn frame_address(&mut self, offset: u16) -> Option<u16> {
+        let fp = self.get_register(register::FP);
+        let address = fp as i32 + offset as i16 as i32;
+        if self.strict_offsets && !(0..=u16::MAX as i32).contains(&address) {
+            self.raise(FaultCause::MemoryFault, fp);
+            return None;
+        }
+        Some(fp.wrapping_add(offset))
+    }
+
+    // Writes the low word of a 32 bit result to `reg` and the high word to HI
+    fn set_wide(&mut self, reg: Register, value: u32) {
+        self.registers.set_u16(register::HI, (value >> 16) as u16);
+        self.registers.set_u16(reg, value as u16);
+    }
+
+    fn compare(&mut self, a: u16, b: u16) {
+        let (difference, borrow) = a.overflowing_sub(b);
+        let mut flags = 0;
+        if difference == 0 {
+            flags |= register::ZERO;
+        }
+        if borrow {
+            flags |= register::CARRY;
+        }
+        if difference & 0x8000 != 0 {
+            flags |= register::SIGN;
+        }
+        self.registers.set_u16(register::FLAGS, flags);
+    }
+
+    fn jump_on_flag(&mut self, flag: u16, set: bool) {
+        let address = self.fetch16();
+        if (self.get_register(register::FLAGS) & flag != 0) == set {
+            self.set_register(register::IP, address);
+        }
+    }
+
     fn fetch_register_index(&mut self) -> Register {
-        self.fetch8() as usize
+        let byte = self.fetch8();
+        register::from_byte(byte).unwrap_or_else(|| {
+            self.raise(FaultCause::IllegalRegister, byte as u16);
+            self.registers.set_u16(SCRATCH_REGISTER, 0);
+            SCRATCH_REGISTER
+        })
     }
 
+    // Calling convention: the caller pushes the arguments, then their count, then CALs. RET
+    // pops the state and then the count and the arguments, so the caller's SP is as before the
+    // first push. Frame layout, from the caller's stack down:
+    //   argument 1..n, n, R1..R8, IP, FP, caller's stack frame size
+    // FP points right below it and the callee's frame size starts from zero. The count is at
+    // FP + 24 and argument i at FP + 24 + 2 * (n - i + 1), so with two arguments the first is at
+    // FP + 28 and the second at FP + 26. Interrupt handlers get the same state without a count.
     fn push_state(&mut self) {
+        let stack_frame_size = self.stack_frame_size;
         for &reg in register::GENERAL_PURPOSE_LIST.iter() {
             self.push_to_stack(self.get_register(reg));
         }
         self.push_to_stack(self.get_register(register::IP));
-        self.push_to_stack(self.stack_frame_size + 2);
+        self.push_to_stack(self.get_register(register::FP));
+        self.push_to_stack(stack_frame_size);
         self.set_register(register::FP, self.get_register(register::SP));
         self.stack_frame_size = 0;
     }
 
-    fn pop_state(&mut self) {
-        let frame_pointer_address = self.get_register(register::FP);
-        self.set_register(register::SP, frame_pointer_address);
-        self.stack_frame_size = 2; //Hack to make popping work with stack_frame_size == 0
+    // False after a stack underflow, the registers are left alone then
+    fn pop_state(&mut self) -> bool {
+        let fp = self.get_register(register::FP);
+        if self.underflows(fp, STATE_SIZE) {
+            return false;
+        }
+        // Drop whatever the callee left on the stack, leaving only the saved state in its frame
+        self.set_register(register::SP, fp);
+        self.stack_frame_size = STATE_SIZE;
 
         let stack_frame_size = self.pop_from_stack();
-        self.stack_frame_size = stack_frame_size;
-
+        let frame_pointer = self.pop_from_stack();
         let ip = self.pop_from_stack();
         self.set_register(register::IP, ip);
-
         for &reg in register::GENERAL_PURPOSE_LIST.iter().rev() {
             let value = self.pop_from_stack();
             self.set_register(reg, value);
         }
 
-        // let n_args = self.pop_from_stack();
-        // for _ in 0..n_args {
-        //     self.pop_from_stack();
-        // }
+        self.set_register(register::FP, frame_pointer);
+        self.stack_frame_size = stack_frame_size;
+        true
+    }
+
+    // After pop_state for RET. A caller that didn't push a count loses whatever its top word
+    // says.
+    fn pop_arguments(&mut self) {
+        let size = self.pop_from_stack().wrapping_mul(2);
+        let sp = self.get_register(register::SP);
+        self.set_register(register::SP, sp.wrapping_add(size));
+        self.stack_frame_size = self.stack_frame_size.saturating_sub(size);
+    }
 
-        self.set_register(register::FP, frame_pointer_address + stack_frame_size);
+    // The context block at `address` if it fits in memory, raises a memory fault at `address`
+    // otherwise
+    fn context_block(&mut self, address: u16) -> Option<usize> {
+        let address = address as usize;
+        if address + CONTEXT_SIZE as usize > self.memory.len() {
+            self.raise(FaultCause::MemoryFault, address as u16);
+            return None;
+        }
+        Some(address)
     }
 
+    fn save_context(&mut self, address: u16) {
+        let address = match self.context_block(address) {
+            Some(address) => address,
+            None => return,
+        };
+        for &reg in register::LIST.iter() {
+            self.memory.set_u16(address + reg, self.get_register(reg));
+        }
+        self.memory
+            .set_u16(address + register::SIZE as usize, self.stack_frame_size);
+    }
+
+    fn load_context(&mut self, address: u16) {
+        let address = match self.context_block(address) {
+            Some(address) => address,
+            None => return,
+        };
+        for &reg in register::LIST.iter() {
+            let value = self.memory.get_u16(address + reg);
+            self.set_register(reg, value);
+        }
+        self.stack_frame_size = self.memory.get_u16(address + register::SIZE as usize);
+    }
+
+    // INT, the timer and devices all come through here, so a number without a vector faults
+    // whichever raised it
     fn handle_interrupt(&mut self, value: u16) {
+        if value >= INTERRUPT_VECTORS {
+            self.raise(FaultCause::IllegalInterrupt, value);
+            return;
+        }
         if (1 << value) & self.get_register(register::IM) == 0 {
             return;
         }
         let address_pointer = INTERRUPT_VECTOR_ADDRESS + (value as usize) * 2;
         let address = self.memory.get_u16(address_pointer);
+        self.enter_handler(address);
+    }
 
+    // Below the interrupted program's SP, if there is an interrupt stack, handlers get IM and then
+    // the state pushed as for CAL. RTI restores both, so a handler may change IM while it runs.
+    fn enter_handler(&mut self, address: u16) {
         if !self.is_in_interrupt_handler {
+            if let Some(top) = self.interrupt_stack {
+                let sp = self.get_register(register::SP);
+                self.set_register(register::SP, top);
+                self.push_to_stack(sp);
+            }
+            self.push_to_stack(self.get_register(register::IM));
             self.push_state();
         }
 
@@ -137,15 +541,85 @@ impl CPU {
         self.set_register(register::IP, address)
     }
 
+    fn raise(&mut self, cause: FaultCause, address: u16) {
+        if self.fault.is_none() {
+            self.fault = Some(FaultInfo {
+                cause,
+                address,
+                ip: self.instruction_address,
+            });
+        }
+    }
+
+    // Returns false if the guest can't handle the fault
+    fn enter_fault_handler(&mut self, info: FaultInfo) -> bool {
+        if self.is_in_interrupt_handler
+            || fault::FAULT_INFO_ADDRESS + 6 > self.memory.len()
+            || (matches!(
+                info.cause,
+                FaultCause::StackOverflow | FaultCause::StackIntoCode
+            ) && self.interrupt_stack.is_none())
+        {
+            return false;
+        }
+        // An unmapped vector reads as open bus, not as a handler
+        let handler = self.memory.get_u16(fault::FAULT_VECTOR_ADDRESS);
+        if handler == 0 || self.memory.take_fault().is_some() {
+            return false;
+        }
+        let words = [info.cause as u16, info.address, info.ip];
+        for (i, &word) in words.iter().enumerate() {
+            self.memory.set_u16(fault::FAULT_INFO_ADDRESS + i * 2, word);
+        }
+        self.enter_handler(handler);
+        true
+    }
+
+    fn syscall(&mut self, number: u16) {
+        match self.syscalls.remove(&number) {
+            Some(mut handler) => {
+                handler.call(self);
+                self.syscalls.insert(number, handler);
+            }
+            None => self.set_register(register::ACC, syscall::STATUS_UNSUPPORTED),
+        }
+    }
+
     fn execute(&mut self, instruction: u8) -> bool {
         match instruction {
             x if x == instruction::INT.opcode => {
                 let value = self.fetch16();
                 self.handle_interrupt(value);
             }
+            x if x == instruction::RESET.opcode => {
+                self.reset_registers();
+                self.memory.reset();
+                self.ports.reset();
+                let start = self.reset_vector();
+                self.set_register(register::IP, start);
+            }
             x if x == instruction::RET_INT.opcode => {
                 self.is_in_interrupt_handler = false;
-                self.pop_from_stack();
+                if self.pop_state() {
+                    let im = self.pop_from_stack();
+                    self.set_register(register::IM, im);
+                    if self.interrupt_stack.is_some() {
+                        let sp = self.pop_from_stack();
+                        self.set_register(register::SP, sp);
+                    }
+                }
+            }
+            x if x == instruction::SAVE_CTX_MEM.opcode => {
+                let address = self.fetch16();
+                self.save_context(address);
+            }
+            x if x == instruction::LOAD_CTX_MEM.opcode => {
+                let address = self.fetch16();
+                self.load_context(address);
+            }
+            x if x == instruction::SYS.opcode => {
+                let number = self.fetch16();
+                self.syscall(number);
             }
             x if x == instruction::MOVE_LIT_MEM.opcode => {
                 let value = self.fetch16();
@@ -173,9 +647,35 @@ impl CPU {
                 let address = self.fetch16();
                 let reg_from = self.fetch_register_index();
                 let reg_to = self.fetch_register_index();
-                let offset = self.get_register(reg_from);
-                let val = self.memory.get_u16((offset + address) as usize);
-                self.set_register(reg_to, val)
+                if let Some(address) = self.offset_address(address, reg_from) {
+                    let val = self.memory.get_u16(address as usize);
+                    self.set_register(reg_to, val)
+                }
+            }
+            x if x == instruction::MOVE_REG_LIT_OFF.opcode => {
+                let reg_value = self.fetch_register_index();
+                let address = self.fetch16();
+                let reg_offset = self.fetch_register_index();
+                if let Some(address) = self.offset_address(address, reg_offset) {
+                    self.memory
+                        .set_u16(address as usize, self.get_register(reg_value))
+                }
+            }
+            x if x == instruction::MOVE_FP_OFF_REG.opcode => {
+                let offset = self.fetch16();
+                let reg = self.fetch_register_index();
+                if let Some(address) = self.frame_address(offset) {
+                    let val = self.memory.get_u16(address as usize);
+                    self.set_register(reg, val)
+                }
+            }
+            x if x == instruction::MOVE_REG_FP_OFF.opcode => {
+                let reg = self.fetch_register_index();
+                let offset = self.fetch16();
+                if let Some(address) = self.frame_address(offset) {
+                    self.memory
+                        .set_u16(address as usize, self.get_register(reg))
+                }
             }
             x if x == instruction::MOVE_REG_MEM.opcode => {
                 let reg = self.fetch_register_index();
@@ -191,75 +691,93 @@ impl CPU {
             x if x == instruction::ADD_REG_REG.opcode => {
                 let r1 = self.fetch_register_index();
                 let r2 = self.fetch_register_index();
-                self.set_register(register::ACC, self.get_register(r1) + self.get_register(r2))
+                let res = self.get_register(r1) as u32 + self.get_register(r2) as u32;
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::ADD_LIT_REG.opcode => {
                 let val = self.fetch16();
                 let reg = self.fetch_register_index();
-                self.set_register(register::ACC, self.get_register(reg) + val)
+                let res = self.get_register(reg) as u32 + val as u32;
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::SUB_LIT_REG.opcode => {
                 let val = self.fetch16();
                 let reg = self.fetch_register_index();
-                self.set_register(register::ACC, val - self.get_register(reg))
+                let res = (val as u32).wrapping_sub(self.get_register(reg) as u32);
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::SUB_REG_LIT.opcode => {
                 let reg = self.fetch_register_index();
                 let val = self.fetch16();
-                self.set_register(register::ACC, self.get_register(reg) - val)
+                let res = (self.get_register(reg) as u32).wrapping_sub(val as u32);
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::SUB_REG_REG.opcode => {
                 let reg_1 = self.fetch_register_index();
                 let reg_2 = self.fetch_register_index();
-                self.set_register(
-                    register::ACC,
-                    self.get_register(reg_1) - self.get_register(reg_2),
-                )
+                let res =
+                    (self.get_register(reg_1) as u32).wrapping_sub(self.get_register(reg_2) as u32);
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::MUL_REG_REG.opcode => {
                 let reg_1 = self.fetch_register_index();
                 let reg_2 = self.fetch_register_index();
-                self.set_register(
-                    register::ACC,
-                    self.get_register(reg_1) * self.get_register(reg_2),
-                )
+                let res = self.get_register(reg_1) as u32 * self.get_register(reg_2) as u32;
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::MUL_LIT_REG.opcode => {
                 let val = self.fetch16();
                 let reg = self.fetch_register_index();
-                self.set_register(register::ACC, val * self.get_register(reg))
+                let res = val as u32 * self.get_register(reg) as u32;
+                self.set_wide(register::ACC, res)
             }
             x if x == instruction::INC_REG.opcode => {
                 let reg = self.fetch_register_index();
-                self.registers.set_u16(reg, self.get_register(reg) + 1);
+                self.set_wide(reg, self.get_register(reg) as u32 + 1);
             }
             x if x == instruction::DEC_REG.opcode => {
                 let reg = self.fetch_register_index();
-                self.registers.set_u16(reg, self.get_register(reg) - 1);
+                self.set_wide(reg, (self.get_register(reg) as u32).wrapping_sub(1));
+            }
+            x if x == instruction::CMP_REG_REG.opcode => {
+                let reg_1 = self.fetch_register_index();
+                let reg_2 = self.fetch_register_index();
+                self.compare(self.get_register(reg_1), self.get_register(reg_2));
+            }
+            x if x == instruction::CMP_REG_LIT.opcode => {
+                let reg = self.fetch_register_index();
+                let val = self.fetch16();
+                self.compare(self.get_register(reg), val);
             }
 
             // Binary operations
             x if x == instruction::LSF_REG_REG.opcode => {
                 let reg_1 = self.fetch_register_index();
                 let reg_2 = self.fetch_register_index();
-                self.registers
-                    .set_u16(reg_1, self.get_register(reg_1) << self.get_register(reg_2))
+                self.registers.set_u16(
+                    reg_1,
+                    shift_left(self.get_register(reg_1), self.get_register(reg_2)),
+                )
             }
             x if x == instruction::LSF_REG_LIT8.opcode => {
                 let reg = self.fetch_register_index();
-                let val = self.fetch16();
-                self.registers.set_u16(reg, self.get_register(reg) << val)
+                let val = self.fetch8();
+                self.registers
+                    .set_u16(reg, shift_left(self.get_register(reg), val as u16))
             }
             x if x == instruction::RSF_REG_REG.opcode => {
                 let reg_1 = self.fetch_register_index();
                 let reg_2 = self.fetch_register_index();
-                self.registers
-                    .set_u16(reg_1, self.get_register(reg_1) >> self.get_register(reg_2))
+                self.registers.set_u16(
+                    reg_1,
+                    shift_right(self.get_register(reg_1), self.get_register(reg_2)),
+                )
             }
             x if x == instruction::RSF_REG_LIT8.opcode => {
                 let reg = self.fetch_register_index();
-                let val = self.fetch16();
-                self.registers.set_u16(reg, self.get_register(reg) >> val)
+                let val = self.fetch8();
+                self.registers
+                    .set_u16(reg, shift_right(self.get_register(reg), val as u16))
             }
             x if x == instruction::AND_REG_REG.opcode => {
                 let reg_1 = self.fetch_register_index();
@@ -394,6 +912,51 @@ impl CPU {
                     self.set_register(register::IP, address)
                 }
             }
+            x if x == instruction::JMP_LIT.opcode => {
+                let address = self.fetch16();
+                self.set_register(register::IP, address);
+            }
+            x if x == instruction::JMP_REG.opcode => {
+                let reg = self.fetch_register_index();
+                self.set_register(register::IP, self.get_register(reg));
+            }
+            // ACC, HI and FLAGS are left alone, so the loop body can use them
+            x if x == instruction::LOOP_REG_MEM.opcode => {
+                let reg = self.fetch_register_index();
+                let address = self.fetch16();
+                let count = self.get_register(reg).wrapping_sub(1);
+                self.set_register(reg, count);
+                if count != 0 {
+                    self.set_register(register::IP, address);
+                }
+            }
+            x if x == instruction::JZ_MEM.opcode => self.jump_on_flag(register::ZERO, true),
+            x if x == instruction::JNZ_MEM.opcode => self.jump_on_flag(register::ZERO, false),
+            x if x == instruction::JC_MEM.opcode => self.jump_on_flag(register::CARRY, true),
+            x if x == instruction::JNC_MEM.opcode => self.jump_on_flag(register::CARRY, false),
+            x if x == instruction::JS_MEM.opcode => self.jump_on_flag(register::SIGN, true),
+
+            x if x == instruction::IN_LIT8_REG.opcode => {
+                let port = self.fetch8();
+                let reg = self.fetch_register_index();
+                self.set_register(reg, self.ports.read(port));
+            }
+            x if x == instruction::IN_REG_REG.opcode => {
+                let port = self.fetch_register_index();
+                let reg = self.fetch_register_index();
+                self.set_register(reg, self.ports.read(self.get_register(port) as u8));
+            }
+            x if x == instruction::OUT_REG_LIT8.opcode => {
+                let reg = self.fetch_register_index();
+                let port = self.fetch8();
+                self.ports.write(port, self.get_register(reg));
+            }
+            x if x == instruction::OUT_REG_REG.opcode => {
+                let reg = self.fetch_register_index();
+                let port = self.fetch_register_index();
+                self.ports
+                    .write(self.get_register(port) as u8, self.get_register(reg));
+            }
 
             x if x == instruction::PSH_LIT.opcode => {
                 let lit = self.fetch16();
@@ -420,31 +983,112 @@ impl CPU {
                 self.set_register(register::IP, address);
             }
             x if x == instruction::RET.opcode => {
-                self.pop_state();
+                if self.pop_state() {
+                    self.pop_arguments();
+                }
             }
             x if x == instruction::HLT.opcode => return true,
-            _ => panic!("Unrecognized instruction: {}", instruction),
+            x if self.extensions.contains_key(&x) => {
+                let mut handler = self.extensions.remove(&x).unwrap();
+                let outcome = handler.execute(&mut CpuView::new(self));
+                self.extensions.insert(x, handler);
+                return outcome == StepOutcome::Halt;
+            }
+            _ => self.raise(FaultCause::IllegalOpcode, self.instruction_address),
         }
         false
     }
 
-    fn step(&mut self) -> bool {
+    // Runs one instruction and the interrupt it may trigger. Returns the halt reason after HLT and
+    // the faults the guest doesn't handle.
+    pub fn step(&mut self) -> Result<Option<HaltReason>, CpuError> {
+        let ip = self.get_register(register::IP);
+        self.instruction_address = ip;
+        if ip as usize >= self.memory.len() {
+            self.raise(FaultCause::MemoryFault, ip);
+            self.deliver_fault(0)?;
+            return Ok(None);
+        }
+
+        // Host accesses between steps, like a memory dump, don't fault the guest
+        self.memory.take_fault();
         let instruction = self.fetch8();
-        self.execute(instruction)
+        if !self.history.is_empty() {
+            self.history[self.history_next] = Some((ip, instruction));
+            self.history_next = (self.history_next + 1) % self.history.len();
+        }
+        let halted = self.execute(instruction);
+        if let Some(address) = self.memory.take_fault() {
+            self.raise(FaultCause::MemoryFault, address);
+        }
+        self.deliver_fault(instruction)?;
+
+        let cycles = self.cycle_table[instruction as usize];
+        self.cycles += cycles as u64;
+        self.instructions += 1;
+        self.memory.tick(cycles);
+        self.ports.tick(cycles);
+        let timer = match self.timer {
+            Some((at_cycle, interrupt)) if self.cycles >= at_cycle => {
+                self.timer = None;
+                Some(interrupt)
+            }
+            _ => None,
+        };
+        // Devices keep their interrupt pending until no handler runs and the timer is quiet
+        let device = if timer.is_none() && !self.is_in_interrupt_handler {
+            self.memory
+                .take_interrupt()
+                .or_else(|| self.ports.take_interrupt())
+        } else {
+            None
+        };
+        if let Some(interrupt) = timer.or(device) {
+            self.handle_interrupt(interrupt);
+            self.deliver_fault(instruction)?;
+        }
+        Ok(halted.then(|| HaltReason {
+            ip,
+            acc: self.get_register(register::ACC),
+        }))
+    }
+
+    // Hands a raised fault to the guest handler, or returns it when there is none
+    fn deliver_fault(&mut self, opcode: u8) -> Result<(), CpuError> {
+        match self.fault.take() {
+            Some(info) if !self.enter_fault_handler(info) => Err(CpuError::new(info, opcode)),
+            _ => Ok(()),
+        }
     }
 }
 
+// Shifting by 16 or more moves every bit out
+fn shift_left(value: u16, by: u16) -> u16 {
+    value.checked_shl(by as u32).unwrap_or(0)
+}
+
+fn shift_right(value: u16, by: u16) -> u16 {
+    value.checked_shr(by as u32).unwrap_or(0)
+}
+
 #[cfg(test)]
+#[cfg_attr(not(feature = "assembler"), allow(unused_imports))]
 mod tests {
+    #[cfg(feature = "assembler")]
+    use crate::assembler;
     use crate::device::banked_memory::BankedMemory;
     use crate::device::memory::Memory;
     use crate::device::memory_mapper::MemoryMapper;
     use crate::device::Device;
 
+    use super::extension::{CpuView, StepOutcome};
+    use super::fault::CpuError;
+    use super::fault::{self, FaultCause, FaultInfo};
     use super::instruction;
     use super::register;
-    use super::CPU;
+    use super::{HaltReason, CPU};
 
+    #[allow(dead_code)]
     fn view_memory_at(mem: Memory, address: usize) {
         print!("{:X}:", address);
         for byte in address..address + 8 {
@@ -506,27 +1150,30 @@ mod tests {
         assert_eq!(cpu.get_register(register::SP), 62);
         assert_eq!(cpu.get_register(register::FP), 62);
 
+        cpu.push_to_stack(10);
         cpu.push_state();
         assert_eq!(cpu.stack_frame_size, 0);
-        assert_eq!(cpu.get_register(register::SP), 42);
-        assert_eq!(cpu.get_register(register::FP), 42);
-        assert_eq!(cpu.memory.get_u16(62), 20); //R1
-        assert_eq!(cpu.memory.get_u16(56), 30); //R4
-        assert_eq!(cpu.memory.get_u16(44), 20); //stack frame size
+        assert_eq!(cpu.get_register(register::SP), 38);
+        assert_eq!(cpu.get_register(register::FP), 38);
+        assert_eq!(cpu.memory.get_u16(60), 20); //R1
+        assert_eq!(cpu.memory.get_u16(54), 30); //R4
+        assert_eq!(cpu.memory.get_u16(42), 62); //FP
+        assert_eq!(cpu.memory.get_u16(40), 2); //stack frame size
         cpu.set_register(register::R4, 40);
         cpu.set_register(register::R3, 50);
 
         cpu.push_state();
         assert_eq!(cpu.stack_frame_size, 0);
-        assert_eq!(cpu.get_register(register::SP), 22);
-        assert_eq!(cpu.get_register(register::FP), 22);
-        assert_eq!(cpu.memory.get_u16(62), 20); //R1
-        assert_eq!(cpu.memory.get_u16(56), 30); //R4
-        assert_eq!(cpu.memory.get_u16(44), 20); //stack frame size
-        assert_eq!(cpu.memory.get_u16(42), 20); //R1
-        assert_eq!(cpu.memory.get_u16(38), 50); //R3
-        assert_eq!(cpu.memory.get_u16(36), 40); //R4
-        assert_eq!(cpu.memory.get_u16(24), 20); //stack frame size
+        assert_eq!(cpu.get_register(register::SP), 16);
+        assert_eq!(cpu.get_register(register::FP), 16);
+        assert_eq!(cpu.memory.get_u16(60), 20); //R1
+        assert_eq!(cpu.memory.get_u16(54), 30); //R4
+        assert_eq!(cpu.memory.get_u16(40), 2); //stack frame size
+        assert_eq!(cpu.memory.get_u16(38), 20); //R1
+        assert_eq!(cpu.memory.get_u16(34), 50); //R3
+        assert_eq!(cpu.memory.get_u16(32), 40); //R4
+        assert_eq!(cpu.memory.get_u16(20), 38); //FP
+        assert_eq!(cpu.memory.get_u16(18), 0); //stack frame size
     }
 
     #[test]
@@ -536,13 +1183,15 @@ mod tests {
         cpu.set_register(register::R1, 20);
         cpu.set_register(register::R4, 30);
 
+        cpu.push_to_stack(10);
         cpu.push_state();
         cpu.set_register(register::R4, 40);
         cpu.set_register(register::R3, 50);
 
         cpu.push_state();
-        assert_eq!(cpu.get_register(register::SP), 22);
-        assert_eq!(cpu.get_register(register::FP), 22);
+        cpu.push_to_stack(10);
+        assert_eq!(cpu.get_register(register::SP), 14);
+        assert_eq!(cpu.get_register(register::FP), 16);
         cpu.set_register(register::R4, 60);
         cpu.set_register(register::R2, 70);
         assert_eq!(cpu.get_register(register::R1), 20);
@@ -551,15 +1200,17 @@ mod tests {
         assert_eq!(cpu.get_register(register::R4), 60);
 
         cpu.pop_state();
-        assert_eq!(cpu.get_register(register::SP), 42);
-        assert_eq!(cpu.get_register(register::FP), 42);
+        assert_eq!(cpu.stack_frame_size, 0);
+        assert_eq!(cpu.get_register(register::SP), 38);
+        assert_eq!(cpu.get_register(register::FP), 38);
         assert_eq!(cpu.get_register(register::R1), 20);
         assert_eq!(cpu.get_register(register::R2), 0);
         assert_eq!(cpu.get_register(register::R3), 50);
         assert_eq!(cpu.get_register(register::R4), 40);
 
         cpu.pop_state();
-        assert_eq!(cpu.get_register(register::SP), 62);
+        assert_eq!(cpu.stack_frame_size, 2);
+        assert_eq!(cpu.get_register(register::SP), 60);
         assert_eq!(cpu.get_register(register::FP), 62);
         assert_eq!(cpu.get_register(register::R1), 20);
         assert_eq!(cpu.get_register(register::R2), 0);
@@ -572,21 +1223,21 @@ mod tests {
         let mut mem = Memory::new(11);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 0x1234);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
         mem.set_u8(4, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(5, 0xABCD);
-        mem.set_u8(7, register::R2 as u8);
+        mem.set_u8(7, register::number(register::R2));
         mem.set_u8(8, instruction::ADD_REG_REG.opcode);
-        mem.set_u8(9, register::R1 as u8);
-        mem.set_u8(10, register::R2 as u8);
+        mem.set_u8(9, register::number(register::R1));
+        mem.set_u8(10, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
 
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.debug_registers()[&register::R1], 0x1234);
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.debug_registers()[&register::R2], 0xABCD);
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.debug_registers()[&register::ACC], 0xBE01);
     }
 
@@ -595,10 +1246,10 @@ mod tests {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 0x1234);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x1234);
         assert_eq!(cpu.registers.get_u8(register::R1), 0x12);
@@ -610,14 +1261,14 @@ mod tests {
         let mut mem = Memory::new(7);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 0x1234);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
         mem.set_u8(4, instruction::MOVE_REG_REG.opcode);
-        mem.set_u8(5, register::R1 as u8);
-        mem.set_u8(6, register::R2 as u8);
+        mem.set_u8(5, register::number(register::R1));
+        mem.set_u8(6, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
-        cpu.step();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.registers.get_u16(register::R2), 0x1234);
         assert_eq!(cpu.registers.get_u8(register::R2), 0x12);
@@ -629,14 +1280,14 @@ mod tests {
         let mut mem = Memory::new(8);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 0x1234);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
         mem.set_u8(4, instruction::MOVE_REG_MEM.opcode);
-        mem.set_u8(5, register::R1 as u8);
+        mem.set_u8(5, register::number(register::R1));
         mem.set_u16(6, 0x1);
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
-        cpu.step();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.memory.get_u16(0x1), 0x1234);
         assert_eq!(cpu.memory.get_u8(0x1), 0x12);
@@ -651,7 +1302,7 @@ mod tests {
         mem.set_u16(3, 0x6);
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.memory.get_u16(0x6), 0x1234);
     }
@@ -660,13 +1311,13 @@ mod tests {
     fn move_reg_ptr_reg() {
         let mut mem = Memory::new(8);
         mem.set_u8(0, instruction::MOVE_REG_PTR_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
         mem.set_u16(0x6, 0x5555);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x6);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R2), 0x5555);
     }
@@ -676,27 +1327,90 @@ mod tests {
         let mut mem = Memory::new(8);
         mem.set_u8(0, instruction::MOVE_LIT_OFF_REG.opcode);
         mem.set_u16(1, 1);
-        mem.set_u8(3, register::R1 as u8);
-        mem.set_u8(4, register::R2 as u8);
+        mem.set_u8(3, register::number(register::R1));
+        mem.set_u8(4, register::number(register::R2));
         mem.set_u16(0x6, 0x5555);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x5);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R2), 0x5555);
     }
 
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn indexed_moves_wrap() {
+        let assembly = assembler::assemble(
+            "mov $fffe R1 R2\nmov R3 $fffe R1\nmov R3 $10 R1\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        assert_eq!(&assembly.bytes[5..10], &[0x1e, 0x04, 0xff, 0xfe, 0x02]);
+        let mut mem = Memory::with_contents(0x100, &assembly.bytes);
+        mem.set_u16(0x20, 0x1234);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_register(register::R1, 0x22);
+        cpu.set_register(register::R3, 0xabcd);
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R2), 0x1234);
+        assert_eq!(cpu.memory.get_u16(0x20), 0xabcd);
+        assert_eq!(cpu.memory.get_u16(0x32), 0xabcd);
+
+        cpu.reset();
+        cpu.set_strict_offsets(true);
+        cpu.set_register(register::R1, 0x22);
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::MemoryFault {
+                address: 0xfffe,
+                ip: 0
+            })
+        );
+        assert_eq!(cpu.get_register(register::R2), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn frame_offsets() {
+        let assembly = assembler::assemble(
+            "mov &[FP + $4] R1\nmov R1 &[FP - $2]\nmov $7 R2\nmov R2 &[FP]\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            &assembly.bytes[..8],
+            &[0x1f, 0x00, 0x04, 0x02, 0x20, 0x02, 0xff, 0xfe]
+        );
+        let mut mem = Memory::with_contents(0x100, &assembly.bytes);
+        mem.set_u16(0x84, 0x1234);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_register(register::FP, 0x80);
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 0x1234);
+        assert_eq!(cpu.memory.get_u16(0x7e), 0x1234);
+        assert_eq!(cpu.memory.get_u16(0x80), 0x7);
+
+        // Going below address 0 faults in strict mode
+        cpu.reset();
+        cpu.set_strict_offsets(true);
+        cpu.set_register(register::FP, 0);
+        cpu.set_register(register::IP, 4);
+        assert_eq!(cpu.step(), Err(CpuError::MemoryFault { address: 0, ip: 4 }));
+    }
+
     #[test]
     fn add_lit_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::ADD_LIT_REG.opcode);
         mem.set_u16(1, 5);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x5);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0xa);
     }
@@ -706,11 +1420,11 @@ mod tests {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::SUB_LIT_REG.opcode);
         mem.set_u16(1, 5);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x3);
     }
@@ -719,12 +1433,12 @@ mod tests {
     fn sub_reg_lit() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::SUB_REG_LIT.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
         mem.set_u16(2, 5);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0xe);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x9);
     }
@@ -733,13 +1447,13 @@ mod tests {
     fn sub_reg_reg() {
         let mut mem = Memory::new(3);
         mem.set_u8(0, instruction::SUB_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0xe);
         cpu.set_register(register::R2, 0x6);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x8);
     }
@@ -748,13 +1462,13 @@ mod tests {
     fn mul_reg_reg() {
         let mut mem = Memory::new(3);
         mem.set_u8(0, instruction::MUL_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
         cpu.set_register(register::R2, 0x6);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0xc);
     }
@@ -764,11 +1478,11 @@ mod tests {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::MUL_LIT_REG.opcode);
         mem.set_u16(1, 0x3);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x6);
     }
@@ -777,12 +1491,12 @@ mod tests {
     fn lst_reg_lit() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::LSF_REG_LIT8.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u16(2, 0x3);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, 0x3);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x10);
     }
@@ -791,13 +1505,13 @@ mod tests {
     fn lst_reg_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::LSF_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
         cpu.set_register(register::R2, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x8);
     }
@@ -806,12 +1520,12 @@ mod tests {
     fn rst_reg_lit() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::RSF_REG_LIT8.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u16(2, 0x1);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, 0x1);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x1);
     }
@@ -820,13 +1534,13 @@ mod tests {
     fn rst_reg_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::RSF_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x8);
         cpu.set_register(register::R2, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x2);
     }
@@ -835,12 +1549,12 @@ mod tests {
     fn and_reg_lit() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::AND_REG_LIT.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
         mem.set_u16(2, 0x1);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x3);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x1);
     }
@@ -849,13 +1563,13 @@ mod tests {
     fn and_reg_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::AND_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0xa);
         cpu.set_register(register::R2, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x2);
     }
@@ -864,12 +1578,12 @@ mod tests {
     fn or_reg_lit() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::OR_REG_LIT.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
         mem.set_u16(2, 0x8);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x3);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0xb);
     }
@@ -878,13 +1592,13 @@ mod tests {
     fn or_reg_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::OR_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0xa);
         cpu.set_register(register::R2, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0xa);
     }
@@ -893,12 +1607,12 @@ mod tests {
     fn xor_reg_lit() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::XOR_REG_LIT.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
         mem.set_u16(2, 0x1);
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x3);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x2);
     }
@@ -907,13 +1621,13 @@ mod tests {
     fn xor_reg_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::XOR_REG_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
-        mem.set_u8(2, register::R2 as u8);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0xa);
         cpu.set_register(register::R2, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0x8);
     }
@@ -922,11 +1636,11 @@ mod tests {
     fn not() {
         let mut mem = Memory::new(2);
         mem.set_u8(0, instruction::NOT_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::ACC), 0xfffd);
     }
@@ -935,11 +1649,11 @@ mod tests {
     fn inc_reg() {
         let mut mem = Memory::new(2);
         mem.set_u8(0, instruction::INC_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x3);
     }
@@ -948,24 +1662,63 @@ mod tests {
     fn dec_reg() {
         let mut mem = Memory::new(2);
         mem.set_u8(0, instruction::DEC_REG.opcode);
-        mem.set_u8(1, register::R1 as u8);
+        mem.set_u8(1, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         cpu.set_register(register::R1, 0x2);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x1);
     }
 
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn wrapping_arithmetic() {
+        let program = "mov $ffff R1\nmov $1 R2\n\
+                       add R1 R2\nadd $1 R2\nsub $0 R2\nsub R2 R2\n\
+                       inc R1\ninc R1\ndec R1\ndec R1\n\
+                       mul $1234 R2\nmov $100 R3\nmul R3 R3\nhlt\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x100, &bytes);
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // (register, its value, HI) after each instruction
+        let expected = [
+            (register::ACC, 0x0000, 0x0001),
+            (register::ACC, 0x0002, 0x0000),
+            (register::ACC, 0xffff, 0xffff),
+            (register::ACC, 0x0000, 0x0000),
+            (register::R1, 0x0000, 0x0001),
+            (register::R1, 0x0001, 0x0000),
+            (register::R1, 0x0000, 0x0000),
+            (register::R1, 0xffff, 0xffff),
+            (register::ACC, 0x1234, 0x0000),
+        ];
+        for &(reg, value, hi) in expected.iter() {
+            cpu.step().unwrap();
+            assert_eq!(
+                (cpu.get_register(reg), cpu.get_register(register::HI)),
+                (value, hi),
+                "{}",
+                cpu.instructions()
+            );
+        }
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), 0x0000);
+        assert_eq!(cpu.get_register(register::HI), 0x0001);
+    }
+
     #[test]
     fn move_mem_reg() {
         let mut mem = Memory::new(4);
         mem.set_u8(0, instruction::MOVE_MEM_REG.opcode);
         mem.set_u16(1, 0x1);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_eq!(cpu.get_register(register::R1), 0x1);
         assert_eq!(cpu.registers.get_u8(register::R1), 0x00);
@@ -977,7 +1730,7 @@ mod tests {
         let mut mem = Memory::new(14);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 0x1234);
-        mem.set_u8(3, register::ACC as u8);
+        mem.set_u8(3, register::number(register::ACC));
         mem.set_u8(4, instruction::JNE_LIT_MEM.opcode);
         mem.set_u16(5, 0x1234);
         mem.set_u16(7, 0x0);
@@ -986,11 +1739,11 @@ mod tests {
         mem.set_u16(12, 0x2);
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.get_register(register::ACC), 0x1234);
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.get_register(register::IP), 0x9);
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.get_register(register::IP), 0x2);
     }
 
@@ -1003,7 +1756,7 @@ mod tests {
         let mut cpu = CPU::new(Box::new(mem));
         let mut sp = cpu.get_register(register::SP);
         assert_eq!(sp, 4);
-        cpu.step();
+        cpu.step().unwrap();
         sp = cpu.get_register(register::SP);
         assert_eq!(sp, 2);
         assert_eq!(cpu.memory.get_u16(sp as usize + 2), 0x1234);
@@ -1014,13 +1767,13 @@ mod tests {
         let mut mem = Memory::new(10);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 0xABCD);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
         mem.set_u8(4, instruction::PSH_REG.opcode);
-        mem.set_u8(5, register::R1 as u8);
+        mem.set_u8(5, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
-        cpu.step();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
         let sp = cpu.get_register(register::SP);
         assert_eq!(sp, 6);
         assert_eq!(cpu.memory.get_u16(sp as usize + 2), 0xABCD);
@@ -1032,15 +1785,15 @@ mod tests {
         mem.set_u8(0, instruction::PSH_LIT.opcode);
         mem.set_u16(1, 0x1234);
         mem.set_u8(3, instruction::POP_REG.opcode);
-        mem.set_u8(4, register::R1 as u8);
+        mem.set_u8(4, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
         let mut sp = cpu.get_register(register::SP);
         assert_eq!(sp, 8);
-        cpu.step();
+        cpu.step().unwrap();
         sp = cpu.get_register(register::SP);
         assert_eq!(sp, 6);
-        cpu.step();
+        cpu.step().unwrap();
         sp = cpu.get_register(register::SP);
         assert_eq!(sp, 8);
         assert_eq!(cpu.get_register(register::R1), 0x1234);
@@ -1048,36 +1801,36 @@ mod tests {
 
     #[test]
     fn cal_lit() {
-        let mut mem = Memory::new(34);
+        let mut mem = Memory::new(64);
         mem.set_u8(0, instruction::CAL_LIT.opcode);
         mem.set_u16(1, 10);
         mem.set_u8(10, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(11, 0x3333);
-        mem.set_u8(13, register::R1 as u8);
+        mem.set_u8(13, register::number(register::R1));
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
-        cpu.step();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
         let r1 = cpu.get_register(register::R1);
         assert_eq!(r1, 0x3333);
     }
 
     #[test]
     fn cal_reg() {
-        let mut mem = Memory::new(34);
+        let mut mem = Memory::new(64);
         mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(1, 10);
-        mem.set_u8(3, register::R1 as u8);
+        mem.set_u8(3, register::number(register::R1));
         mem.set_u8(4, instruction::CAL_REG.opcode);
-        mem.set_u8(5, register::R1 as u8);
+        mem.set_u8(5, register::number(register::R1));
         mem.set_u8(10, instruction::MOVE_LIT_REG.opcode);
         mem.set_u16(11, 0x3333);
-        mem.set_u8(13, register::R2 as u8);
+        mem.set_u8(13, register::number(register::R2));
 
         let mut cpu = CPU::new(Box::new(mem));
-        cpu.step();
-        cpu.step();
-        cpu.step();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
         let r2 = cpu.get_register(register::R2);
         assert_eq!(r2, 0x3333);
     }
@@ -1088,8 +1841,8 @@ mod tests {
         let mem = Memory::new(0xff00);
         let mem_bank = BankedMemory::new(8, 256);
 
-        mm.map(Box::new(mem_bank), 0x0000, 0x00ff, false);
-        mm.map(Box::new(mem), 0x00ff, 0xffff, true);
+        mm.map(Box::new(mem_bank), 0x0000, 0x00ff, false).unwrap();
+        mm.map(Box::new(mem), 0x0100, 0xffff, true).unwrap();
         let mut cpu = CPU::new(Box::new(mm));
 
         cpu.memory.set_u8(123, 0x8);
@@ -1103,4 +1856,799 @@ mod tests {
         cpu.set_register(register::MB, 0);
         assert_eq!(cpu.memory.get_u8(123), 0x8);
     }
+
+    #[test]
+    fn cycles() {
+        let mut mem = Memory::new(256);
+        mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
+        mem.set_u16(1, 0x1234);
+        mem.set_u8(3, register::number(register::R1));
+        mem.set_u8(4, instruction::INC_REG.opcode);
+        mem.set_u8(5, register::number(register::R1));
+        mem.set_u8(6, instruction::ADD_REG_REG.opcode);
+        mem.set_u8(7, register::number(register::R1));
+        mem.set_u8(8, register::number(register::R2));
+        mem.set_u8(9, instruction::HLT.opcode);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.run().unwrap();
+        assert_eq!(cpu.cycles(), 4 + 2 + 3 + 1);
+
+        cpu.set_register(register::IP, 0);
+        cpu.set_cycles(instruction::INC_REG.opcode, 10);
+        cpu.run().unwrap();
+        assert_eq!(cpu.cycles(), 10 + 4 + 10 + 3 + 1);
+    }
+
+    #[test]
+    fn timer() {
+        let mut mem = Memory::new(0x2000);
+        for address in (0..10).step_by(2) {
+            mem.set_u8(address, instruction::INC_REG.opcode);
+            mem.set_u8(address + 1, register::number(register::R1));
+        }
+        mem.set_u16(0x1002, 0x100);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_timer(5, 1);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles(), 4);
+        assert_eq!(cpu.get_register(register::IP), 4);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles(), 6);
+        assert_eq!(cpu.get_register(register::R1), 3);
+        assert_eq!(cpu.get_register(register::IP), 0x100);
+    }
+
+    #[test]
+    fn interrupt_inside_call() {
+        let mut mem = Memory::new(0x2000);
+        mem.set_u8(0, instruction::PSH_LIT.opcode);
+        mem.set_u16(1, 0x1111);
+        // No arguments
+        mem.set_u8(3, instruction::PSH_LIT.opcode);
+        mem.set_u16(4, 0);
+        mem.set_u8(6, instruction::CAL_LIT.opcode);
+        mem.set_u16(7, 0x100);
+        mem.set_u8(9, instruction::POP_REG.opcode);
+        mem.set_u8(10, register::number(register::R2));
+        mem.set_u8(11, instruction::HLT.opcode);
+
+        // Callee
+        mem.set_u8(0x100, instruction::PSH_LIT.opcode);
+        mem.set_u16(0x101, 0x2222);
+        mem.set_u8(0x103, instruction::INT.opcode);
+        mem.set_u16(0x104, 3);
+        mem.set_u8(0x106, instruction::POP_REG.opcode);
+        mem.set_u8(0x107, register::number(register::R3));
+        mem.set_u8(0x108, instruction::RET.opcode);
+
+        // Interrupt handler
+        mem.set_u16(0x1006, 0x200);
+        mem.set_u8(0x200, instruction::PSH_LIT.opcode);
+        mem.set_u16(0x201, 0x3333);
+        mem.set_u8(0x203, instruction::MOVE_LIT_REG.opcode);
+        mem.set_u16(0x204, 0x4444);
+        mem.set_u8(0x206, register::number(register::R3));
+        mem.set_u8(0x207, instruction::RET_INT.opcode);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        let sp = cpu.get_register(register::SP);
+        let fp = cpu.get_register(register::FP);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let callee_sp = cpu.get_register(register::SP);
+        let callee_fp = cpu.get_register(register::FP);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        // Back in the callee after RET_INT
+        assert_eq!(cpu.get_register(register::IP), 0x106);
+        assert_eq!(cpu.get_register(register::SP), callee_sp);
+        assert_eq!(cpu.get_register(register::FP), callee_fp);
+        assert_eq!(cpu.get_register(register::R3), 0);
+        assert_eq!(cpu.stack_frame_size, 2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::R3), 0x2222);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R3), 0);
+        assert_eq!(cpu.get_register(register::R2), 0x1111);
+        assert_eq!(cpu.get_register(register::SP), sp);
+        assert_eq!(cpu.get_register(register::FP), fp);
+        assert_eq!(cpu.stack_frame_size, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn stack_arguments() {
+        // sub(a, b) with a = 9 and b = 4 pushed in order, the callee reads them and the count off
+        // FP and leaves a word of its own on the stack
+        let program = "mov $1234 R1\npsh $aaaa\n\
+                       psh $9\npsh $4\npsh $2\ncal [!sub]\n\
+                       pop R2\nhlt\n\
+                       sub:\nmov $1c FP R1\nmov $1a FP R2\nmov $18 FP R3\n\
+                       mov R3 &f0\npsh $ffff\nsub R1 R2\nret\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x100, &bytes);
+        let mut cpu = CPU::new(Box::new(mem));
+        let sp = cpu.get_register(register::SP);
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), 5);
+        assert_eq!(cpu.memory.get_u16(0xf0), 2);
+        assert_eq!(cpu.get_register(register::R1), 0x1234);
+        assert_eq!(cpu.get_register(register::R2), 0xaaaa);
+        assert_eq!(cpu.get_register(register::SP), sp);
+        assert_eq!(cpu.stack_frame_size, 0);
+    }
+
+    fn interrupt_program() -> Memory {
+        let mut mem = Memory::new(0x2000);
+        mem.set_u8(0, instruction::PSH_LIT.opcode);
+        mem.set_u16(1, 0x1111);
+        mem.set_u8(3, instruction::INT.opcode);
+        mem.set_u16(4, 1);
+        mem.set_u8(6, instruction::POP_REG.opcode);
+        mem.set_u8(7, register::number(register::R1));
+        mem.set_u8(8, instruction::HLT.opcode);
+
+        // The handler pushes 16 words, deeper than the data kept right below the main stack
+        mem.set_u16(0x1002, 0x100);
+        for i in 0..16 {
+            mem.set_u8(0x100 + i * 3, instruction::PSH_LIT.opcode);
+            mem.set_u16(0x101 + i * 3, 0xeeee);
+        }
+        mem.set_u8(0x130, instruction::RET_INT.opcode);
+        // Program data right below the main stack
+        for address in (0x1fc0..0x1ffc).step_by(2) {
+            mem.set_u16(address, 0xdada);
+        }
+        mem
+    }
+
+    #[test]
+    fn interrupt_stack() {
+        let mut cpu = CPU::new(Box::new(interrupt_program()));
+        cpu.set_interrupt_stack(Some(0x1800));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let main_stack: Vec<u16> = (0x1fc0..0x2000)
+            .step_by(2)
+            .map(|address| cpu.memory.get_u16(address))
+            .collect();
+        assert_eq!(cpu.get_register(register::IP), 0x100);
+        assert!(cpu.get_register(register::SP) < 0x1800);
+
+        for _ in 0..16 {
+            cpu.step().unwrap();
+        }
+        let handler_stack: Vec<u16> = (0x1fc0..0x2000)
+            .step_by(2)
+            .map(|address| cpu.memory.get_u16(address))
+            .collect();
+        assert_eq!(handler_stack, main_stack);
+        for address in (0x1fc0..0x1ffc).step_by(2) {
+            assert_eq!(cpu.memory.get_u16(address), 0xdada);
+        }
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 0x1111);
+        assert_eq!(cpu.get_register(register::SP), 0x1ffe);
+        assert_eq!(cpu.get_register(register::FP), 0x1ffe);
+        assert_eq!(cpu.stack_frame_size, 0);
+    }
+
+    #[test]
+    fn handler_mask_restored() {
+        let mut mem = Memory::new(0x2000);
+        mem.set_u8(0, instruction::INT.opcode);
+        mem.set_u16(1, 1);
+        mem.set_u8(3, instruction::MOVE_REG_REG.opcode);
+        mem.set_u8(4, register::number(register::IM));
+        mem.set_u8(5, register::number(register::R1));
+        mem.set_u8(6, instruction::HLT.opcode);
+
+        // The handler masks every line and returns
+        mem.set_u16(0x1002, 0x100);
+        mem.set_u8(0x100, instruction::MOVE_LIT_REG.opcode);
+        mem.set_u16(0x101, 0);
+        mem.set_u8(0x103, register::number(register::IM));
+        mem.set_u8(0x104, instruction::MOVE_REG_MEM.opcode);
+        mem.set_u8(0x105, register::number(register::IM));
+        mem.set_u16(0x106, 0x800);
+        mem.set_u8(0x108, instruction::RET_INT.opcode);
+        mem.set_u16(0x800, 0xffff);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_register(register::IM, 0x0006);
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory.get_u16(0x800), 0);
+        assert_eq!(cpu.get_register(register::R1), 0x0006);
+        assert_eq!(cpu.get_register(register::SP), 0x1ffe);
+        assert_eq!(cpu.get_register(register::FP), 0x1ffe);
+    }
+
+    #[test]
+    fn shared_interrupt_stack() {
+        // Without a dedicated stack the handler runs over the data below SP
+        let mut cpu = CPU::new(Box::new(interrupt_program()));
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 0x1111);
+        assert_eq!(cpu.memory.get_u16(0x1fd0), 0xeeee);
+    }
+
+    #[test]
+    fn context() {
+        let mut mem = Memory::new(0x100);
+        mem.set_u8(0, instruction::SAVE_CTX_MEM.opcode);
+        mem.set_u16(1, 0x80);
+        mem.set_u8(3, instruction::PSH_LIT.opcode);
+        mem.set_u16(4, 0x1234);
+        mem.set_u8(6, instruction::LOAD_CTX_MEM.opcode);
+        mem.set_u16(7, 0x80);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_register(register::R4, 0x4444);
+        cpu.set_register(register::MB, 0x1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.get_u16(0x80 + register::IP), 3);
+        assert_eq!(cpu.memory.get_u16(0x80 + register::R4), 0x4444);
+        assert_eq!(cpu.memory.get_u16(0x80 + register::SP), 0xfe);
+        assert_eq!(cpu.memory.get_u16(0x80 + register::MB), 0x1);
+        assert_eq!(cpu.memory.get_u16(0x80 + register::IM), 0xff);
+        assert_eq!(
+            cpu.memory.get_u16(0x80 + super::CONTEXT_SIZE as usize - 2),
+            0
+        );
+
+        cpu.set_register(register::R4, 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.stack_frame_size, 2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::IP), 3);
+        assert_eq!(cpu.get_register(register::R4), 0x4444);
+        assert_eq!(cpu.get_register(register::SP), 0xfe);
+        assert_eq!(cpu.stack_frame_size, 0);
+    }
+
+    #[test]
+    fn context_at_top_of_memory() {
+        // The last block that fits ends at the last byte, one at 0xfff0 would run past it
+        let top = 0xffff - super::CONTEXT_SIZE;
+        for &opcode in [
+            instruction::SAVE_CTX_MEM.opcode,
+            instruction::LOAD_CTX_MEM.opcode,
+        ]
+        .iter()
+        {
+            let mut mem = Memory::new(0xffff);
+            mem.set_u8(0, instruction::SAVE_CTX_MEM.opcode);
+            mem.set_u16(1, top);
+            mem.set_u8(3, opcode);
+            mem.set_u16(4, 0xfff0);
+            let mut cpu = CPU::new(Box::new(mem));
+            cpu.set_register(register::R4, 0x4444);
+            cpu.step().unwrap();
+            assert_eq!(
+                cpu.memory.get_u16((top + register::R4 as u16) as usize),
+                0x4444
+            );
+            assert_eq!(
+                cpu.run(),
+                Err(CpuError::MemoryFault {
+                    address: 0xfff0,
+                    ip: 3
+                })
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn round_robin_tasks() {
+        // Each task appends its id as a base 4 digit to the log at $800, then saves its context,
+        // points the saved IP past the switch and loads the other task
+        let program = "mov $3 R5\n\
+                       savectx &940\n\
+                       mov [!taskb] &940\n\
+                       mov $e00 &954\n\
+                       mov $e00 &956\n\
+                       taska:\n\
+                       mov &800 R1\nlsf R1 $2\nadd $1 R1\nmov ACC &800\n\
+                       savectx &900\nmov [!resumea] &900\nloadctx &940\n\
+                       resumea:\n\
+                       dec R5\nmov R5 ACC\njne $0 &[!taska]\nhlt\n\
+                       taskb:\n\
+                       mov &800 R1\nlsf R1 $2\nadd $2 R1\nmov ACC &800\n\
+                       savectx &940\nmov [!resumeb] &940\nloadctx &900\n\
+                       resumeb:\n\
+                       dec R5\nmov R5 ACC\njne $0 &[!taskb]\nhlt\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x1000, &bytes);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory.get_u16(0x800), 0b01_10_01_10_01_10);
+        assert_eq!(cpu.memory.get_u16(0x940 + register::SP), 0xe00);
+        assert_eq!(cpu.get_register(register::SP), 0xffe);
+    }
+
+    #[test]
+    fn cmp() {
+        let flags = |a: u16, b: u16| {
+            let mut mem = Memory::new(3);
+            mem.set_u8(0, instruction::CMP_REG_REG.opcode);
+            mem.set_u8(1, register::number(register::R1));
+            mem.set_u8(2, register::number(register::R2));
+            let mut cpu = CPU::new(Box::new(mem));
+            cpu.set_register(register::ACC, 0x1234);
+            cpu.set_register(register::R1, a);
+            cpu.set_register(register::R2, b);
+            cpu.step().unwrap();
+            assert_eq!(cpu.get_register(register::ACC), 0x1234);
+            cpu.get_register(register::FLAGS)
+        };
+        assert_eq!(flags(7, 7), register::ZERO);
+        assert_eq!(flags(3, 7), register::CARRY | register::SIGN);
+        assert_eq!(flags(7, 3), 0);
+        // -2 against 1: no borrow as unsigned, but the difference is negative
+        assert_eq!(flags(0xfffe, 1), register::SIGN);
+        assert_eq!(flags(0x8000, 0), register::SIGN);
+
+        let mut mem = Memory::new(4);
+        mem.set_u8(0, instruction::CMP_REG_LIT.opcode);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u16(2, 0x10);
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_register(register::R1, 0xf);
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.get_register(register::FLAGS),
+            register::CARRY | register::SIGN
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn flag_jumps() {
+        // R3 collects one bit per jump taken, ACC keeps the value the jeq at the end tests
+        let program = "mov $2a ACC\nmov $5 R1\nmov $9 R2\n\
+                       cmp R1 R2\njc &[!below]\nhlt\n\
+                       below:\n\
+                       or R3 $1\nmov ACC R3\nmov $2a ACC\njnc &[!wrong]\njs &[!negative]\nhlt\n\
+                       negative:\n\
+                       or R3 $2\nmov ACC R3\nmov $2a ACC\ncmp R1 $5\njnz &[!wrong]\njz &[!equal]\nhlt\n\
+                       equal:\n\
+                       or R3 $4\nmov ACC R3\nmov $2a ACC\njeq $2a &[!done]\n\
+                       wrong:\n\
+                       mov $ff R3\n\
+                       done:\n\
+                       hlt\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x1000, &bytes);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R3), 0b111);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn loop_counter() {
+        // Fills the words from &800 down with the counter, ACC survives the loop
+        let program = "mov $2a ACC\nmov $5 R3\n\
+                       top:\n\
+                       mov R3 R1\nlsf R1 $1\nmov R3 $7fe R1\nloop R3 &[!top]\nhlt\n";
+        let assembly = assembler::assemble(program, &assembler::Options::default()).unwrap();
+        assert_eq!(&assembly.bytes[19..23], &[0x5e, 0x04, 0x00, 0x08]);
+        let mem = Memory::with_contents(0x1000, &assembly.bytes);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.run().unwrap();
+        let words: Vec<u16> = (0..6).map(|i| cpu.memory.get_u16(0x800 + i * 2)).collect();
+        assert_eq!(words, vec![1, 2, 3, 4, 5, 0]);
+        assert_eq!(cpu.get_register(register::R3), 0);
+        assert_eq!(cpu.get_register(register::ACC), 0x2a);
+        assert_eq!(cpu.instructions(), 2 + 5 * 4 + 1);
+
+        // A counter of 0 wraps to $ffff, so the body runs 65536 times
+        let program = "top:\ninc R2\nloop R3 &[!top]\nhlt\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x100, &bytes);
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R2), 0);
+        assert_eq!(cpu.get_register(register::HI), 1);
+        assert_eq!(cpu.instructions(), 2 * 0x10000 + 1);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn jmp() {
+        let program = "mov $5 R2\n\
+                       loop:\n\
+                       inc R1\nmov R1 ACC\njeq R2 &[!done]\njmp &[!loop]\n\
+                       done:\n\
+                       mov [!end] R3\njmp R3\nmov $ff R1\n\
+                       end:\n\
+                       hlt\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x1000, &bytes);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 5);
+        // 1 + 5 rounds of the loop, the last one leaving before the jmp, then 3
+        assert_eq!(cpu.instructions(), 1 + 5 * 4 - 1 + 3);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn extension() {
+        // $e3 reg addr: stores twice the register at addr and counts calls in ACC
+        let program =
+            "mov $21 R2\n.opcode $e3 $06 $08 $00\n.opcode $e3 $06 $08 $02\n.opcode $e4\nhlt\n";
+        let bytes = assembler::compile(program, &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x1000, &bytes);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.register_extension(
+            0xe3,
+            Box::new(|cpu: &mut CpuView| {
+                let reg = cpu.fetch8() as usize;
+                let address = cpu.fetch16();
+                cpu.set_u16(address, cpu.get_register(reg) * 2);
+                cpu.set_register(register::ACC, cpu.get_register(register::ACC) + 1);
+                StepOutcome::Continue
+            }),
+        )
+        .unwrap();
+        cpu.register_extension(0xe4, Box::new(|_: &mut CpuView| StepOutcome::Halt))
+            .unwrap();
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory.get_u16(0x800), 0x42);
+        assert_eq!(cpu.memory.get_u16(0x802), 0x42);
+        assert_eq!(cpu.get_register(register::ACC), 2);
+        assert_eq!(cpu.get_register(register::IP), 13);
+        assert_eq!(cpu.cycles(), 4 + 1 + 1 + 1);
+
+        assert_eq!(
+            cpu.register_extension(0x06, Box::new(|_: &mut CpuView| StepOutcome::Continue)),
+            Err("Opcode 0x06 is outside the extension range 0xe0..=0xef".to_string())
+        );
+        assert_eq!(
+            cpu.register_extension(0x10, Box::new(|_: &mut CpuView| StepOutcome::Continue)),
+            Err("Opcode 0x10 is already defined by mov".to_string())
+        );
+        assert_eq!(
+            cpu.register_extension(0xe4, Box::new(|_: &mut CpuView| StepOutcome::Continue)),
+            Err("Opcode 0xe4 is already defined by an extension".to_string())
+        );
+    }
+
+    #[cfg(feature = "assembler")]
+    const FAULTING: &str = "mov $5 R1\n.opcode $e5\ninc R1\nhlt\n\
+                            handler:\nmov &1012 R2\nmov R2 &900\nmov &1014 R2\nmov R2 &902\nrti\n";
+
+    #[cfg(feature = "assembler")]
+    fn faulting_cpu(handler: bool) -> CPU {
+        let assembly = assembler::assemble(FAULTING, &assembler::Options::default()).unwrap();
+        let mut mem = Memory::with_contents(0x2000, &assembly.bytes);
+        if handler {
+            mem.set_u16(fault::FAULT_VECTOR_ADDRESS, assembly.symbols[0].1);
+        }
+        CPU::new(Box::new(mem))
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn guest_fault_handler() {
+        let mut cpu = faulting_cpu(true);
+        assert_eq!(cpu.run(), Ok(HaltReason { ip: 7, acc: 0 }));
+        assert_eq!(cpu.memory.get_u16(0x900), FaultCause::IllegalOpcode as u16);
+        assert_eq!(cpu.memory.get_u16(0x902), 4);
+        assert_eq!(cpu.memory.get_u16(fault::FAULT_INFO_ADDRESS + 4), 4);
+        assert_eq!(cpu.get_register(register::R1), 6);
+        assert_eq!(cpu.get_register(register::R2), 0);
+        assert_eq!(cpu.get_register(register::SP), 0x1ffe);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn host_fault() {
+        let mut cpu = faulting_cpu(false);
+        assert_eq!(cpu.step(), Ok(None));
+        let error = CpuError::IllegalOpcode {
+            opcode: 0xe5,
+            ip: 4,
+        };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(error.to_string(), "Illegal opcode 0xe5 at 0x0004");
+        assert_eq!(
+            error.info(),
+            FaultInfo {
+                cause: FaultCause::IllegalOpcode,
+                address: 4,
+                ip: 4,
+            }
+        );
+        assert_eq!(
+            error.info().to_string(),
+            "Illegal opcode at 0x0004 (IP 0x0004)"
+        );
+
+        let mut cpu = CPU::new(Box::new(Memory::new(0x10)));
+        cpu.set_register(register::IP, 0x10);
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::MemoryFault {
+                address: 0x10,
+                ip: 0x10
+            })
+        );
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::MemoryFault {
+                address: 0x10,
+                ip: 0x10
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn unmapped_access() {
+        let assembly =
+            assembler::assemble("mov &2000 R1\nhlt\n", &assembler::Options::default()).unwrap();
+        let mem = Memory::with_contents(0x100, &assembly.bytes);
+        let mut mapper = MemoryMapper::new();
+        mapper.map(Box::new(mem), 0x0000, 0x00ff, true).unwrap();
+        let mut cpu = CPU::new(Box::new(mapper));
+        let error = cpu.run().unwrap_err();
+        assert_eq!(
+            error,
+            CpuError::MemoryFault {
+                address: 0x2000,
+                ip: 0
+            }
+        );
+        assert_eq!(
+            error.info().to_string(),
+            "Memory fault at 0x2000 (IP 0x0000)"
+        );
+
+        // Reads by the host don't fault the next instruction
+        cpu.reset();
+        cpu.memory().get_u8(0x3000);
+        cpu.set_register(register::IP, 4);
+        assert!(cpu.step().unwrap().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn history() {
+        let mut cpu = faulting_cpu(false);
+        cpu.run().unwrap_err();
+        assert_eq!(cpu.history(), vec![]);
+
+        cpu.reset();
+        cpu.set_history(4);
+        cpu.run().unwrap_err();
+        assert_eq!(
+            cpu.history(),
+            vec![(0, instruction::MOVE_LIT_REG.opcode), (4, 0xe5)]
+        );
+
+        // Only the newest entries survive a wrap
+        let mut cpu = faulting_cpu(true);
+        cpu.set_history(3);
+        cpu.run().unwrap();
+        let ips: Vec<u16> = cpu.history().iter().map(|&(ip, _)| ip).collect();
+        assert_eq!(ips, vec![0x18, 0x5, 0x7]);
+
+        cpu.reset();
+        assert_eq!(cpu.history(), vec![]);
+    }
+
+    #[test]
+    fn illegal_register() {
+        let mut mem = Memory::new(0x10);
+        mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
+        mem.set_u16(1, 0x1234);
+        // R8 as the offset into the register file, how operands encoded it before register numbers
+        mem.set_u8(3, 0x12);
+        mem.set_u8(4, instruction::MOVE_REG_REG.opcode);
+        mem.set_u8(5, 0x80);
+        mem.set_u8(6, register::number(register::R2));
+        let mut cpu = CPU::new(Box::new(mem));
+        let registers = cpu.debug_registers();
+
+        let error = CpuError::IllegalRegister { byte: 0x12, ip: 0 };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(error.to_string(), "Illegal register byte 0x12 (IP 0x0000)");
+        let mut after = cpu.debug_registers();
+        after.insert(register::IP, 0);
+        assert_eq!(after, registers);
+
+        cpu.set_register(register::IP, 4);
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::IllegalRegister { byte: 0x80, ip: 4 })
+        );
+        assert_eq!(cpu.get_register(register::R2), 0);
+    }
+
+    #[test]
+    fn shifts_and_interrupts() {
+        let code = [
+            instruction::LSF_REG_LIT8.opcode,
+            register::number(register::R1),
+            0x10,
+            instruction::RSF_REG_LIT8.opcode,
+            register::number(register::R2),
+            0x20,
+            instruction::LSF_REG_REG.opcode,
+            register::number(register::R3),
+            register::number(register::R4),
+            instruction::INT.opcode,
+            0x00,
+            0x20,
+        ];
+        let mut cpu = CPU::new(Box::new(Memory::with_contents(0x100, &code)));
+        for &reg in [register::R1, register::R2, register::R3].iter() {
+            cpu.set_register(reg, 0xffff);
+        }
+        cpu.set_register(register::R4, 0xf);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.get_register(register::R1), 0);
+        assert_eq!(cpu.get_register(register::R2), 0);
+        assert_eq!(cpu.get_register(register::R3), 0x8000);
+
+        let error = CpuError::IllegalInterrupt {
+            number: 0x20,
+            ip: 9,
+        };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(error.to_string(), "Illegal interrupt 0x20 (IP 0x0009)");
+
+        // Vector 8 would be the fault vector, the timer is checked like INT
+        let code = [instruction::INT.opcode, 0x00, 0x08];
+        let mut cpu = CPU::new(Box::new(Memory::with_contents(0x100, &code)));
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::IllegalInterrupt { number: 8, ip: 0 })
+        );
+        let code = [
+            instruction::MOVE_LIT_REG.opcode,
+            0x00,
+            0x01,
+            register::number(register::R1),
+        ];
+        let mut cpu = CPU::new(Box::new(Memory::with_contents(0x100, &code)));
+        cpu.set_timer(0, 9);
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::IllegalInterrupt { number: 9, ip: 0 })
+        );
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut mem = Memory::new(0x100);
+        mem.set_u8(0, instruction::POP_REG.opcode);
+        mem.set_u8(1, register::number(register::R1));
+        mem.set_u8(2, instruction::RET.opcode);
+        let mut cpu = CPU::new(Box::new(mem));
+        let error = CpuError::StackUnderflow { sp: 0xfe, ip: 0 };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(error.to_string(), "Stack underflow, SP 0x00fe (IP 0x0000)");
+        assert_eq!(cpu.get_register(register::SP), 0xfe);
+
+        cpu.reset();
+        cpu.set_register(register::IP, 2);
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::StackUnderflow { sp: 0xfe, ip: 2 })
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut mem = Memory::new(0x2000);
+        mem.set_u8(0, instruction::PSH_LIT.opcode);
+        mem.set_u16(1, 0x1234);
+        mem.set_u16(fault::FAULT_VECTOR_ADDRESS, 0x100);
+        mem.set_u8(0x100, instruction::HLT.opcode);
+
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_register(register::SP, 0);
+        assert_eq!(cpu.step(), Err(CpuError::StackOverflow { sp: 0, ip: 0 }));
+
+        cpu.reset();
+        cpu.set_register(register::SP, 0);
+        cpu.set_interrupt_stack(Some(0x1800));
+        assert_eq!(cpu.step(), Ok(None));
+        assert_eq!(cpu.get_register(register::IP), 0x100);
+        assert_eq!(
+            cpu.memory.get_u16(fault::FAULT_INFO_ADDRESS),
+            FaultCause::StackOverflow as u16
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn stack_into_code() {
+        let assembly = assembler::assemble(
+            "mov $8 SP\ncal [!f]\nhlt\nf:\nret\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mem = Memory::with_contents(0x100, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.set_code_region(Some(0..assembly.bytes.len() as u16));
+        cpu.step().unwrap();
+
+        let error = cpu.step().unwrap_err();
+        assert_eq!(error, CpuError::StackIntoCode { sp: 8, ip: 4 });
+        assert_eq!(
+            cpu.fault_message(&error),
+            "State push during CAL at IP=0x0004: SP 0x0008 is inside the code region 0x0000-0x0008"
+        );
+        assert_eq!(cpu.memory.get_u8(8), instruction::RET.opcode);
+
+        cpu.reset();
+        cpu.set_stack_floor(0x80);
+        cpu.step().unwrap();
+        cpu.set_register(register::SP, 0x7e);
+        let error = cpu.step().unwrap_err();
+        assert_eq!(
+            cpu.fault_message(&error),
+            "State push during CAL at IP=0x0004: SP 0x007e is below the stack floor 0x0080"
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let mut mm = MemoryMapper::new();
+        let mut mem = Memory::new(0xff00);
+        mem.set_u8(0, instruction::MOVE_LIT_REG.opcode);
+        mem.set_u16(1, 1);
+        mem.set_u8(3, register::number(register::MB));
+        mem.set_u8(4, instruction::PSH_LIT.opcode);
+        mem.set_u16(5, 0x1234);
+        mem.set_u8(7, instruction::HLT.opcode);
+        mem.set_u16(0x1002, 0x100);
+        mm.map(Box::new(mem), 0x0000, 0xfeff, true).unwrap();
+        mm.map(Box::new(BankedMemory::new(2, 0x100)), 0xff00, 0xffff, true)
+            .unwrap();
+        let mut cpu = CPU::new(Box::new(mm));
+        cpu.memory.set_u8(0xff10, 0xaa);
+        cpu.set_timer(20, 1);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.get_u8(0xff10), 0);
+        assert_eq!(cpu.stack_frame_size, 2);
+
+        cpu.reset();
+        assert_eq!(cpu.memory.get_u8(0xff10), 0xaa);
+        assert_eq!(cpu.get_register(register::IP), 0);
+        assert_eq!(cpu.get_register(register::MB), 0);
+        assert_eq!(cpu.get_register(register::SP), 0xfffd);
+        assert_eq!(cpu.get_register(register::IM), 0xff);
+        assert_eq!(cpu.stack_frame_size, 0);
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(cpu.timer, None);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::IP), 8);
+    }
 }
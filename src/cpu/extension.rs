@@ -0,0 +1,68 @@
+// Custom instructions for embedders. Opcodes in EXTENSION_RANGE are never used by built-in
+// instructions, the CPU hands them to the handler registered for the opcode. The handler fetches
+// its own operands, so an extension instruction can be any length.
+use std::ops::RangeInclusive;
+
+use super::register::Register;
+use super::CPU;
+
+pub const EXTENSION_RANGE: RangeInclusive<u8> = 0xe0..=0xef;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum StepOutcome {
+    Continue,
+    Halt,
+}
+
+pub trait Extension {
+    fn execute(&mut self, cpu: &mut CpuView) -> StepOutcome;
+}
+
+impl<F: FnMut(&mut CpuView) -> StepOutcome> Extension for F {
+    fn execute(&mut self, cpu: &mut CpuView) -> StepOutcome {
+        self(cpu)
+    }
+}
+
+// What an extension may do while its instruction executes, IP points past the opcode
+pub struct CpuView<'a> {
+    cpu: &'a mut CPU,
+}
+
+impl<'a> CpuView<'a> {
+    pub(super) fn new(cpu: &'a mut CPU) -> CpuView<'a> {
+        CpuView { cpu }
+    }
+
+    pub fn fetch8(&mut self) -> u8 {
+        self.cpu.fetch8()
+    }
+
+    pub fn fetch16(&mut self) -> u16 {
+        self.cpu.fetch16()
+    }
+
+    pub fn get_register(&self, reg: Register) -> u16 {
+        self.cpu.get_register(reg)
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: u16) {
+        self.cpu.set_register(reg, value)
+    }
+
+    pub fn get_u8(&self, address: u16) -> u8 {
+        self.cpu.memory.get_u8(address as usize)
+    }
+
+    pub fn get_u16(&self, address: u16) -> u16 {
+        self.cpu.memory.get_u16(address as usize)
+    }
+
+    pub fn set_u8(&mut self, address: u16, value: u8) {
+        self.cpu.memory.set_u8(address as usize, value)
+    }
+
+    pub fn set_u16(&mut self, address: u16, value: u16) {
+        self.cpu.memory.set_u16(address as usize, value)
+    }
+}
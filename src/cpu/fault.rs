@@ -0,0 +1,158 @@
+// Faults detected by the CPU. If a guest handler address is stored at FAULT_VECTOR_ADDRESS the
+// fault is delivered like an interrupt: the info is written to FAULT_INFO_ADDRESS as three words
+// (cause, address, IP) and the handler runs with the state pushed as for INT, ignoring IM. RTI
+// resumes at the IP the fault left behind, right after the opcode byte for an illegal opcode, so
+// returning skips it.
+//
+// Without a handler, or while a handler is already running, the fault stops the CPU and is
+// returned to the host from step as a CpuError.
+use std::fmt;
+
+pub const FAULT_VECTOR_ADDRESS: usize = 0x1010;
+pub const FAULT_INFO_ADDRESS: usize = 0x1012;
+
+// Cause codes as seen by the guest
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FaultCause {
+    // The opcode is neither built in nor a registered extension, address is the opcode's
+    IllegalOpcode = 1,
+    // An instruction was fetched past the end of memory or an access hit an address no device is
+    // mapped at, address is the fetch or access address
+    MemoryFault = 2,
+    // A push would have gone below address 0, address is SP. Only delivered to the guest when a
+    // dedicated interrupt stack is set, the program's own stack is unusable.
+    StackOverflow = 3,
+    // A push would have written into the code region set with CPU::set_code_region, address is SP.
+    // Delivered to the guest under the same condition as StackOverflow.
+    StackIntoCode = 4,
+    // A register operand byte is odd or past the last register, address is the byte itself
+    IllegalRegister = 5,
+    // INT, the timer or a device raised a number past the last interrupt vector, address is the
+    // number
+    IllegalInterrupt = 6,
+    // A pop, RET or RTI would have read past the top of the stack at the end of memory, address
+    // is SP
+    StackUnderflow = 7,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct FaultInfo {
+    pub cause: FaultCause,
+    pub address: u16,
+    // Address of the faulting instruction
+    pub ip: u16,
+}
+
+impl fmt::Display for FaultInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cause {
+            FaultCause::IllegalOpcode => write!(
+                f,
+                "Illegal opcode at {:#06x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+            FaultCause::MemoryFault => write!(
+                f,
+                "Memory fault at {:#06x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+            FaultCause::StackOverflow => write!(
+                f,
+                "Stack overflow, SP {:#06x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+            FaultCause::StackIntoCode => write!(
+                f,
+                "Stack push into code, SP {:#06x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+            FaultCause::IllegalRegister => write!(
+                f,
+                "Illegal register byte {:#04x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+            FaultCause::IllegalInterrupt => write!(
+                f,
+                "Illegal interrupt {:#x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+            FaultCause::StackUnderflow => write!(
+                f,
+                "Stack underflow, SP {:#06x} (IP {:#06x})",
+                self.address, self.ip
+            ),
+        }
+    }
+}
+
+// A fault the guest did not handle, as returned by CPU::step and CPU::run
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CpuError {
+    IllegalOpcode { opcode: u8, ip: u16 },
+    MemoryFault { address: u16, ip: u16 },
+    StackOverflow { sp: u16, ip: u16 },
+    StackIntoCode { sp: u16, ip: u16 },
+    IllegalRegister { byte: u8, ip: u16 },
+    IllegalInterrupt { number: u16, ip: u16 },
+    StackUnderflow { sp: u16, ip: u16 },
+}
+
+impl CpuError {
+    // `opcode` is the byte at the faulting instruction, only kept for illegal opcodes
+    pub fn new(info: FaultInfo, opcode: u8) -> CpuError {
+        let ip = info.ip;
+        match info.cause {
+            FaultCause::IllegalOpcode => CpuError::IllegalOpcode { opcode, ip },
+            FaultCause::MemoryFault => CpuError::MemoryFault {
+                address: info.address,
+                ip,
+            },
+            FaultCause::StackOverflow => CpuError::StackOverflow {
+                sp: info.address,
+                ip,
+            },
+            FaultCause::StackIntoCode => CpuError::StackIntoCode {
+                sp: info.address,
+                ip,
+            },
+            FaultCause::IllegalRegister => CpuError::IllegalRegister {
+                byte: info.address as u8,
+                ip,
+            },
+            FaultCause::IllegalInterrupt => CpuError::IllegalInterrupt {
+                number: info.address,
+                ip,
+            },
+            FaultCause::StackUnderflow => CpuError::StackUnderflow {
+                sp: info.address,
+                ip,
+            },
+        }
+    }
+
+    pub fn info(&self) -> FaultInfo {
+        let (cause, address, ip) = match *self {
+            CpuError::IllegalOpcode { ip, .. } => (FaultCause::IllegalOpcode, ip, ip),
+            CpuError::MemoryFault { address, ip } => (FaultCause::MemoryFault, address, ip),
+            CpuError::StackOverflow { sp, ip } => (FaultCause::StackOverflow, sp, ip),
+            CpuError::StackIntoCode { sp, ip } => (FaultCause::StackIntoCode, sp, ip),
+            CpuError::IllegalRegister { byte, ip } => {
+                (FaultCause::IllegalRegister, byte as u16, ip)
+            }
+            CpuError::IllegalInterrupt { number, ip } => (FaultCause::IllegalInterrupt, number, ip),
+            CpuError::StackUnderflow { sp, ip } => (FaultCause::StackUnderflow, sp, ip),
+        };
+        FaultInfo { cause, address, ip }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::IllegalOpcode { opcode, ip } => {
+                write!(f, "Illegal opcode {:#04x} at {:#06x}", opcode, ip)
+            }
+            _ => write!(f, "{}", self.info()),
+        }
+    }
+}
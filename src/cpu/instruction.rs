@@ -2,217 +2,423 @@
 // Maybe would be possible to implement this as a macro
 #[derive(Eq, PartialEq, Debug, Copy, Clone)]
 pub struct Instruction {
+    pub mnemonic: &'static str,
     pub opcode: u8,
+    pub format: Format,
     pub size: u16,
+    pub cycles: u16,
+    // One line for the instruction set reference, operands named as in `syntax`
+    pub description: &'static str,
 }
 
-const LIT_REG: u16 = 4;
-const REG_LIT: u16 = 4;
-const REG_LIT8: u16 = 3;
-const REG_REG: u16 = 3;
-const REG_MEM: u16 = 4;
-const MEM_REG: u16 = 4;
-const LIT_MEM: u16 = 5;
-const REG_PTR_REG: u16 = 3;
-const LIT_OFF_REG: u16 = 5;
-const NONE: u16 = 1;
-const REG: u16 = 2;
-const LIT: u16 = 3;
+impl Instruction {
+    const fn new(
+        mnemonic: &'static str,
+        opcode: u8,
+        format: Format,
+        description: &'static str,
+    ) -> Instruction {
+        Instruction {
+            mnemonic,
+            opcode,
+            format,
+            size: format.size(),
+            // One cycle per byte fetched for now, the CPU lets embedders override it per opcode
+            cycles: format.size(),
+            description,
+        }
+    }
 
-pub const INT: Instruction = Instruction {
-    opcode: 0x00,
-    size: LIT,
-};
-pub const RET_INT: Instruction = Instruction {
-    opcode: 0x01,
-    size: NONE,
-};
+    // Assembler template like `mov $lit reg`
+    pub fn syntax(&self) -> String {
+        let mut res = self.mnemonic.to_string();
+        for kind in self.format.operands() {
+            res.push(' ');
+            res.push_str(match kind {
+                OperandKind::Literal => "$lit",
+                OperandKind::Literal8 => "$lit8",
+                OperandKind::Register => "reg",
+                OperandKind::Address => "&addr",
+                OperandKind::RegisterIndirect => "&reg",
+                OperandKind::FrameOffset => "&[FP + $lit]",
+            });
+        }
+        res
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum OperandKind {
+    Literal,
+    Literal8,
+    Register,
+    Address,
+    RegisterIndirect,
+    // A word offset from FP in two's complement, so locals below the frame are negative
+    FrameOffset,
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Format {
+    LitReg,
+    RegLit,
+    RegLit8,
+    Lit8Reg,
+    RegReg,
+    RegMem,
+    MemReg,
+    LitMem,
+    RegPtrReg,
+    LitOffReg,
+    RegLitOff,
+    FpOffReg,
+    RegFpOff,
+    NoArg,
+    Reg,
+    Lit,
+    Mem,
+}
+
+impl Format {
+    pub const fn size(self) -> u16 {
+        match self {
+            Format::LitReg => 4,
+            Format::RegLit => 4,
+            Format::RegLit8 => 3,
+            Format::Lit8Reg => 3,
+            Format::RegReg => 3,
+            Format::RegMem => 4,
+            Format::MemReg => 4,
+            Format::LitMem => 5,
+            Format::RegPtrReg => 3,
+            Format::LitOffReg => 5,
+            Format::RegLitOff => 5,
+            Format::FpOffReg => 4,
+            Format::RegFpOff => 4,
+            Format::NoArg => 1,
+            Format::Reg => 2,
+            Format::Lit => 3,
+            Format::Mem => 3,
+        }
+    }
+
+    pub fn operands(self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Format::LitReg => &[Literal, Register],
+            Format::RegLit => &[Register, Literal],
+            Format::RegLit8 => &[Register, Literal8],
+            Format::Lit8Reg => &[Literal8, Register],
+            Format::RegReg => &[Register, Register],
+            Format::RegMem => &[Register, Address],
+            Format::MemReg => &[Address, Register],
+            Format::LitMem => &[Literal, Address],
+            Format::RegPtrReg => &[RegisterIndirect, Register],
+            Format::LitOffReg => &[Literal, Register, Register],
+            Format::RegLitOff => &[Register, Literal, Register],
+            Format::FpOffReg => &[FrameOffset, Register],
+            Format::RegFpOff => &[Register, FrameOffset],
+            Format::NoArg => &[],
+            Format::Reg => &[Register],
+            Format::Lit => &[Literal],
+            Format::Mem => &[Address],
+        }
+    }
+}
+
+pub const INT: Instruction = Instruction::new(
+    "int",
+    0x00,
+    Format::Lit,
+    "Raise interrupt $lit if IM allows it",
+);
+pub const RET_INT: Instruction = Instruction::new(
+    "rti",
+    0x01,
+    Format::NoArg,
+    "Return from an interrupt handler, restoring the saved state",
+);
+pub const SYS: Instruction = Instruction::new("sys", 0x02, Format::Lit, "Call host service $lit");
+pub const SAVE_CTX_MEM: Instruction = Instruction::new(
+    "savectx",
+    0x03,
+    Format::Mem,
+    "Save every register and the frame size to &addr",
+);
+pub const LOAD_CTX_MEM: Instruction = Instruction::new(
+    "loadctx",
+    0x04,
+    Format::Mem,
+    "Load every register and the frame size from &addr",
+);
+pub const RESET: Instruction = Instruction::new(
+    "reset",
+    0x05,
+    Format::NoArg,
+    "Warm reset: reset the CPU and devices, keep memory and jump to the reset vector",
+);
+
+pub const MOVE_LIT_MEM: Instruction =
+    Instruction::new("mov", 0x09, Format::LitMem, "Store $lit at &addr");
+pub const MOVE_LIT_REG: Instruction =
+    Instruction::new("mov", 0x10, Format::LitReg, "Load $lit into reg");
+pub const MOVE_REG_REG: Instruction = Instruction::new(
+    "mov",
+    0x11,
+    Format::RegReg,
+    "Copy the first reg into the second",
+);
+pub const MOVE_REG_MEM: Instruction =
+    Instruction::new("mov", 0x12, Format::RegMem, "Store reg at &addr");
+pub const MOVE_MEM_REG: Instruction = Instruction::new(
+    "mov",
+    0x13,
+    Format::MemReg,
+    "Load the word at &addr into reg",
+);
+pub const PSH_LIT: Instruction = Instruction::new("psh", 0x16, Format::Lit, "Push $lit");
+pub const PSH_REG: Instruction = Instruction::new("psh", 0x17, Format::Reg, "Push reg");
+pub const POP_REG: Instruction = Instruction::new("pop", 0x18, Format::Reg, "Pop into reg");
+pub const CAL_LIT: Instruction =
+    Instruction::new("cal", 0x19, Format::Lit, "Push the state and jump to $lit");
+pub const CAL_REG: Instruction = Instruction::new(
+    "cal",
+    0x1a,
+    Format::Reg,
+    "Push the state and jump to the address in reg",
+);
+pub const RET: Instruction = Instruction::new(
+    "ret",
+    0x1b,
+    Format::NoArg,
+    "Return from a subroutine, restoring the saved state and dropping the arguments",
+);
+pub const MOVE_REG_PTR_REG: Instruction = Instruction::new(
+    "mov",
+    0x1c,
+    Format::RegPtrReg,
+    "Load the word at the address in &reg into reg",
+);
+pub const MOVE_LIT_OFF_REG: Instruction = Instruction::new(
+    "mov",
+    0x1d,
+    Format::LitOffReg,
+    "Load the word at $lit plus the first reg into the second",
+);
+pub const MOVE_REG_LIT_OFF: Instruction = Instruction::new(
+    "mov",
+    0x1e,
+    Format::RegLitOff,
+    "Store the first reg at $lit plus the second reg",
+);
+pub const MOVE_FP_OFF_REG: Instruction = Instruction::new(
+    "mov",
+    0x1f,
+    Format::FpOffReg,
+    "Load the word at FP plus the signed $lit into reg",
+);
+pub const MOVE_REG_FP_OFF: Instruction = Instruction::new(
+    "mov",
+    0x20,
+    Format::RegFpOff,
+    "Store reg at FP plus the signed $lit",
+);
+
+pub const ADD_REG_REG: Instruction =
+    Instruction::new("add", 0x14, Format::RegReg, "ACC = reg + reg");
+pub const ADD_LIT_REG: Instruction =
+    Instruction::new("add", 0x30, Format::LitReg, "ACC = $lit + reg");
+pub const SUB_LIT_REG: Instruction =
+    Instruction::new("sub", 0x31, Format::LitReg, "ACC = $lit - reg");
+pub const SUB_REG_LIT: Instruction =
+    Instruction::new("sub", 0x32, Format::RegLit, "ACC = reg - $lit");
+pub const SUB_REG_REG: Instruction =
+    Instruction::new("sub", 0x33, Format::RegReg, "ACC = reg - reg");
+pub const MUL_LIT_REG: Instruction =
+    Instruction::new("mul", 0x34, Format::LitReg, "ACC = $lit * reg");
+pub const MUL_REG_REG: Instruction =
+    Instruction::new("mul", 0x35, Format::RegReg, "ACC = reg * reg");
+pub const INC_REG: Instruction = Instruction::new("inc", 0x36, Format::Reg, "Increment reg");
+pub const DEC_REG: Instruction = Instruction::new("dec", 0x37, Format::Reg, "Decrement reg");
+pub const CMP_REG_REG: Instruction = Instruction::new(
+    "cmp",
+    0x38,
+    Format::RegReg,
+    "Set FLAGS from reg - reg, ACC is left alone",
+);
+pub const CMP_REG_LIT: Instruction = Instruction::new(
+    "cmp",
+    0x39,
+    Format::RegLit,
+    "Set FLAGS from reg - $lit, ACC is left alone",
+);
+
+pub const LSF_REG_LIT8: Instruction =
+    Instruction::new("lsf", 0x40, Format::RegLit8, "Shift reg left by $lit8 bits");
+pub const LSF_REG_REG: Instruction = Instruction::new(
+    "lsf",
+    0x41,
+    Format::RegReg,
+    "Shift the first reg left by the second",
+);
+pub const RSF_REG_LIT8: Instruction = Instruction::new(
+    "rsf",
+    0x42,
+    Format::RegLit8,
+    "Shift reg right by $lit8 bits",
+);
+pub const RSF_REG_REG: Instruction = Instruction::new(
+    "rsf",
+    0x43,
+    Format::RegReg,
+    "Shift the first reg right by the second",
+);
+pub const AND_REG_LIT: Instruction =
+    Instruction::new("and", 0x44, Format::RegLit, "ACC = reg & $lit");
+pub const AND_REG_REG: Instruction =
+    Instruction::new("and", 0x45, Format::RegReg, "ACC = reg & reg");
+pub const OR_REG_LIT: Instruction =
+    Instruction::new("or", 0x46, Format::RegLit, "ACC = reg | $lit");
+pub const OR_REG_REG: Instruction = Instruction::new("or", 0x47, Format::RegReg, "ACC = reg | reg");
+pub const XOR_REG_LIT: Instruction =
+    Instruction::new("xor", 0x48, Format::RegLit, "ACC = reg ^ $lit");
+pub const XOR_REG_REG: Instruction =
+    Instruction::new("xor", 0x49, Format::RegReg, "ACC = reg ^ reg");
+pub const NOT_REG: Instruction = Instruction::new("not", 0x4a, Format::Reg, "ACC = !reg");
 
-pub const MOVE_LIT_MEM: Instruction = Instruction {
-    opcode: 0x09,
-    size: LIT_MEM,
-};
-pub const MOVE_LIT_REG: Instruction = Instruction {
-    opcode: 0x10,
-    size: LIT_REG,
-};
-pub const MOVE_REG_REG: Instruction = Instruction {
-    opcode: 0x11,
-    size: REG_REG,
-};
-pub const MOVE_REG_MEM: Instruction = Instruction {
-    opcode: 0x12,
-    size: REG_MEM,
-};
-pub const MOVE_MEM_REG: Instruction = Instruction {
-    opcode: 0x13,
-    size: MEM_REG,
-};
-pub const PSH_LIT: Instruction = Instruction {
-    opcode: 0x16,
-    size: LIT,
-};
-pub const PSH_REG: Instruction = Instruction {
-    opcode: 0x17,
-    size: REG,
-};
-pub const POP_REG: Instruction = Instruction {
-    opcode: 0x18,
-    size: REG,
-};
-pub const CAL_LIT: Instruction = Instruction {
-    opcode: 0x19,
-    size: LIT,
-};
-pub const CAL_REG: Instruction = Instruction {
-    opcode: 0x1a,
-    size: REG,
-};
-pub const RET: Instruction = Instruction {
-    opcode: 0x1b,
-    size: NONE,
-};
-pub const MOVE_REG_PTR_REG: Instruction = Instruction {
-    opcode: 0x1c,
-    size: REG_PTR_REG,
-};
-pub const MOVE_LIT_OFF_REG: Instruction = Instruction {
-    opcode: 0x1d,
-    size: LIT_OFF_REG,
-};
+pub const JNE_LIT_MEM: Instruction =
+    Instruction::new("jne", 0x50, Format::LitMem, "Jump to &addr if ACC != $lit");
+pub const JNE_REG_MEM: Instruction =
+    Instruction::new("jne", 0x51, Format::RegMem, "Jump to &addr if ACC != reg");
+pub const JEQ_LIT_MEM: Instruction =
+    Instruction::new("jeq", 0x52, Format::LitMem, "Jump to &addr if ACC == $lit");
+pub const JEQ_REG_MEM: Instruction =
+    Instruction::new("jeq", 0x53, Format::RegMem, "Jump to &addr if ACC == reg");
+pub const JGT_LIT_MEM: Instruction =
+    Instruction::new("jgt", 0x54, Format::LitMem, "Jump to &addr if ACC > $lit");
+pub const JGT_REG_MEM: Instruction =
+    Instruction::new("jgt", 0x55, Format::RegMem, "Jump to &addr if ACC > reg");
+pub const JLT_LIT_MEM: Instruction =
+    Instruction::new("jlt", 0x56, Format::LitMem, "Jump to &addr if ACC < $lit");
+pub const JLT_REG_MEM: Instruction =
+    Instruction::new("jlt", 0x57, Format::RegMem, "Jump to &addr if ACC < reg");
+pub const JGE_LIT_MEM: Instruction =
+    Instruction::new("jge", 0x58, Format::LitMem, "Jump to &addr if ACC >= $lit");
+pub const JGE_REG_MEM: Instruction =
+    Instruction::new("jge", 0x59, Format::RegMem, "Jump to &addr if ACC >= reg");
+pub const JLE_LIT_MEM: Instruction =
+    Instruction::new("jle", 0x5a, Format::LitMem, "Jump to &addr if ACC <= $lit");
+pub const JLE_REG_MEM: Instruction =
+    Instruction::new("jle", 0x5b, Format::RegMem, "Jump to &addr if ACC <= reg");
+pub const JMP_LIT: Instruction = Instruction::new("jmp", 0x5c, Format::Mem, "Jump to &addr");
+pub const JMP_REG: Instruction =
+    Instruction::new("jmp", 0x5d, Format::Reg, "Jump to the address in reg");
+pub const LOOP_REG_MEM: Instruction = Instruction::new(
+    "loop",
+    0x5e,
+    Format::RegMem,
+    "Decrement reg and jump to &addr unless it reached 0, a reg of 0 wraps and loops 65536 times",
+);
 
-pub const ADD_REG_REG: Instruction = Instruction {
-    opcode: 0x14,
-    size: REG_REG,
-};
-pub const ADD_LIT_REG: Instruction = Instruction {
-    opcode: 0x30,
-    size: LIT_REG,
-};
-pub const SUB_LIT_REG: Instruction = Instruction {
-    opcode: 0x31,
-    size: LIT_REG,
-};
-pub const SUB_REG_LIT: Instruction = Instruction {
-    opcode: 0x32,
-    size: REG_LIT,
-};
-pub const SUB_REG_REG: Instruction = Instruction {
-    opcode: 0x33,
-    size: REG_REG,
-};
-pub const MUL_LIT_REG: Instruction = Instruction {
-    opcode: 0x34,
-    size: LIT_REG,
-};
-pub const MUL_REG_REG: Instruction = Instruction {
-    opcode: 0x35,
-    size: REG_REG,
-};
-pub const INC_REG: Instruction = Instruction {
-    opcode: 0x36,
-    size: REG,
-};
-pub const DEC_REG: Instruction = Instruction {
-    opcode: 0x37,
-    size: REG,
-};
+// Jumps on the FLAGS of the last cmp
+pub const JZ_MEM: Instruction =
+    Instruction::new("jz", 0x70, Format::Mem, "Jump to &addr if ZERO is set");
+pub const JNZ_MEM: Instruction =
+    Instruction::new("jnz", 0x71, Format::Mem, "Jump to &addr if ZERO is clear");
+pub const JC_MEM: Instruction =
+    Instruction::new("jc", 0x72, Format::Mem, "Jump to &addr if CARRY is set");
+pub const JNC_MEM: Instruction =
+    Instruction::new("jnc", 0x73, Format::Mem, "Jump to &addr if CARRY is clear");
+pub const JS_MEM: Instruction =
+    Instruction::new("js", 0x74, Format::Mem, "Jump to &addr if SIGN is set");
 
-pub const LSF_REG_LIT8: Instruction = Instruction {
-    opcode: 0x40,
-    size: REG_LIT8,
-};
-pub const LSF_REG_REG: Instruction = Instruction {
-    opcode: 0x41,
-    size: REG_REG,
-};
-pub const RSF_REG_LIT8: Instruction = Instruction {
-    opcode: 0x42,
-    size: REG_LIT8,
-};
-pub const RSF_REG_REG: Instruction = Instruction {
-    opcode: 0x43,
-    size: REG_REG,
-};
-pub const AND_REG_LIT: Instruction = Instruction {
-    opcode: 0x44,
-    size: REG_LIT,
-};
-pub const AND_REG_REG: Instruction = Instruction {
-    opcode: 0x45,
-    size: REG_REG,
-};
-pub const OR_REG_LIT: Instruction = Instruction {
-    opcode: 0x46,
-    size: REG_LIT,
-};
-pub const OR_REG_REG: Instruction = Instruction {
-    opcode: 0x47,
-    size: REG_REG,
-};
-pub const XOR_REG_LIT: Instruction = Instruction {
-    opcode: 0x48,
-    size: REG_LIT,
-};
-pub const XOR_REG_REG: Instruction = Instruction {
-    opcode: 0x49,
-    size: REG_REG,
-};
-pub const NOT_REG: Instruction = Instruction {
-    opcode: 0x4a,
-    size: REG,
-};
+pub const IN_LIT8_REG: Instruction =
+    Instruction::new("in", 0x60, Format::Lit8Reg, "Read port $lit8 into reg");
+pub const IN_REG_REG: Instruction = Instruction::new(
+    "in",
+    0x61,
+    Format::RegReg,
+    "Read the port in the first reg's low byte into the second",
+);
+pub const OUT_REG_LIT8: Instruction =
+    Instruction::new("out", 0x62, Format::RegLit8, "Write reg to port $lit8");
+pub const OUT_REG_REG: Instruction = Instruction::new(
+    "out",
+    0x63,
+    Format::RegReg,
+    "Write the first reg to the port in the second reg's low byte",
+);
 
-pub const JNE_LIT_MEM: Instruction = Instruction {
-    opcode: 0x50,
-    size: LIT_MEM,
-};
-pub const JNE_REG_MEM: Instruction = Instruction {
-    opcode: 0x51,
-    size: REG_MEM,
-};
-pub const JEQ_LIT_MEM: Instruction = Instruction {
-    opcode: 0x52,
-    size: LIT_MEM,
-};
-pub const JEQ_REG_MEM: Instruction = Instruction {
-    opcode: 0x53,
-    size: REG_MEM,
-};
-pub const JGT_LIT_MEM: Instruction = Instruction {
-    opcode: 0x54,
-    size: LIT_MEM,
-};
-pub const JGT_REG_MEM: Instruction = Instruction {
-    opcode: 0x55,
-    size: REG_MEM,
-};
-pub const JLT_LIT_MEM: Instruction = Instruction {
-    opcode: 0x56,
-    size: LIT_MEM,
-};
-pub const JLT_REG_MEM: Instruction = Instruction {
-    opcode: 0x57,
-    size: REG_MEM,
-};
-pub const JGE_LIT_MEM: Instruction = Instruction {
-    opcode: 0x58,
-    size: LIT_MEM,
-};
-pub const JGE_REG_MEM: Instruction = Instruction {
-    opcode: 0x59,
-    size: REG_MEM,
-};
-pub const JLE_LIT_MEM: Instruction = Instruction {
-    opcode: 0x5a,
-    size: LIT_MEM,
-};
-pub const JLE_REG_MEM: Instruction = Instruction {
-    opcode: 0x5b,
-    size: REG_MEM,
-};
+pub const HLT: Instruction = Instruction::new("hlt", 0xff, Format::NoArg, "Stop the CPU");
 
-pub const HLT: Instruction = Instruction {
-    opcode: 0xff,
-    size: NONE,
-};
+pub const LIST: [Instruction; 69] = [
+    INT,
+    RET_INT,
+    SYS,
+    SAVE_CTX_MEM,
+    LOAD_CTX_MEM,
+    RESET,
+    MOVE_LIT_MEM,
+    MOVE_LIT_REG,
+    MOVE_REG_REG,
+    MOVE_REG_MEM,
+    MOVE_MEM_REG,
+    PSH_LIT,
+    PSH_REG,
+    POP_REG,
+    CAL_LIT,
+    CAL_REG,
+    RET,
+    MOVE_REG_PTR_REG,
+    MOVE_LIT_OFF_REG,
+    MOVE_REG_LIT_OFF,
+    MOVE_FP_OFF_REG,
+    MOVE_REG_FP_OFF,
+    ADD_REG_REG,
+    ADD_LIT_REG,
+    SUB_LIT_REG,
+    SUB_REG_LIT,
+    SUB_REG_REG,
+    MUL_LIT_REG,
+    MUL_REG_REG,
+    INC_REG,
+    DEC_REG,
+    CMP_REG_REG,
+    CMP_REG_LIT,
+    LSF_REG_LIT8,
+    LSF_REG_REG,
+    RSF_REG_LIT8,
+    RSF_REG_REG,
+    AND_REG_LIT,
+    AND_REG_REG,
+    OR_REG_LIT,
+    OR_REG_REG,
+    XOR_REG_LIT,
+    XOR_REG_REG,
+    NOT_REG,
+    JNE_LIT_MEM,
+    JNE_REG_MEM,
+    JEQ_LIT_MEM,
+    JEQ_REG_MEM,
+    JGT_LIT_MEM,
+    JGT_REG_MEM,
+    JLT_LIT_MEM,
+    JLT_REG_MEM,
+    JGE_LIT_MEM,
+    JGE_REG_MEM,
+    JLE_LIT_MEM,
+    JLE_REG_MEM,
+    JMP_LIT,
+    JMP_REG,
+    LOOP_REG_MEM,
+    JZ_MEM,
+    JNZ_MEM,
+    JC_MEM,
+    JNC_MEM,
+    JS_MEM,
+    IN_LIT8_REG,
+    IN_REG_REG,
+    OUT_REG_LIT8,
+    OUT_REG_REG,
+    HLT,
+];
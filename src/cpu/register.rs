@@ -14,10 +14,61 @@ pub const SP: usize = 20;
 pub const FP: usize = 22;
 pub const MB: usize = 24; // Memory bank
 pub const IM: usize = 26; // Interrupt mask
-pub const LIST: [usize; 14] = [IP, ACC, R1, R2, R3, R4, R5, R6, R7, R8, SP, FP, MB, IM];
+
+// High word of the last add, sub, mul, inc or dec computed at 32 bits: the carry of an add or inc,
+// 0xffff after a borrow, the upper half of a product
+pub const HI: usize = 28;
+// ZERO, CARRY and SIGN as set by the last cmp
+pub const FLAGS: usize = 30;
+pub const LIST: [usize; 16] = [
+    IP, ACC, R1, R2, R3, R4, R5, R6, R7, R8, SP, FP, MB, IM, HI, FLAGS,
+];
 pub const GENERAL_PURPOSE_LIST: [usize; 8] = [R1, R2, R3, R4, R5, R6, R7, R8];
 pub const SIZE: u16 = LIST.len() as u16 * 2;
 
+// Bits of FLAGS for `cmp a b`: the difference a - b is zero, a - b borrowed because a is below b,
+// bit 15 of the difference is set
+pub const ZERO: u16 = 1;
+pub const CARRY: u16 = 2;
+pub const SIGN: u16 = 4;
+
+// Operands encode a register by its number, its position in LIST, and the CPU turns that into
+// the offset into the register file. Binaries don't depend on the layout of the file, so
+// registers can be added to the end of LIST without breaking them. Containers before version 3
+// encoded the offset itself and are rejected when loading.
+pub fn number(reg: Register) -> u8 {
+    LIST.iter()
+        .position(|&r| r == reg)
+        .unwrap_or_else(|| panic!("Unrecognized register {}", reg)) as u8
+}
+
+// The register an operand byte encodes, None for bytes past the last register
+pub fn from_byte(byte: u8) -> Option<Register> {
+    LIST.get(byte as usize).copied()
+}
+
+pub fn name(reg: Register) -> &'static str {
+    match reg {
+        IP => "IP",
+        ACC => "ACC",
+        R1 => "R1",
+        R2 => "R2",
+        R3 => "R3",
+        R4 => "R4",
+        R5 => "R5",
+        R6 => "R6",
+        R7 => "R7",
+        R8 => "R8",
+        SP => "SP",
+        FP => "FP",
+        MB => "MB",
+        IM => "IM",
+        HI => "HI",
+        FLAGS => "FLAGS",
+        x => panic!("Unrecognized register {}", x),
+    }
+}
+
 pub fn get_from_string(s: &str) -> usize {
     match s {
         "IP" => IP,
@@ -32,8 +83,52 @@ pub fn get_from_string(s: &str) -> usize {
         "R8" => R8,
         "SP" => SP,
         "FP" => FP,
-        "MB" => FP,
+        "MB" => MB,
         "IM" => IM,
+        "HI" => HI,
+        "FLAGS" => FLAGS,
         x => panic!("Unrecognized register {}", x),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{from_byte, get_from_string, name, number, Register, LIST, R8};
+
+    #[test]
+    fn numbers() {
+        // Binaries depend on these, a register keeps its number for good
+        let numbers: Vec<(&str, u8, Register)> = LIST
+            .iter()
+            .map(|&reg| (name(reg), number(reg), reg))
+            .collect();
+        assert_eq!(
+            numbers,
+            vec![
+                ("IP", 0, 0),
+                ("ACC", 1, 2),
+                ("R1", 2, 4),
+                ("R2", 3, 6),
+                ("R3", 4, 8),
+                ("R4", 5, 10),
+                ("R5", 6, 12),
+                ("R6", 7, 14),
+                ("R7", 8, 16),
+                ("R8", 9, 18),
+                ("SP", 10, 20),
+                ("FP", 11, 22),
+                ("MB", 12, 24),
+                ("IM", 13, 26),
+                ("HI", 14, 28),
+                ("FLAGS", 15, 30),
+            ]
+        );
+        for &reg in LIST.iter() {
+            assert_eq!(from_byte(number(reg)), Some(reg));
+            assert_eq!(get_from_string(name(reg)), reg);
+        }
+        // Offsets past the last number, like R8's old encoding, aren't registers
+        assert_eq!(from_byte(16), None);
+        assert_eq!(from_byte(R8 as u8), None);
+    }
+}
@@ -0,0 +1,505 @@
+// Host services reachable from the guest through `sys $n`.
+//
+// Register conventions shared by all built-in syscalls:
+//   R1, R2 - arguments (see each syscall)
+//   ACC    - status: STATUS_OK, STATUS_IO_ERROR or STATUS_UNSUPPORTED
+//   R3     - number of bytes transferred, 0 on error
+//
+// READ_LINE and WRITE fail with an IO error and leave memory and input alone when R2 bytes from R1
+// run past the end of memory.
+//
+// PRINT copies an `.asciiz` string to the screen: R1 points at the string, R2 is the address of
+// the first cell. A newline continues at the start of the next row, anything else wraps at the
+// end of a row by itself. Characters past the last cell are dropped. R2 is left at the cell after
+// the last character so prints can follow each other, a cell outside the screen is an IO error.
+//
+// The FORMAT syscalls write the value in R1 as text into the buffer at R2, which holds R3 bytes.
+// The text is NUL terminated so PRINT can show it as it is, R3 is its length without the NUL. A
+// buffer that is too small or runs past the end of memory is an IO error and left untouched.
+use std::fs;
+use std::io::{self, BufRead};
+
+use super::register;
+use super::CPU;
+
+pub const READ_LINE: u16 = 0x01; // R1: buffer address, R2: max length
+pub const WRITE: u16 = 0x02; // R1: data address, R2: length
+pub const READ_FILE: u16 = 0x03; // R1: NUL-terminated file name, R2: destination address
+pub const PRINT: u16 = 0x04; // R1: NUL-terminated string, R2: screen cell address
+pub const FORMAT_UNSIGNED: u16 = 0x05; // R1: value, R2: buffer address, R3: buffer size
+pub const FORMAT_SIGNED: u16 = 0x06; // R1: value, R2: buffer address, R3: buffer size
+pub const FORMAT_HEX: u16 = 0x07; // R1: value, R2: buffer address, R3: buffer size
+
+pub const STATUS_OK: u16 = 0;
+pub const STATUS_IO_ERROR: u16 = 1;
+pub const STATUS_UNSUPPORTED: u16 = 0xffff;
+
+pub trait Syscall {
+    fn call(&mut self, cpu: &mut CPU);
+}
+
+impl<F: FnMut(&mut CPU)> Syscall for F {
+    fn call(&mut self, cpu: &mut CPU) {
+        self(cpu)
+    }
+}
+
+pub fn register_host_services(cpu: &mut CPU, allow_fs: bool) {
+    // Stdin is buffered already, reading it through a one byte buffer keeps the guest from taking
+    // more than its line and does not hold the stdin lock, which the debugger needs for commands
+    let stdin = io::BufReader::with_capacity(1, io::stdin());
+    cpu.register_syscall(READ_LINE, Box::new(ReadLine::new(stdin)));
+    cpu.register_syscall(WRITE, Box::new(Write::new(io::stdout())));
+    cpu.register_syscall(FORMAT_UNSIGNED, Box::new(FormatNumber::Unsigned));
+    cpu.register_syscall(FORMAT_SIGNED, Box::new(FormatNumber::Signed));
+    cpu.register_syscall(FORMAT_HEX, Box::new(FormatNumber::Hex));
+    if allow_fs {
+        cpu.register_syscall(READ_FILE, Box::new(ReadFile {}));
+    }
+}
+
+pub struct ReadLine<R: BufRead> {
+    input: R,
+}
+
+impl<R: BufRead> ReadLine<R> {
+    pub fn new(input: R) -> ReadLine<R> {
+        ReadLine { input }
+    }
+}
+
+impl<R: BufRead> Syscall for ReadLine<R> {
+    fn call(&mut self, cpu: &mut CPU) {
+        let address = cpu.get_register(register::R1) as usize;
+        let max_length = cpu.get_register(register::R2) as usize;
+        if address + max_length > cpu.memory.len() {
+            return finish(cpu, STATUS_IO_ERROR, 0);
+        }
+
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(_) => {
+                let bytes = line.trim_end_matches(&['\r', '\n'][..]).as_bytes();
+                let length = bytes.len().min(max_length);
+                store(cpu, address, &bytes[..length]);
+                finish(cpu, STATUS_OK, length);
+            }
+            Err(_) => finish(cpu, STATUS_IO_ERROR, 0),
+        }
+    }
+}
+
+pub struct Write<W: io::Write> {
+    output: W,
+}
+
+impl<W: io::Write> Write<W> {
+    pub fn new(output: W) -> Write<W> {
+        Write { output }
+    }
+}
+
+impl<W: io::Write> Syscall for Write<W> {
+    fn call(&mut self, cpu: &mut CPU) {
+        let address = cpu.get_register(register::R1) as usize;
+        let length = cpu.get_register(register::R2) as usize;
+        if address + length > cpu.memory.len() {
+            return finish(cpu, STATUS_IO_ERROR, 0);
+        }
+        let bytes: Vec<u8> = (address..address + length)
+            .map(|a| cpu.memory.get_u8(a))
+            .collect();
+
+        match self
+            .output
+            .write_all(&bytes)
+            .and_then(|_| self.output.flush())
+        {
+            Ok(_) => finish(cpu, STATUS_OK, length),
+            Err(_) => finish(cpu, STATUS_IO_ERROR, 0),
+        }
+    }
+}
+
+pub struct ReadFile {}
+
+impl Syscall for ReadFile {
+    fn call(&mut self, cpu: &mut CPU) {
+        let mut address = cpu.get_register(register::R1) as usize;
+        let destination = cpu.get_register(register::R2) as usize;
+
+        let mut name = vec![];
+        while address < cpu.memory.len() && cpu.memory.get_u8(address) != 0 {
+            name.push(cpu.memory.get_u8(address));
+            address += 1;
+        }
+
+        match fs::read(String::from_utf8_lossy(&name).as_ref()) {
+            Ok(bytes) if destination + bytes.len() <= cpu.memory.len() => {
+                store(cpu, destination, &bytes);
+                finish(cpu, STATUS_OK, bytes.len());
+            }
+            _ => finish(cpu, STATUS_IO_ERROR, 0),
+        }
+    }
+}
+
+// Registered by machines with a screen, `start` is where the screen is mapped
+pub struct Print {
+    start: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Print {
+    pub fn new(start: usize, width: usize, height: usize) -> Print {
+        Print {
+            start,
+            width,
+            height,
+        }
+    }
+}
+
+impl Syscall for Print {
+    fn call(&mut self, cpu: &mut CPU) {
+        let mut address = cpu.get_register(register::R1) as usize;
+        let end = self.start + self.width * self.height;
+        let mut cell = cpu.get_register(register::R2) as usize;
+        if !(self.start..end).contains(&cell) {
+            return finish(cpu, STATUS_IO_ERROR, 0);
+        }
+
+        let mut length = 0;
+        while address < cpu.memory.len() && cpu.memory.get_u8(address) != 0 && cell < end {
+            match cpu.memory.get_u8(address) {
+                b'\n' => cell += self.width - (cell - self.start) % self.width,
+                byte => {
+                    cpu.memory.set_u8(cell, byte);
+                    cell += 1;
+                    length += 1;
+                }
+            }
+            address += 1;
+        }
+        cpu.set_register(register::R2, cell as u16);
+        finish(cpu, STATUS_OK, length);
+    }
+}
+
+// `65535`, `-1` and `ffff` for 0xffff
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FormatNumber {
+    Unsigned,
+    Signed,
+    Hex,
+}
+
+impl Syscall for FormatNumber {
+    fn call(&mut self, cpu: &mut CPU) {
+        let value = cpu.get_register(register::R1);
+        let address = cpu.get_register(register::R2) as usize;
+        let size = cpu.get_register(register::R3) as usize;
+
+        let mut text = match self {
+            FormatNumber::Unsigned => value.to_string(),
+            FormatNumber::Signed => (value as i16).to_string(),
+            FormatNumber::Hex => format!("{:04x}", value),
+        }
+        .into_bytes();
+        let length = text.len();
+        text.push(0);
+        if text.len() > size || address + text.len() > cpu.memory.len() {
+            return finish(cpu, STATUS_IO_ERROR, 0);
+        }
+        store(cpu, address, &text);
+        finish(cpu, STATUS_OK, length);
+    }
+}
+
+fn store(cpu: &mut CPU, address: usize, bytes: &[u8]) {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        cpu.memory.set_u8(address + offset, byte);
+    }
+}
+
+fn finish(cpu: &mut CPU, status: u16, length: usize) {
+    cpu.set_register(register::ACC, status);
+    cpu.set_register(register::R3, length as u16);
+}
+
+#[cfg(test)]
+#[cfg_attr(
+    not(all(feature = "assembler", feature = "devices-terminal")),
+    allow(unused_imports)
+)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Cursor};
+    use std::rc::Rc;
+
+    use super::{
+        FormatNumber, Print, ReadFile, ReadLine, Write, STATUS_IO_ERROR, STATUS_OK,
+        STATUS_UNSUPPORTED,
+    };
+    #[cfg(all(feature = "assembler", feature = "devices-terminal"))]
+    use crate::assembler;
+    use crate::cpu::instruction;
+    use crate::cpu::register;
+    use crate::cpu::CPU;
+    use crate::device::memory::Memory;
+    #[cfg(all(feature = "assembler", feature = "devices-terminal"))]
+    use crate::device::screen::Screen;
+    use crate::device::Device;
+    #[cfg(all(feature = "assembler", feature = "devices-terminal"))]
+    use crate::machine::Builder;
+
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cpu_with_syscall(number: u16) -> CPU {
+        let mut mem = Memory::new(256);
+        mem.set_u8(0, instruction::SYS.opcode);
+        mem.set_u16(1, number);
+        CPU::new(Box::new(mem))
+    }
+
+    #[test]
+    fn read_line_and_write() {
+        let mut cpu = cpu_with_syscall(super::READ_LINE);
+        cpu.register_syscall(
+            super::READ_LINE,
+            Box::new(ReadLine::new(Cursor::new("hello world\nsecond\n"))),
+        );
+        cpu.set_register(register::R1, 0x80);
+        cpu.set_register(register::R2, 5);
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.get_register(register::ACC), STATUS_OK);
+        assert_eq!(cpu.get_register(register::R3), 5);
+        assert_eq!(cpu.memory.get_u8(0x80), b'h');
+        assert_eq!(cpu.memory.get_u8(0x84), b'o');
+        assert_eq!(cpu.memory.get_u8(0x85), 0);
+
+        let output = SharedOutput(Rc::new(RefCell::new(vec![])));
+        cpu.register_syscall(super::WRITE, Box::new(Write::new(output.clone())));
+        cpu.memory.set_u8(3, instruction::SYS.opcode);
+        cpu.memory.set_u16(4, super::WRITE);
+        cpu.set_register(register::R2, 4);
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.get_register(register::ACC), STATUS_OK);
+        assert_eq!(cpu.get_register(register::R3), 4);
+        assert_eq!(output.0.borrow().as_slice(), b"hell");
+    }
+
+    #[test]
+    fn buffer_past_end_of_memory() {
+        let mut cpu = cpu_with_syscall(super::READ_LINE);
+        cpu.register_syscall(
+            super::READ_LINE,
+            Box::new(ReadLine::new(Cursor::new("hello\n"))),
+        );
+        cpu.set_register(register::R1, 0xf0);
+        cpu.set_register(register::R2, 0x20);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), STATUS_IO_ERROR);
+        assert_eq!(cpu.get_register(register::R3), 0);
+        assert_eq!(cpu.memory.get_u8(0xf0), 0);
+        // The line is still there for a buffer that fits
+        cpu.set_register(register::IP, 0);
+        cpu.set_register(register::R2, 0x10);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::R3), 5);
+        assert_eq!(cpu.memory.get_u8(0xf4), b'o');
+
+        cpu.set_register(register::R2, 0x20);
+        let output = SharedOutput(Rc::new(RefCell::new(vec![])));
+        cpu.register_syscall(super::WRITE, Box::new(Write::new(output.clone())));
+        cpu.memory.set_u8(3, instruction::SYS.opcode);
+        cpu.memory.set_u16(4, super::WRITE);
+        cpu.set_register(register::ACC, STATUS_OK);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), STATUS_IO_ERROR);
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn read_file() {
+        let path = env::temp_dir().join("vm_syscall_read_file.bin");
+        fs::write(&path, [0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let name = path.to_str().unwrap().as_bytes();
+
+        let mut mem = Memory::new(0x1000);
+        mem.set_u8(0, instruction::SYS.opcode);
+        mem.set_u16(1, super::READ_FILE);
+        for (i, &byte) in name.iter().enumerate() {
+            mem.set_u8(0x100 + i, byte);
+        }
+        let mut cpu = CPU::new(Box::new(mem));
+        cpu.register_syscall(super::READ_FILE, Box::new(ReadFile {}));
+        cpu.set_register(register::R1, 0x100);
+        cpu.set_register(register::R2, 0x800);
+        cpu.step().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(cpu.get_register(register::ACC), STATUS_OK);
+        assert_eq!(cpu.get_register(register::R3), 4);
+        assert_eq!(cpu.memory.get_u16(0x800), 0xdead);
+        assert_eq!(cpu.memory.get_u16(0x802), 0xbeef);
+
+        cpu.set_register(register::IP, 0);
+        cpu.memory.set_u8(0x100, b'?');
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), STATUS_IO_ERROR);
+        assert_eq!(cpu.get_register(register::R3), 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "assembler", feature = "devices-terminal"))]
+    fn print() {
+        let assembly = assembler::assemble(
+            "mov [!text] R1\nmov $fe02 R2\nsys $4\nmov [!more] R1\nsys $4\nhlt\n\
+             text:\n.asciiz \"Hello\\nwrapping at 8\\n\"\n\
+             more:\n.asciiz \"end\"\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mem = Memory::with_contents(0xfe00, &assembly.bytes);
+        let mut cpu = Builder::new()
+            .map(Box::new(mem), 0x0000, 0xfdff, true)
+            .unwrap()
+            .screen(Screen::headless(8, 4), 0xfe00, 0xfe1f)
+            .unwrap()
+            .map(Box::new(Memory::new(0x1e0)), 0xfe20, 0xffff, true)
+            .unwrap()
+            .build();
+        cpu.register_syscall(super::PRINT, Box::new(Print::new(0xfe00, 8, 4)));
+        cpu.run().unwrap();
+
+        let rows: Vec<String> = (0..4)
+            .map(|row| {
+                (0..8)
+                    .map(|x| match cpu.memory.get_u8(0xfe00 + row * 8 + x) {
+                        0 => '.',
+                        c => c as char,
+                    })
+                    .collect()
+            })
+            .collect();
+        // The second print starts after the newline that ended the first
+        assert_eq!(rows, vec!["..Hello.", "wrapping", " at 8...", "end....."]);
+        assert_eq!(cpu.get_register(register::ACC), STATUS_OK);
+        assert_eq!(cpu.get_register(register::R3), 3);
+        assert_eq!(cpu.get_register(register::R2), 0xfe1b);
+
+        cpu.set_register(register::IP, 8);
+        cpu.set_register(register::R2, 0xfe20);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), STATUS_IO_ERROR);
+    }
+
+    #[test]
+    fn format_number() {
+        let mut cpu = cpu_with_syscall(super::FORMAT_UNSIGNED);
+        let mut format = |format: FormatNumber, value: u16, size: u16| {
+            cpu.register_syscall(super::FORMAT_UNSIGNED, Box::new(format));
+            cpu.set_register(register::IP, 0);
+            cpu.set_register(register::R1, value);
+            cpu.set_register(register::R2, 0x80);
+            cpu.set_register(register::R3, size);
+            cpu.memory.set_u16(0x80, u16::from_be_bytes([b'?', 0]));
+            cpu.step().unwrap();
+            let text: Vec<u8> = (0x80..0x88)
+                .map(|a| cpu.memory.get_u8(a))
+                .take_while(|&byte| byte != 0)
+                .collect();
+            (
+                cpu.get_register(register::ACC),
+                cpu.get_register(register::R3),
+                String::from_utf8(text).unwrap(),
+            )
+        };
+
+        assert_eq!(
+            format(FormatNumber::Unsigned, 0, 8),
+            (STATUS_OK, 1, "0".to_string())
+        );
+        assert_eq!(
+            format(FormatNumber::Unsigned, 65535, 6),
+            (STATUS_OK, 5, "65535".to_string())
+        );
+        assert_eq!(
+            format(FormatNumber::Signed, 0xffff, 8),
+            (STATUS_OK, 2, "-1".to_string())
+        );
+        assert_eq!(
+            format(FormatNumber::Signed, 0x8000, 8),
+            (STATUS_OK, 6, "-32768".to_string())
+        );
+        assert_eq!(
+            format(FormatNumber::Hex, 0xbe, 8),
+            (STATUS_OK, 4, "00be".to_string())
+        );
+        // The NUL needs room too
+        assert_eq!(
+            format(FormatNumber::Unsigned, 65535, 5),
+            (STATUS_IO_ERROR, 0, "?".to_string())
+        );
+
+        // A buffer that fits its size but not the memory
+        cpu.set_register(register::IP, 0);
+        cpu.set_register(register::R2, 0xfe);
+        cpu.set_register(register::R3, 8);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), STATUS_IO_ERROR);
+        assert_eq!(cpu.memory.get_u16(0xfe), 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "assembler", feature = "devices-terminal"))]
+    fn print_formatted_number() {
+        let assembly = assembler::assemble(
+            "mov $fff6 R1\nmov [!buffer] R2\nmov $8 R3\nsys $6\n\
+             mov [!buffer] R1\nmov $fe00 R2\nsys $4\nhlt\nbuffer:\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mem = Memory::with_contents(0xfe00, &assembly.bytes);
+        let mut cpu = Builder::new()
+            .map(Box::new(mem), 0x0000, 0xfdff, true)
+            .unwrap()
+            .screen(Screen::headless(8, 4), 0xfe00, 0xfe1f)
+            .unwrap()
+            .map(Box::new(Memory::new(0x1e0)), 0xfe20, 0xffff, true)
+            .unwrap()
+            .build();
+        cpu.register_syscall(super::FORMAT_SIGNED, Box::new(FormatNumber::Signed));
+        cpu.register_syscall(super::PRINT, Box::new(Print::new(0xfe00, 8, 4)));
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.get_register(register::ACC), STATUS_OK);
+        assert_eq!(cpu.get_register(register::R3), 3);
+        let cells: Vec<u8> = (0xfe00..0xfe04).map(|a| cpu.memory.get_u8(a)).collect();
+        assert_eq!(cells, b"-10\0");
+    }
+
+    #[test]
+    fn unregistered_syscall() {
+        let mut cpu = cpu_with_syscall(super::READ_FILE);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(register::ACC), STATUS_UNSUPPORTED);
+    }
+}
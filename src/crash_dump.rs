@@ -0,0 +1,157 @@
+// Crash dump written by `vm run --crash-dump <dir>` when the guest faults.
+// Plain text made of sections, each starting with a `== name ==` header line:
+//   fault     - what went wrong
+//   location  - source line of the faulting instruction, only with debug info
+//   registers - the register file
+//   ip        - 64 bytes around IP
+//   stack     - 64 bytes around SP
+//   history   - the last instructions oldest first, only when the CPU remembers them
+use std::any::Any;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::container::DebugInfo;
+use crate::cpu::register;
+use crate::cpu::CPU;
+use crate::inspect;
+
+pub fn write(dir: &Path, cpu: &CPU, fault: &str, debug: Option<&DebugInfo>) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("crash-{}.txt", process::id()));
+    fs::write(&path, render(cpu, fault, debug))?;
+    Ok(path)
+}
+
+pub fn render(cpu: &CPU, fault: &str, debug: Option<&DebugInfo>) -> String {
+    let ip = cpu.get_register(register::IP);
+    let mut sections = vec![("fault", fault.to_string())];
+    // Faults are raised after the opcode has been fetched, so IP is already past it
+    if let Some(location) = debug.and_then(|debug| debug.location(ip.saturating_sub(1))) {
+        sections.push(("location", location));
+    }
+    sections.push(("registers", inspect::registers(cpu)));
+    sections.push(("ip", around(cpu, ip)));
+    sections.push(("stack", around(cpu, cpu.get_register(register::SP))));
+    if !cpu.history().is_empty() {
+        sections.push(("history", inspect::history(cpu, debug)));
+    }
+
+    sections
+        .iter()
+        .map(|(name, body)| format!("== {} ==\n{}\n", name, body))
+        .collect()
+}
+
+// Devices may still panic, this turns a caught panic back into its message
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "Unknown fault".to_string()),
+    }
+}
+
+// A device that faulted once may fault again when read, that must not lose the rest of the dump
+fn around(cpu: &CPU, address: u16) -> String {
+    let start = address.saturating_sub(32) & !7;
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        inspect::hexdump(cpu.memory(), start, 64)
+    }))
+    .unwrap_or_else(|payload| format!("unreadable: {}", panic_message(payload.as_ref())))
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+
+    use crate::assembler;
+    use crate::cpu::CPU;
+    use crate::device::memory::Memory;
+    use crate::device::Device;
+
+    fn sections(dump: &str) -> BTreeMap<String, Vec<String>> {
+        let mut res = BTreeMap::new();
+        let mut current = String::new();
+        for line in dump.lines() {
+            if line.starts_with("== ") && line.ends_with(" ==") {
+                current = line[3..line.len() - 3].to_string();
+                res.insert(current.clone(), vec![]);
+            } else {
+                res.get_mut(&current).unwrap().push(line.to_string());
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn illegal_opcode() {
+        let assembly = assembler::assemble(
+            "mov $1234 R1\npsh R1\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mut memory = Memory::with_contents(0x100, &assembly.bytes);
+        memory.set_u8(6, 0xee);
+        let mut cpu = CPU::new(Box::new(memory));
+
+        let error = cpu.run().unwrap_err();
+        let fault = cpu.fault_message(&error);
+        assert_eq!(fault, "Illegal opcode 0xee at 0x0006");
+
+        let dir = env::temp_dir().join("vm_crash_dump_test");
+        let path =
+            super::write(&dir, &cpu, &fault, Some(&assembly.debug_info("prog.asm"))).unwrap();
+        let dump = sections(&fs::read_to_string(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            dump.keys().collect::<Vec<_>>(),
+            vec!["fault", "ip", "location", "registers", "stack"]
+        );
+        assert_eq!(dump["fault"], vec!["Illegal opcode 0xee at 0x0006"]);
+        assert_eq!(dump["location"], vec!["prog.asm:3"]);
+        assert_eq!(dump["registers"][0], "IP: 0x0007");
+        assert_eq!(dump["registers"][2], "R1: 0x1234");
+        assert_eq!(dump["ip"][0], "0x0000: 10 12 34 02 17 02 ee 00");
+        assert_eq!(dump["ip"].len(), 8);
+        assert_eq!(
+            dump["stack"].last().unwrap(),
+            "0x00f8: 00 00 00 00 00 00 12 34"
+        );
+    }
+
+    #[test]
+    fn history() {
+        let code = "mov $2 R1\nloop:\ndec R1\nmov R1 ACC\njne $0 &[!loop]\npsh R1\n";
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        let mut memory = Memory::with_contents(0x100, &assembly.bytes);
+        memory.set_u8(assembly.bytes.len(), 0xee);
+        let mut cpu = CPU::new(Box::new(memory));
+        cpu.set_history(6);
+
+        let error = cpu.run().unwrap_err();
+        let dump = sections(&super::render(
+            &cpu,
+            &cpu.fault_message(&error),
+            Some(&assembly.debug_info("loop.asm")),
+        ));
+        assert_eq!(
+            dump["history"],
+            vec![
+                "0x0009: 50 jne $lit &addr (loop.asm:5)",
+                "0x0004: 37 dec reg (loop.asm:3)",
+                "0x0006: 11 mov reg reg (loop.asm:4)",
+                "0x0009: 50 jne $lit &addr (loop.asm:5)",
+                "0x000e: 17 psh reg (loop.asm:6)",
+                "0x0010: ee ??? (loop.asm:6)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,796 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::AtomicUsize;
+
+use crate::assembler::{self, Runtime};
+use crate::container::DebugInfo;
+use crate::cpu::instruction;
+use crate::cpu::register;
+use crate::cpu::CPU;
+use crate::disassembler;
+use crate::inspect;
+use crate::sigint;
+use crate::snapshot::Snapshot;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Action {
+    Break,
+    Watch,
+    Display,
+    Undisplay,
+    Mem,
+    Step,
+    Continue,
+    Regs,
+    Restart,
+    Snapshot,
+    Help,
+    Quit,
+}
+
+pub struct Command {
+    pub action: Action,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static str,
+    pub description: &'static str,
+}
+
+// Every command the debugger understands, help is generated from this table
+pub const COMMANDS: &[Command] = &[
+    Command {
+        action: Action::Break,
+        name: "break",
+        aliases: &["b"],
+        args: "<address>",
+        description: "Stop when IP reaches the address",
+    },
+    Command {
+        action: Action::Watch,
+        name: "watch",
+        aliases: &["w"],
+        args: "<address>",
+        description: "Stop when the word at the address changes",
+    },
+    Command {
+        action: Action::Display,
+        name: "display",
+        aliases: &[],
+        args: "[expression]",
+        description: "Show the expression at every stop, or list the displays",
+    },
+    Command {
+        action: Action::Undisplay,
+        name: "undisplay",
+        aliases: &[],
+        args: "<number>",
+        description: "Stop showing a display",
+    },
+    Command {
+        action: Action::Mem,
+        name: "mem",
+        aliases: &["m"],
+        args: "<address> [length]",
+        description: "Dump memory, 16 bytes by default",
+    },
+    Command {
+        action: Action::Step,
+        name: "step",
+        aliases: &["s", "si"],
+        args: "",
+        description: "Execute one instruction and show it with the registers",
+    },
+    Command {
+        action: Action::Continue,
+        name: "continue",
+        aliases: &["c"],
+        args: "",
+        description: "Run until a breakpoint, a watch or halt",
+    },
+    Command {
+        action: Action::Regs,
+        name: "regs",
+        aliases: &["r"],
+        args: "",
+        description: "Show the registers",
+    },
+    Command {
+        action: Action::Restart,
+        name: "restart",
+        aliases: &[],
+        args: "",
+        description: "Reset the CPU to the start of the program",
+    },
+    Command {
+        action: Action::Snapshot,
+        name: "snapshot",
+        aliases: &[],
+        args: "<file>",
+        description: "Write the registers and memory to a file for snapshot-diff",
+    },
+    Command {
+        action: Action::Help,
+        name: "help",
+        aliases: &[],
+        args: "[command]",
+        description: "List the commands or describe one",
+    },
+    Command {
+        action: Action::Quit,
+        name: "quit",
+        aliases: &["q"],
+        args: "[report]",
+        description: "Leave the debugger, optionally printing the final state",
+    },
+];
+
+impl Command {
+    fn usage(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+}
+
+// Finds a command by name, alias or unambiguous prefix of its name
+pub fn lookup(name: &str) -> Result<&'static Command, String> {
+    if let Some(command) = COMMANDS
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+    {
+        return Ok(command);
+    }
+    let matches: Vec<&Command> = COMMANDS
+        .iter()
+        .filter(|command| command.name.starts_with(name))
+        .collect();
+    match matches.as_slice() {
+        [command] => Ok(command),
+        [] => {
+            let names: Vec<&str> = COMMANDS.iter().map(|command| command.name).collect();
+            Err(format!(
+                "Unknown command: {}, valid commands are {}",
+                name,
+                names.join(", ")
+            ))
+        }
+        _ => {
+            let names: Vec<&str> = matches.iter().map(|command| command.name).collect();
+            Err(format!(
+                "Ambiguous command: {} could be {}",
+                name,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+// Splits a line into its command and the trimmed arguments
+pub fn parse(line: &str) -> Result<(&'static Command, &str), String> {
+    let (name, args) = match line.find(' ') {
+        Some(index) => (&line[..index], line[index + 1..].trim()),
+        None => (line, ""),
+    };
+    Ok((lookup(name)?, args))
+}
+
+fn help(args: &str) -> Result<String, String> {
+    if args.is_empty() {
+        let rows: Vec<String> = COMMANDS
+            .iter()
+            .map(|command| format!("{:<26}{}", command.usage(), command.description))
+            .collect();
+        return Ok(rows.join("\n"));
+    }
+    let command = lookup(args)?;
+    let mut res = format!("{}\n  {}", command.usage(), command.description);
+    if !command.aliases.is_empty() {
+        res.push_str(&format!("\n  Aliases: {}", command.aliases.join(", ")));
+    }
+    Ok(res)
+}
+
+// Line based debugger driving a CPU, reads commands from any BufRead so sessions can be scripted.
+// Addresses are expressions in assembler syntax: `!label`, `$1f`, `0x1f` and `+ - * / &`.
+// Displays can also read registers by name and memory with `word(...)` and `byte(...)`.
+pub struct Debugger {
+    cpu: CPU,
+    symbols: BTreeMap<String, u16>,
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<(String, u16, u16)>,
+    // Numbered expressions shown at every stop, numbers aren't reused
+    displays: Vec<(usize, String)>,
+    next_display: usize,
+    halted: bool,
+    // Message of the fault that stopped the program, it can only be restarted
+    fault: Option<String>,
+    // Ctrl-C presses, see `sigint`. A press stops `continue` like a breakpoint.
+    interrupt: Option<&'static AtomicUsize>,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU, debug: Option<&DebugInfo>) -> Debugger {
+        let symbols = debug
+            .map(|debug| debug.symbols.iter().cloned().collect())
+            .unwrap_or_default();
+        Debugger {
+            cpu,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            watches: vec![],
+            displays: vec![],
+            next_display: 1,
+            halted: false,
+            fault: None,
+            interrupt: None,
+        }
+    }
+
+    pub fn with_interrupt(mut self, presses: &'static AtomicUsize) -> Debugger {
+        self.interrupt = Some(presses);
+        self
+    }
+
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let result = match parse(line) {
+                Ok((command, args)) if command.action == Action::Quit => match args {
+                    "" => break,
+                    "report" => {
+                        writeln!(output, "{}", self.report())?;
+                        break;
+                    }
+                    _ => Err(format!("Unknown argument: {}, expected report", args)),
+                },
+                Ok((command, args)) => self.command(command.action, args),
+                Err(message) => Err(message),
+            };
+            match result {
+                Ok(message) => writeln!(output, "{}", message)?,
+                Err(message) => writeln!(output, "Error: {}", message)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, action: Action, args: &str) -> Result<String, String> {
+        match action {
+            Action::Break => {
+                let address = self.resolve(args)?;
+                self.breakpoints.insert(address);
+                Ok(format!("Breakpoint at {}", self.describe(address)))
+            }
+            Action::Watch => {
+                let address = self.resolve(args)?;
+                let value = self.cpu.memory().get_u16(address as usize);
+                self.watches.push((args.to_string(), address, value));
+                Ok(format!("Watching {} = {:#06x}", args, value))
+            }
+            Action::Display if args.is_empty() => {
+                if self.displays.is_empty() {
+                    Ok("No displays".to_string())
+                } else {
+                    Ok(self.show_displays())
+                }
+            }
+            Action::Display => {
+                self.evaluate(args)?;
+                self.displays.push((self.next_display, args.to_string()));
+                self.next_display += 1;
+                Ok(self.show_display(self.next_display - 1, args))
+            }
+            Action::Undisplay => {
+                let number = args
+                    .parse::<usize>()
+                    .map_err(|_| format!("Expected a display number, got {}", args))?;
+                let index = self
+                    .displays
+                    .iter()
+                    .position(|&(n, _)| n == number)
+                    .ok_or_else(|| format!("No display {}", number))?;
+                self.displays.remove(index);
+                Ok(format!("Removed display {}", number))
+            }
+            Action::Mem => {
+                let (expression, length) = match args.rfind(' ') {
+                    Some(index) => match args[index + 1..].parse::<u16>() {
+                        Ok(length) => (&args[..index], length),
+                        Err(_) => (args, 16),
+                    },
+                    None => (args, 16),
+                };
+                let address = self.resolve(expression)?;
+                Ok(inspect::hexdump(self.cpu.memory(), address, length))
+            }
+            Action::Step if self.halted => Ok(self.stop_message()),
+            Action::Step => {
+                let ip = self.cpu.get_register(register::IP);
+                let executed = self.instruction_at(ip);
+                self.step();
+                Ok(format!(
+                    "{:#06x}: {}\n{}\n{}",
+                    ip,
+                    executed,
+                    inspect::register_row(&self.cpu),
+                    self.stop_message()
+                ))
+            }
+            Action::Continue => {
+                if let Some(message) = self.resume() {
+                    return Ok(message);
+                }
+                Ok(self.stop_message())
+            }
+            Action::Regs => Ok(inspect::registers(&self.cpu)),
+            Action::Restart => {
+                self.cpu.reset();
+                self.halted = false;
+                self.fault = None;
+                Ok(self.stop_message())
+            }
+            Action::Snapshot => {
+                if args.is_empty() {
+                    return Err("Expected a file".to_string());
+                }
+                fs::write(args, Snapshot::capture(&self.cpu).to_bytes())
+                    .map_err(|e| e.to_string())?;
+                Ok(format!("Snapshot written to {}", args))
+            }
+            Action::Help => help(args),
+            Action::Quit => Ok(String::new()),
+        }
+    }
+
+    fn report(&self) -> String {
+        let symbols: Vec<(String, u16)> = self
+            .symbols
+            .iter()
+            .map(|(name, &address)| (name.clone(), address))
+            .collect();
+        inspect::report(&self.cpu, &symbols)
+    }
+
+    fn step(&mut self) {
+        if self.halted {
+            return;
+        }
+        match self.cpu.step() {
+            Ok(None) => {}
+            Ok(Some(_)) => self.halted = true,
+            Err(error) => {
+                self.halted = true;
+                self.fault = Some(self.cpu.fault_message(&error));
+            }
+        }
+    }
+
+    // Runs until a breakpoint, a watched word changes, Ctrl-C or the program halts.
+    // Returns the message if a watch or Ctrl-C triggered the stop.
+    fn resume(&mut self) -> Option<String> {
+        if let Some(presses) = self.interrupt {
+            sigint::clear(presses);
+        }
+        loop {
+            self.step();
+            if self.halted {
+                return None;
+            }
+            if self.interrupt.is_some_and(sigint::pressed) {
+                return Some(format!("Interrupted\n{}", self.stop_message()));
+            }
+            for (expression, address, value) in self.watches.iter_mut() {
+                let new_value = self.cpu.memory().get_u16(*address as usize);
+                if new_value != *value {
+                    let message = format!(
+                        "Watch {}: {:#06x} -> {:#06x}\n",
+                        expression, value, new_value
+                    );
+                    *value = new_value;
+                    return Some(message + &self.stop_message());
+                }
+            }
+            if self
+                .breakpoints
+                .contains(&self.cpu.get_register(register::IP))
+            {
+                return None;
+            }
+        }
+    }
+
+    // Where the program stopped, followed by the displays
+    fn stop_message(&self) -> String {
+        let message = if let Some(fault) = &self.fault {
+            format!("Faulted: {}", fault)
+        } else if self.halted {
+            "Halted".to_string()
+        } else {
+            format!(
+                "Stopped at {}",
+                self.describe(self.cpu.get_register(register::IP))
+            )
+        };
+        if self.displays.is_empty() {
+            message
+        } else {
+            format!("{}\n{}", message, self.show_displays())
+        }
+    }
+
+    fn show_displays(&self) -> String {
+        self.displays
+            .iter()
+            .map(|(number, expression)| self.show_display(*number, expression))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn show_display(&self, number: usize, expression: &str) -> String {
+        match self.evaluate(expression) {
+            Ok(value) => format!("{}: {} = {:#06x}", number, expression, value),
+            Err(message) => format!("{}: {}: {}", number, expression, message),
+        }
+    }
+
+    // Only the bytes of the instruction are read, like the CPU would
+    fn instruction_at(&self, address: u16) -> String {
+        let memory = self.cpu.memory();
+        let opcode = memory.get_u8(address as usize);
+        let size = instruction::LIST
+            .iter()
+            .find(|instruction| instruction.opcode == opcode)
+            .map_or(1, |instruction| instruction.size as usize);
+        let bytes: Vec<u8> = (address as usize..memory.len())
+            .take(size)
+            .map(|address| memory.get_u8(address))
+            .collect();
+        disassembler::decode(&bytes, 0)
+            .map_or_else(|| format!("db {:#04x}", opcode), |(text, _)| text)
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match self.symbols.iter().find(|(_, &a)| a == address) {
+            Some((name, _)) => format!("{:#06x} (!{})", address, name),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    fn resolve(&self, expression: &str) -> Result<u16, String> {
+        if expression.is_empty() {
+            return Err("Expected an address".to_string());
+        }
+        self.check_symbols(expression)?;
+        assembler::evaluate_expression(&expression.replace("0x", "$"), &self.symbols)
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<u16, String> {
+        self.check_symbols(expression)?;
+        assembler::evaluate_runtime_expression(
+            &expression.replace("0x", "$"),
+            &self.symbols,
+            &self.cpu,
+        )
+    }
+
+    fn check_symbols(&self, expression: &str) -> Result<(), String> {
+        match variables(expression)
+            .into_iter()
+            .find(|name| !self.symbols.contains_key(*name))
+        {
+            Some(name) => Err(self.unknown_symbol(name)),
+            None => Ok(()),
+        }
+    }
+
+    fn unknown_symbol(&self, name: &str) -> String {
+        let near: Vec<String> = self
+            .symbols
+            .keys()
+            .filter(|symbol| {
+                symbol.contains(name)
+                    || name.contains(symbol.as_str())
+                    || distance(symbol, name) <= 2
+            })
+            .map(|symbol| format!("!{}", symbol))
+            .collect();
+        if near.is_empty() {
+            format!("Unknown symbol !{}", name)
+        } else {
+            format!(
+                "Unknown symbol !{}, did you mean {}?",
+                name,
+                near.join(", ")
+            )
+        }
+    }
+}
+
+impl Runtime for CPU {
+    fn register(&self, name: &str) -> u16 {
+        let reg = register::LIST
+            .iter()
+            .find(|&&reg| register::name(reg) == name)
+            .unwrap();
+        self.get_register(*reg)
+    }
+
+    fn read(&self, address: u16, wide: bool) -> Result<u16, String> {
+        let memory = self.memory();
+        let size = if wide { 2 } else { 1 };
+        if address as usize + size > memory.len() {
+            Err(format!("{:#06x} is outside memory", address))
+        } else if wide {
+            Ok(memory.get_u16(address as usize))
+        } else {
+            Ok(memory.get_u8(address as usize) as u16)
+        }
+    }
+}
+
+fn variables(expression: &str) -> Vec<&str> {
+    expression
+        .split('!')
+        .skip(1)
+        .map(|rest| {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            &rest[..end]
+        })
+        .collect()
+}
+
+// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Debugger;
+    use crate::assembler;
+    use crate::cpu::register;
+    use crate::cpu::CPU;
+    use crate::device::memory::Memory;
+
+    fn session(code: &str, script: &str) -> (String, Vec<(String, u16)>) {
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        let (bytes, debug) = (assembly.bytes.clone(), assembly.debug_info("prog.asm"));
+        let memory = Memory::with_contents(0x1000, &bytes);
+        let mut debugger = Debugger::new(CPU::new(Box::new(memory)), Some(&debug));
+        let mut output = vec![];
+        debugger.run(Cursor::new(script), &mut output).unwrap();
+        (String::from_utf8(output).unwrap(), debug.symbols)
+    }
+
+    const PROGRAM: &str =
+        "mov $3 R1\nloop:\ndec R1\nmov R1 &800\nmov R1 ACC\njne $0 &[!loop]\ndone:\nhlt\n";
+
+    #[test]
+    fn quit_report() {
+        let (output, _) = session(PROGRAM, "quit now\ncontinue\nquit report\nregs\n");
+        let mut lines = output.lines();
+        assert_eq!(
+            lines.next(),
+            Some("Error: Unknown argument: now, expected report")
+        );
+        assert_eq!(lines.nth(1), Some("Registers:"));
+        assert_eq!(
+            lines.find(|line| line.starts_with("Routine")),
+            Some("Routine: done")
+        );
+        assert_eq!(lines.last(), Some("0x0ffe: 0x0000 <- SP <- FP"));
+    }
+
+    #[test]
+    fn symbolic_breakpoint() {
+        let (output, symbols) = session(PROGRAM, "break !loop\ncontinue\ncontinue\n");
+        let address = symbols.iter().find(|(name, _)| name == "loop").unwrap().1;
+        assert_eq!(address, 4);
+        assert_eq!(
+            output,
+            "Breakpoint at 0x0004 (!loop)\nStopped at 0x0004 (!loop)\nStopped at 0x0004 (!loop)\n"
+        );
+
+        let (output, symbols) = session(PROGRAM, "break !done\ncontinue\ncontinue\n");
+        let address = symbols.iter().find(|(name, _)| name == "done").unwrap().1;
+        assert_eq!(address, 0x12);
+        assert_eq!(
+            output,
+            "Breakpoint at 0x0012 (!done)\nStopped at 0x0012 (!done)\nHalted\n"
+        );
+    }
+
+    #[test]
+    fn memory_expressions() {
+        let (output, _) = session(PROGRAM, "mem !loop+0x2 3\nmem [!done - $2] * $2\n");
+        assert_eq!(
+            output,
+            "0x0006: 12 02 08\n0x0020: 00 00 00 00 00 00 00 00\n0x0028: 00 00 00 00 00 00 00 00\n"
+        );
+    }
+
+    #[test]
+    fn watch() {
+        let (output, _) = session(PROGRAM, "watch $800\ncontinue\nc\nregs\n");
+        assert!(output.starts_with(
+            "Watching $800 = 0x0000\nWatch $800: 0x0000 -> 0x0002\nStopped at 0x000a\n\
+             Watch $800: 0x0002 -> 0x0001\nStopped at 0x000a\nIP: 0x000a\nACC: 0x0002\nR1: 0x0001\n"
+        ));
+    }
+
+    #[test]
+    fn step() {
+        let (output, _) = session(
+            PROGRAM,
+            "break !done
+step
+step
+continue
+step
+step
+",
+        );
+        assert_eq!(
+            output,
+            "Breakpoint at 0x0012 (!done)\n\
+             0x0000: mov $3 R1\n\
+             IP=0004 ACC=0000 R1=0003 R2=0000 R3=0000 R4=0000 R5=0000 R6=0000 R7=0000 R8=0000 \
+             SP=0ffe FP=0ffe MB=0000 IM=00ff HI=0000 FLAGS=0000\n\
+             Stopped at 0x0004 (!loop)\n\
+             0x0004: dec R1\n\
+             IP=0006 ACC=0000 R1=0002 R2=0000 R3=0000 R4=0000 R5=0000 R6=0000 R7=0000 R8=0000 \
+             SP=0ffe FP=0ffe MB=0000 IM=00ff HI=0000 FLAGS=0000\n\
+             Stopped at 0x0006\n\
+             Stopped at 0x0012 (!done)\n\
+             0x0012: hlt\n\
+             IP=0013 ACC=0000 R1=0000 R2=0000 R3=0000 R4=0000 R5=0000 R6=0000 R7=0000 R8=0000 \
+             SP=0ffe FP=0ffe MB=0000 IM=00ff HI=0000 FLAGS=0000\n\
+             Halted\nHalted\n"
+        );
+    }
+
+    #[test]
+    fn displays() {
+        let (output, _) = session(
+            PROGRAM,
+            "display R1\ndisplay byte(0x801) & 0x1\nbreak !loop\ncontinue\ncontinue\n\
+             undisplay 1\nstep\nstep\ndisplay\nundisplay 1\ndisplay word(!nothing)\n",
+        );
+        // The register rows of the steps are left out
+        let output: Vec<&str> = output
+            .lines()
+            .filter(|line| !line.starts_with("IP="))
+            .collect();
+        assert_eq!(
+            output.join("\n") + "\n",
+            "1: R1 = 0x0000\n2: byte(0x801) & 0x1 = 0x0000\nBreakpoint at 0x0004 (!loop)\n\
+             Stopped at 0x0004 (!loop)\n1: R1 = 0x0003\n2: byte(0x801) & 0x1 = 0x0000\n\
+             Stopped at 0x0004 (!loop)\n1: R1 = 0x0002\n2: byte(0x801) & 0x1 = 0x0000\n\
+             Removed display 1\n\
+             0x0004: dec R1\nStopped at 0x0006\n2: byte(0x801) & 0x1 = 0x0000\n\
+             0x0006: mov R1 &800\nStopped at 0x000a\n2: byte(0x801) & 0x1 = 0x0001\n\
+             2: byte(0x801) & 0x1 = 0x0001\n\
+             Error: No display 1\nError: Unknown symbol !nothing\n"
+        );
+    }
+
+    #[test]
+    fn restart() {
+        let (output, _) = session(PROGRAM, "break !done\ncontinue\nrestart\ncontinue\n");
+        assert_eq!(
+            output,
+            "Breakpoint at 0x0012 (!done)\nStopped at 0x0012 (!done)\nStopped at 0x0000\n\
+             Stopped at 0x0012 (!done)\n"
+        );
+    }
+
+    #[test]
+    fn fault() {
+        let (output, _) = session(
+            "mov $1 R1\n.opcode $ee\nhlt\n",
+            "continue\nstep\nrestart\nstep\n",
+        );
+        assert_eq!(
+            output,
+            "Faulted: Illegal opcode 0xee at 0x0004\n\
+             Faulted: Illegal opcode 0xee at 0x0004\n\
+             Stopped at 0x0000\n0x0000: mov $1 R1\n\
+             IP=0004 ACC=0000 R1=0001 R2=0000 R3=0000 R4=0000 R5=0000 R6=0000 R7=0000 R8=0000 \
+             SP=0ffe FP=0ffe MB=0000 IM=00ff HI=0000 FLAGS=0000\n\
+             Stopped at 0x0004\n"
+        );
+    }
+
+    #[test]
+    fn interrupt() {
+        static PRESSES: AtomicUsize = AtomicUsize::new(1);
+        let assembly = assembler::assemble(
+            "loop:\ninc R1\njmp &[!loop]\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let memory = Memory::with_contents(0x1000, &assembly.bytes);
+        let mut debugger = Debugger::new(CPU::new(Box::new(memory)), None).with_interrupt(&PRESSES);
+        // The press from before continue is dropped, the loop runs until the next one
+        let presser = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(20));
+            PRESSES.fetch_add(1, Ordering::Relaxed);
+        });
+        let mut output = vec![];
+        debugger
+            .run(Cursor::new("continue\nregs\n"), &mut output)
+            .unwrap();
+        presser.join().unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("Interrupted\nStopped at 0x000"),
+            "{}",
+            output
+        );
+        assert!(debugger.cpu.get_register(register::R1) > 0);
+    }
+
+    #[test]
+    fn unknown_symbol() {
+        let (output, _) = session(PROGRAM, "break !lop\nbreak !nothing\nfoo\n");
+        assert_eq!(
+            output,
+            "Error: Unknown symbol !lop, did you mean !loop?\nError: Unknown symbol !nothing\n\
+             Error: Unknown command: foo, valid commands are break, watch, display, undisplay, \
+             mem, step, continue, regs, restart, snapshot, help, quit\n"
+        );
+    }
+
+    #[test]
+    fn help() {
+        let (output, _) = session(PROGRAM, "he\nhel co\nre\nsi\n");
+        let mut lines = output.lines();
+        assert_eq!(
+            lines.next(),
+            Some("break <address>           Stop when IP reaches the address")
+        );
+        assert_eq!(
+            lines.nth(10),
+            Some(
+                "quit [report]             Leave the debugger, optionally printing the final state"
+            )
+        );
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            vec![
+                "continue",
+                "  Run until a breakpoint, a watch or halt",
+                "  Aliases: c",
+                "Error: Ambiguous command: re could be regs, restart",
+                "0x0000: mov $3 R1",
+                "IP=0004 ACC=0000 R1=0003 R2=0000 R3=0000 R4=0000 R5=0000 R6=0000 R7=0000 R8=0000 \
+                 SP=0ffe FP=0ffe MB=0000 IM=00ff HI=0000 FLAGS=0000",
+                "Stopped at 0x0004 (!loop)",
+            ]
+        );
+    }
+}
@@ -1,8 +1,27 @@
+use std::cell::Cell;
+
 pub mod banked_memory;
+#[cfg(feature = "devices-terminal")]
+pub mod console;
+pub mod interrupt_controller;
+#[cfg(feature = "devices-terminal")]
+pub mod keyboard;
+#[cfg(feature = "devices-terminal")]
+pub mod line_input;
 pub mod memory;
 pub mod memory_mapper;
+pub mod null;
+pub mod port_bus;
+pub mod rng;
+pub mod rom;
+#[cfg(feature = "devices-terminal")]
 pub mod screen;
+pub mod script;
+pub mod test_harness;
+pub mod testing;
 
+// Devices have a fixed size, there is no empty one
+#[allow(clippy::len_without_is_empty)]
 pub trait Device {
     fn get_u16(&self, address: usize) -> u16;
     fn get_u8(&self, address: usize) -> u8;
@@ -10,4 +29,58 @@ pub trait Device {
     fn set_u8(&mut self, address: usize, value: u8);
     fn len(&self) -> usize;
     fn set_mb(&mut self, mb: u16);
+    // Called after every instruction with the number of cycles it took
+    fn tick(&mut self, _cycles: u16) {}
+    // Puts the device back into its power-on state, memory contents are kept
+    fn reset(&mut self) {}
+    // Bytes the device wants written to guest memory at an address, the memory mapper copies
+    // them after every instruction
+    fn take_transfer(&mut self) -> Option<(usize, Vec<u8>)> {
+        None
+    }
+    // Interrupt the device raises, the CPU takes at most one after each instruction's tick
+    fn take_interrupt(&mut self) -> Option<u16> {
+        None
+    }
+    // Address of an access no device answered, the CPU takes it after every instruction and
+    // raises a memory fault
+    fn take_fault(&mut self) -> Option<u16> {
+        None
+    }
+    // Used in reports like the memory traffic statistics
+    fn name(&self) -> &str {
+        "device"
+    }
+    // Memory traffic counted so far, if the device keeps statistics
+    fn traffic_report(&self) -> Option<String> {
+        None
+    }
+}
+
+// The first access past the end of a device since the last `take_fault`. Devices answer such an
+// access like an unmapped address: reads are all ones, writes are dropped and the CPU raises a
+// memory fault at the address accessed.
+#[derive(Debug, Default)]
+pub struct FaultLatch(Cell<Option<usize>>);
+
+impl FaultLatch {
+    // Whether `bytes` bytes from `address` fit in a device of `len` bytes, remembers the address
+    // if not
+    pub fn check(&self, address: usize, bytes: usize, len: usize) -> bool {
+        let fits = address + bytes <= len;
+        if !fits {
+            self.record(address);
+        }
+        fits
+    }
+
+    pub fn record(&self, address: usize) {
+        if self.0.get().is_none() {
+            self.0.set(Some(address));
+        }
+    }
+
+    pub fn take(&self) -> Option<u16> {
+        self.0.take().map(|address| address as u16)
+    }
 }
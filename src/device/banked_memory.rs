@@ -1,10 +1,16 @@
-use super::Device;
+use super::{Device, FaultLatch};
 use crate::device::memory::Memory;
 
+// Banks of the same size behind one window, MB picks the bank the window shows. With MB past
+// the last bank the window is open bus, like an unmapped address: reads are 0xff, writes are
+// dropped and either raises a memory fault at the address accessed. So does an access running
+// past the end of the bank.
 pub struct BankedMemory {
     mb: u16,
     banks: Vec<Memory>,
     size: u16,
+    // Also remembers the first access to a missing bank
+    fault: FaultLatch,
 }
 
 impl BankedMemory {
@@ -13,25 +19,89 @@ impl BankedMemory {
         for _ in 0..count {
             banks.push(Memory::new(size))
         }
-        BankedMemory { mb: 0, banks, size }
+        BankedMemory {
+            mb: 0,
+            banks,
+            size,
+            fault: FaultLatch::default(),
+        }
+    }
+
+    // One bank per entry of `contents`, they all need the same size
+    pub fn with_contents(contents: &[Vec<u8>]) -> Result<BankedMemory, String> {
+        let size = contents.first().map_or(0, Vec::len);
+        if contents.is_empty() || contents.len() > u8::MAX as usize || size > u16::MAX as usize {
+            return Err(format!(
+                "Banked memory needs 1 to 255 banks of up to {:#x} bytes",
+                u16::MAX
+            ));
+        }
+        if let Some(bank) = contents.iter().position(|bank| bank.len() != size) {
+            return Err(format!(
+                "Bank {} has {} bytes, bank 0 has {}",
+                bank,
+                contents[bank].len(),
+                size
+            ));
+        }
+        let mut memory = BankedMemory::new(contents.len() as u8, size as u16);
+        for (bank, bytes) in memory.banks.iter_mut().zip(contents) {
+            for (address, &byte) in bytes.iter().enumerate() {
+                bank.set_u8(address, byte);
+            }
+        }
+        Ok(memory)
+    }
+
+    pub fn banks(&self) -> u16 {
+        self.banks.len() as u16
+    }
+
+    pub fn bank_size(&self) -> u16 {
+        self.size
+    }
+
+    // Registers describing this memory for the guest
+    pub fn info(&self) -> BankInfo {
+        BankInfo::new(self.banks(), self.size)
+    }
+
+    // The selected bank if it exists and holds `bytes` bytes from `address`, remembers a fault
+    // otherwise
+    fn bank(&self, address: usize, bytes: usize) -> Option<usize> {
+        let bank = self.mb as usize;
+        if bank >= self.banks.len() {
+            self.fault.record(address);
+            None
+        } else if self.fault.check(address, bytes, self.size as usize) {
+            Some(bank)
+        } else {
+            None
+        }
     }
 }
 
 impl Device for BankedMemory {
     fn get_u16(&self, address: usize) -> u16 {
-        self.banks[self.mb as usize].get_u16(address)
+        self.bank(address, 2)
+            .map_or(0xffff, |bank| self.banks[bank].get_u16(address))
     }
 
     fn get_u8(&self, address: usize) -> u8 {
-        self.banks[self.mb as usize].get_u8(address)
+        self.bank(address, 1)
+            .map_or(0xff, |bank| self.banks[bank].get_u8(address))
     }
 
     fn set_u16(&mut self, address: usize, value: u16) {
-        self.banks[self.mb as usize].set_u16(address, value)
+        if let Some(bank) = self.bank(address, 2) {
+            self.banks[bank].set_u16(address, value)
+        }
     }
 
     fn set_u8(&mut self, address: usize, value: u8) {
-        self.banks[self.mb as usize].set_u8(address, value)
+        if let Some(bank) = self.bank(address, 1) {
+            self.banks[bank].set_u8(address, value)
+        }
     }
 
     fn len(&self) -> usize {
@@ -41,7 +111,150 @@ impl Device for BankedMemory {
     fn set_mb(&mut self, mb: u16) {
         self.mb = mb;
     }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "banked"
+    }
+
+    fn reset(&mut self) {
+        self.mb = 0;
+        self.fault.take();
+    }
+}
+
+// Read-only registers a guest can find the banks MB selects between with. Writes are ignored,
+// reads past the last register fault. Registers are big endian words:
+//   0 count - number of banks
+//   2 size  - bytes per bank
+pub const COUNT: usize = 0;
+pub const SIZE: usize = 2;
+
+pub struct BankInfo {
+    registers: [u8; 4],
+    fault: FaultLatch,
+}
+
+impl BankInfo {
+    pub fn new(count: u16, size: u16) -> BankInfo {
+        let [count_high, count_low] = count.to_be_bytes();
+        let [size_high, size_low] = size.to_be_bytes();
+        BankInfo {
+            registers: [count_high, count_low, size_high, size_low],
+            fault: FaultLatch::default(),
+        }
+    }
+
+    // `bytes` registers from `address`, remembers a fault if they run past the last one
+    fn read(&self, address: usize, bytes: usize) -> Option<&[u8]> {
+        if !self.fault.check(address, bytes, self.registers.len()) {
+            return None;
+        }
+        Some(&self.registers[address..address + bytes])
+    }
+}
+
+impl Device for BankInfo {
+    fn get_u16(&self, address: usize) -> u16 {
+        self.read(address, 2)
+            .map_or(0xffff, |word| u16::from_be_bytes([word[0], word[1]]))
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        self.read(address, 1).map_or(0xff, |byte| byte[0])
+    }
+
+    fn set_u16(&mut self, _: usize, _: u16) {}
+
+    fn set_u8(&mut self, _: usize, _: u8) {}
+
+    fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn name(&self) -> &str {
+        "bank info"
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::{BankedMemory, COUNT, SIZE};
+    use crate::device::Device;
+
+    #[test]
+    fn isolated_banks() {
+        let mut memory = BankedMemory::with_contents(&[
+            vec![0x11, 0x12, 0x13, 0x14],
+            vec![0x21, 0x22, 0x23, 0x24],
+        ])
+        .unwrap();
+        assert_eq!(memory.get_u16(0), 0x1112);
+        memory.set_mb(1);
+        assert_eq!(memory.get_u16(2), 0x2324);
+        memory.set_u16(0, 0xabcd);
+        memory.set_mb(0);
+        assert_eq!(memory.get_u16(0), 0x1112);
+        memory.set_mb(1);
+        assert_eq!(memory.get_u16(0), 0xabcd);
+        assert_eq!(memory.take_fault(), None);
+
+        assert_eq!(
+            BankedMemory::with_contents(&[vec![0; 4], vec![0; 2]]).err(),
+            Some("Bank 1 has 2 bytes, bank 0 has 4".to_string())
+        );
+        assert_eq!(
+            BankedMemory::with_contents(&[]).err(),
+            Some("Banked memory needs 1 to 255 banks of up to 0xffff bytes".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_bank() {
+        let mut memory = BankedMemory::new(2, 0x10);
+        memory.set_u8(4, 0x42);
+        memory.set_mb(0xff);
+        assert_eq!(memory.get_u8(4), 0xff);
+        assert_eq!(memory.get_u16(6), 0xffff);
+        assert_eq!(memory.take_fault(), Some(4));
+        assert_eq!(memory.take_fault(), None);
+        memory.set_u8(8, 0x01);
+        assert_eq!(memory.take_fault(), Some(8));
+
+        memory.set_mb(2);
+        memory.set_u16(4, 0x1234);
+        assert_eq!(memory.take_fault(), Some(4));
+        memory.set_mb(1);
+        assert_eq!(memory.get_u16(0xf), 0xffff);
+        memory.set_u16(0xf, 0x1234);
+        assert_eq!(memory.take_fault(), Some(0xf));
+        assert_eq!(memory.get_u8(0xf), 0);
+        memory.reset();
+        assert_eq!(memory.get_u8(4), 0x42);
+        assert_eq!(memory.take_fault(), None);
+    }
+
+    #[test]
+    fn info() {
+        let memory = BankedMemory::new(8, 0x100);
+        assert_eq!((memory.banks(), memory.bank_size()), (8, 0x100));
+        let mut info = memory.info();
+        info.set_u16(COUNT, 0x1234);
+        assert_eq!(info.get_u16(COUNT), 8);
+        assert_eq!(info.get_u16(SIZE), 0x100);
+        assert_eq!(info.take_fault(), None);
+        assert_eq!(info.get_u16(3), 0xffff);
+        assert_eq!(info.get_u8(4), 0xff);
+        assert_eq!(info.take_fault(), Some(3));
+        assert_eq!(info.take_fault(), None);
+    }
+}
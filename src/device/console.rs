@@ -0,0 +1,65 @@
+use std::io;
+
+use super::{Device, FaultLatch};
+
+// Character output for the port bus or a memory window. Writing a word to register 0 prints its
+// low byte, reads return zero.
+pub struct Console<W: io::Write> {
+    output: W,
+    fault: FaultLatch,
+}
+
+impl<W: io::Write> Console<W> {
+    pub fn new(output: W) -> Console<W> {
+        Console {
+            output,
+            fault: FaultLatch::default(),
+        }
+    }
+}
+
+impl<W: io::Write> Device for Console<W> {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.len()) {
+            return 0xffff;
+        }
+        0
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.len()) {
+            return 0xff;
+        }
+        0
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if self.fault.check(address, 2, self.len()) {
+            self.set_u8(address + 1, value as u8);
+        }
+    }
+
+    // Output errors are dropped, like on a disconnected terminal
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if self.fault.check(address, 1, self.len()) && address == 1 {
+            let _ = self
+                .output
+                .write_all(&[value])
+                .and_then(|_| self.output.flush());
+        }
+    }
+
+    fn len(&self) -> usize {
+        2
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "console"
+    }
+}
@@ -0,0 +1,237 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::{Device, FaultLatch};
+
+// Lets several devices share the CPU's interrupt vectors. Each device raises its line through an
+// `IrqLine`, and the controller presents the lowest numbered line that is pending, not masked and
+// not already in service. The CPU takes it like `int` with the line number, so IM still applies.
+// A line stays pending and in service until the guest writes its number to ACK, a handler that
+// returns without acknowledging doesn't get its line again. Registers are big endian words:
+//   0 pending - one bit per line, writes are ignored
+//   2 mask    - a set bit keeps the line from being presented
+//   4 ack     - reads the lines in service, writing a line number acknowledges it
+pub const PENDING: usize = 0;
+pub const MASK: usize = 2;
+pub const ACK: usize = 4;
+// One line per interrupt vector
+pub const LINES: u16 = 8;
+
+pub struct InterruptController {
+    pending: Rc<Cell<u16>>,
+    mask: u16,
+    in_service: u16,
+    fault: FaultLatch,
+}
+
+// A device's handle on its line
+#[derive(Clone)]
+pub struct IrqLine {
+    pending: Rc<Cell<u16>>,
+    line: u16,
+}
+
+impl IrqLine {
+    pub fn raise(&self) {
+        self.pending.set(self.pending.get() | 1 << self.line);
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            pending: Rc::new(Cell::new(0)),
+            mask: 0,
+            in_service: 0,
+            fault: FaultLatch::default(),
+        }
+    }
+
+    pub fn line(&self, line: u16) -> Result<IrqLine, String> {
+        if line >= LINES {
+            return Err(format!(
+                "Interrupt line {} is out of range, there are {}",
+                line, LINES
+            ));
+        }
+        Ok(IrqLine {
+            pending: Rc::clone(&self.pending),
+            line,
+        })
+    }
+
+    fn register(&self, address: usize) -> u16 {
+        match address & !1 {
+            PENDING => self.pending.get(),
+            MASK => self.mask,
+            _ => self.in_service,
+        }
+    }
+
+    fn acknowledge(&mut self, line: u8) {
+        if let Some(bit) = 1u16.checked_shl(line as u32) {
+            self.pending.set(self.pending.get() & !bit);
+            self.in_service &= !bit;
+        }
+    }
+}
+
+impl Default for InterruptController {
+    fn default() -> InterruptController {
+        InterruptController::new()
+    }
+}
+
+impl Device for InterruptController {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.len()) {
+            return 0xffff;
+        }
+        u16::from_be_bytes([self.get_u8(address), self.get_u8(address + 1)])
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.len()) {
+            return 0xff;
+        }
+        self.register(address).to_be_bytes()[address & 1]
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if !self.fault.check(address, 2, self.len()) {
+            return;
+        }
+        let [high, low] = value.to_be_bytes();
+        self.set_u8(address, high);
+        self.set_u8(address + 1, low);
+    }
+
+    // An acknowledgement takes effect with the low byte of ACK
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if !self.fault.check(address, 1, self.len()) {
+            return;
+        }
+        match address {
+            a if a == MASK => self.mask = self.mask & 0x00ff | (value as u16) << 8,
+            a if a == MASK + 1 => self.mask = self.mask & 0xff00 | value as u16,
+            a if a == ACK + 1 => self.acknowledge(value),
+            _ => {}
+        }
+    }
+
+    fn len(&self) -> usize {
+        6
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn name(&self) -> &str {
+        "interrupt controller"
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn take_interrupt(&mut self) -> Option<u16> {
+        let ready = self.pending.get() & !self.mask & !self.in_service;
+        if ready == 0 {
+            return None;
+        }
+        let line = ready.trailing_zeros() as u16;
+        self.in_service |= 1 << line;
+        Some(line)
+    }
+
+    fn reset(&mut self) {
+        self.pending.set(0);
+        self.mask = 0;
+        self.in_service = 0;
+        self.fault.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InterruptController, ACK, MASK, PENDING};
+    #[cfg(feature = "assembler")]
+    use crate::assembler;
+    #[cfg(feature = "assembler")]
+    use crate::device::memory::Memory;
+    use crate::device::Device;
+    #[cfg(feature = "assembler")]
+    use crate::machine::Builder;
+
+    #[test]
+    fn registers() {
+        let mut controller = InterruptController::new();
+        let (one, three) = (controller.line(1).unwrap(), controller.line(3).unwrap());
+        three.raise();
+        one.raise();
+        assert_eq!(controller.get_u16(PENDING), 0b1010);
+        assert_eq!(controller.take_interrupt(), Some(1));
+        assert_eq!(controller.take_interrupt(), Some(3));
+        assert_eq!(controller.take_interrupt(), None);
+        assert_eq!(controller.get_u16(ACK), 0b1010);
+
+        // Raising a line in service doesn't present it again before the acknowledgement
+        one.raise();
+        assert_eq!(controller.take_interrupt(), None);
+        controller.set_u16(ACK, 1);
+        assert_eq!(controller.get_u16(PENDING), 0b1000);
+        assert_eq!(controller.get_u16(ACK), 0b1000);
+        one.raise();
+        assert_eq!(controller.take_interrupt(), Some(1));
+
+        controller.set_u8(MASK + 1, 0b10);
+        controller.set_u8(ACK + 1, 1);
+        one.raise();
+        assert_eq!(controller.get_u16(MASK), 0b10);
+        assert_eq!(controller.take_interrupt(), None);
+        controller.set_u16(MASK, 0);
+        assert_eq!(controller.take_interrupt(), Some(1));
+
+        // Acknowledging a line that isn't one does nothing
+        controller.set_u16(ACK, 0xff);
+        assert_eq!(controller.get_u16(ACK), 0b1010);
+        controller.reset();
+        assert_eq!(controller.get_u16(PENDING), 0);
+        assert_eq!(controller.get_u16(ACK), 0);
+        assert_eq!(
+            controller.line(8).err(),
+            Some("Interrupt line 8 is out of range, there are 8".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn priority() {
+        // Both handlers append their line to the log at &800 and acknowledge it. RTI restores
+        // the registers, so the end of the log is kept at &900.
+        let code = "mov [!low] &1006\nmov [!high] &1002\nmov $800 &900\n\
+                    loop:\nmov &900 ACC\njne $804 &[!loop]\nhlt\n\
+                    high:\nmov $1 R2\njmp &[!log]\n\
+                    low:\nmov $3 R2\n\
+                    log:\nmov &900 R1\nmov R2 $0 R1\nmov R2 &1f04\n\
+                    add $2 R1\nmov ACC &900\nrti\n";
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        let memory = Memory::with_contents(0xffff, &assembly.bytes);
+        let controller = InterruptController::new();
+        let (one, three) = (controller.line(1).unwrap(), controller.line(3).unwrap());
+        let mut cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfffe, true)
+            .unwrap()
+            .map(Box::new(controller), 0x1f00, 0x1f05, true)
+            .unwrap()
+            .build();
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        three.raise();
+        one.raise();
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory().get_u16(0x800), 1);
+        assert_eq!(cpu.memory().get_u16(0x802), 3);
+        assert_eq!(cpu.memory().get_u16(0x1f00), 0);
+    }
+}
@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use super::{Device, FaultLatch};
+
+// Key presses for the guest, two byte registers:
+//   0 status - 1 while a key is waiting, 0 otherwise
+//   1 data   - the next key, reading it takes the key off the queue, 0 when there is none
+// Reading the word at 0 gives both, so a single read polls and takes the key. Writes are
+// ignored. Reads have a side effect, so anything reading memory through the map, like a hexdump,
+// takes keys as well.
+pub const STATUS: usize = 0;
+pub const DATA: usize = 1;
+
+pub struct Keyboard {
+    keys: RefCell<VecDeque<u8>>,
+    // Bytes read from stdin by a background thread, moved into the queue after every instruction
+    input: Option<Receiver<u8>>,
+    // The terminal is switched back to line mode when the keyboard is dropped
+    raw_mode: bool,
+    fault: FaultLatch,
+}
+
+impl Keyboard {
+    pub fn with_queue(keys: Vec<u8>) -> Keyboard {
+        Keyboard {
+            keys: RefCell::new(keys.into()),
+            input: None,
+            raw_mode: false,
+            fault: FaultLatch::default(),
+        }
+    }
+
+    // Keys arrive as they are typed and are not echoed while the terminal is in raw mode. Input
+    // that isn't a terminal, like a pipe, is read as it is.
+    pub fn stdin() -> Keyboard {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0];
+            while let Ok(1) = io::stdin().read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        Keyboard {
+            keys: RefCell::new(VecDeque::new()),
+            input: Some(receiver),
+            raw_mode: stty(&["-icanon", "-echo"]),
+            fault: FaultLatch::default(),
+        }
+    }
+}
+
+// Fails when stdin is not a terminal
+fn stty(args: &[&str]) -> bool {
+    Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        if self.raw_mode {
+            stty(&["icanon", "echo"]);
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.len()) {
+            return 0xffff;
+        }
+        u16::from_be_bytes([self.get_u8(address), self.get_u8(address + 1)])
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.len()) {
+            return 0xff;
+        }
+        match address {
+            STATUS => !self.keys.borrow().is_empty() as u8,
+            _ => self.keys.borrow_mut().pop_front().unwrap_or(0),
+        }
+    }
+
+    fn set_u16(&mut self, address: usize, _: u16) {
+        self.fault.check(address, 2, self.len());
+    }
+
+    fn set_u8(&mut self, address: usize, _: u8) {
+        self.fault.check(address, 1, self.len());
+    }
+
+    fn len(&self) -> usize {
+        2
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn tick(&mut self, _cycles: u16) {
+        if let Some(input) = &self.input {
+            self.keys.get_mut().extend(input.try_iter());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::{Keyboard, DATA, STATUS};
+    use crate::assembler;
+    use crate::cpu::register;
+    use crate::device::memory::Memory;
+    use crate::device::screen::Screen;
+    use crate::device::Device;
+    use crate::machine::Builder;
+
+    #[test]
+    fn registers() {
+        let mut keyboard = Keyboard::with_queue(b"ab".to_vec());
+        assert_eq!(keyboard.get_u8(STATUS), 1);
+        assert_eq!(keyboard.get_u8(STATUS), 1);
+        assert_eq!(keyboard.get_u8(DATA), b'a');
+        keyboard.set_u8(DATA, b'x');
+        assert_eq!(keyboard.get_u16(STATUS), 0x0100 | b'b' as u16);
+        assert_eq!(keyboard.get_u8(STATUS), 0);
+        assert_eq!(keyboard.get_u8(DATA), 0);
+        assert_eq!(keyboard.get_u16(STATUS), 0);
+    }
+
+    #[test]
+    fn echo() {
+        // Copies keys to the screen until a newline, the status byte lands in the command byte
+        // of the cell, where 1 means nothing
+        let code = "mov $0 R2\n\
+                    loop:\n\
+                    mov &fdfc R1\n\
+                    mov R1 ACC\n\
+                    jeq $0 &[!loop]\n\
+                    and R1 $ff\n\
+                    jeq $a &[!done]\n\
+                    mov R1 $fe00 R2\n\
+                    inc R2\n\
+                    jmp &[!loop]\n\
+                    done:\n\
+                    hlt\n";
+        let bytes = assembler::compile(code, &assembler::Options::default()).unwrap();
+        let memory = Memory::with_contents(0xfe00, &bytes);
+        let mut cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfdff, true)
+            .unwrap()
+            .map(
+                Box::new(Keyboard::with_queue(b"hi!\nx".to_vec())),
+                0xfdfc,
+                0xfdfd,
+                true,
+            )
+            .unwrap()
+            .screen(Screen::headless(16, 16), 0xfe00, 0xfeff)
+            .unwrap()
+            .map(Box::new(Memory::new(0x100)), 0xff00, 0xffff, true)
+            .unwrap()
+            .build();
+        cpu.run().unwrap();
+
+        let cells: Vec<u8> = (0xfe00..0xfe05).map(|a| cpu.memory().get_u8(a)).collect();
+        assert_eq!(cells, b"hi!\0\0");
+        assert_eq!(cpu.get_register(register::R2), 3);
+        // The key after the newline is still waiting
+        assert_eq!(cpu.memory().get_u8(0xfdfd), b'x');
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::{self, BufRead};
+
+use super::{Device, FaultLatch};
+
+// Reads a whole line for the guest. Registers are big endian words:
+//   0 command  - write REQUEST_LINE to read a line
+//   2 buffer   - guest address the line is copied to
+//   4 max      - maximum number of bytes copied
+//   6 status   - STATUS_OK, STATUS_IO_ERROR or STATUS_END_OF_INPUT
+//   8 length   - number of bytes copied
+// The line is read when the command is written and copied into guest memory by the memory mapper
+// after the instruction, so the next instruction sees the status, length and buffer. A terminal
+// echoes and edits the line itself, backspaces that reach the device, like from a pipe, still
+// delete the character before them. Accesses past the last register fault.
+pub const REQUEST_LINE: u16 = 1;
+
+pub const STATUS_OK: u16 = 0;
+pub const STATUS_IO_ERROR: u16 = 1;
+pub const STATUS_END_OF_INPUT: u16 = 2;
+
+const COMMAND: usize = 0;
+const BUFFER: usize = 2;
+const MAX: usize = 4;
+const STATUS: usize = 6;
+const LENGTH: usize = 8;
+
+pub struct LineInput {
+    input: Box<dyn BufRead>,
+    registers: [u8; 10],
+    transfer: Option<(usize, Vec<u8>)>,
+    fault: FaultLatch,
+}
+
+impl LineInput {
+    pub fn new(input: Box<dyn BufRead>) -> LineInput {
+        LineInput {
+            input,
+            registers: [0; 10],
+            transfer: None,
+            fault: FaultLatch::default(),
+        }
+    }
+
+    // Like the READ_LINE syscall, one byte at a time so the rest of stdin is left alone
+    pub fn stdin() -> LineInput {
+        LineInput::new(Box::new(io::BufReader::with_capacity(1, io::stdin())))
+    }
+
+    // Whether `bytes` registers from `address` exist, remembers a fault if not
+    fn check(&self, address: usize, bytes: usize) -> bool {
+        self.fault.check(address, bytes, self.registers.len())
+    }
+
+    fn word(&self, register: usize) -> u16 {
+        u16::from_be_bytes([self.registers[register], self.registers[register + 1]])
+    }
+
+    fn set_word(&mut self, register: usize, value: u16) {
+        self.registers[register..register + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn read_line(&mut self) {
+        let mut line = vec![];
+        let (status, bytes) = match self.input.read_until(b'\n', &mut line) {
+            Ok(0) => (STATUS_END_OF_INPUT, vec![]),
+            Ok(_) => (STATUS_OK, edit(&line)),
+            Err(_) => (STATUS_IO_ERROR, vec![]),
+        };
+        let length = bytes.len().min(self.word(MAX) as usize);
+        self.set_word(STATUS, status);
+        self.set_word(LENGTH, length as u16);
+        self.transfer = Some((self.word(BUFFER) as usize, bytes[..length].to_vec()));
+    }
+
+    fn written(&mut self) {
+        if self.word(COMMAND) == REQUEST_LINE {
+            self.set_word(COMMAND, 0);
+            self.read_line();
+        }
+    }
+}
+
+// Drops the line ending and applies backspaces
+fn edit(line: &[u8]) -> Vec<u8> {
+    let mut res = vec![];
+    for &byte in line {
+        match byte {
+            b'\n' | b'\r' => {}
+            0x08 | 0x7f => {
+                res.pop();
+            }
+            _ => res.push(byte),
+        }
+    }
+    res
+}
+
+impl Device for LineInput {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.check(address, 2) {
+            return 0xffff;
+        }
+        self.word(address)
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.check(address, 1) {
+            return 0xff;
+        }
+        self.registers[address]
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if self.check(address, 2) {
+            self.set_word(address, value);
+            self.written();
+        }
+    }
+
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if self.check(address, 1) {
+            self.registers[address] = value;
+            self.written();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn name(&self) -> &str {
+        "line input"
+    }
+
+    fn take_transfer(&mut self) -> Option<(usize, Vec<u8>)> {
+        self.transfer.take()
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{LineInput, STATUS_END_OF_INPUT, STATUS_OK};
+    use crate::assembler;
+    use crate::cpu::{register, CPU};
+    use crate::device::memory::Memory;
+    use crate::device::memory_mapper::MemoryMapper;
+    use crate::device::Device;
+
+    // Reads two lines into $100 and $200, keeping their lengths and statuses in R1-R4
+    const PROGRAM: &str = "mov $100 &f02\nmov $8 &f04\nmov $1 &f00\nmov &f08 R1\nmov &f06 R2\n\
+                           mov $200 &f02\nmov $1 &f00\nmov &f08 R3\nmov &f06 R4\nhlt\n";
+
+    fn run(input: &'static str) -> CPU {
+        let bytes = assembler::compile(PROGRAM, &assembler::Options::default()).unwrap();
+        let memory = Memory::with_contents(0xf00, &bytes);
+        let mut mapper = MemoryMapper::new();
+        mapper.map(Box::new(memory), 0x000, 0xeff, true).unwrap();
+        mapper
+            .map(
+                Box::new(LineInput::new(Box::new(Cursor::new(input)))),
+                0xf00,
+                0xf09,
+                true,
+            )
+            .unwrap();
+        let mut cpu = CPU::new(Box::new(mapper));
+        cpu.run().unwrap();
+        cpu
+    }
+
+    fn read(cpu: &CPU, address: usize, length: usize) -> Vec<u8> {
+        (address..address + length)
+            .map(|a| cpu.memory().get_u8(a))
+            .collect()
+    }
+
+    #[test]
+    fn lines() {
+        let cpu = run("hello\nworld, too long\n");
+        assert_eq!(read(&cpu, 0x100, 6), b"hello\0");
+        assert_eq!(cpu.get_register(register::R1), 5);
+        assert_eq!(cpu.get_register(register::R2), STATUS_OK);
+        assert_eq!(read(&cpu, 0x200, 9), b"world, t\0");
+        assert_eq!(cpu.get_register(register::R3), 8);
+        assert_eq!(cpu.get_register(register::R4), STATUS_OK);
+    }
+
+    #[test]
+    fn past_the_end() {
+        let mut device = LineInput::new(Box::new(Cursor::new("")));
+        assert_eq!(device.get_u16(9), 0xffff);
+        device.set_u16(9, 0x1234);
+        device.set_u8(10, 0x12);
+        assert_eq!(device.take_fault(), Some(9));
+        assert_eq!(device.take_fault(), None);
+        assert_eq!(device.get_u8(9), 0);
+    }
+
+    #[test]
+    fn backspace_and_end_of_input() {
+        let cpu = run("helx\x08lo\r\n");
+        assert_eq!(read(&cpu, 0x100, 5), b"hello");
+        assert_eq!(cpu.get_register(register::R1), 5);
+        assert_eq!(cpu.get_register(register::R3), 0);
+        assert_eq!(cpu.get_register(register::R4), STATUS_END_OF_INPUT);
+    }
+}
@@ -1,29 +1,50 @@
-use crate::device::Device;
+use crate::device::{Device, FaultLatch};
 
 #[derive(Debug)]
 pub struct Memory {
     memory: Box<[u8]>,
+    fault: FaultLatch,
 }
 impl Memory {
     pub fn new(size: u16) -> Memory {
         Memory {
             memory: vec![0; size as usize].into_boxed_slice(),
+            fault: FaultLatch::default(),
         }
     }
+
+    // Memory of `size` bytes starting with `bytes`, the rest is zero
+    pub fn with_contents(size: u16, bytes: &[u8]) -> Memory {
+        let mut memory = Memory::new(size);
+        memory.memory[..bytes.len()].copy_from_slice(bytes);
+        memory
+    }
+
+    fn fits(&self, address: usize, bytes: usize) -> bool {
+        self.fault.check(address, bytes, self.memory.len())
+    }
 }
 impl Device for Memory {
     fn get_u8(&self, address: usize) -> u8 {
+        if !self.fits(address, 1) {
+            return 0xff;
+        }
         self.memory[address]
     }
     fn set_u8(&mut self, address: usize, value: u8) {
-        self.memory[address] = value;
+        if self.fits(address, 1) {
+            self.memory[address] = value;
+        }
     }
     fn get_u16(&self, address: usize) -> u16 {
+        if !self.fits(address, 2) {
+            return 0xffff;
+        }
         u16::from_be_bytes([self.memory[address], self.memory[address + 1]])
     }
     fn set_u16(&mut self, address: usize, value: u16) {
-        for (offset, &byte) in value.to_be_bytes().iter().enumerate() {
-            self.memory[address + offset] = byte;
+        if self.fits(address, 2) {
+            self.memory[address..address + 2].copy_from_slice(&value.to_be_bytes());
         }
     }
     fn len(&self) -> usize {
@@ -31,6 +52,14 @@ impl Device for Memory {
     }
 
     fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "RAM"
+    }
 }
 
 #[cfg(test)]
@@ -1,96 +1,577 @@
 use super::Device;
+use std::cell::Cell;
 use std::collections::VecDeque;
 
+// What the guest may do with a mapped region, the mapper enforces it before the device is called
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct DeviceCapability {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl DeviceCapability {
+    pub const READ_WRITE: DeviceCapability = DeviceCapability {
+        readable: true,
+        writable: true,
+    };
+    pub const READ_ONLY: DeviceCapability = DeviceCapability {
+        readable: true,
+        writable: false,
+    };
+    pub const WRITE_ONLY: DeviceCapability = DeviceCapability {
+        readable: false,
+        writable: true,
+    };
+}
+
+// What happens on an access the region's capability does not allow
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Violation {
+    // Reads return 0xff in every byte, writes are dropped
+    OpenBus,
+    // The access panics like any other fault
+    Fault,
+}
+
+// Accesses that reached a region's device, each get or set is one access
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct Traffic {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
 struct Region {
     device: Box<dyn Device>,
     start: usize,
     end: usize,
     remap: bool,
+    capability: DeviceCapability,
+    // Only counted once statistics are enabled
+    traffic: Option<Cell<Traffic>>,
 }
+
+impl Region {
+    fn offset(&self, address: usize) -> usize {
+        if self.remap {
+            address - self.start
+        } else {
+            address
+        }
+    }
+
+    fn count(&self, write: bool, bytes: u64) {
+        if let Some(traffic) = &self.traffic {
+            let mut t = traffic.get();
+            if write {
+                t.writes += 1;
+                t.bytes_written += bytes;
+            } else {
+                t.reads += 1;
+                t.bytes_read += bytes;
+            }
+            traffic.set(t);
+        }
+    }
+}
+
 pub struct MemoryMapper {
     regions: VecDeque<Region>,
+    violation: Violation,
+    stats: bool,
+    // The first unmapped address accessed, or word access running past the end of its region,
+    // since the last `take_fault`
+    unmapped: Cell<Option<usize>>,
 }
 impl MemoryMapper {
     pub fn new() -> MemoryMapper {
         MemoryMapper {
             regions: VecDeque::new(),
+            violation: Violation::OpenBus,
+            stats: false,
+            unmapped: Cell::new(None),
         }
     }
 
-    pub fn map(&mut self, device: Box<dyn Device>, start: usize, end: usize, remap: bool) {
+    pub fn map(
+        &mut self,
+        device: Box<dyn Device>,
+        start: usize,
+        end: usize,
+        remap: bool,
+    ) -> Result<(), String> {
+        self.map_with(device, start, end, remap, DeviceCapability::READ_WRITE)
+    }
+
+    // Both ends are inclusive. A remapped device sees offsets from `start`, otherwise the absolute
+    // addresses, so it must be long enough for the end of the region either way.
+    pub fn map_with(
+        &mut self,
+        device: Box<dyn Device>,
+        start: usize,
+        end: usize,
+        remap: bool,
+        capability: DeviceCapability,
+    ) -> Result<(), String> {
+        if end < start {
+            return Err(format!(
+                "Region {:#06x}-{:#06x} of {} ends before it starts",
+                start,
+                end,
+                device.name()
+            ));
+        }
+        let (last, addressing) = if remap {
+            (end - start, "offsets")
+        } else {
+            (end, "absolute addresses")
+        };
+        if last >= device.len() {
+            return Err(format!(
+                "Region {:#06x}-{:#06x} does not fit {} of {:#x} bytes, it gets {} up to {:#06x}",
+                start,
+                end,
+                device.name(),
+                device.len(),
+                addressing,
+                last
+            ));
+        }
         let region = Region {
             device,
             start,
             end,
             remap,
+            capability,
+            traffic: if self.stats {
+                Some(Cell::new(Traffic::default()))
+            } else {
+                None
+            },
         };
         self.regions.push_front(region);
+        Ok(())
+    }
+
+    // Counts reads and writes per region from now on, regions mapped later included
+    pub fn enable_stats(&mut self) {
+        self.stats = true;
+        for region in self.regions.iter_mut() {
+            region.traffic.get_or_insert_with(Cell::default);
+        }
     }
 
-    fn find_region(&self, address: usize) -> &Region {
+    // Per region in mapping order, empty unless statistics are enabled
+    pub fn traffic(&self) -> Vec<(String, Traffic)> {
         self.regions
             .iter()
-            .find(|region| (region.start..=region.end).contains(&address))
-            .unwrap()
+            .rev()
+            .filter_map(|region| {
+                region
+                    .traffic
+                    .as_ref()
+                    .map(|traffic| (region.device.name().to_string(), traffic.get()))
+            })
+            .collect()
     }
 
-    fn find_region_mut(&mut self, address: usize) -> &mut Region {
+    // One line per region in mapping order, like `0x0000-0x7fff RAM` or
+    // `0xf000-0xffff ROM read-only absolute`
+    pub fn describe(&self) -> String {
+        self.regions
+            .iter()
+            .rev()
+            .map(|region| {
+                let mut res = format!(
+                    "{:#06x}-{:#06x} {}",
+                    region.start,
+                    region.end,
+                    region.device.name()
+                );
+                match region.capability {
+                    DeviceCapability::READ_ONLY => res.push_str(" read-only"),
+                    DeviceCapability::WRITE_ONLY => res.push_str(" write-only"),
+                    _ => {}
+                }
+                if !region.remap {
+                    res.push_str(" absolute");
+                }
+                res
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn set_violation(&mut self, violation: Violation) {
+        self.violation = violation;
+    }
+
+    // Remembers a miss for `take_fault`
+    fn find_region(&self, address: usize) -> Option<&Region> {
+        let region = self
+            .regions
+            .iter()
+            .find(|region| (region.start..=region.end).contains(&address));
+        if region.is_none() {
+            self.miss(address);
+        }
+        region
+    }
+
+    fn miss(&self, address: usize) {
+        if self.unmapped.get().is_none() {
+            self.unmapped.set(Some(address));
+        }
+    }
+
+    // Both bytes of a word have to be in the region of its first byte, the device only knows its
+    // own addresses. A word at the last byte faults instead.
+    fn word_fits(&self, region: &Region, address: usize) -> bool {
+        let fits = address < region.end;
+        if !fits {
+            self.miss(address);
+        }
+        fits
+    }
+
+    fn find_region_mut(&mut self, address: usize) -> Option<&mut Region> {
         self.regions
             .iter_mut()
             .find(|region| (region.start..=region.end).contains(&address))
-            .unwrap()
+    }
+
+    // Returns false if the access has to be skipped, like every access to an unmapped address
+    fn allowed(&self, address: usize, write: bool) -> bool {
+        let capability = match self.find_region(address) {
+            Some(region) => region.capability,
+            None => return false,
+        };
+        let allowed = if write {
+            capability.writable
+        } else {
+            capability.readable
+        };
+        if !allowed && self.violation == Violation::Fault {
+            panic!(
+                "{} {:#06x} in a {} region",
+                if write { "Write to" } else { "Read from" },
+                address,
+                if write { "read-only" } else { "write-only" }
+            );
+        }
+        allowed
     }
 }
+
+impl Default for MemoryMapper {
+    fn default() -> MemoryMapper {
+        MemoryMapper::new()
+    }
+}
+
 impl Device for MemoryMapper {
     fn get_u16(&self, address: usize) -> u16 {
-        let region = self.find_region(address);
-        region.device.get_u16(if region.remap {
-            address - region.start
-        } else {
-            address
-        })
+        match self.find_region(address) {
+            Some(region) if self.allowed(address, false) && self.word_fits(region, address) => {
+                region.count(false, 2);
+                region.device.get_u16(region.offset(address))
+            }
+            _ => 0xffff,
+        }
     }
 
     fn get_u8(&self, address: usize) -> u8 {
-        let region = self.find_region(address);
-        region.device.get_u8(if region.remap {
-            address - region.start
-        } else {
-            address
-        })
+        match self.find_region(address) {
+            Some(region) if self.allowed(address, false) => {
+                region.count(false, 1);
+                region.device.get_u8(region.offset(address))
+            }
+            _ => 0xff,
+        }
     }
 
     fn set_u16(&mut self, address: usize, value: u16) {
-        let region = self.find_region_mut(address);
-        region.device.set_u16(
-            if region.remap {
-                address - region.start
-            } else {
-                address
-            },
-            value,
-        )
+        if !self.allowed(address, true)
+            || !self
+                .find_region(address)
+                .is_some_and(|region| self.word_fits(region, address))
+        {
+            return;
+        }
+        if let Some(region) = self.find_region_mut(address) {
+            region.count(true, 2);
+            region.device.set_u16(region.offset(address), value)
+        }
     }
 
     fn set_u8(&mut self, address: usize, value: u8) {
-        let region = self.find_region_mut(address);
-        region.device.set_u8(
-            if region.remap {
-                address - region.start
-            } else {
-                address
-            },
-            value,
-        )
+        if !self.allowed(address, true) {
+            return;
+        }
+        if let Some(region) = self.find_region_mut(address) {
+            region.count(true, 1);
+            region.device.set_u8(region.offset(address), value)
+        }
     }
 
     fn len(&self) -> usize {
         0xffff
     }
 
+    fn name(&self) -> &str {
+        "memory map"
+    }
+
+    // `RAM: 1.2M reads / 300k writes (2.4M / 600k bytes); screen: 0`
+    fn traffic_report(&self) -> Option<String> {
+        if !self.stats {
+            return None;
+        }
+        let regions: Vec<String> = self
+            .traffic()
+            .iter()
+            .map(|(name, t)| {
+                if t.reads + t.writes == 0 {
+                    return format!("{}: 0", name);
+                }
+                let mut parts = vec![];
+                if t.reads > 0 {
+                    parts.push(format!("{} reads", count(t.reads)));
+                }
+                if t.writes > 0 {
+                    parts.push(format!("{} writes", count(t.writes)));
+                }
+                let bytes = match (t.bytes_read, t.bytes_written) {
+                    (read, 0) => count(read),
+                    (0, written) => count(written),
+                    (read, written) => format!("{} / {}", count(read), count(written)),
+                };
+                format!("{}: {} ({} bytes)", name, parts.join(" / "), bytes)
+            })
+            .collect();
+        Some(regions.join("; "))
+    }
+
     fn set_mb(&mut self, mb: u16) {
         for region in self.regions.iter_mut() {
             region.device.set_mb(mb)
         }
     }
+
+    // Regions are searched newest first, resetting goes the other way so it follows mapping order
+    fn reset(&mut self) {
+        for region in self.regions.iter_mut().rev() {
+            region.device.reset()
+        }
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        let mut transfers = vec![];
+        for region in self.regions.iter_mut() {
+            region.device.tick(cycles);
+            transfers.extend(region.device.take_transfer());
+        }
+        for (address, bytes) in transfers {
+            for (i, byte) in bytes.into_iter().enumerate() {
+                self.set_u8(address + i, byte);
+            }
+        }
+    }
+
+    fn take_interrupt(&mut self) -> Option<u16> {
+        self.regions
+            .iter_mut()
+            .find_map(|region| region.device.take_interrupt())
+    }
+
+    // Devices report faults at their own addresses, remapped ones are translated back. Every
+    // device's fault is taken so none is left over for the next instruction.
+    fn take_fault(&mut self) -> Option<u16> {
+        let mut fault = self.unmapped.take();
+        for region in self.regions.iter_mut() {
+            if let Some(address) = region.device.take_fault() {
+                let address = address as usize + if region.remap { region.start } else { 0 };
+                fault.get_or_insert(address);
+            }
+        }
+        fault.map(|address| address as u16)
+    }
+}
+
+// Exact below a thousand, otherwise one decimal with a k or M suffix
+fn count(n: u64) -> String {
+    let (value, suffix) = match n {
+        0..=999 => return n.to_string(),
+        1_000..=999_999 => (n as f64 / 1e3, "k"),
+        _ => (n as f64 / 1e6, "M"),
+    };
+    let value = format!("{:.1}", value);
+    format!("{}{}", value.trim_end_matches(".0"), suffix)
+}
+
+#[cfg(all(test, feature = "devices-terminal"))]
+mod tests {
+    use super::{count, DeviceCapability, MemoryMapper, Traffic, Violation};
+    use crate::device::memory::Memory;
+    use crate::device::screen::Screen;
+    use crate::device::Device;
+
+    fn mapper() -> MemoryMapper {
+        let mut mapper = MemoryMapper::new();
+        mapper
+            .map(Box::new(Memory::new(0x100)), 0x000, 0x0ff, true)
+            .unwrap();
+        mapper
+            .map_with(
+                Box::new(Screen::headless(4, 4)),
+                0x100,
+                0x10f,
+                true,
+                DeviceCapability::WRITE_ONLY,
+            )
+            .unwrap();
+        let mut keys = Memory::new(0x10);
+        keys.set_u8(0, b'k');
+        mapper
+            .map_with(
+                Box::new(keys),
+                0x110,
+                0x11f,
+                true,
+                DeviceCapability::READ_ONLY,
+            )
+            .unwrap();
+        mapper
+    }
+
+    #[test]
+    fn region_fits_device() {
+        let mut mapper = MemoryMapper::new();
+        assert_eq!(
+            mapper.map(Box::new(Memory::new(0x100)), 0x1000, 0x10ff, true),
+            Ok(())
+        );
+        assert_eq!(
+            mapper.map(Box::new(Memory::new(0x100)), 0x1000, 0x1100, true),
+            Err(
+                "Region 0x1000-0x1100 does not fit RAM of 0x100 bytes, it gets offsets up to 0x0100"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            mapper.map(Box::new(Memory::new(0x200)), 0x0100, 0x01ff, false),
+            Ok(())
+        );
+        assert_eq!(
+            mapper.map(Box::new(Memory::new(0x100)), 0x0100, 0x01ff, false),
+            Err(
+                "Region 0x0100-0x01ff does not fit RAM of 0x100 bytes, it gets absolute addresses \
+                 up to 0x01ff"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            mapper.map(Box::new(Memory::new(0x100)), 0x0200, 0x0100, true),
+            Err("Region 0x0200-0x0100 of RAM ends before it starts".to_string())
+        );
+    }
+
+    #[test]
+    fn open_bus() {
+        let mut mapper = mapper();
+        mapper.set_u16(0x100, 0x0041);
+        assert_eq!(mapper.get_u8(0x100), 0xff);
+        assert_eq!(mapper.get_u16(0x100), 0xffff);
+
+        mapper.set_u8(0x110, b'x');
+        mapper.set_u16(0x110, 0x1234);
+        assert_eq!(mapper.get_u8(0x110), b'k');
+
+        mapper.set_u8(0x10, 0x42);
+        assert_eq!(mapper.get_u8(0x10), 0x42);
+    }
+
+    #[test]
+    fn unmapped() {
+        let mut mapper = MemoryMapper::new();
+        mapper
+            .map(Box::new(Memory::new(0x100)), 0x0000, 0x00ff, true)
+            .unwrap();
+        assert_eq!(mapper.take_fault(), None);
+        assert_eq!(mapper.get_u8(0x2000), 0xff);
+        assert_eq!(mapper.get_u16(0x2002), 0xffff);
+        mapper.set_u8(0x3000, 0x42);
+        assert_eq!(mapper.take_fault(), Some(0x2000));
+        assert_eq!(mapper.take_fault(), None);
+        mapper.set_u16(0x3000, 0x1234);
+        assert_eq!(mapper.take_fault(), Some(0x3000));
+    }
+
+    #[test]
+    fn word_at_region_end() {
+        let mut mapper = mapper();
+        mapper.set_u8(0x0ff, 0x42);
+        assert_eq!(mapper.get_u16(0x0ff), 0xffff);
+        assert_eq!(mapper.take_fault(), Some(0x0ff));
+        mapper.set_u16(0x0ff, 0x1234);
+        assert_eq!(mapper.take_fault(), Some(0x0ff));
+        assert_eq!(mapper.get_u8(0x0ff), 0x42);
+        assert_eq!(mapper.get_u16(0x0fe), 0x0042);
+        assert_eq!(mapper.take_fault(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Read from 0x0101 in a write-only region")]
+    fn read_fault() {
+        let mut mapper = mapper();
+        mapper.set_violation(Violation::Fault);
+        mapper.get_u8(0x101);
+    }
+
+    #[test]
+    #[should_panic(expected = "Write to 0x0110 in a read-only region")]
+    fn write_fault() {
+        let mut mapper = mapper();
+        mapper.set_violation(Violation::Fault);
+        mapper.set_u16(0x110, 0x1234);
+    }
+
+    #[test]
+    fn traffic() {
+        let mut mapper = mapper();
+        mapper.get_u8(0x10);
+        mapper.enable_stats();
+        mapper.set_u16(0x10, 0x1234);
+        mapper.get_u16(0x10);
+        mapper.get_u8(0x11);
+        mapper.set_u8(0x100, b'a');
+        mapper
+            .map(Box::new(Memory::new(0x10)), 0x0f0, 0x0ff, true)
+            .unwrap();
+        mapper.set_u8(0x0f0, 1);
+        assert_eq!(
+            mapper.traffic()[0],
+            (
+                "RAM".to_string(),
+                Traffic {
+                    reads: 2,
+                    writes: 1,
+                    bytes_read: 3,
+                    bytes_written: 2,
+                }
+            )
+        );
+        assert_eq!(
+            mapper.traffic_report(),
+            Some(
+                "RAM: 2 reads / 1 writes (3 / 2 bytes); screen: 1 writes (1 bytes); RAM: 0; \
+                 RAM: 1 writes (1 bytes)"
+                    .to_string()
+            )
+        );
+        assert_eq!(count(999), "999");
+        assert_eq!(count(4_000), "4k");
+        assert_eq!(count(1_234_567), "1.2M");
+    }
 }
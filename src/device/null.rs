@@ -0,0 +1,54 @@
+use super::{Device, FaultLatch};
+
+// Fills a hole in the memory map: reads are zero and writes are dropped
+pub struct Null {
+    len: usize,
+    fault: FaultLatch,
+}
+
+impl Null {
+    pub fn new(len: usize) -> Null {
+        Null {
+            len,
+            fault: FaultLatch::default(),
+        }
+    }
+}
+
+impl Device for Null {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.len) {
+            return 0xffff;
+        }
+        0
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.len) {
+            return 0xff;
+        }
+        0
+    }
+
+    fn set_u16(&mut self, address: usize, _: u16) {
+        self.fault.check(address, 2, self.len);
+    }
+
+    fn set_u8(&mut self, address: usize, _: u8) {
+        self.fault.check(address, 1, self.len);
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "null"
+    }
+}
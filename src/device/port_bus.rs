@@ -0,0 +1,117 @@
+use super::Device;
+
+// Separate IO space of 256 word ports reached with IN and OUT, so devices need no memory
+// addresses. A device registered on ports `first..=last` sees port `first + i` as its word at
+// offset `2 * i`. Unconnected ports read as 0xffff and ignore writes.
+pub struct PortBus {
+    devices: Vec<Box<dyn Device>>,
+    // Device index and offset per port
+    ports: Vec<Option<(usize, usize)>>,
+}
+
+impl PortBus {
+    pub fn new() -> PortBus {
+        PortBus {
+            devices: vec![],
+            ports: vec![None; 256],
+        }
+    }
+
+    pub fn register(&mut self, device: Box<dyn Device>, first: u8, last: u8) -> Result<(), String> {
+        if last < first {
+            return Err(format!(
+                "Ports {:#04x}-{:#04x} of {} end before they start",
+                first,
+                last,
+                device.name()
+            ));
+        }
+        let count = (last - first) as usize + 1;
+        if count * 2 > device.len() {
+            return Err(format!(
+                "Ports {:#04x}-{:#04x} need {} bytes of {}, it has {}",
+                first,
+                last,
+                count * 2,
+                device.name(),
+                device.len()
+            ));
+        }
+        if let Some(port) = (first..=last).find(|&port| self.ports[port as usize].is_some()) {
+            let (taken, _) = self.ports[port as usize].unwrap();
+            return Err(format!(
+                "Port {:#04x} is already taken by {}",
+                port,
+                self.devices[taken].name()
+            ));
+        }
+        for (i, port) in (first..=last).enumerate() {
+            self.ports[port as usize] = Some((self.devices.len(), i * 2));
+        }
+        self.devices.push(device);
+        Ok(())
+    }
+
+    pub fn read(&self, port: u8) -> u16 {
+        match self.ports[port as usize] {
+            Some((device, offset)) => self.devices[device].get_u16(offset),
+            None => 0xffff,
+        }
+    }
+
+    pub fn write(&mut self, port: u8, value: u16) {
+        if let Some((device, offset)) = self.ports[port as usize] {
+            self.devices[device].set_u16(offset, value);
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u16) {
+        for device in self.devices.iter_mut() {
+            device.tick(cycles);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for device in self.devices.iter_mut() {
+            device.reset();
+        }
+    }
+
+    pub fn take_interrupt(&mut self) -> Option<u16> {
+        self.devices
+            .iter_mut()
+            .find_map(|device| device.take_interrupt())
+    }
+}
+
+impl Default for PortBus {
+    fn default() -> PortBus {
+        PortBus::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PortBus;
+    use crate::device::memory::Memory;
+
+    #[test]
+    fn ports() {
+        let mut bus = PortBus::new();
+        bus.register(Box::new(Memory::new(4)), 0x10, 0x11).unwrap();
+        bus.write(0x11, 0x1234);
+        assert_eq!(bus.read(0x11), 0x1234);
+        assert_eq!(bus.read(0x10), 0);
+        bus.write(0x12, 0x5678);
+        assert_eq!(bus.read(0x12), 0xffff);
+
+        assert_eq!(
+            bus.register(Box::new(Memory::new(4)), 0x11, 0x12),
+            Err("Port 0x11 is already taken by RAM".to_string())
+        );
+        assert_eq!(
+            bus.register(Box::new(Memory::new(4)), 0x20, 0x22),
+            Err("Ports 0x20-0x22 need 6 bytes of RAM, it has 4".to_string())
+        );
+    }
+}
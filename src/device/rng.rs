@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+
+use super::{Device, FaultLatch};
+use crate::error::VmError;
+
+// Source of the numbers handed out by the RNG device
+pub trait RngBackend {
+    fn next_u16(&mut self) -> u16;
+    fn reseed(&mut self, seed: u16);
+}
+
+pub const DEFAULT_SEED: u16 = 0xace1;
+
+// 16-bit xorshift, the same seed always gives the same sequence
+pub struct XorShift {
+    state: u16,
+}
+
+impl XorShift {
+    pub fn new(seed: u16) -> XorShift {
+        let mut rng = XorShift { state: 0 };
+        rng.reseed(seed);
+        rng
+    }
+}
+
+impl RngBackend for XorShift {
+    fn next_u16(&mut self) -> u16 {
+        let mut x = self.state;
+        x ^= x << 7;
+        x ^= x >> 9;
+        x ^= x << 8;
+        self.state = x;
+        x
+    }
+
+    // Zero would stick at zero forever
+    fn reseed(&mut self, seed: u16) {
+        self.state = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
+}
+
+// Reads the operating system's entropy pool, seeds are ignored
+#[cfg(feature = "os-rng")]
+pub struct OsEntropy {
+    source: std::fs::File,
+}
+
+#[cfg(feature = "os-rng")]
+impl OsEntropy {
+    pub fn new() -> Result<OsEntropy, VmError> {
+        std::fs::File::open("/dev/urandom")
+            .map(|source| OsEntropy { source })
+            .map_err(|e| VmError::Device(format!("Could not open the entropy source: {}", e)))
+    }
+}
+
+#[cfg(feature = "os-rng")]
+impl RngBackend for OsEntropy {
+    fn next_u16(&mut self) -> u16 {
+        use std::io::Read;
+        let mut bytes = [0; 2];
+        self.source
+            .read_exact(&mut bytes)
+            .expect("Entropy source failed");
+        u16::from_be_bytes(bytes)
+    }
+
+    fn reseed(&mut self, _: u16) {}
+}
+
+// Picks the backend for `vm run --rng`: `seed:<decimal>` or `os`. Replayable runs always get the
+// deterministic backend, an `os` request then falls back to the default seed.
+pub fn backend(spec: &str, replayable: bool) -> Result<Box<dyn RngBackend>, VmError> {
+    if let Some(seed) = spec.strip_prefix("seed:") {
+        let seed = seed
+            .parse::<u16>()
+            .map_err(|_| VmError::Config(format!("Invalid RNG seed: {}", seed)))?;
+        return Ok(Box::new(XorShift::new(seed)));
+    }
+    match spec {
+        "os" if replayable => Ok(Box::new(XorShift::new(DEFAULT_SEED))),
+        #[cfg(feature = "os-rng")]
+        "os" => Ok(Box::new(OsEntropy::new()?)),
+        #[cfg(not(feature = "os-rng"))]
+        "os" => Err(VmError::Device(
+            "--rng os needs vm built with the os-rng feature".to_string(),
+        )),
+        _ => Err(VmError::Config(format!(
+            "Unknown RNG {}, expected seed:<number> or os",
+            spec
+        ))),
+    }
+}
+
+// Two byte register: every read returns fresh random bits, a write reseeds. Reads of single
+// bytes take a fresh number each.
+pub struct Rng {
+    backend: RefCell<Box<dyn RngBackend>>,
+    fault: FaultLatch,
+}
+
+impl Rng {
+    pub fn new(backend: Box<dyn RngBackend>) -> Rng {
+        Rng {
+            backend: RefCell::new(backend),
+            fault: FaultLatch::default(),
+        }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng::new(Box::new(XorShift::new(DEFAULT_SEED)))
+    }
+}
+
+impl Device for Rng {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.len()) {
+            return 0xffff;
+        }
+        self.backend.borrow_mut().next_u16()
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.len()) {
+            return 0xff;
+        }
+        self.backend.borrow_mut().next_u16().to_be_bytes()[address]
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if self.fault.check(address, 2, self.len()) {
+            self.backend.get_mut().reseed(value)
+        }
+    }
+
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if self.fault.check(address, 1, self.len()) {
+            self.backend.get_mut().reseed(value as u16)
+        }
+    }
+
+    fn len(&self) -> usize {
+        2
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "rng"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backend, Rng, RngBackend, XorShift, DEFAULT_SEED};
+    use crate::device::Device;
+
+    fn sequence(backend: &mut dyn RngBackend) -> Vec<u16> {
+        (0..4).map(|_| backend.next_u16()).collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        let expected = sequence(&mut XorShift::new(1234));
+        assert_eq!(sequence(&mut XorShift::new(1234)), expected);
+        assert_ne!(sequence(&mut XorShift::new(1235)), expected);
+        assert_eq!(
+            sequence(&mut XorShift::new(0)),
+            sequence(&mut XorShift::new(DEFAULT_SEED))
+        );
+
+        let mut rng = Rng::default();
+        rng.set_u16(0, 1234);
+        let read: Vec<u16> = (0..4).map(|_| rng.get_u16(0)).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn selection() {
+        let expected = sequence(&mut XorShift::new(1234));
+        assert_eq!(
+            sequence(backend("seed:1234", false).unwrap().as_mut()),
+            expected
+        );
+        assert_eq!(
+            sequence(backend("seed:1234", true).unwrap().as_mut()),
+            expected
+        );
+        assert_eq!(
+            sequence(backend("os", true).unwrap().as_mut()),
+            sequence(&mut XorShift::new(DEFAULT_SEED))
+        );
+        #[cfg(not(feature = "os-rng"))]
+        assert_eq!(
+            backend("os", false).err().map(|error| error.to_string()),
+            Some("--rng os needs vm built with the os-rng feature".to_string())
+        );
+        #[cfg(feature = "os-rng")]
+        assert!(backend("os", false).is_ok());
+        assert_eq!(
+            backend("seed:x", false)
+                .err()
+                .map(|error| error.to_string()),
+            Some("Invalid RNG seed: x".to_string())
+        );
+        assert_eq!(
+            backend("dice", false).err().map(|error| error.to_string()),
+            Some("Unknown RNG dice, expected seed:<number> or os".to_string())
+        );
+    }
+}
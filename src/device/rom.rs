@@ -0,0 +1,114 @@
+use crate::device::{Device, FaultLatch};
+
+// Read-only memory holding the bytes it was created from. Writes change nothing and raise a
+// memory fault at the written address, like an access to an unmapped one.
+pub struct Rom {
+    bytes: Box<[u8]>,
+    // Also remembers the first write
+    fault: FaultLatch,
+}
+
+impl Rom {
+    pub fn from_bytes(bytes: &[u8]) -> Rom {
+        Rom {
+            bytes: bytes.into(),
+            fault: FaultLatch::default(),
+        }
+    }
+}
+
+impl Device for Rom {
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.bytes.len()) {
+            return 0xff;
+        }
+        self.bytes[address]
+    }
+
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.bytes.len()) {
+            return 0xffff;
+        }
+        u16::from_be_bytes([self.bytes[address], self.bytes[address + 1]])
+    }
+
+    fn set_u8(&mut self, address: usize, _: u8) {
+        self.fault.record(address);
+    }
+
+    fn set_u16(&mut self, address: usize, _: u16) {
+        self.fault.record(address);
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn name(&self) -> &str {
+        "ROM"
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature = "assembler"), allow(unused_imports))]
+mod tests {
+    use super::Rom;
+    #[cfg(feature = "assembler")]
+    use crate::assembler;
+    use crate::cpu::fault::CpuError;
+    use crate::device::memory::Memory;
+    use crate::device::Device;
+    #[cfg(feature = "assembler")]
+    use crate::machine::Builder;
+
+    #[test]
+    fn writes_fault() {
+        let mut rom = Rom::from_bytes(&[0x12, 0x34, 0x56]);
+        assert_eq!(rom.len(), 3);
+        assert_eq!(rom.get_u16(1), 0x3456);
+        rom.set_u16(1, 0xffff);
+        rom.set_u8(0, 0xff);
+        assert_eq!((rom.get_u8(0), rom.get_u16(1)), (0x12, 0x3456));
+        assert_eq!(rom.take_fault(), Some(1));
+        assert_eq!(rom.take_fault(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn protected_code() {
+        // The store into its own first instruction faults at the ROM address, mapped above RAM
+        // the program keeps running from the original bytes
+        let code = assembler::compile(
+            "mov $7 R1\nmov $1 &[!data]\nmov $2 &0000\nhlt\ndata:\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let memory = Memory::with_contents(0x1000, &code);
+        let mut cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0x0fff, true)
+            .unwrap()
+            .map(
+                Box::new(Rom::from_bytes(&code)),
+                0x0000,
+                code.len() - 1,
+                true,
+            )
+            .unwrap()
+            .build();
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::MemoryFault {
+                address: 0x0000,
+                ip: 0x0009
+            })
+        );
+        assert_eq!(cpu.memory().get_u16(code.len()), 0x0001);
+        assert_eq!(cpu.memory().get_u8(0), code[0]);
+    }
+}
@@ -1,45 +1,241 @@
-use super::Device;
+use std::io;
 
-pub struct Screen {}
+use super::{Device, FaultLatch};
+
+// Every byte address is one character cell, row by row. The screen must be mapped with remap so
+// it sees addresses starting at 0. Cells are kept in a framebuffer that reads return, and every
+// write is drawn on the output with ANSI escapes. A headless screen only updates the framebuffer.
+//
+// The high byte of a word write is a command, the low byte a character:
+//   0xff  clear the screen, then write the character to the cell written to
+//   0xfe  move the cursor to the cell written to, the character is ignored
+//   0xfd  data port: write the character at the cursor and move the cursor to the next cell,
+//         from the last cell back to the first. The cell written to doesn't matter, so a print
+//         loop can send every character to the same address.
+// Anything else just writes the character to the cell written to.
+// Accesses past the last cell read 0xff, are dropped and fault at the address accessed.
+pub struct Screen {
+    width: usize,
+    height: usize,
+    cells: Vec<u8>,
+    cursor: usize,
+    output: Box<dyn io::Write>,
+    fault: FaultLatch,
+}
+
+const SET_CURSOR: u8 = 0xfe;
+const DATA_PORT: u8 = 0xfd;
+const CLEAR: u8 = 0xff;
 
 impl Screen {
-    fn move_to(&self, x: usize, y: usize) {
-        print!("\x1b[{};{}H", y, x)
+    pub fn new(width: usize, height: usize) -> Screen {
+        Screen::with_output(width, height, Box::new(io::stdout()))
+    }
+
+    pub fn headless(width: usize, height: usize) -> Screen {
+        Screen::with_output(width, height, Box::new(io::sink()))
+    }
+
+    pub fn with_output(width: usize, height: usize, output: Box<dyn io::Write>) -> Screen {
+        Screen {
+            width,
+            height,
+            cells: vec![0; width * height],
+            cursor: 0,
+            output,
+            fault: FaultLatch::default(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
     }
 
-    fn clear_screen(&self) {
-        print!("\x1b[24")
+    // Output errors are dropped, like on a disconnected terminal
+    fn draw(&mut self, bytes: &[u8]) {
+        let _ = self
+            .output
+            .write_all(bytes)
+            .and_then(|_| self.output.flush());
+    }
+
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.draw(format!("\x1b[{};{}H", y, x).as_bytes())
+    }
+
+    fn clear_screen(&mut self) {
+        self.cells.iter_mut().for_each(|cell| *cell = 0);
+        self.draw(b"\x1b[2J")
+    }
+
+    // Whether `bytes` cells from `address` are on the screen, remembers a fault if not
+    fn check(&self, address: usize, bytes: usize) -> bool {
+        self.fault.check(address, bytes, self.len())
     }
 }
 
 impl Device for Screen {
-    fn get_u16(&self, _: usize) -> u16 {
-        panic!("Attempted reading from a screen")
+    // Reads the characters of two neighbouring cells
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.check(address, 2) {
+            return 0xffff;
+        }
+        u16::from_be_bytes([self.cells[address], self.cells[address + 1]])
     }
 
-    fn get_u8(&self, _: usize) -> u8 {
-        panic!("Attempted reading from a screen")
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.check(address, 1) {
+            return 0xff;
+        }
+        self.cells[address]
     }
 
     fn set_u16(&mut self, address: usize, value: u16) {
-        let command = (value & 0xff00) >> 8;
-        if command == 0xff {
-            self.clear_screen();
+        if !self.check(address, 1) {
+            return;
         }
-        let char_value = value & 0x00ff;
-        let x = address % 16 + 1;
-        let y = address / 16 + 1;
+        let [command, char_value] = value.to_be_bytes();
+        let address = match command {
+            SET_CURSOR => {
+                self.cursor = address;
+                return;
+            }
+            DATA_PORT => {
+                let cursor = self.cursor;
+                self.cursor = (cursor + 1) % self.len();
+                cursor
+            }
+            CLEAR => {
+                self.clear_screen();
+                address
+            }
+            _ => address,
+        };
+        self.cells[address] = char_value;
+        let x = address % self.width + 1;
+        let y = address / self.width + 1;
         self.move_to(x, y);
-        print!("{}", (char_value as u8) as char)
+        self.draw(&[char_value])
     }
 
-    fn set_u8(&mut self, _: usize, _: u8) {
-        unimplemented!()
+    // A single byte is a character without a command
+    fn set_u8(&mut self, address: usize, value: u8) {
+        self.set_u16(address, value as u16)
     }
 
     fn len(&self) -> usize {
-        unimplemented!()
+        self.width * self.height
     }
 
     fn set_mb(&mut self, _: u16) {}
+
+    fn name(&self) -> &str {
+        "screen"
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn reset(&mut self) {
+        self.cursor = 0;
+        self.fault.take();
+        self.clear_screen();
+        self.move_to(1, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::Screen;
+    use crate::device::Device;
+
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let output = SharedOutput(Rc::new(RefCell::new(vec![])));
+        let mut screen = Screen::with_output(16, 16, Box::new(output.clone()));
+        assert_eq!(screen.len(), 256);
+        screen.set_u16(0x100, 0x0041);
+        assert!(output.0.borrow().is_empty());
+        assert_eq!(screen.take_fault(), Some(0x100));
+        assert_eq!(screen.get_u8(0x100), 0xff);
+        assert_eq!(screen.get_u16(0xff), 0xffff);
+        assert_eq!(screen.take_fault(), Some(0x100));
+        assert_eq!(screen.take_fault(), None);
+        screen.set_u16(0xff, 0x0041);
+        assert_eq!(screen.get_u16(0xfe), 0x0041);
+        assert_eq!(screen.take_fault(), None);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let mut screen = Screen::headless(4, 2);
+        screen.set_u16(5, 0x0041);
+        screen.set_u16(6, 0x0042);
+        assert_eq!(screen.get_u8(5), b'A');
+        assert_eq!(screen.get_u16(5), 0x4142);
+
+        screen.set_u16(0, 0xff43);
+        assert_eq!(screen.get_u8(0), b'C');
+        assert_eq!(screen.get_u8(5), 0);
+    }
+
+    #[test]
+    fn data_port() {
+        let mut screen = Screen::headless(4, 2);
+        screen.set_u16(5, 0x0041);
+        screen.set_u16(6, 0xfe00);
+        for &c in b"HELLO" {
+            screen.set_u16(0, 0xfd00 | c as u16);
+        }
+        assert_eq!(&screen.cells, b"LLO\0\0AHE");
+        // Positional writes leave the cursor alone
+        screen.set_u16(4, 0x0058);
+        screen.set_u16(7, 0xfd21);
+        assert_eq!(&screen.cells, b"LLO!XAHE");
+
+        screen.reset();
+        screen.set_u16(7, 0xfd3f);
+        assert_eq!(screen.get_u8(0), b'?');
+    }
+
+    #[test]
+    fn output() {
+        let output = SharedOutput(Rc::new(RefCell::new(vec![])));
+        let mut screen = Screen::with_output(4, 2, Box::new(output.clone()));
+        screen.set_u8(5, b'A');
+        screen.set_u16(0, 0xff42);
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "\x1b[2;2HA\x1b[2J\x1b[1;1HB"
+        );
+        assert_eq!(screen.get_u16(4), 0);
+        assert_eq!(screen.get_u8(0), b'B');
+
+        output.0.borrow_mut().clear();
+        screen.reset();
+        assert_eq!(*output.0.borrow(), b"\x1b[2J\x1b[1;1H");
+        assert_eq!(screen.get_u8(0), 0);
+    }
 }
@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+
+use super::{Device, FaultLatch};
+
+// Replays external events at fixed points of a run so tests of interrupt driven or polling guest
+// code are deterministic. Each entry of the script runs on the tick after that many
+// instructions. The device is a block of big endian word registers, the first one is the key
+// register set by `Action::Key`.
+pub const KEY: usize = 0;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Action {
+    // Writes the word at a byte offset of the device
+    Write { register: usize, value: u16 },
+    // Raised like INT, so IM applies
+    Interrupt(u16),
+    // Puts the byte into the key register, clearing its high byte
+    Key(u8),
+}
+
+pub struct ScriptedDevice {
+    registers: Vec<u8>,
+    script: Vec<(u64, Action)>,
+    next: usize,
+    instructions: u64,
+    interrupts: VecDeque<u16>,
+    fault: FaultLatch,
+}
+
+impl ScriptedDevice {
+    // Every write has to land inside the registers, a script that doesn't fit is rejected up
+    // front rather than when it runs
+    pub fn new(len: usize, mut script: Vec<(u64, Action)>) -> Result<ScriptedDevice, String> {
+        for (instructions, action) in &script {
+            let register = match action {
+                Action::Write { register, .. } => *register,
+                Action::Key(_) => KEY,
+                Action::Interrupt(_) => continue,
+            };
+            if len < 2 || register > len - 2 {
+                return Err(format!(
+                    "Scripted write at {} to register {:#x} is past the end of the {}-byte device",
+                    instructions, register, len
+                ));
+            }
+        }
+        script.sort_by_key(|(instructions, _)| *instructions);
+        Ok(ScriptedDevice {
+            registers: vec![0; len],
+            script,
+            next: 0,
+            instructions: 0,
+            interrupts: VecDeque::new(),
+            fault: FaultLatch::default(),
+        })
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Write { register, value } => {
+                self.registers[register..register + 2].copy_from_slice(&value.to_be_bytes())
+            }
+            Action::Interrupt(interrupt) => self.interrupts.push_back(interrupt),
+            Action::Key(byte) => self.registers[KEY..KEY + 2].copy_from_slice(&[0, byte]),
+        }
+    }
+}
+
+impl Device for ScriptedDevice {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.registers.len()) {
+            return 0xffff;
+        }
+        u16::from_be_bytes([self.registers[address], self.registers[address + 1]])
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.registers.len()) {
+            return 0xff;
+        }
+        self.registers[address]
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if self.fault.check(address, 2, self.registers.len()) {
+            self.registers[address..address + 2].copy_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if self.fault.check(address, 1, self.registers.len()) {
+            self.registers[address] = value;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn name(&self) -> &str {
+        "script"
+    }
+
+    fn tick(&mut self, _cycles: u16) {
+        self.instructions += 1;
+        while let Some(&(at, action)) = self.script.get(self.next) {
+            if at > self.instructions {
+                break;
+            }
+            self.next += 1;
+            self.apply(action);
+        }
+    }
+
+    fn take_interrupt(&mut self) -> Option<u16> {
+        self.interrupts.pop_front()
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    // The script starts over with the run
+    fn reset(&mut self) {
+        self.registers.iter_mut().for_each(|byte| *byte = 0);
+        self.next = 0;
+        self.instructions = 0;
+        self.interrupts.clear();
+        self.fault.take();
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::{Action, ScriptedDevice};
+    use crate::assembler;
+    use crate::cpu::{register, CPU};
+    use crate::device::memory::Memory;
+    use crate::device::testing;
+    use crate::machine::Builder;
+
+    fn machine(code: &str, script: Vec<(u64, Action)>) -> (CPU, Vec<(String, u16)>) {
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        let memory = Memory::with_contents(0xffff, &assembly.bytes);
+        let cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfffe, true)
+            .unwrap()
+            .map(
+                Box::new(ScriptedDevice::new(4, script).unwrap()),
+                0x1f00,
+                0x1f03,
+                true,
+            )
+            .unwrap()
+            .build();
+        (cpu, assembly.symbols)
+    }
+
+    #[test]
+    fn contract() {
+        testing::assert_device_contract(&mut ScriptedDevice::new(8, vec![]).unwrap(), 8);
+    }
+
+    #[test]
+    fn script_past_the_registers() {
+        let write = |register| Action::Write {
+            register,
+            value: 0x1234,
+        };
+        assert!(ScriptedDevice::new(4, vec![(5, write(2))]).is_ok());
+        assert_eq!(
+            ScriptedDevice::new(4, vec![(5, write(2)), (9, write(3))]).err(),
+            Some("Scripted write at 9 to register 0x3 is past the end of the 4-byte device".into())
+        );
+        assert!(ScriptedDevice::new(4, vec![(0, write(usize::MAX))]).is_err());
+        assert_eq!(
+            ScriptedDevice::new(1, vec![(7, Action::Key(b'a'))]).err(),
+            Some("Scripted write at 7 to register 0x0 is past the end of the 1-byte device".into())
+        );
+    }
+
+    #[test]
+    fn scripted_interrupt() {
+        let code = "mov [!handler] &1002\n\
+                    loop:\n\
+                    mov &0800 ACC\n\
+                    jeq $0 &[!loop]\n\
+                    mov $40 R2\n\
+                    wait:\n\
+                    dec R2\n\
+                    mov R2 ACC\n\
+                    jne $0 &[!wait]\n\
+                    hlt\n\
+                    handler:\n\
+                    mov &0800 R1\n\
+                    inc R1\n\
+                    mov R1 &0800\n\
+                    rti\n";
+        let (mut cpu, symbols) = machine(code, vec![(100, Action::Interrupt(1))]);
+        let handler = symbols
+            .iter()
+            .find(|(name, _)| name == "handler")
+            .unwrap()
+            .1;
+        while cpu.get_register(register::IP) != handler {
+            assert_eq!(cpu.step(), Ok(None));
+        }
+        assert_eq!(cpu.instructions(), 100);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory().get_u16(0x0800), 1);
+        assert!(cpu.instructions() > 0x40 * 3);
+    }
+
+    #[test]
+    fn polled_registers() {
+        let code = "keys:\n\
+                    mov &1f00 ACC\n\
+                    jeq $0 &[!keys]\n\
+                    mov ACC R1\n\
+                    data:\n\
+                    mov &1f02 ACC\n\
+                    jeq $0 &[!data]\n\
+                    hlt\n";
+        let script = vec![
+            (
+                50,
+                Action::Write {
+                    register: 2,
+                    value: 0x1234,
+                },
+            ),
+            (20, Action::Key(b'a')),
+        ];
+        let (mut cpu, _) = machine(code, script);
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), b'a' as u16);
+        assert_eq!(cpu.get_register(register::ACC), 0x1234);
+        // The write lands after the 50th instruction, the poll at the 52nd sees it
+        assert_eq!(cpu.instructions(), 54);
+
+        cpu.reset();
+        cpu.run().unwrap();
+        assert_eq!(cpu.instructions(), 54);
+    }
+}
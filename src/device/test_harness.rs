@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{Device, FaultLatch};
+
+// Lets assembly programs test themselves. Registers are big endian words:
+//   0 expected - value the next assertion expects
+//   2 actual   - writing it compares against expected and records the result
+//   4 done     - writing anything ends the run
+// The results are shared with the host, which stamps each assertion with the address of the
+// instruction that made it.
+pub const EXPECTED: usize = 0;
+pub const ACTUAL: usize = 2;
+pub const DONE: usize = 4;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Assertion {
+    pub expected: u16,
+    pub actual: u16,
+    pub ip: Option<u16>,
+}
+
+impl Assertion {
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Results {
+    pub assertions: Vec<Assertion>,
+    pub done: bool,
+}
+
+pub struct TestHarness {
+    registers: [u8; 6],
+    results: Rc<RefCell<Results>>,
+    fault: FaultLatch,
+}
+
+impl TestHarness {
+    pub fn new() -> (TestHarness, Rc<RefCell<Results>>) {
+        let results = Rc::new(RefCell::new(Results::default()));
+        let harness = TestHarness {
+            registers: [0; 6],
+            results: Rc::clone(&results),
+            fault: FaultLatch::default(),
+        };
+        (harness, results)
+    }
+
+    fn word(&self, register: usize) -> u16 {
+        u16::from_be_bytes([self.registers[register], self.registers[register + 1]])
+    }
+
+    // Byte writes take effect with the low byte of a register
+    fn written(&mut self, address: usize) {
+        match address {
+            a if a == ACTUAL + 1 => self.results.borrow_mut().assertions.push(Assertion {
+                expected: self.word(EXPECTED),
+                actual: self.word(ACTUAL),
+                ip: None,
+            }),
+            a if a == DONE + 1 => self.results.borrow_mut().done = true,
+            _ => {}
+        }
+    }
+}
+
+impl Device for TestHarness {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.registers.len()) {
+            return 0xffff;
+        }
+        self.word(address)
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.registers.len()) {
+            return 0xff;
+        }
+        self.registers[address]
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if self.fault.check(address, 2, self.registers.len()) {
+            self.registers[address..address + 2].copy_from_slice(&value.to_be_bytes());
+            self.written(address + 1);
+        }
+    }
+
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if self.fault.check(address, 1, self.registers.len()) {
+            self.registers[address] = value;
+            self.written(address);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn name(&self) -> &str {
+        "test harness"
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; 6];
+        *self.results.borrow_mut() = Results::default();
+        self.fault.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Assertion, TestHarness, ACTUAL, DONE, EXPECTED};
+    use crate::device::Device;
+
+    #[test]
+    fn records_assertions() {
+        let (mut harness, results) = TestHarness::new();
+        harness.set_u16(EXPECTED, 0x1234);
+        harness.set_u16(ACTUAL, 0x1234);
+        harness.set_u8(ACTUAL + 1, 0x35);
+        assert!(!results.borrow().done);
+        harness.set_u16(DONE, 1);
+
+        let results = results.borrow();
+        assert_eq!(
+            results.assertions,
+            vec![
+                Assertion {
+                    expected: 0x1234,
+                    actual: 0x1234,
+                    ip: None,
+                },
+                Assertion {
+                    expected: 0x1234,
+                    actual: 0x1235,
+                    ip: None,
+                },
+            ]
+        );
+        assert!(results.done);
+    }
+}
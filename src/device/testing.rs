@@ -0,0 +1,289 @@
+use super::{Device, FaultLatch};
+
+// Reference device for the contracts below: reads echo back whatever was written, words are
+// composed from bytes big endian first.
+pub struct EchoDevice {
+    bytes: Vec<u8>,
+    fault: FaultLatch,
+}
+
+impl EchoDevice {
+    pub fn new(len: usize) -> EchoDevice {
+        EchoDevice {
+            bytes: vec![0; len],
+            fault: FaultLatch::default(),
+        }
+    }
+}
+
+impl Device for EchoDevice {
+    fn get_u16(&self, address: usize) -> u16 {
+        if !self.fault.check(address, 2, self.bytes.len()) {
+            return 0xffff;
+        }
+        u16::from_be_bytes([self.get_u8(address), self.get_u8(address + 1)])
+    }
+
+    fn get_u8(&self, address: usize) -> u8 {
+        if !self.fault.check(address, 1, self.bytes.len()) {
+            return 0xff;
+        }
+        self.bytes[address]
+    }
+
+    fn set_u16(&mut self, address: usize, value: u16) {
+        if !self.fault.check(address, 2, self.bytes.len()) {
+            return;
+        }
+        let [high, low] = value.to_be_bytes();
+        self.set_u8(address, high);
+        self.set_u8(address + 1, low);
+    }
+
+    fn set_u8(&mut self, address: usize, value: u8) {
+        if self.fault.check(address, 1, self.bytes.len()) {
+            self.bytes[address] = value;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn set_mb(&mut self, _: u16) {}
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+}
+
+// What the CPU relies on from every device of `len` bytes, read-only ones and ones with side
+// effects included: reads inside the device don't fault, and a word read at the last byte
+// returns 0xffff and faults at that byte instead of panicking.
+pub fn assert_device_bounds(dev: &mut dyn Device, len: usize) {
+    assert!(len >= 2, "contract needs a device of at least 2 bytes");
+    assert_eq!(
+        dev.len(),
+        len,
+        "len() returned {:#x} for a device of {:#x} bytes",
+        dev.len(),
+        len
+    );
+
+    dev.take_fault();
+    dev.get_u8(len - 1);
+    dev.get_u16(len - 2);
+    let fault = dev.take_fault();
+    assert_eq!(
+        fault,
+        None,
+        "get_u8({:#x}) or get_u16({:#x}) faulted inside the device",
+        len - 1,
+        len - 2
+    );
+
+    let read = dev.get_u16(len - 1);
+    assert_eq!(
+        read,
+        0xffff,
+        "get_u16({:#x}) past the end returned {:#x}, not 0xffff",
+        len - 1,
+        read
+    );
+    let fault = dev.take_fault();
+    assert_eq!(
+        fault,
+        Some((len - 1) as u16),
+        "get_u16({:#x}) past the end reported fault {:x?}",
+        len - 1,
+        fault
+    );
+    assert_eq!(dev.take_fault(), None, "take_fault() kept the fault");
+}
+
+// What the CPU relies on from any readable and writable device of `len` bytes, on top of the
+// bounds above. It overwrites the first, middle and last few bytes.
+pub fn assert_device_contract(dev: &mut dyn Device, len: usize) {
+    assert!(len >= 4, "contract needs a device of at least 4 bytes");
+    assert_device_bounds(dev, len);
+
+    let bytes = [0, 1, len / 2, len - 2, len - 1];
+    for (i, &address) in bytes.iter().enumerate() {
+        let value = 0xa0 + i as u8;
+        dev.set_u8(address, value);
+        let read = dev.get_u8(address);
+        assert_eq!(
+            read, value,
+            "set_u8({:#x}, {:#x}) then get_u8 returned {:#x}",
+            address, value, read
+        );
+    }
+
+    let words = [0, len / 2, len - 2];
+    for &address in words.iter() {
+        let before = if address > 0 {
+            Some(dev.get_u8(address - 1))
+        } else {
+            None
+        };
+        let after = if address + 2 < len {
+            Some(dev.get_u8(address + 2))
+        } else {
+            None
+        };
+
+        dev.set_u16(address, 0x1234);
+        let read = dev.get_u16(address);
+        assert_eq!(
+            read, 0x1234,
+            "set_u16({:#x}, 0x1234) then get_u16 returned {:#x}",
+            address, read
+        );
+        let (high, low) = (dev.get_u8(address), dev.get_u8(address + 1));
+        assert_eq!(
+            (high, low),
+            (0x12, 0x34),
+            "set_u16({:#x}, 0x1234) stored bytes {:#x} {:#x}, words are big endian",
+            address,
+            high,
+            low
+        );
+        if let Some(before) = before {
+            let read = dev.get_u8(address - 1);
+            assert_eq!(
+                read,
+                before,
+                "set_u16({:#x}) changed the byte at {:#x}",
+                address,
+                address - 1
+            );
+        }
+        if let Some(after) = after {
+            let read = dev.get_u8(address + 2);
+            assert_eq!(
+                read,
+                after,
+                "set_u16({:#x}) changed the byte at {:#x}",
+                address,
+                address + 2
+            );
+        }
+
+        dev.set_u8(address, 0xab);
+        dev.set_u8(address + 1, 0xcd);
+        let read = dev.get_u16(address);
+        assert_eq!(
+            read, 0xabcd,
+            "bytes 0xab 0xcd at {:#x} read back as word {:#x}, words are big endian",
+            address, read
+        );
+    }
+
+    // Devices are only ever used with MB 0 unless the program switches banks
+    dev.set_mb(0);
+    for &address in words.iter() {
+        let read = dev.get_u16(address);
+        assert_eq!(
+            read, 0xabcd,
+            "set_mb(0) changed the word at {:#x} to {:#x}",
+            address, read
+        );
+    }
+    assert_eq!(dev.len(), len, "len() changed after writes");
+    let fault = dev.take_fault();
+    assert_eq!(
+        fault, None,
+        "accesses inside the device reported fault {:x?}",
+        fault
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "devices-terminal")]
+    use std::io;
+
+    use super::{assert_device_bounds, assert_device_contract, EchoDevice};
+    use crate::device::banked_memory::BankedMemory;
+    #[cfg(feature = "devices-terminal")]
+    use crate::device::console::Console;
+    use crate::device::interrupt_controller::InterruptController;
+    #[cfg(feature = "devices-terminal")]
+    use crate::device::keyboard::Keyboard;
+    #[cfg(feature = "devices-terminal")]
+    use crate::device::line_input::LineInput;
+    use crate::device::memory::Memory;
+    use crate::device::memory_mapper::MemoryMapper;
+    use crate::device::null::Null;
+    use crate::device::rng::Rng;
+    use crate::device::rom::Rom;
+    #[cfg(feature = "devices-terminal")]
+    use crate::device::screen::Screen;
+    use crate::device::test_harness::TestHarness;
+    use crate::device::Device;
+
+    #[test]
+    fn devices() {
+        assert_device_contract(&mut EchoDevice::new(16), 16);
+        assert_device_contract(&mut Memory::new(0x100), 0x100);
+        assert_device_contract(&mut BankedMemory::new(4, 0x40), 0x40);
+
+        // The mapper's len is 0xffff, so the last region ends a byte early for a word read at
+        // 0xfffe to run past it
+        let mut mapper = MemoryMapper::new();
+        mapper
+            .map(Box::new(Memory::new(0xff00)), 0x0000, 0xfeff, true)
+            .unwrap();
+        mapper
+            .map(Box::new(BankedMemory::new(2, 0xff)), 0xff00, 0xfffe, true)
+            .unwrap();
+        assert_device_contract(&mut mapper, 0xffff);
+    }
+
+    #[test]
+    fn read_only_and_side_effect_devices() {
+        assert_device_bounds(&mut Rom::from_bytes(&[1, 2, 3]), 3);
+        assert_device_bounds(&mut Null::new(0x10), 0x10);
+        assert_device_bounds(&mut BankedMemory::new(2, 0x10).info(), 4);
+        assert_device_bounds(&mut InterruptController::new(), 6);
+        assert_device_bounds(&mut Rng::default(), 2);
+        assert_device_bounds(&mut TestHarness::new().0, 6);
+    }
+
+    #[test]
+    #[cfg(feature = "devices-terminal")]
+    fn terminal_devices() {
+        assert_device_bounds(&mut Screen::headless(8, 2), 16);
+        assert_device_bounds(&mut Console::new(io::sink()), 2);
+        assert_device_bounds(&mut Keyboard::with_queue(vec![b'a']), 2);
+        assert_device_contract(&mut LineInput::new(Box::new(io::empty())), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "stored bytes 0x34 0x12, words are big endian")]
+    fn little_endian_device() {
+        struct LittleEndian(EchoDevice);
+        impl Device for LittleEndian {
+            fn get_u16(&self, address: usize) -> u16 {
+                self.0.get_u16(address).swap_bytes()
+            }
+            fn get_u8(&self, address: usize) -> u8 {
+                self.0.get_u8(address)
+            }
+            fn set_u16(&mut self, address: usize, value: u16) {
+                self.0.set_u16(address, value.swap_bytes())
+            }
+            fn set_u8(&mut self, address: usize, value: u8) {
+                self.0.set_u8(address, value)
+            }
+            fn len(&self) -> usize {
+                self.0.len()
+            }
+            fn set_mb(&mut self, _: u16) {}
+            fn take_fault(&mut self) -> Option<u16> {
+                self.0.take_fault()
+            }
+        }
+        assert_device_contract(&mut LittleEndian(EchoDevice::new(8)), 8);
+    }
+}
@@ -0,0 +1,166 @@
+// Turns code back into assembler syntax, one row per instruction like a listing:
+//   0004  37 04                    dec R1
+// A byte that doesn't start a known instruction, or starts one that is cut off by the end of the
+// code or has an invalid register byte, is shown as `db 0xNN` and decoding goes on after it.
+// Data in the code, like pools, is decoded like any other bytes.
+use crate::container::DebugInfo;
+use crate::cpu::instruction::{self, OperandKind};
+use crate::cpu::register;
+
+// The instruction at `address` in assembler syntax and its size
+pub fn decode(code: &[u8], address: usize) -> Option<(String, usize)> {
+    let opcode = *code.get(address)?;
+    let instruction = instruction::LIST
+        .iter()
+        .find(|instruction| instruction.opcode == opcode)?;
+    let bytes = code.get(address..address + instruction.size as usize)?;
+    let mut res = instruction.mnemonic.to_string();
+    let mut offset = 1;
+    for kind in instruction.format.operands() {
+        let word = || u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let (operand, size) = match kind {
+            OperandKind::Literal => (format!("${:x}", word()), 2),
+            OperandKind::Literal8 => (format!("${:x}", bytes[offset]), 1),
+            OperandKind::Address => (format!("&{:x}", word()), 2),
+            OperandKind::Register => (register_name(bytes[offset])?.to_string(), 1),
+            OperandKind::RegisterIndirect => (format!("&{}", register_name(bytes[offset])?), 1),
+            OperandKind::FrameOffset => match word() {
+                0 => (String::from("&[FP]"), 2),
+                offset if offset >= 0x8000 => (format!("&[FP - ${:x}]", offset.wrapping_neg()), 2),
+                offset => (format!("&[FP + ${:x}]", offset), 2),
+            },
+        };
+        res.push(' ');
+        res.push_str(&operand);
+        offset += size;
+    }
+    Some((res, instruction.size as usize))
+}
+
+fn register_name(byte: u8) -> Option<&'static str> {
+    register::from_byte(byte).map(register::name)
+}
+
+// Labels from the debug info get a row of their own before the instruction they point at
+pub fn disassemble(code: &[u8], debug: Option<&DebugInfo>) -> String {
+    let mut res = String::new();
+    let mut address = 0;
+    while address < code.len() {
+        if let Some(debug) = debug {
+            for (name, _) in debug
+                .symbols
+                .iter()
+                .filter(|(_, label)| *label as usize == address)
+            {
+                res.push_str(&format!("{}:\n", name));
+            }
+        }
+        let (text, size) =
+            decode(code, address).unwrap_or_else(|| (format!("db {:#04x}", code[address]), 1));
+        let bytes: Vec<String> = code[address..address + size]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        res.push_str(&format!(
+            "{:04x}  {:<24} {}\n",
+            address,
+            bytes.join(" "),
+            text
+        ));
+        address += size;
+    }
+    res
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::disassemble;
+    use crate::assembler;
+
+    #[test]
+    fn rows() {
+        let code = "start:\nmov $a R1\nloop:\ndec R1\nmov R1 ACC\njne $0 &[!loop]\n\
+                    mov &R1 R2\nlsf R1 $3\nmov $2 &fe00\nhlt\n";
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        assert_eq!(
+            disassemble(&assembly.bytes, Some(&assembly.debug_info("loop.asm"))),
+            "start:\n\
+             0000  10 00 0a 02              mov $a R1\n\
+             loop:\n\
+             0004  37 02                    dec R1\n\
+             0006  11 02 01                 mov R1 ACC\n\
+             0009  50 00 00 00 04           jne $0 &4\n\
+             000e  1c 02 03                 mov &R1 R2\n\
+             0011  40 02 03                 lsf R1 $3\n\
+             0014  09 00 02 fe 00           mov $2 &fe00\n\
+             0019  ff                       hlt\n"
+        );
+    }
+
+    #[test]
+    fn unknown_bytes() {
+        // An unknown opcode, a register byte that isn't a register and an instruction cut off
+        // by the end of the code
+        assert_eq!(
+            disassemble(&[0xe5, 0x37, 0x17, 0xff, 0x10, 0x00], None),
+            "0000  e5                       db 0xe5\n\
+             0001  37                       db 0x37\n\
+             0002  17                       db 0x17\n\
+             0003  ff                       hlt\n\
+             0004  10                       db 0x10\n\
+             0005  00                       db 0x00\n"
+        );
+    }
+
+    #[test]
+    fn frame_offsets() {
+        let code = "mov &[FP + $4] R1\nmov R1 &[FP - $2]\nmov &[FP] R2\nmov R2 &[FP - $8000]\n";
+        let bytes = assembler::compile(code, &assembler::Options::default()).unwrap();
+        assert_eq!(
+            disassemble(&bytes, None),
+            "0000  1f 00 04 02              mov &[FP + $4] R1\n\
+             0004  20 02 ff fe              mov R1 &[FP - $2]\n\
+             0008  1f 00 00 03              mov &[FP] R2\n\
+             000c  20 03 80 00              mov R2 &[FP - $8000]\n"
+        );
+    }
+
+    // Every decoded row assembles back into its own bytes
+    #[test]
+    fn round_trip() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/asm");
+        let mut programs = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "asm") {
+                continue;
+            }
+            let code = fs::read_to_string(&path).unwrap();
+            let bytes = assembler::compile(&code, &assembler::Options::default()).unwrap();
+            for row in disassemble(&bytes, None).lines() {
+                let (bytes, text) = row[6..].split_at(24);
+                let text = text.trim();
+                if text.starts_with("db ") {
+                    continue;
+                }
+                let expected: Vec<u8> = bytes
+                    .split_whitespace()
+                    .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+                    .collect();
+                assert_eq!(
+                    assembler::compile(&format!("{}\n", text), &assembler::Options::default())
+                        .unwrap(),
+                    expected,
+                    "{}: {}",
+                    path.display(),
+                    row
+                );
+            }
+            programs += 1;
+        }
+        assert_eq!(programs, 4);
+    }
+}
@@ -0,0 +1,117 @@
+// One error type for everything the library can fail at, so embedding code can use `?` across
+// assembling, building a machine, loading files and running. The assembler and the CPU keep
+// their own error types, which carry positions and fault details, and convert into this one.
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[cfg(feature = "assembler")]
+use crate::assembler::Diagnostics;
+use crate::cpu::fault::CpuError;
+
+#[derive(Debug)]
+pub enum VmError {
+    Io(io::Error),
+    // Source that does not assemble
+    #[cfg(feature = "assembler")]
+    Parse(Diagnostics),
+    // A fault the guest did not handle
+    Fault(CpuError),
+    // Input the host rejects: memory maps, map files, containers, snapshots and command lines
+    Config(String),
+    // A device that cannot be set up or attached
+    Device(String),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Io(error) => write!(f, "{}", error),
+            #[cfg(feature = "assembler")]
+            VmError::Parse(diagnostics) => write!(f, "{}", diagnostics),
+            VmError::Fault(fault) => write!(f, "{}", fault),
+            VmError::Config(message) | VmError::Device(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(error: io::Error) -> VmError {
+        VmError::Io(error)
+    }
+}
+
+#[cfg(feature = "assembler")]
+impl From<Diagnostics> for VmError {
+    fn from(diagnostics: Diagnostics) -> VmError {
+        VmError::Parse(diagnostics)
+    }
+}
+
+impl From<CpuError> for VmError {
+    fn from(fault: CpuError) -> VmError {
+        VmError::Fault(fault)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VmError;
+    #[cfg(feature = "assembler")]
+    use crate::assembler::{self, Options};
+    use crate::cpu::fault::CpuError;
+    use std::error::Error;
+    use std::io;
+
+    #[test]
+    fn io() {
+        let error = VmError::from(io::Error::new(
+            io::ErrorKind::NotFound,
+            "prog.bin is missing",
+        ));
+        assert_eq!(error.to_string(), "prog.bin is missing");
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn parse() {
+        let error = VmError::from(assembler::compile("hlt R1\n", &Options::default()).unwrap_err());
+        assert_eq!(
+            error.to_string(),
+            "line 1, column 5: Unexpected trailing characters: 'R1'\n    hlt R1\n        ^"
+        );
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn fault() {
+        let error = VmError::from(CpuError::IllegalOpcode {
+            opcode: 0xee,
+            ip: 0x10,
+        });
+        assert_eq!(error.to_string(), "Illegal opcode 0xee at 0x0010");
+        let error = VmError::from(CpuError::MemoryFault {
+            address: 0x2000,
+            ip: 0x10,
+        });
+        assert_eq!(error.to_string(), "Memory fault at 0x2000 (IP 0x0010)");
+    }
+
+    #[test]
+    fn config_and_device() {
+        let error = VmError::Config("line 1: rom needs a file".to_string());
+        assert_eq!(error.to_string(), "line 1: rom needs a file");
+        let error = VmError::Device("Could not open the entropy source".to_string());
+        assert_eq!(error.to_string(), "Could not open the entropy source");
+    }
+}
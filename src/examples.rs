@@ -0,0 +1,201 @@
+// Assembles every program in examples/asm, runs it on the default machine with a headless screen
+// and checks the expectations in the `.expect` file next to it. One expectation per line, `#`
+// starts a comment:
+//   steps <n>               - step limit, the program must halt within it (default 100000)
+//   acc <value>             - final ACC
+//   reg <register> <value>  - final value of a register
+//   word <address> <value>  - word in memory, the address is an expression like `!label`
+//   screen <row> [text]     - screen row, blank and unprintable cells trimmed from both ends
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler;
+use crate::cpu::{register, CPU};
+use crate::device::banked_memory::BankedMemory;
+use crate::device::memory::Memory;
+use crate::device::screen::Screen;
+use crate::machine::Builder;
+
+const SCREEN: usize = 0xfe00;
+const WIDTH: usize = 16;
+const DEFAULT_STEPS: usize = 100_000;
+
+fn machine(bytes: &[u8]) -> CPU {
+    let memory = Memory::with_contents(0xff00, bytes);
+    Builder::new()
+        .map(Box::new(memory), 0x0000, SCREEN - 1, true)
+        .unwrap()
+        .screen(Screen::headless(WIDTH, 16), SCREEN, SCREEN + WIDTH * 16 - 1)
+        .unwrap()
+        .map(Box::new(BankedMemory::new(8, 256)), 0xff00, 0xffff, true)
+        .unwrap()
+        .build()
+}
+
+fn value(text: &str) -> Result<u16, String> {
+    u16::from_str_radix(text.trim_start_matches('$'), 16)
+        .map_err(|_| format!("Invalid value {}", text))
+}
+
+fn screen_row(cpu: &CPU, row: usize) -> String {
+    let text: String = (0..WIDTH)
+        .map(|x| match cpu.memory().get_u8(SCREEN + row * WIDTH + x) {
+            c if c.is_ascii_graphic() => c as char,
+            _ => ' ',
+        })
+        .collect();
+    text.trim().to_string()
+}
+
+// Every expectation that does not hold, empty if the example passed
+fn check(source: &str, expect: &str) -> Result<Vec<String>, String> {
+    let assembly = assembler::assemble(source, &assembler::Options::default())
+        .map_err(|diagnostics| diagnostics.to_string())?;
+    let symbols: BTreeMap<String, u16> = assembly.symbols.iter().cloned().collect();
+    let lines: Vec<(usize, Vec<&str>)> = expect
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(index, line)| (index, line.splitn(3, ' ').collect()))
+        .collect();
+
+    let mut steps = DEFAULT_STEPS;
+    for (line, words) in &lines {
+        if words[0] == "steps" {
+            steps = words
+                .get(1)
+                .and_then(|steps| steps.parse().ok())
+                .ok_or_else(|| format!("line {}: Expected a step count", line))?;
+        }
+    }
+
+    let mut cpu = machine(&assembly.bytes);
+    let mut halted = false;
+    for _ in 0..steps {
+        match cpu.step() {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                halted = true;
+                break;
+            }
+            Err(error) => return Ok(vec![cpu.fault_message(&error)]),
+        }
+    }
+    if !halted {
+        return Ok(vec![format!("Did not halt within {} steps", steps)]);
+    }
+
+    let mut failures = vec![];
+    for (line, words) in &lines {
+        let (what, actual, expected) = match words.as_slice() {
+            ["steps", ..] => continue,
+            ["acc", expected] => (
+                "ACC".to_string(),
+                format!("{:#06x}", cpu.get_register(register::ACC)),
+                format!("{:#06x}", value(expected)?),
+            ),
+            ["reg", name, expected] => {
+                let reg = register::LIST
+                    .iter()
+                    .find(|&&reg| register::name(reg) == *name)
+                    .ok_or_else(|| format!("line {}: Unknown register {}", line, name))?;
+                (
+                    name.to_string(),
+                    format!("{:#06x}", cpu.get_register(*reg)),
+                    format!("{:#06x}", value(expected)?),
+                )
+            }
+            ["word", address, expected] => {
+                let address = assembler::evaluate_expression(address, &symbols)?;
+                (
+                    format!("word at {:#06x}", address),
+                    format!("{:#06x}", cpu.memory().get_u16(address as usize)),
+                    format!("{:#06x}", value(expected)?),
+                )
+            }
+            ["screen", row, rest @ ..] => {
+                let row: usize = row
+                    .parse()
+                    .map_err(|_| format!("line {}: Invalid row {}", line, row))?;
+                (
+                    format!("screen row {}", row),
+                    format!("{:?}", screen_row(&cpu, row)),
+                    format!("{:?}", rest.first().map_or("", |text| text.trim())),
+                )
+            }
+            _ => {
+                return Err(format!(
+                    "line {}: Unknown expectation {}",
+                    line,
+                    words.join(" ")
+                ))
+            }
+        };
+        if actual != expected {
+            failures.push(format!(
+                "line {}: {} is {}, expected {}",
+                line, what, actual, expected
+            ));
+        }
+    }
+    Ok(failures)
+}
+
+fn run_example(path: &Path) -> Vec<String> {
+    let expect_path = path.with_extension("expect");
+    let result = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|source| {
+            let expect = fs::read_to_string(&expect_path)
+                .map_err(|e| format!("{}: {}", expect_path.display(), e))?;
+            check(&source, &expect)
+        });
+    match result {
+        Ok(failures) => failures,
+        Err(message) => vec![message],
+    }
+}
+
+#[test]
+fn examples() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/asm");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "asm"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "No examples in {}", dir.display());
+
+    let failures: Vec<String> = paths
+        .iter()
+        .flat_map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            run_example(path)
+                .into_iter()
+                .map(move |failure| format!("{}: {}", name, failure))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn reports_mismatches() {
+    let source = "mov $2 R1\nmov $58 &fe12\nhlt\n";
+    assert_eq!(
+        check(
+            source,
+            "acc $0\nreg R1 $3\nword $0800 $0 # untouched\nscreen 1 Y\n"
+        ),
+        Ok(vec![
+            "line 2: R1 is 0x0002, expected 0x0003".to_string(),
+            "line 4: screen row 1 is \"X\", expected \"Y\"".to_string(),
+        ])
+    );
+    assert_eq!(
+        check("loop:\njne $1 &[!loop]\nhlt\n", "steps 10\n"),
+        Ok(vec!["Did not halt within 10 steps".to_string()])
+    );
+}
@@ -0,0 +1,94 @@
+// Runs the programs in tests/golden headless and compares the final screen, or the final state
+// report, with the checked in text next to them. Run with UPDATE_GOLDEN=1 to write the text
+// instead.
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::assembler;
+use crate::cpu::CPU;
+use crate::device::memory::Memory;
+use crate::device::screen::Screen;
+use crate::inspect;
+use crate::machine::Builder;
+
+const SCREEN: usize = 0xfe00;
+
+fn machine(bytes: &[u8]) -> CPU {
+    let memory = Memory::with_contents(0xfe00, bytes);
+    let mut cpu = Builder::new()
+        .map(Box::new(memory), 0x0000, SCREEN - 1, true)
+        .unwrap()
+        .screen(Screen::headless(16, 16), SCREEN, SCREEN + 255)
+        .unwrap()
+        .build();
+    cpu.run().unwrap();
+    cpu
+}
+
+// One line per row, empty cells as '.'
+fn frame(code: &str) -> String {
+    let cpu = machine(&assembler::compile(code, &assembler::Options::default()).unwrap());
+    let (width, height) = (16, 16);
+    let mut res = String::new();
+    for y in 0..height {
+        for x in 0..width {
+            res.push(match cpu.memory().get_u8(SCREEN + y * width + x) {
+                c if c.is_ascii_graphic() => c as char,
+                b' ' => ' ',
+                _ => '.',
+            });
+        }
+        res.push('\n');
+    }
+    res
+}
+
+fn state_report(code: &str) -> String {
+    let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+    inspect::report(&machine(&assembly.bytes), &assembly.symbols)
+}
+
+fn assert_golden(name: &str, render: fn(&str) -> String) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let code = fs::read_to_string(dir.join(format!("{}.asm", name))).unwrap();
+    let actual = render(&code);
+    let path = dir.join(format!("{}.txt", name));
+    if env::var("UPDATE_GOLDEN").as_deref() == Ok("1") {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_default();
+    let rows: Vec<String> = expected
+        .lines()
+        .chain(std::iter::repeat(""))
+        .zip(actual.lines())
+        .enumerate()
+        .filter(|(_, (expected, actual))| expected != actual)
+        .map(|(row, (expected, actual))| format!("row {}:\n-{}\n+{}", row, expected, actual))
+        .collect();
+    if !rows.is_empty() || expected.lines().count() != actual.lines().count() {
+        panic!(
+            "Output of {} differs from {}, rerun with UPDATE_GOLDEN=1 if this is expected\n{}",
+            name,
+            path.display(),
+            rows.join("\n")
+        );
+    }
+}
+
+#[test]
+fn banner() {
+    assert_golden("banner", frame);
+}
+
+#[test]
+fn animation() {
+    assert_golden("animation", frame);
+}
+
+#[test]
+fn report() {
+    assert_golden("report", state_report);
+}
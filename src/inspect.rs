@@ -0,0 +1,130 @@
+// Plain text views of the machine state shared by the debugger, crash dumps and reports
+use crate::container::DebugInfo;
+use crate::cpu::fault::CpuError;
+use crate::cpu::instruction;
+use crate::cpu::register;
+use crate::cpu::CPU;
+use crate::device::Device;
+
+// Final state printed by `run --report` and the debugger's `quit report`: the registers, the
+// instruction count, the routine holding the last instruction and the top 16 stack words
+pub fn report(cpu: &CPU, symbols: &[(String, u16)]) -> String {
+    let ip = cpu.get_register(register::IP);
+    let mut res = format!(
+        "Registers:\n{}\nInstructions: {}\n",
+        registers(cpu),
+        cpu.instructions()
+    );
+    // IP is already past the last instruction
+    if let Some(routine) = routine(symbols, ip.saturating_sub(1)) {
+        res.push_str(&format!("Routine: {}\n", routine));
+    }
+    res.push_str("Stack:\n");
+    res.push_str(&stack(cpu));
+    res
+}
+
+// The closest symbol at or before the address, with the distance from it
+pub fn routine(symbols: &[(String, u16)], address: u16) -> Option<String> {
+    symbols
+        .iter()
+        .filter(|(_, start)| *start <= address)
+        .max_by_key(|(_, start)| *start)
+        .map(|(name, start)| match address - start {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+}
+
+// 16 words from SP towards the top of memory
+fn stack(cpu: &CPU) -> String {
+    let sp = cpu.get_register(register::SP) as usize;
+    let fp = cpu.get_register(register::FP) as usize;
+    let end = cpu.memory().len().saturating_sub(1);
+    (sp..end)
+        .step_by(2)
+        .take(16)
+        .map(|address| {
+            let mut row = format!("{:#06x}: {:#06x}", address, cpu.memory().get_u16(address));
+            if address == sp {
+                row.push_str(" <- SP");
+            }
+            if address == fp {
+                row.push_str(" <- FP");
+            }
+            row
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// The instructions remembered by `CPU::set_history`, oldest first, decoded from their opcodes
+pub fn history(cpu: &CPU, debug: Option<&DebugInfo>) -> String {
+    cpu.history()
+        .iter()
+        .map(|&(ip, opcode)| {
+            let syntax = instruction::LIST
+                .iter()
+                .find(|instruction| instruction.opcode == opcode)
+                .map_or("???".to_string(), |instruction| instruction.syntax());
+            let mut row = format!("{:#06x}: {:02x} {}", ip, opcode, syntax);
+            if let Some(location) = debug.and_then(|debug| debug.location(ip)) {
+                row.push_str(&format!(" ({})", location));
+            }
+            row
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// The fault as `CPU::fault_message` explains it, followed by the source line of the faulting
+// instruction when there is debug info
+pub fn fault(cpu: &CPU, error: &CpuError, debug: Option<&DebugInfo>) -> String {
+    let message = cpu.fault_message(error);
+    match debug.and_then(|debug| debug.location(error.info().ip)) {
+        Some(location) => format!("{} ({})", message, location),
+        None => message,
+    }
+}
+
+// Every register on one line, like `IP=0004 ACC=0000 R1=0003 ...`
+pub fn register_row(cpu: &CPU) -> String {
+    register::LIST
+        .iter()
+        .map(|&reg| format!("{}={:04x}", register::name(reg), cpu.get_register(reg)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn registers(cpu: &CPU) -> String {
+    register::LIST
+        .iter()
+        .map(|&reg| format!("{}: {:#06x}", register::name(reg), cpu.get_register(reg)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Eight bytes per row, each row prefixed with its address
+pub fn hexdump(memory: &dyn Device, address: u16, length: u16) -> String {
+    let end = (address as usize + length as usize).min(memory.len());
+    rows(address as usize, end, |a| memory.get_u8(a))
+}
+
+// Same layout for bytes outside a device, like a snapshot
+pub fn hexdump_bytes(bytes: &[u8], address: u16, length: u16) -> String {
+    let end = (address as usize + length as usize).min(bytes.len());
+    rows(address as usize, end, |a| bytes[a])
+}
+
+fn rows(address: usize, end: usize, byte: impl Fn(usize) -> u8) -> String {
+    (address..end)
+        .step_by(8)
+        .map(|row| {
+            let bytes: Vec<String> = (row..(row + 8).min(end))
+                .map(|a| format!("{:02x}", byte(a)))
+                .collect();
+            format!("{:#06x}: {}", row, bytes.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
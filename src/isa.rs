@@ -0,0 +1,130 @@
+// Instruction set reference generated from cpu::instruction::LIST for `vm isa`
+use crate::cpu::instruction::{self, Instruction};
+
+pub fn text() -> String {
+    rows()
+        .map(|i| {
+            format!(
+                "{:#04x}  {:<20} size {}  cycles {}  {}\n",
+                i.opcode,
+                i.syntax(),
+                i.size,
+                i.cycles,
+                i.description
+            )
+        })
+        .collect()
+}
+
+pub fn markdown() -> String {
+    let mut res =
+        String::from("| Opcode | Syntax | Size | Cycles | Description |\n|---|---|---|---|---|\n");
+    for i in rows() {
+        res.push_str(&format!(
+            "| `{:#04x}` | `{}` | {} | {} | {} |\n",
+            i.opcode,
+            i.syntax(),
+            i.size,
+            i.cycles,
+            i.description.replace('|', "\\|")
+        ));
+    }
+    res
+}
+
+// One object per instruction, for editors and highlighters
+pub fn json() -> String {
+    let objects: Vec<String> = rows()
+        .map(|i| {
+            format!(
+                "  {{\"mnemonic\": {}, \"syntax\": {}, \"opcode\": \"{:#04x}\", \"size\": {}, \"cycles\": {}, \"description\": {}}}",
+                json_string(i.mnemonic),
+                json_string(&i.syntax()),
+                i.opcode,
+                i.size,
+                i.cycles,
+                json_string(i.description)
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+// In opcode order
+fn rows() -> impl Iterator<Item = &'static Instruction> {
+    let mut list: Vec<&Instruction> = instruction::LIST.iter().collect();
+    list.sort_by_key(|i| i.opcode);
+    list.into_iter()
+}
+
+// A JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "assembler")]
+    use crate::assembler;
+    use crate::cpu::instruction;
+
+    // Every `Instruction::new(` in the source, not just the ones in LIST
+    fn declared_opcodes() -> Vec<String> {
+        include_str!("cpu/instruction.rs")
+            .split("Instruction::new(")
+            .skip(1)
+            .map(|rest| rest.split(',').nth(1).unwrap().trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn every_opcode_once() {
+        let opcodes = declared_opcodes();
+        assert_eq!(opcodes.len(), instruction::LIST.len());
+        for output in [super::text(), super::markdown(), super::json()].iter() {
+            for opcode in &opcodes {
+                let opcode = format!("{:#04x}", u8::from_str_radix(&opcode[2..], 16).unwrap());
+                assert_eq!(
+                    output.matches(&opcode).count(),
+                    1,
+                    "{} in\n{}",
+                    opcode,
+                    output
+                );
+            }
+        }
+        assert!(super::json().contains(
+            "{\"mnemonic\": \"mov\", \"syntax\": \"mov $lit reg\", \"opcode\": \"0x10\", \
+             \"size\": 4, \"cycles\": 4, \"description\": \"Load $lit into reg\"}"
+        ));
+        assert!(super::markdown().contains("| `0x47` | `or reg reg` | 3 | 3 | ACC = reg \\| reg |"));
+    }
+
+    #[test]
+    #[cfg(feature = "assembler")]
+    fn templates_assemble() {
+        for i in instruction::LIST.iter() {
+            let line = i
+                .syntax()
+                .replace("$lit8", "$3")
+                .replace("$lit", "$12")
+                .replace("&reg", "&R2")
+                .replace("reg", "R1")
+                .replace("&addr", "&800");
+            let bytes =
+                assembler::compile(&(line.clone() + "\n"), &assembler::Options::default()).unwrap();
+            assert_eq!(bytes[0], i.opcode, "{}", line);
+            assert_eq!(bytes.len(), i.size as usize, "{}", line);
+        }
+    }
+}
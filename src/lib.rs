@@ -0,0 +1,35 @@
+// The VM as a library: assemble programs, build machines out of devices and run them. The `vm`
+// binary is a command line front end over these modules.
+pub mod analyze;
+#[cfg(feature = "assembler")]
+pub mod assembler;
+pub mod checksum;
+pub mod container;
+pub mod cpu;
+pub mod crash_dump;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+pub mod device;
+pub mod disassembler;
+pub mod error;
+#[cfg(all(test, feature = "assembler", feature = "devices-terminal"))]
+mod examples;
+#[cfg(all(test, feature = "assembler", feature = "devices-terminal"))]
+mod golden;
+pub mod inspect;
+pub mod isa;
+pub mod machine;
+#[allow(dead_code)]
+mod parser_combinator;
+#[cfg(feature = "assembler")]
+pub mod patch;
+pub mod selftest;
+pub mod sigint;
+#[cfg(feature = "snapshot-serde")]
+pub mod snapshot;
+pub mod trace;
+
+#[cfg(feature = "assembler")]
+pub use assembler::compile;
+pub use cpu::{instruction, register, CPU};
+pub use error::VmError;
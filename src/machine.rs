@@ -0,0 +1,508 @@
+use crate::container;
+use crate::cpu::{CPU, RESET_VECTOR_ADDRESS};
+#[cfg(feature = "devices-terminal")]
+use crate::device::banked_memory::BankedMemory;
+#[cfg(feature = "devices-terminal")]
+use crate::device::memory::Memory;
+use crate::device::memory_mapper::{DeviceCapability, MemoryMapper};
+use crate::device::port_bus::PortBus;
+use crate::device::rom::Rom;
+#[cfg(feature = "devices-terminal")]
+use crate::device::screen::Screen;
+use crate::device::Device;
+use crate::error::VmError;
+
+#[cfg(feature = "devices-terminal")]
+pub mod config;
+
+// Layout of guest memory as programs can rely on it:
+//   0x0000         the program image, unless it is loaded elsewhere with `vm run --at`
+//   0x1000-0x1017  interrupt vectors, the fault vector and the fault info, see `cpu::fault`
+//   0x1018         the heap start word, filled in by the loader
+//   0x101a         the reset vector, where a warm reset starts, see `CPU::warm_reset`
+//   heap start     free memory up to the stack, which grows down from the top of memory
+//   0xfc00         the program's metadata with `vm run --meta`, see `Builder::meta`
+// The system area up to 0x101f is never part of the heap, an image running over it gets it
+// overwritten.
+pub const HEAP_START_ADDRESS: usize = 0x1018;
+pub const SYSTEM_AREA_END: usize = 0x1020;
+// Bytes of RAM below the screen of the standard machine
+pub const STANDARD_CODE_SIZE: usize = 0xfe00;
+// The metadata block ends before the devices `vm run` maps from 0xfde0
+pub const META_ADDRESS: usize = 0xfc00;
+pub const META_END: usize = 0xfde0;
+
+// The first free byte after an image of `len` bytes and the system area
+pub fn heap_start(len: usize) -> u16 {
+    len.max(SYSTEM_AREA_END) as u16
+}
+
+// Copies the program to address 0 of `memory` and fills in the heap start word. The reset vector
+// is set to the entry point, which is 0 for every program, until the program sets its own.
+pub fn load_image(memory: &mut dyn Device, program: &[u8]) {
+    load_image_at(memory, program, 0)
+}
+
+// Like `load_image` with the program and its entry point at `at`. The heap starts after it.
+pub fn load_image_at(memory: &mut dyn Device, program: &[u8], at: usize) {
+    for (i, &byte) in program.iter().enumerate() {
+        memory.set_u8(at + i, byte);
+    }
+    memory.set_u16(HEAP_START_ADDRESS, heap_start(at + program.len()));
+    memory.set_u16(RESET_VECTOR_ADDRESS, at as u16);
+}
+
+// Assembles the devices of a machine into a memory map and a port bus and builds a CPU on top
+pub struct Builder {
+    mapper: MemoryMapper,
+    ports: PortBus,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            mapper: MemoryMapper::new(),
+            ports: PortBus::new(),
+        }
+    }
+
+    // The machine of `vm run`: RAM up to a 16x16 screen at 0xfe00 and banked memory at 0xff00,
+    // with the program loaded into RAM. The bank count and size words are at 0xfde6.
+    #[cfg(feature = "devices-terminal")]
+    pub fn standard(program: &[u8]) -> Result<Builder, VmError> {
+        Builder::standard_at(program, 0)
+    }
+
+    // The standard machine with the program loaded at `at`, the CPU has to start there
+    #[cfg(feature = "devices-terminal")]
+    pub fn standard_at(program: &[u8], at: usize) -> Result<Builder, VmError> {
+        if at + program.len() > STANDARD_CODE_SIZE {
+            return Err(VmError::Config(format!(
+                "Program of {} bytes at {:#06x} does not fit below the screen at {:#06x}",
+                program.len(),
+                at,
+                STANDARD_CODE_SIZE
+            )));
+        }
+        let mut memory = Memory::new(0xff00);
+        load_image_at(&mut memory, program, at);
+        let banked = BankedMemory::new(8, 256);
+        Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfdff, true)?
+            .map(Box::new(banked.info()), 0xfde6, 0xfde9, true)?
+            .screen(Screen::new(16, 16), 0xfe00, 0xfeff)?
+            .map(Box::new(banked), 0xff00, 0xffff, true)
+    }
+
+    pub fn map(
+        mut self,
+        device: Box<dyn Device>,
+        start: usize,
+        end: usize,
+        remap: bool,
+    ) -> Result<Builder, VmError> {
+        self.mapper
+            .map(device, start, end, remap)
+            .map_err(VmError::Config)?;
+        Ok(self)
+    }
+
+    // Like `map` with the guest's access limited to `capability`
+    pub fn map_with(
+        mut self,
+        device: Box<dyn Device>,
+        start: usize,
+        end: usize,
+        remap: bool,
+        capability: DeviceCapability,
+    ) -> Result<Builder, VmError> {
+        self.mapper
+            .map_with(device, start, end, remap, capability)
+            .map_err(VmError::Config)?;
+        Ok(self)
+    }
+
+    // Puts a device on ports `first..=last` instead of into memory, see `PortBus::register`
+    pub fn port(
+        mut self,
+        device: Box<dyn Device>,
+        first: u8,
+        last: u8,
+    ) -> Result<Builder, VmError> {
+        self.ports
+            .register(device, first, last)
+            .map_err(VmError::Config)?;
+        Ok(self)
+    }
+
+    // The window from `start` up to and including `end` must hold exactly one byte per screen cell
+    #[cfg(feature = "devices-terminal")]
+    pub fn screen(self, screen: Screen, start: usize, end: usize) -> Result<Builder, VmError> {
+        if end < start || end - start + 1 != screen.len() {
+            return Err(VmError::Config(format!(
+                "Screen window {:#06x}-{:#06x} does not match a {}x{} screen, expected {} bytes",
+                start,
+                end,
+                screen.width(),
+                screen.height(),
+                screen.len()
+            )));
+        }
+        self.map(Box::new(screen), start, end, true)
+    }
+
+    // Maps the `.meta` keys and values read-only at META_ADDRESS, laid out by
+    // `container::meta_block`. Programs without metadata get just the end of the block.
+    pub fn meta(self, meta: &[(String, Vec<u8>)]) -> Result<Builder, VmError> {
+        let block = container::meta_block(meta);
+        if block.len() > META_END - META_ADDRESS {
+            return Err(VmError::Config(format!(
+                "Metadata of {} bytes does not fit {:#06x}-{:#06x}",
+                block.len(),
+                META_ADDRESS,
+                META_END - 1
+            )));
+        }
+        let end = META_ADDRESS + block.len() - 1;
+        self.map(Box::new(Rom::from_bytes(&block)), META_ADDRESS, end, true)
+    }
+
+    // Counts memory traffic per region, see `MemoryMapper::traffic`
+    pub fn stats(mut self) -> Builder {
+        self.mapper.enable_stats();
+        self
+    }
+
+    // The memory map so far, see `MemoryMapper::describe`
+    pub fn describe(&self) -> String {
+        self.mapper.describe()
+    }
+
+    pub fn build(self) -> CPU {
+        let mut cpu = CPU::new(Box::new(self.mapper));
+        cpu.set_ports(self.ports);
+        cpu
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+#[cfg(all(test, feature = "assembler", feature = "devices-terminal"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::Builder;
+    use crate::assembler;
+    use crate::cpu::fault::CpuError;
+    use crate::cpu::register;
+    use crate::device::banked_memory::BankedMemory;
+    use crate::device::console::Console;
+    use crate::device::memory::Memory;
+    use crate::device::screen::Screen;
+
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn screen_window() {
+        assert!(Builder::new()
+            .map(Box::new(Memory::new(0xff00)), 0x0000, 0xfdff, true)
+            .unwrap()
+            .screen(Screen::new(16, 16), 0xfe00, 0xfeff)
+            .is_ok());
+        assert_eq!(
+            Builder::new()
+                .screen(Screen::new(16, 16), 0xfe00, 0xfe80)
+                .err()
+                .map(|error| error.to_string()),
+            Some(
+                "Screen window 0xfe00-0xfe80 does not match a 16x16 screen, expected 256 bytes"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn heap_start() {
+        let heap = |program: Vec<u8>| {
+            let mut cpu = Builder::standard(&program).unwrap().build();
+            cpu.run().unwrap();
+            cpu.get_register(register::R1)
+        };
+        let code =
+            assembler::compile("mov &1018 R1\nhlt\n", &assembler::Options::default()).unwrap();
+        assert_eq!(heap(code.clone()), 0x1020);
+        let mut large = code;
+        large.resize(0x1800, 0);
+        assert_eq!(heap(large.clone()), 0x1800);
+        large.resize(0x2345, 0);
+        assert_eq!(heap(large), 0x2345);
+
+        assert_eq!(
+            Builder::standard(&[0; 0xfe01])
+                .err()
+                .map(|error| error.to_string()),
+            Some(
+                "Program of 65025 bytes at 0x0000 does not fit below the screen at 0xfe00"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn standard_at() {
+        let code = assembler::compile(
+            "mov &1018 R1\nmov &101a R2\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let run = |at: usize| {
+            let mut cpu = Builder::standard_at(&code, at).unwrap().build();
+            cpu.set_register(register::IP, at as u16);
+            cpu.run().unwrap();
+            (
+                cpu.get_register(register::R1),
+                cpu.get_register(register::R2),
+            )
+        };
+        assert_eq!(run(0), (0x1020, 0));
+        assert_eq!(run(0x2000), (0x2000 + code.len() as u16, 0x2000));
+        // Up to the last byte below the screen
+        let at = 0xfe00 - code.len();
+        assert_eq!(run(at), (0xfe00, at as u16));
+
+        assert_eq!(
+            Builder::standard_at(&code, at + 1)
+                .err()
+                .map(|error| error.to_string()),
+            Some(format!(
+                "Program of {} bytes at {:#06x} does not fit below the screen at 0xfe00",
+                code.len(),
+                at + 1
+            ))
+        );
+        assert!(Builder::standard_at(&[0; 0xfe00], 0).is_ok());
+        assert!(Builder::standard_at(&[0; 0x11000], 0).is_err());
+    }
+
+    #[test]
+    fn word_past_region() {
+        // The screen's last cell, the bank size and the last byte of memory end their regions
+        for address in ["feff", "fde9", "ffff"].iter() {
+            let code = assembler::compile(
+                &format!("mov &{} R1\nhlt\n", address),
+                &assembler::Options::default(),
+            )
+            .unwrap();
+            let mut cpu = Builder::standard(&code).unwrap().build();
+            assert_eq!(
+                cpu.run(),
+                Err(CpuError::MemoryFault {
+                    address: u16::from_str_radix(address, 16).unwrap(),
+                    ip: 0
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn banks() {
+        // The guest finds the banks from the info words and selecting one past them faults
+        let code = assembler::compile(
+            "mov &fde6 R1\nmov &fde8 R2\nmov $7 MB\nmov $1234 &ff00\n\
+             mov $0 MB\nmov &ff00 R3\nmov R1 MB\nmov &ff10 R4\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mut cpu = Builder::standard(&code).unwrap().build();
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::MemoryFault {
+                address: 0xff10,
+                ip: 0x1c
+            })
+        );
+        assert_eq!(cpu.get_register(register::R1), 8);
+        assert_eq!(cpu.get_register(register::R2), 0x100);
+        assert_eq!(cpu.get_register(register::R3), 0);
+        cpu.set_register(register::MB, 7);
+        assert_eq!(cpu.memory().get_u16(0xff00), 0x1234);
+    }
+
+    #[test]
+    fn warm_reset() {
+        // A cold start sets the flag and the reset vector and reboots itself, the warm start
+        // finds the flag still set
+        let code = assembler::compile(
+            "mov &[!flag] R1\nmov R1 ACC\njne $0 &[!warm]\n\
+             mov $1 &[!flag]\nmov [!warm] &101a\nmov $55 R3\nreset\nhlt\n\
+             warm:\nmov &[!flag] R1\nmov $2 R2\nhlt\n\
+             flag:\n.word $0\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let machine = || {
+            let mut memory = Memory::new(0xff00);
+            super::load_image(&mut memory, &code);
+            Builder::new()
+                .map(Box::new(memory), 0x0000, 0xfdff, true)
+                .unwrap()
+                .screen(Screen::headless(16, 16), 0xfe00, 0xfeff)
+                .unwrap()
+                .build()
+        };
+        let mut cpu = machine();
+        let halt = cpu.run().unwrap();
+        assert_eq!(halt.ip, 0x24);
+        assert_eq!(cpu.get_register(register::R1), 0x1);
+        assert_eq!(cpu.get_register(register::R2), 0x2);
+        assert_eq!(cpu.get_register(register::R3), 0);
+        assert_eq!(cpu.instructions(), 10);
+
+        // The host can reboot it the same way, a fresh machine starts with the flag cleared and
+        // the vector at the entry point
+        cpu.warm_reset();
+        assert_eq!(cpu.get_register(register::IP), 0x1c);
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 0x1);
+        let mut cpu = machine();
+        cpu.warm_reset();
+        assert_eq!(cpu.get_register(register::IP), 0);
+        assert_eq!(cpu.memory().get_u16(0x24 + 1), 0);
+        cpu.run().unwrap();
+        assert_eq!(cpu.instructions(), 10);
+    }
+
+    #[test]
+    fn meta() {
+        // The guest skips the key by its length byte and finds the value after it
+        let code = assembler::compile(
+            "mov &fc00 R1\nrsf R1 $8\nadd $fc02 R1\nmov ACC R2\nmov &R2 R3\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mut memory = Memory::new(0xff00);
+        super::load_image(&mut memory, &code);
+        let mut cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfdff, true)
+            .unwrap()
+            .meta(&[("version".to_string(), b"1.2".to_vec())])
+            .unwrap()
+            .build();
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 7);
+        assert_eq!(cpu.get_register(register::R3), 0x0331);
+
+        assert_eq!(
+            Builder::new()
+                .meta(&vec![("notes".to_string(), vec![b'a'; 255]); 2])
+                .err()
+                .map(|error| error.to_string()),
+            Some("Metadata of 530 bytes does not fit 0xfc00-0xfddf".to_string())
+        );
+    }
+
+    #[test]
+    fn screen_data_port() {
+        // Sets the cursor to the last cell of the first row, then sends every character to the
+        // same address
+        let code = assembler::compile(
+            "mov $fe00 &fe0f\nmov [!text] R1\n\
+             loop:\n\
+             mov &R1 R2\nrsf R2 $8\nmov R2 ACC\njeq $0 &[!done]\n\
+             or R2 $fd00\nmov ACC &fe00\ninc R1\njmp &[!loop]\n\
+             done:\nhlt\n\
+             text:\n.asciiz \"Hello\"\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mut memory = Memory::new(0xff00);
+        super::load_image(&mut memory, &code);
+        let mut cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfdff, true)
+            .unwrap()
+            .screen(Screen::headless(16, 16), 0xfe00, 0xfeff)
+            .unwrap()
+            .build();
+        cpu.run().unwrap();
+        let cells: Vec<u8> = (0xfe0e..0xfe15)
+            .map(|address| cpu.memory().get_u8(address))
+            .collect();
+        assert_eq!(cells, b"\0Hello\0");
+    }
+
+    #[test]
+    fn stats() {
+        let assembly = assembler::assemble(
+            "mov $4141 &fe00\nmov &0100 R1\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mem = Memory::with_contents(0xff00, &assembly.bytes);
+        let mut cpu = Builder::new()
+            .map(Box::new(mem), 0x0000, 0xfdff, true)
+            .unwrap()
+            .screen(Screen::headless(16, 16), 0xfe00, 0xfeff)
+            .unwrap()
+            .map(Box::new(BankedMemory::new(8, 256)), 0xff00, 0xffff, true)
+            .unwrap()
+            .stats()
+            .build();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.instructions(), 3);
+        assert_eq!(
+            cpu.memory().traffic_report(),
+            Some("RAM: 8 reads (12 bytes); screen: 1 writes (2 bytes); banked: 0".to_string())
+        );
+        assert_eq!(Builder::new().build().memory().traffic_report(), None);
+    }
+
+    #[test]
+    fn ports() {
+        let assembly = assembler::assemble(
+            "mov $48 R1\nout R1 $3\nmov $69 R1\nmov $3 R2\nout R1 R2\nin $4 R3\nin R2 R4\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let mem = Memory::with_contents(0xffff, &assembly.bytes);
+        let output = SharedOutput(Rc::new(RefCell::new(vec![])));
+        let mut cpu = Builder::new()
+            .map(Box::new(mem), 0x0000, 0xfffe, true)
+            .unwrap()
+            .port(Box::new(Console::new(output.clone())), 0x03, 0x03)
+            .unwrap()
+            .build();
+        cpu.run().unwrap();
+
+        assert_eq!(*output.0.borrow(), b"Hi");
+        // Unconnected ports read as all ones
+        assert_eq!(cpu.get_register(register::R3), 0xffff);
+        assert_eq!(cpu.get_register(register::R4), 0);
+        assert_eq!(
+            Builder::new()
+                .port(Box::new(Console::new(io::sink())), 0x03, 0x03)
+                .unwrap()
+                .port(Box::new(Memory::new(4)), 0x02, 0x03)
+                .err()
+                .map(|error| error.to_string()),
+            Some("Port 0x03 is already taken by console".to_string())
+        );
+    }
+}
@@ -0,0 +1,409 @@
+// Memory map for `vm run --map`, one region per line:
+//   <type> <start> <end> [key=value ...]
+// The end is inclusive, addresses are hex with a `0x` prefix or decimal and `#` starts a comment.
+// Types and their keys, every type also takes `remap=true|false`:
+//   ram     file=<path>  optional initial contents
+//   rom     file=<path>  required, the guest can't write to it
+//   screen  size=<W>x<H> defaults to 16x16, must cover the whole region
+//   banked  banks=<n>    defaults to 8, each bank is as long as the region
+//   console
+//   null                 reads zero and drops writes
+// Files are relative to the map file. Regions may not overlap. The heap start word and the reset
+// vector are filled in when a RAM region holds them.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{heap_start, Builder, HEAP_START_ADDRESS};
+use crate::cpu::RESET_VECTOR_ADDRESS;
+use crate::device::banked_memory::BankedMemory;
+use crate::device::console::Console;
+use crate::device::memory::Memory;
+use crate::device::memory_mapper::DeviceCapability;
+use crate::device::null::Null;
+use crate::device::screen::Screen;
+use crate::device::Device;
+use crate::error::VmError;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Kind {
+    Ram { file: Option<String> },
+    Rom { file: String },
+    Screen { width: usize, height: usize },
+    Banked { banks: u8 },
+    Console,
+    Null,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Region {
+    // 1 based, for errors
+    pub line: usize,
+    pub kind: Kind,
+    pub start: usize,
+    pub end: usize,
+    pub remap: bool,
+}
+
+impl Region {
+    fn len(&self) -> usize {
+        self.end - self.start + 1
+    }
+
+    // The device of a region that isn't remapped sees absolute addresses. Memory stops at
+    // 0xffff bytes, so such a region can't end at 0xffff and mapping it fails.
+    fn device_len(&self) -> usize {
+        let len = if self.remap { self.len() } else { self.end + 1 };
+        len.min(0xffff)
+    }
+
+    fn offset(&self, address: usize) -> usize {
+        if self.remap {
+            address - self.start
+        } else {
+            address
+        }
+    }
+
+    fn error(&self, message: String) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Map {
+    pub regions: Vec<Region>,
+}
+
+impl Map {
+    pub fn parse(text: &str) -> Result<Map, VmError> {
+        let mut regions: Vec<Region> = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let region = parse_region(index + 1, line)
+                .map_err(|error| VmError::Config(format!("line {}: {}", index + 1, error)))?;
+            if let Some(other) = regions
+                .iter()
+                .find(|other| region.start <= other.end && other.start <= region.end)
+            {
+                return Err(VmError::Config(region.error(format!(
+                    "Region {:#06x}-{:#06x} overlaps {:#06x}-{:#06x} on line {}",
+                    region.start, region.end, other.start, other.end, other.line
+                ))));
+            }
+            regions.push(region);
+        }
+        Ok(Map { regions })
+    }
+
+    // Start and size of the first screen, for the PRINT syscall
+    pub fn screen(&self) -> Option<(usize, usize, usize)> {
+        self.regions.iter().find_map(|region| match region.kind {
+            Kind::Screen { width, height } => Some((region.start, width, height)),
+            _ => None,
+        })
+    }
+
+    // Files are read relative to `base`. The program is loaded from address `at` and has to land
+    // in RAM or ROM regions.
+    pub fn build(&self, base: &Path, program: &[u8], at: usize) -> Result<Builder, VmError> {
+        let mut memories: Vec<Option<Memory>> = vec![];
+        for region in &self.regions {
+            let file = match &region.kind {
+                Kind::Ram { file } => file.as_ref(),
+                Kind::Rom { file } => Some(file),
+                _ => {
+                    memories.push(None);
+                    continue;
+                }
+            };
+            let mut memory = Memory::new(region.device_len() as u16);
+            if let Some(file) = file {
+                let bytes = fs::read(base.join(file)).map_err(|error| {
+                    VmError::Config(region.error(format!("Cannot read {}: {}", file, error)))
+                })?;
+                if bytes.len() > region.len() {
+                    return Err(VmError::Config(region.error(format!(
+                        "{} has {} bytes, the region only {}",
+                        file,
+                        bytes.len(),
+                        region.len()
+                    ))));
+                }
+                for (i, &byte) in bytes.iter().enumerate() {
+                    memory.set_u8(region.offset(region.start + i), byte);
+                }
+            }
+            memories.push(Some(memory));
+        }
+
+        for (i, &byte) in program.iter().enumerate() {
+            let address = at + i;
+            let index = self
+                .regions
+                .iter()
+                .position(|region| (region.start..=region.end).contains(&address));
+            match index.and_then(|index| Some((&self.regions[index], memories[index].as_mut()?))) {
+                Some((region, memory)) if address - region.start < region.device_len() => {
+                    memory.set_u8(region.offset(address), byte)
+                }
+                _ => {
+                    return Err(VmError::Config(format!(
+                        "Program of {} bytes does not fit the map, {:#06x} is not in RAM or ROM",
+                        program.len(),
+                        address
+                    )))
+                }
+            }
+        }
+
+        // The words the loader fills in, if RAM holds them
+        let words = [
+            (HEAP_START_ADDRESS, heap_start(at + program.len())),
+            (RESET_VECTOR_ADDRESS, at as u16),
+        ];
+        for &(address, value) in words.iter() {
+            if let Some(index) = self.regions.iter().position(|region| {
+                matches!(region.kind, Kind::Ram { .. })
+                    && region.start <= address
+                    && address < region.end
+            }) {
+                let region = &self.regions[index];
+                if let Some(memory) = memories[index].as_mut() {
+                    memory.set_u16(region.offset(address), value);
+                }
+            }
+        }
+
+        let mut builder = Builder::new();
+        for (region, memory) in self.regions.iter().zip(memories) {
+            let (start, end, remap) = (region.start, region.end, region.remap);
+            builder = match (&region.kind, memory) {
+                (Kind::Rom { .. }, Some(memory)) => builder.map_with(
+                    Box::new(memory),
+                    start,
+                    end,
+                    remap,
+                    DeviceCapability::READ_ONLY,
+                ),
+                (_, Some(memory)) => builder.map(Box::new(memory), start, end, remap),
+                (_, None) => builder.map(device(region), start, end, remap),
+            }
+            .map_err(|error| VmError::Config(region.error(error.to_string())))?;
+        }
+        Ok(builder)
+    }
+}
+
+fn device(region: &Region) -> Box<dyn Device> {
+    match region.kind {
+        Kind::Screen { width, height } => Box::new(Screen::new(width, height)),
+        Kind::Banked { banks } => Box::new(BankedMemory::new(banks, region.device_len() as u16)),
+        Kind::Console => Box::new(Console::new(io::stdout())),
+        _ => Box::new(Null::new(region.device_len())),
+    }
+}
+
+fn parse_region(line: usize, text: &str) -> Result<Region, String> {
+    let mut words = text.split_whitespace();
+    let kind = words.next().unwrap_or_default();
+    if !["ram", "rom", "screen", "banked", "console", "null"].contains(&kind) {
+        return Err(format!("Unknown device type {}", kind));
+    }
+    let (start, end) = match (words.next(), words.next()) {
+        (Some(start), Some(end)) => (address(start)?, address(end)?),
+        _ => return Err(format!("Expected <type> <start> <end>, got {}", text)),
+    };
+    if end < start {
+        return Err(format!(
+            "Region {:#06x}-{:#06x} ends before it starts",
+            start, end
+        ));
+    }
+    let mut options = vec![];
+    for word in words {
+        let index = word
+            .find('=')
+            .ok_or_else(|| format!("Expected key=value, got {}", word))?;
+        options.push((&word[..index], &word[index + 1..]));
+    }
+
+    let mut remap = true;
+    let mut file = None;
+    let mut size = None;
+    let mut banks = None;
+    for &(key, value) in &options {
+        match (key, kind) {
+            ("remap", _) => {
+                remap = value
+                    .parse()
+                    .map_err(|_| format!("remap expects true or false, got {}", value))?
+            }
+            ("file", "ram") | ("file", "rom") => file = Some(value.to_string()),
+            ("size", "screen") => size = Some(value),
+            ("banks", "banked") => {
+                banks = Some(
+                    value
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|&banks| banks > 0)
+                        .ok_or_else(|| format!("banks expects 1 to 255, got {}", value))?,
+                )
+            }
+            _ => return Err(format!("Unknown key {} for {}", key, kind)),
+        }
+    }
+
+    let len = end - start + 1;
+    let kind = match kind {
+        "ram" => Kind::Ram { file },
+        "rom" => Kind::Rom {
+            file: file.ok_or("rom needs a file")?,
+        },
+        "screen" => {
+            let (width, height) = match size {
+                Some(size) => screen_size(size)?,
+                None => (16, 16),
+            };
+            if width * height != len {
+                return Err(format!(
+                    "A {}x{} screen needs {} bytes, the region has {}",
+                    width,
+                    height,
+                    width * height,
+                    len
+                ));
+            }
+            Kind::Screen { width, height }
+        }
+        "banked" => Kind::Banked {
+            banks: banks.unwrap_or(8),
+        },
+        "console" => Kind::Console,
+        _ => Kind::Null,
+    };
+    Ok(Region {
+        line,
+        kind,
+        start,
+        end,
+        remap,
+    })
+}
+
+fn address(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed
+        .ok()
+        .filter(|&address| address <= 0xffff)
+        .ok_or_else(|| format!("Invalid address: {}", text))
+}
+
+fn screen_size(text: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("size expects <width>x<height>, got {}", text);
+    let index = text.find('x').ok_or_else(invalid)?;
+    let width = text[..index].parse().map_err(|_| invalid())?;
+    let height = text[index + 1..].parse().map_err(|_| invalid())?;
+    Ok((width, height))
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use std::path::Path;
+
+    use super::{Kind, Map};
+    use crate::assembler;
+    use crate::cpu::register;
+
+    const MAP: &str = "# Program and data\n\
+                       ram 0x0000 0x7fff\n\
+                       \n\
+                       screen 0x8000 0x80ff size=32x8\n\
+                       banked 0xff00 0xffff banks=4 remap=true # swapped by MB\n";
+
+    #[test]
+    fn three_regions() {
+        let map = Map::parse(MAP).unwrap();
+        assert_eq!(map.regions[1].line, 4);
+        assert_eq!(map.regions[2].kind, Kind::Banked { banks: 4 });
+        assert_eq!(map.screen(), Some((0x8000, 32, 8)));
+
+        let assembly = assembler::assemble(
+            "mov $4142 &8000\nmov $3 &ff00\nmov &ff00 R1\nhlt\n",
+            &assembler::Options::default(),
+        )
+        .unwrap();
+        let builder = map.build(Path::new("."), &assembly.bytes, 0).unwrap();
+        assert_eq!(
+            builder.describe(),
+            "0x0000-0x7fff RAM\n0x8000-0x80ff screen\n0xff00-0xffff banked"
+        );
+        let mut cpu = builder.build();
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory().get_u8(0x8000), 0x42);
+        assert_eq!(cpu.get_register(register::R1), 3);
+        assert_eq!(cpu.memory().get_u16(0x1018), 0x1020);
+
+        let mut cpu = map
+            .build(Path::new("."), &assembly.bytes, 0x2000)
+            .unwrap()
+            .build();
+        cpu.set_register(register::IP, 0x2000);
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(register::R1), 3);
+        assert_eq!(
+            cpu.memory().get_u16(0x1018),
+            0x2000 + assembly.bytes.len() as u16
+        );
+        assert_eq!(cpu.memory().get_u16(0x101a), 0x2000);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Map::parse("ram 0x0000 0x7fff\nflash 0x8000 0x8fff\n")
+                .map_err(|error| error.to_string()),
+            Err("line 2: Unknown device type flash".to_string())
+        );
+        assert_eq!(
+            Map::parse("ram 0x0000 0x7fff\n\nnull 0x7000 0x8fff\n")
+                .map_err(|error| error.to_string()),
+            Err("line 3: Region 0x7000-0x8fff overlaps 0x0000-0x7fff on line 1".to_string())
+        );
+        assert_eq!(
+            Map::parse("screen 0x8000 0x80ff size=16x8\n").map_err(|error| error.to_string()),
+            Err("line 1: A 16x8 screen needs 128 bytes, the region has 256".to_string())
+        );
+        assert_eq!(
+            Map::parse("rom 0 0xfff\n").map_err(|error| error.to_string()),
+            Err("line 1: rom needs a file".to_string())
+        );
+        assert_eq!(
+            Map::parse("ram 0 0xff\n")
+                .unwrap()
+                .build(Path::new("."), &[0; 0x101], 0)
+                .err()
+                .map(|error| error.to_string()),
+            Some(
+                "Program of 257 bytes does not fit the map, 0x0100 is not in RAM or ROM"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            Map::parse("ram 0 0xff\n")
+                .unwrap()
+                .build(Path::new("."), &[0; 0x10], 0xf8)
+                .err()
+                .map(|error| error.to_string()),
+            Some(
+                "Program of 16 bytes does not fit the map, 0x0100 is not in RAM or ROM".to_string()
+            )
+        );
+    }
+}
@@ -1,65 +1,639 @@
-use crate::device::screen::Screen;
-use crate::device::Device;
-use device::memory::Memory;
 use std::fs::File;
-use std::io::{Error, Read, Write};
-use std::{env, fs};
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::{env, fs, process};
 
-mod assembler;
-mod cpu;
-mod device;
-mod parser_combinator;
+use vm::container::{Container, DebugInfo};
+use vm::device::Device;
+use vm::VmError;
+use vm::{
+    analyze, assembler, checksum, cpu, crash_dump, debugger, device, disassembler, inspect, isa,
+    machine, patch, selftest, sigint, snapshot, trace,
+};
 
-fn main() -> Result<(), String> {
-    let args: Vec<String> = env::args().collect();
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("{}", error);
+        process::exit(exit_code(&error));
+    }
+}
+
+fn run() -> Result<(), VmError> {
+    let mut args: Vec<String> = env::args().collect();
 
     match args.get(1).map(|command| command.as_str()) {
         Some("compile") => {
+            // The output only depends on the source: containers have no timestamps, and debug
+            // info and `.meta` entries are written in source order. The flag is accepted so build
+            // scripts can insist on it.
+            take_flag(&mut args, "--reproducible");
+            let debug_info = take_flag(&mut args, "-g");
+            let listing = take_flag(&mut args, "--listing");
+            let size_report = take_flag(&mut args, "--size-report");
+            let max_size = match take_option(&mut args, "--max-size")? {
+                Some(size) => u16::from_str_radix(size.trim_start_matches("0x"), 16)
+                    .map_err(|_| VmError::Config(format!("Invalid maximum size: {}", size)))?,
+                None => machine::STANDARD_CODE_SIZE as u16,
+            };
+            let options = assembler::Options {
+                wrap_expressions: take_flag(&mut args, "--wrap-expressions"),
+                warn_shadowing: take_flag(&mut args, "--warn-shadowing"),
+                max_size: Some(max_size),
+                optimize: take_flag(&mut args, "--optimize"),
+            };
             match args.as_slice() {
                 [_, _, file, output] => {
-                    let bin = assembler::compile(
-                        fs::read_to_string(file).map_err(err_to_string)?.as_str(),
-                    );
-                    let mut file = File::create(output).map_err(err_to_string)?;
+                    let code = fs::read_to_string(file)?;
+                    let assembly = assembler::assemble(&code, &options)?;
+                    for warning in &assembly.warnings {
+                        eprintln!("Warning: {}", warning);
+                    }
+                    if listing {
+                        print!("{}", assembly.listing(&code));
+                    }
+                    if size_report {
+                        print!("{}", assembly.size_report());
+                    }
+                    // Metadata needs a container as well
+                    let bin = if debug_info || !assembly.meta.is_empty() {
+                        Container {
+                            debug: if debug_info {
+                                Some(assembly.debug_info(file))
+                            } else {
+                                None
+                            },
+                            code: assembly.bytes,
+                            meta: assembly.meta,
+                        }
+                        .to_bytes()
+                    } else {
+                        assembly.bytes
+                    };
+                    let mut file = File::create(output)?;
                     // Write a slice of bytes to the file
-                    file.write_all(&bin).map_err(err_to_string)?;
+                    file.write_all(&bin)?;
+                }
+                _ => {
+                    return Err(VmError::Config(
+                        "Usage: vm compile [-g] [--listing] [--size-report] [--reproducible] [--wrap-expressions] [--warn-shadowing] [--max-size <hex>] [--optimize] <input_file> <output_file>"
+                            .to_string(),
+                    ))
                 }
-                _ => return Err("Usage: vm compile <input_file> <output_file>".to_string()),
             };
         }
+        Some("fmt") => {
+            let check = take_flag(&mut args, "--check");
+            if let Some(file) = args.get(2) {
+                let code = fs::read_to_string(file)?;
+                let formatted = assembler::format(&code)?;
+                if check {
+                    let differences: Vec<String> = code
+                        .lines()
+                        .zip(formatted.lines())
+                        .enumerate()
+                        .filter(|(_, (original, formatted))| original != formatted)
+                        .map(|(line, (original, formatted))| {
+                            format!("{}:{}\n-{}\n+{}", file, line + 1, original, formatted)
+                        })
+                        .collect();
+                    if !differences.is_empty() {
+                        eprintln!("{}", differences.join("\n"));
+                        return Err(VmError::Config(format!("{} is not formatted", file)));
+                    }
+                } else if formatted != code {
+                    fs::write(file, formatted)?;
+                }
+            } else {
+                return Err(VmError::Config(
+                    "Usage: vm fmt [--check] <input_file>".to_string(),
+                ));
+            }
+        }
+        Some("strip") => {
+            if let Some(file) = args.get(2) {
+                let mut container = Container::from_bytes(&fs::read(file)?)?;
+                container.debug = None;
+                fs::write(file, container.to_bytes())?;
+            } else {
+                return Err(VmError::Config("Usage: vm strip <binary_file>".to_string()));
+            }
+        }
+        Some("patch") => {
+            let at = take_option(&mut args, "--at")?;
+            let bytes = take_option(&mut args, "--bytes")?;
+            let word = take_option(&mut args, "--word")?;
+            let assemble = take_option(&mut args, "--assemble")?;
+            let patch = match (bytes, word, assemble) {
+                (Some(bytes), None, None) => {
+                    Some(patch::Patch::bytes(&bytes).map_err(VmError::Config)?)
+                }
+                (None, Some(word), None) => {
+                    Some(patch::Patch::word(&word).map_err(VmError::Config)?)
+                }
+                (None, None, Some(source)) => Some(patch::Patch::Assemble(source)),
+                _ => None,
+            };
+            match (args.get(2), at, patch) {
+                (Some(file), Some(at), Some(patch)) => {
+                    let bin = fs::read(file)?;
+                    fs::write(file, patch::apply(&bin, &at, &patch).map_err(VmError::Config)?)?;
+                }
+                _ => {
+                    return Err(VmError::Config(
+                        "Usage: vm patch <binary_file> --at <address> (--bytes \"<hex bytes>\" | --word <value> | --assemble \"<instruction>\")".to_string(),
+                    ))
+                }
+            }
+        }
         Some("run") => {
+            let allow_fs = take_flag(&mut args, "--allow-fs");
+            let trace = take_flag(&mut args, "--trace");
+            let trace_range = take_option(&mut args, "--trace-range")?;
+            let trace_only = take_option(&mut args, "--trace-only")?;
+            let trace_skip = take_option(&mut args, "--trace-skip")?;
+            let trace_limit = take_option(&mut args, "--trace-limit")?;
+            let trace_format = take_option(&mut args, "--trace-format")?;
+            let trace_out = take_option(&mut args, "--trace-out")?;
+            let cycles = take_flag(&mut args, "--cycles");
+            let crash_dump = take_option(&mut args, "--crash-dump")?;
+            let expect_crc32 = take_option(&mut args, "--expect-crc32")?;
+            let interrupt_stack = take_option(&mut args, "--interrupt-stack")?;
+            let snapshot = take_option(&mut args, "--snapshot")?;
+            let rng = take_option(&mut args, "--rng")?;
+            let line_input = take_flag(&mut args, "--line-input");
+            let keyboard = take_flag(&mut args, "--keyboard");
+            let stats = take_flag(&mut args, "--stats");
+            let selftest = take_flag(&mut args, "--selftest");
+            let report = take_flag(&mut args, "--report");
+            let debug_on_interrupt = take_flag(&mut args, "--debug-on-interrupt");
+            let history = take_flag(&mut args, "--history");
+            let protect_code = take_flag(&mut args, "--protect-code");
+            let meta = take_flag(&mut args, "--meta");
+            let map = take_option(&mut args, "--map")?;
+            let at = match take_option(&mut args, "--at")? {
+                Some(at) => u16::from_str_radix(at.trim_start_matches("0x"), 16)
+                    .map_err(|_| VmError::Config(format!("Invalid load address: {}", at)))?,
+                None => 0,
+            };
+            // Any filter turns tracing on
+            let mut trace = if trace
+                || trace_range.is_some()
+                || trace_only.is_some()
+                || trace_skip.is_some()
+                || trace_limit.is_some()
+                || trace_format.is_some()
+                || trace_out.is_some()
+            {
+                let mut filter = trace::Filter::new();
+                if let Some(range) = trace_range {
+                    filter = filter.range(&range).map_err(VmError::Config)?;
+                }
+                if let Some(mnemonics) = trace_only {
+                    filter = filter.only(&mnemonics).map_err(VmError::Config)?;
+                }
+                if let Some(skip) = trace_skip {
+                    filter = filter.skip(parse_count("--trace-skip", &skip)?);
+                }
+                if let Some(limit) = trace_limit {
+                    filter = filter.limit(parse_count("--trace-limit", &limit)?);
+                }
+                let format = match trace_format {
+                    Some(format) => trace::Format::parse(&format).map_err(VmError::Config)?,
+                    None => trace::Format::Text,
+                };
+                let out: Box<dyn Write> = match trace_out {
+                    Some(file) => {
+                        Box::new(BufWriter::new(File::create(&file).map_err(|error| {
+                            io::Error::new(error.kind(), format!("{}: {}", file, error))
+                        })?))
+                    }
+                    None => Box::new(io::stderr()),
+                };
+                Some(trace::Tracer::new(filter, format, out))
+            } else {
+                None
+            };
             if let Some(file) = args.get(2) {
-                let mut bin = File::open(file).map_err(err_to_string)?;
-                let mut buf = [0u8; 0xfe00];
-                bin.read(&mut buf).map_err(err_to_string)?;
-
-                let mem_bank = device::banked_memory::BankedMemory::new(8, 256);
-                let screen = Screen {};
-                let mut mem = Memory::new(0xff00);
-
-                for i in 0..0xfe00 {
-                    mem.set_u8(i, *buf.get(i).ok_or("Mismatched buffer size".to_string())?)
+                if let Some(expected) = expect_crc32 {
+                    let expected = u32::from_str_radix(expected.trim_start_matches("0x"), 16)
+                        .map_err(|_| VmError::Config(format!("Invalid CRC32: {}", expected)))?;
+                    let actual = checksum::crc32(&fs::read(file)?);
+                    if actual != expected {
+                        return Err(VmError::Config(format!(
+                            "{} has CRC32 {:08x}, expected {:08x}",
+                            file, actual, expected
+                        )));
+                    }
+                }
+                let mut devices: Vec<(Box<dyn Device>, usize, usize)> = vec![];
+                if let Some(spec) = rng {
+                    // Nothing records or replays runs yet, so any backend may be used
+                    let rng = device::rng::Rng::new(device::rng::backend(&spec, false)?);
+                    devices.push((Box::new(rng), 0xfdfe, 0xfdff));
+                }
+                if line_input {
+                    let line_input = device::line_input::LineInput::stdin();
+                    devices.push((Box::new(line_input), 0xfdf0, 0xfdf9));
+                }
+                if keyboard {
+                    let keyboard = device::keyboard::Keyboard::stdin();
+                    devices.push((Box::new(keyboard), 0xfdfc, 0xfdfd));
+                }
+                let mut results = None;
+                if selftest {
+                    let (harness, shared) = device::test_harness::TestHarness::new();
+                    devices.push((Box::new(harness), 0xfde0, 0xfde5));
+                    results = Some(shared);
+                }
+                let (mut cpu, debug) =
+                    load(file, at, devices, stats, protect_code, meta, map.as_deref())?;
+                if let Some(results) = results {
+                    cpu::syscall::register_host_services(&mut cpu, allow_fs);
+                    let summary = selftest::run(&mut cpu, &results);
+                    eprintln!("{}", summary.render(debug.as_ref()));
+                    return if summary.ok() {
+                        Ok(())
+                    } else {
+                        Err(VmError::Config("Self test failed".to_string()))
+                    };
+                }
+                cpu::syscall::register_host_services(&mut cpu, allow_fs);
+                if let Some(top) = interrupt_stack {
+                    cpu.set_interrupt_stack(Some(
+                        u16::from_str_radix(top.trim_start_matches("0x"), 16).map_err(|_| {
+                            VmError::Config(format!("Invalid interrupt stack top: {}", top))
+                        })?,
+                    ));
                 }
 
-                let mut mm = device::memory_mapper::MemoryMapper::new();
-                mm.map(Box::new(mem), 0x0000, 0xfe00, true);
-                mm.map(Box::new(screen), 0xfe00, 0xff00, true);
-                mm.map(Box::new(mem_bank), 0xff00, 0xffff, false);
-
-                let mut cpu = cpu::CPU::new(Box::new(mm));
+                if history {
+                    cpu.set_history(cpu::DEFAULT_HISTORY);
+                }
 
-                cpu.run()
+                sigint::install();
+                let result = match crash_dump {
+                    Some(dir) => {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            execute(&mut cpu, trace.as_mut(), debug.as_ref())
+                        }));
+                        let message = match &result {
+                            Ok(Err(VmError::Fault(fault))) => Some(cpu.fault_message(fault)),
+                            Ok(_) => None,
+                            Err(payload) => Some(crash_dump::panic_message(payload.as_ref())),
+                        };
+                        if let Some(message) = message {
+                            let path =
+                                crash_dump::write(Path::new(&dir), &cpu, &message, debug.as_ref())?;
+                            eprintln!("Crash dump written to {}", path.display());
+                        }
+                        // A host panic carries on once it has its dump
+                        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+                    }
+                    None => execute(&mut cpu, trace.as_mut(), debug.as_ref()),
+                };
+                let stop = match result {
+                    Err(VmError::Fault(fault)) => {
+                        return Err(report_fault(&cpu, fault, history, debug.as_ref()))
+                    }
+                    result => result?,
+                };
+                if stop == Stop::Interrupted {
+                    // Reset attributes and move below the 16 screen rows before printing anything
+                    println!("\x1b[0m\x1b[17H");
+                    if debug_on_interrupt {
+                        eprintln!(
+                            "Interrupted at {:#06x}",
+                            cpu.get_register(cpu::register::IP)
+                        );
+                        let stdin = io::stdin();
+                        debugger::Debugger::new(cpu, debug.as_ref())
+                            .with_interrupt(&sigint::PRESSES)
+                            .run(stdin.lock(), &mut io::stdout())?;
+                        return Ok(());
+                    }
+                    let symbols = debug.as_ref().map(|debug| debug.symbols.as_slice());
+                    eprintln!("{}", inspect::report(&cpu, symbols.unwrap_or_default()));
+                    return Err(VmError::Config("Interrupted".to_string()));
+                }
+                if cycles {
+                    eprintln!("cycles: {}", cpu.cycles());
+                }
+                if report {
+                    let symbols = debug.as_ref().map(|debug| debug.symbols.as_slice());
+                    eprintln!("{}", inspect::report(&cpu, symbols.unwrap_or_default()));
+                }
+                // Before the snapshot, which reads memory through the map as well
+                if stats {
+                    eprintln!("instructions: {}", cpu.instructions());
+                    if let Some(report) = cpu.memory().traffic_report() {
+                        eprintln!("{}", report);
+                    }
+                }
+                if let Some(output) = snapshot {
+                    fs::write(output, snapshot::Snapshot::capture(&cpu).to_bytes())?;
+                }
             } else {
-                return Err("Usage: vm run <binary_file>".to_string());
+                return Err(VmError::Config(
+                    "Usage: vm run [--allow-fs] [--trace] [--trace-range <start>..<end>] [--trace-only <mnemonics>] [--trace-skip <n>] [--trace-limit <n>] [--trace-format text|jsonl|csv] [--trace-out <file>] [--cycles] [--crash-dump <dir>] [--expect-crc32 <hex>] [--interrupt-stack <hex>] [--snapshot <file>] [--rng seed:<n>|os] [--line-input] [--keyboard] [--stats] [--selftest] [--report] [--debug-on-interrupt] [--history] [--protect-code] [--meta] [--map <file>] [--at <hex>] <binary_file>".to_string(),
+                ));
             }
         }
-        Some(command) => return Err(format!("{} is not a vm command", command)),
-        _ => return Err("Usage: vm <command> [args]".to_string()),
+        Some("debug") => {
+            let allow_fs = take_flag(&mut args, "--allow-fs");
+            if let Some(file) = args.get(2) {
+                let (mut cpu, debug) = load(file, 0, vec![], false, false, false, None)?;
+                cpu::syscall::register_host_services(&mut cpu, allow_fs);
+                sigint::install();
+                let stdin = io::stdin();
+                debugger::Debugger::new(cpu, debug.as_ref())
+                    .with_interrupt(&sigint::PRESSES)
+                    .run(stdin.lock(), &mut io::stdout())?;
+            } else {
+                return Err(VmError::Config(
+                    "Usage: vm debug [--allow-fs] <binary_file>".to_string(),
+                ));
+            }
+        }
+        Some("analyze") => {
+            let tail_calls = take_flag(&mut args, "--tail-calls");
+            let calls = take_option(&mut args, "--calls")?;
+            match (args.get(2), calls) {
+                (Some(file), Some(output)) => {
+                    let Container { code, debug, .. } = read_binary(file)?;
+                    let edges = analyze::call_graph(&code, tail_calls);
+                    fs::write(output, analyze::to_dot(&edges, debug.as_ref()))?;
+                }
+                _ => {
+                    return Err(VmError::Config(
+                        "Usage: vm analyze [--tail-calls] --calls <output.dot> <binary_file>"
+                            .to_string(),
+                    ))
+                }
+            }
+        }
+        Some("info") => match args.get(2) {
+            Some(file) => print!("{}", read_binary(file)?.info()),
+            None => return Err(VmError::Config("Usage: vm info <binary_file>".to_string())),
+        },
+        Some("disassemble") => match args.get(2) {
+            Some(file) => {
+                let Container { code, debug, .. } = read_binary(file)?;
+                print!("{}", disassembler::disassemble(&code, debug.as_ref()));
+            }
+            None => {
+                return Err(VmError::Config(
+                    "Usage: vm disassemble <binary_file>".to_string(),
+                ))
+            }
+        },
+        Some("isa") => match args.get(2).map(|format| format.as_str()) {
+            None => print!("{}", isa::text()),
+            Some("--markdown") => print!("{}", isa::markdown()),
+            Some("--json") => print!("{}", isa::json()),
+            Some(_) => {
+                return Err(VmError::Config(
+                    "Usage: vm isa [--markdown|--json]".to_string(),
+                ))
+            }
+        },
+        Some("snapshot-diff") => {
+            let map = take_option(&mut args, "--map")?;
+            // The screen of the default machine changes on every frame
+            let mut exclude = vec![0xfe00..=0xfeff];
+            while let Some(range) = take_option(&mut args, "--exclude")? {
+                exclude.push(parse_range(&range)?);
+            }
+            match args.as_slice() {
+                [_, _, a, b] => {
+                    let read = |file: &String| {
+                        snapshot::Snapshot::from_bytes(&fs::read(file)?)
+                    };
+                    let (a, b) = (read(a)?, read(b)?);
+                    let symbols = match map {
+                        Some(file) => read_binary(&file)?.debug.unwrap_or_default().symbols,
+                        None => vec![],
+                    };
+                    let entries = snapshot::diff(&a, &b, &exclude);
+                    print!("{}", snapshot::render(&entries, &a, &b, &symbols));
+                }
+                _ => {
+                    return Err(VmError::Config(
+                        "Usage: vm snapshot-diff [--map <binary_file>] [--exclude <start>-<end>] <a> <b>"
+                            .to_string(),
+                    ))
+                }
+            }
+        }
+        Some(command) => return Err(VmError::Config(format!("{} is not a vm command", command))),
+        _ => return Err(VmError::Config("Usage: vm <command> [args]".to_string())),
     }
 
     Ok(())
 }
 
-fn err_to_string(err: Error) -> String {
-    format!("{:?}", err)
+#[derive(Debug, Eq, PartialEq)]
+enum Stop {
+    Halted,
+    // Ctrl-C, with the program paused before the next instruction
+    Interrupted,
+}
+
+// Faults the guest doesn't handle end the run as VmError::Fault
+fn execute(
+    cpu: &mut cpu::CPU,
+    mut trace: Option<&mut trace::Tracer>,
+    debug: Option<&DebugInfo>,
+) -> Result<Stop, VmError> {
+    let trace_error = |error: io::Error| {
+        io::Error::new(error.kind(), format!("Cannot write the trace: {}", error))
+    };
+    let stop = loop {
+        if sigint::pressed(&sigint::PRESSES) {
+            break Ok(Stop::Interrupted);
+        }
+        if let Some(tracer) = trace.as_mut() {
+            tracer.before(cpu, debug).map_err(trace_error)?;
+        }
+        let result = cpu.step();
+        if let Some(tracer) = trace.as_mut() {
+            tracer.after(cpu).map_err(trace_error)?;
+        }
+        match result {
+            Ok(None) => {}
+            Ok(Some(_)) => break Ok(Stop::Halted),
+            Err(fault) => break Err(VmError::Fault(fault)),
+        }
+    };
+    if let Some(tracer) = trace {
+        tracer.flush().map_err(trace_error)?;
+    }
+    stop
+}
+
+// The CPU explains stack faults better than the fault itself and debug info names the source
+// line, and with --history the recent instructions go first. The fault is then reported like any
+// other error.
+fn report_fault(
+    cpu: &cpu::CPU,
+    fault: cpu::fault::CpuError,
+    history: bool,
+    debug: Option<&DebugInfo>,
+) -> VmError {
+    let message = inspect::fault(cpu, &fault, debug);
+    if message != fault.to_string() {
+        eprintln!("{}", message);
+    }
+    if history {
+        eprintln!("Recent instructions:\n{}", inspect::history(cpu, debug));
+    }
+    VmError::Fault(fault)
+}
+
+// A raw binary is read as a container with just the code
+fn read_binary(file: &str) -> Result<Container, VmError> {
+    let bin = fs::read(file)?;
+    if Container::is_container(&bin) {
+        Container::from_bytes(&bin)
+    } else {
+        Ok(Container {
+            code: bin,
+            debug: None,
+            meta: vec![],
+        })
+    }
+}
+
+// Devices are mapped over RAM below the screen. `run` puts the RNG at the last word, the keyboard
+// at the word before it, the line input device at the ten bytes from 0xfdf0 and the test harness
+// at the six bytes from 0xfde0. The standard machine keeps the bank count and size of banked
+// memory in the four bytes after the harness.
+// The PRINT syscall draws on the 16x16 screen at 0xfe00. A map file, see `machine::config`,
+// replaces RAM, screen and banked memory, the other devices are mapped over it.
+// `protect_code` maps the program a second time as ROM over RAM, so stores into it fault.
+// `meta` maps the program's metadata at 0xfc00, see `machine::Builder::meta`.
+// The program is loaded at `at` and runs from there.
+fn load(
+    file: &str,
+    at: u16,
+    devices: Vec<(Box<dyn Device>, usize, usize)>,
+    stats: bool,
+    protect_code: bool,
+    meta: bool,
+    map: Option<&str>,
+) -> Result<(cpu::CPU, Option<DebugInfo>), VmError> {
+    let Container {
+        code: program,
+        debug,
+        meta: metadata,
+    } = read_binary(file)?;
+    if let Some(map) = map {
+        let text = fs::read_to_string(map)?;
+        let config = machine::config::Map::parse(&text)
+            .map_err(|e| VmError::Config(format!("{}: {}", map, e)))?;
+        let base = Path::new(map).parent().unwrap_or_else(|| Path::new("."));
+        let mut builder = config
+            .build(base, &program, at as usize)
+            .map_err(|e| VmError::Config(format!("{}: {}", map, e)))?;
+        if protect_code {
+            builder = protect(builder, &program, at)?;
+        }
+        if meta {
+            builder = builder.meta(&metadata)?;
+        }
+        for (device, start, end) in devices {
+            builder = builder.map(device, start, end, true)?;
+        }
+        if stats {
+            builder = builder.stats();
+        }
+        let mut cpu = builder.build();
+        start(&mut cpu, at, program.len());
+        if let Some((start, width, height)) = config.screen() {
+            cpu.register_syscall(
+                cpu::syscall::PRINT,
+                Box::new(cpu::syscall::Print::new(start, width, height)),
+            );
+        }
+        return Ok((cpu, debug));
+    }
+    let mut builder = machine::Builder::standard_at(&program, at as usize)?;
+    if protect_code {
+        builder = protect(builder, &program, at)?;
+    }
+    if meta {
+        builder = builder.meta(&metadata)?;
+    }
+    for (device, start, end) in devices {
+        builder = builder.map(device, start, end, true)?;
+    }
+    if stats {
+        builder = builder.stats();
+    }
+    let mut cpu = builder.build();
+    start(&mut cpu, at, program.len());
+    cpu.register_syscall(
+        cpu::syscall::PRINT,
+        Box::new(cpu::syscall::Print::new(0xfe00, 16, 16)),
+    );
+    Ok((cpu, debug))
+}
+
+fn protect(builder: machine::Builder, code: &[u8], at: u16) -> Result<machine::Builder, VmError> {
+    if code.is_empty() {
+        return Ok(builder);
+    }
+    let rom = device::rom::Rom::from_bytes(code);
+    let at = at as usize;
+    builder.map(Box::new(rom), at, at + code.len() - 1, true)
+}
+
+fn start(cpu: &mut cpu::CPU, at: u16, length: usize) {
+    cpu.set_code_region(Some(at..at.saturating_add(length as u16)));
+    cpu.set_register(cpu::register::IP, at);
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+// Removes `option value` from the arguments and returns the value
+fn take_option(args: &mut Vec<String>, option: &str) -> Result<Option<String>, VmError> {
+    match args.iter().position(|arg| arg == option) {
+        Some(index) if index + 1 < args.len() => {
+            let value = args.remove(index + 1);
+            args.remove(index);
+            Ok(Some(value))
+        }
+        Some(_) => Err(VmError::Config(format!("{} expects a value", option))),
+        None => Ok(None),
+    }
+}
+
+fn parse_count(option: &str, count: &str) -> Result<u64, VmError> {
+    count
+        .parse()
+        .map_err(|_| VmError::Config(format!("{} expects a number, got {}", option, count)))
+}
+
+// Inclusive hex range like `fe00-feff`
+fn parse_range(range: &str) -> Result<RangeInclusive<u16>, VmError> {
+    let invalid = || VmError::Config(format!("Invalid range: {}", range));
+    let (start, end) = range.split_at(range.find('-').ok_or_else(invalid)?);
+    let hex = |s: &str| u16::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| invalid());
+    Ok(hex(start)?..=hex(&end[1..])?)
+}
+
+// Every error ends the process here, each kind with its own exit status
+fn exit_code(error: &VmError) -> i32 {
+    match error {
+        VmError::Config(_) => 1,
+        VmError::Parse(_) => 2,
+        VmError::Fault(_) => 3,
+        VmError::Io(_) => 4,
+        VmError::Device(_) => 5,
+    }
 }
@@ -1,6 +1,8 @@
-use super::core::{ParseError, Parser, ParserState};
+// Parsers for binary files. Numbers are big endian like everything else in the VM, errors point
+// at the byte where the input stopped making sense.
+use super::core::{take, ParseError, Parser, ParserState};
 
-fn match_literal(expected: &[u8]) -> Parser<[u8], ()> {
+pub fn literal(expected: &[u8]) -> Parser<'_, [u8], ()> {
     Parser::new(move |input: &[u8]| match input.get(0..expected.len()) {
         Some(next) if next == expected => Ok(ParserState {
             index: expected.len(),
@@ -13,28 +15,125 @@ fn match_literal(expected: &[u8]) -> Parser<[u8], ()> {
     })
 }
 
-#[test]
-fn literal_parser() {
-    // vec![1,23].iter().map()
-    let parse_joe = match_literal(b"Hello Joe!");
-    assert_eq!(
-        parse_joe.parse(b"Hello Joe!"),
-        Ok(ParserState {
-            index: 10,
-            result: ()
-        }),
-    );
-    assert_eq!(
-        parse_joe.parse(b"Hello Joe! Hello Robert!"),
-        Ok(ParserState {
-            index: 10,
-            result: ()
-        }),
-    );
-    assert_eq!(
-        parse_joe.parse(b"Hello Mike!"),
-        Err(ParseError::new(String::from(
-            "Could not match literal: [72, 101, 108, 108, 111, 32, 74, 111, 101, 33]"
-        ),)),
-    );
+pub fn u8<'a>() -> Parser<'a, [u8], u8> {
+    take(1).map(|bytes: &[u8]| bytes[0])
+}
+
+pub fn u16<'a>() -> Parser<'a, [u8], u16> {
+    take(2).map(|bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn u32<'a>() -> Parser<'a, [u8], u32> {
+    take(4).map(|bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// The bytes before the next `terminator`, which is consumed but not part of the result. Fails at
+// the end of the input if there is none.
+pub fn terminated<'a>(terminator: u8) -> Parser<'a, [u8], &'a [u8]> {
+    Parser::new(
+        move |input: &'a [u8]| match input.iter().position(|&byte| byte == terminator) {
+            Some(index) => Ok(ParserState {
+                index: index + 1,
+                result: &input[..index],
+            }),
+            None => Err(ParseError {
+                message: format!("Missing terminator {:#04x}", terminator),
+                index: input.len(),
+            }),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{literal, terminated, u16, u32, u8};
+    use crate::parser_combinator::core::{length_prefixed, ParseError, ParserState};
+
+    #[test]
+    fn literal_parser() {
+        let parse_joe = literal(b"Hello Joe!");
+        assert_eq!(
+            parse_joe.parse(b"Hello Joe!"),
+            Ok(ParserState {
+                index: 10,
+                result: ()
+            }),
+        );
+        assert_eq!(
+            parse_joe.parse(b"Hello Joe! Hello Robert!"),
+            Ok(ParserState {
+                index: 10,
+                result: ()
+            }),
+        );
+        assert_eq!(
+            parse_joe.parse(b"Hello Mike!"),
+            Err(ParseError::new(String::from(
+                "Could not match literal: [72, 101, 108, 108, 111, 32, 74, 111, 101, 33]"
+            ),)),
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        let bytes = [0x12, 0x34, 0x56, 0x78, 0x9a];
+        assert_eq!(u8().parse(&bytes).map(|state| state.result), Ok(0x12));
+        assert_eq!(u16().parse(&bytes).map(|state| state.result), Ok(0x1234));
+        assert_eq!(
+            u32().parse_at(&bytes, 1).map(|state| state.result),
+            Ok(0x3456789a)
+        );
+        assert_eq!(
+            u32().parse_at(&bytes, 2),
+            Err(ParseError {
+                message: String::from("Unexpected end of input, 4 needed and 3 left"),
+                index: 2
+            })
+        );
+    }
+
+    #[test]
+    fn records() {
+        // NUL terminated names, each with a length prefixed payload, up to an 0xff marker
+        let record = terminated(0).left(length_prefixed(u8().map(usize::from)));
+        let records = record.clone().many_till(literal(&[0xff]));
+        let bytes = b"ab\0\x02xyc\0\x00\xff";
+        assert_eq!(
+            records.parse(bytes),
+            Ok(ParserState {
+                index: 10,
+                result: vec![&b"ab"[..], &b"c"[..]]
+            })
+        );
+
+        // A length prefix cut off, a payload cut off and a name without its terminator
+        assert_eq!(
+            records.parse(b"ab\0\x02xyc\0"),
+            Err(ParseError {
+                message: String::from("Unexpected end of input, 1 needed and 0 left"),
+                index: 8
+            })
+        );
+        assert_eq!(
+            records.parse(b"ab\0\x05xy"),
+            Err(ParseError {
+                message: String::from("Unexpected end of input, 5 needed and 2 left"),
+                index: 4
+            })
+        );
+        assert_eq!(
+            records.parse(b"ab\0\x00cd"),
+            Err(ParseError {
+                message: String::from("Missing terminator 0x00"),
+                index: 6
+            })
+        );
+        assert_eq!(
+            records.parse(b"ab\0\x00"),
+            Err(ParseError {
+                message: String::from("Could not match literal: [255]"),
+                index: 4
+            })
+        );
+    }
 }
@@ -1,4 +1,5 @@
 use std::ops::{Index, Range};
+use std::rc::Rc;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ParserState<T> {
@@ -18,22 +19,87 @@ impl ParseError {
 }
 pub type ParseResult<Output> = Result<ParserState<Output>, ParseError>;
 
+// Lengths and indices count bytes for `str` and items for slices
 pub trait ParseInput: Index<Range<usize>, Output = Self> {
     fn get_from(&self, i: usize) -> Option<&Self>;
+    fn get_range(&self, range: Range<usize>) -> Option<&Self>;
+    fn length(&self) -> usize;
 }
 impl ParseInput for str {
     fn get_from(&self, i: usize) -> Option<&Self> {
         self.get(i..)
     }
+    fn get_range(&self, range: Range<usize>) -> Option<&Self> {
+        self.get(range)
+    }
+    fn length(&self) -> usize {
+        self.len()
+    }
 }
 impl<T> ParseInput for [T] {
     fn get_from(&self, i: usize) -> Option<&Self> {
         self.get(i..)
     }
+    fn get_range(&self, range: Range<usize>) -> Option<&Self> {
+        self.get(range)
+    }
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+// The next `count` items. Fails where they would start if fewer are left, for `str` also if
+// they would end inside a character.
+pub fn take<'a, I: ?Sized + ParseInput>(count: usize) -> Parser<'a, I, &'a I> {
+    Parser::new(move |input: &'a I| match input.get_range(0..count) {
+        Some(result) => Ok(ParserState {
+            index: count,
+            result,
+        }),
+        None if input.length() < count => Err(ParseError::new(format!(
+            "Unexpected end of input, {} needed and {} left",
+            count,
+            input.length()
+        ))),
+        None => Err(ParseError::new(format!("Cannot take {} here", count))),
+    })
+}
+
+// A count followed by that many items
+pub fn counted<'a, I: ?Sized + ParseInput, O: 'a>(
+    count: Parser<'a, I, usize>,
+    item: Parser<'a, I, O>,
+) -> Parser<'a, I, Vec<O>> {
+    Parser::new(move |input| {
+        let state = count.parse(input)?;
+        item.clone()
+            .repeat(state.result)
+            .parse_at(input, state.index)
+    })
 }
 
+// A length followed by that many items, like a u16 byte count and the bytes
+pub fn length_prefixed<'a, I: ?Sized + ParseInput>(
+    length: Parser<'a, I, usize>,
+) -> Parser<'a, I, &'a I> {
+    Parser::new(move |input| {
+        let state = length.parse(input)?;
+        take(state.result).parse_at(input, state.index)
+    })
+}
+
+// Cloning is cheap and shares the parsing function, so a sub-parser can be built once and used in
+// several places of a grammar
 pub struct Parser<'a, Input: ?Sized + ParseInput, Output: 'a> {
-    fun: Box<dyn Fn(&'a Input) -> ParseResult<Output> + 'a>,
+    fun: Rc<dyn Fn(&'a Input) -> ParseResult<Output> + 'a>,
+}
+
+impl<'a, I: ?Sized + ParseInput, O> Clone for Parser<'a, I, O> {
+    fn clone(&self) -> Self {
+        Parser {
+            fun: Rc::clone(&self.fun),
+        }
+    }
 }
 
 impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
@@ -41,7 +107,7 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
     where
         F: Fn(&'a I) -> ParseResult<O> + 'a,
     {
-        Parser { fun: Box::new(fun) }
+        Parser { fun: Rc::new(fun) }
     }
 
     pub fn parse(&self, slice: &'a I) -> ParseResult<O> {
@@ -79,14 +145,49 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
     where
         F: Fn(ParseError) -> ParseError + 'a,
     {
-        Parser::new(move |input| self.parse(input).map_err(|err| err_map_fn(err)))
+        Parser::new(move |input| self.parse(input).map_err(&err_map_fn))
     }
 
     pub fn and_then<F, B>(self, chain_fn: F) -> Parser<'a, I, B>
     where
         F: Fn(ParserState<O>) -> ParseResult<B> + 'a,
     {
-        Parser::new(move |input| self.parse(input).and_then(|state| chain_fn(state)))
+        Parser::new(move |input| self.parse(input).and_then(&chain_fn))
+    }
+
+    // Succeeds or fails like the inner parser but never consumes any input
+    pub fn peek(self) -> Parser<'a, I, O> {
+        Parser::new(move |input| {
+            self.parse(input)
+                .map(|state| ParserState { index: 0, ..state })
+        })
+    }
+
+    // Never fails, a failing inner parser gives `None` and consumes nothing
+    pub fn optional(self) -> Parser<'a, I, Option<O>> {
+        Parser::new(move |input| match self.parse(input) {
+            Ok(state) => Ok(ParserState {
+                index: state.index,
+                result: Some(state.result),
+            }),
+            Err(_) => Ok(ParserState {
+                index: 0,
+                result: None,
+            }),
+        })
+    }
+
+    pub fn not_followed_by<B>(self, b: Parser<'a, I, B>) -> Parser<'a, I, O> {
+        Parser::new(move |input| {
+            let state = self.parse(input)?;
+            match b.parse_at(input, state.index) {
+                Ok(_) => Err(ParseError {
+                    message: String::from("Unexpected input"),
+                    index: state.index,
+                }),
+                Err(_) => Ok(state),
+            }
+        })
     }
 
     pub fn zero_or_more(self) -> Parser<'a, I, Vec<O>> {
@@ -112,6 +213,90 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
         })
     }
 
+    // Zero or more items with a separator between them. A separator that isn't followed by an
+    // item is left unparsed, like chainl1 does with a dangling operator.
+    pub fn sep_by<S>(self, separator: Parser<'a, I, S>) -> Parser<'a, I, Vec<O>> {
+        Parser::new(move |input| {
+            let mut result = Vec::new();
+            let mut index = match self.parse(input) {
+                Ok(state) => {
+                    result.push(state.result);
+                    state.index
+                }
+                Err(_) => return Ok(ParserState { result, index: 0 }),
+            };
+            while let Ok(separator_state) = separator.parse_at(input, index) {
+                match self.parse_at(input, separator_state.index) {
+                    Ok(state) => {
+                        result.push(state.result);
+                        index = state.index;
+                    }
+                    Err(_) => break,
+                }
+            }
+            Ok(ParserState { result, index })
+        })
+    }
+
+    pub fn pair<B>(self, b: Parser<'a, I, B>) -> Parser<'a, I, (O, B)> {
+        Parser::new(move |input| {
+            let a_res = self.parse(input)?;
+            let b_res = b.parse_at(input, a_res.index)?;
+            Ok(ParserState {
+                index: b_res.index,
+                result: (a_res.result, b_res.result),
+            })
+        })
+    }
+
+    // Exactly `count` items in a row
+    pub fn repeat(self, count: usize) -> Parser<'a, I, Vec<O>> {
+        Parser::new(move |input| {
+            let mut result = Vec::with_capacity(count);
+            let mut index = 0;
+            for _ in 0..count {
+                let state = self.parse_at(input, index)?;
+                result.push(state.result);
+                index = state.index;
+            }
+            Ok(ParserState { result, index })
+        })
+    }
+
+    // Items up to `end`, which is consumed too, like the records of a file up to its end marker.
+    // Where neither matches the error is the item's, or the end's once the input ran out.
+    pub fn many_till<E>(self, end: Parser<'a, I, E>) -> Parser<'a, I, Vec<O>> {
+        Parser::new(move |input| {
+            let mut result = Vec::new();
+            let mut index = 0;
+            loop {
+                let end_error = match end.parse_at(input, index) {
+                    Ok(state) => {
+                        return Ok(ParserState {
+                            result,
+                            index: state.index,
+                        })
+                    }
+                    Err(err) => err,
+                };
+                match self.parse_at(input, index) {
+                    Ok(state) if state.index > index => {
+                        result.push(state.result);
+                        index = state.index;
+                    }
+                    Ok(_) => {
+                        return Err(ParseError {
+                            message: String::from("Item consumed no input"),
+                            index,
+                        })
+                    }
+                    Err(_) if input.length() == index => return Err(end_error),
+                    Err(err) => return Err(err),
+                }
+            }
+        })
+    }
+
     pub fn left<B>(self, b: Parser<'a, I, B>) -> Parser<'a, I, O> {
         Parser::new(move |input| {
             let a_res = self.parse(input)?;
@@ -136,7 +321,7 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
             let mut results = Vec::with_capacity(parsers.len());
 
             for p in parsers.iter() {
-                match p.parse_at(&input, i) {
+                match p.parse_at(input, i) {
                     Err(err) => return Err(err),
                     Ok(ParserState { index, result }) => {
                         results.push(result);
@@ -161,7 +346,7 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
             let mut results = Vec::with_capacity(parsers.len());
 
             for (parser_index, p) in parsers.iter().enumerate() {
-                match p.parse_at(&input, i) {
+                match p.parse_at(input, i) {
                     Err(err) => return Err(err),
                     Ok(ParserState { index, result }) => {
                         results.push(result);
@@ -169,7 +354,7 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
                     }
                 }
                 if parser_index != parsers.len() - 1 {
-                    match separator.parse_at(&input, i) {
+                    match separator.parse_at(input, i) {
                         Err(err) => return Err(err),
                         Ok(ParserState { index, result: _ }) => i = index,
                     }
@@ -199,11 +384,35 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
         })
     }
 
+    // `term (op term)*` folded from the left, so `a - b - c` is `(a - b) - c`. An operator that
+    // isn't followed by a term is left unparsed for the caller to report.
+    pub fn chainl1<S, F>(self, op: Parser<'a, I, S>, combine: F) -> Parser<'a, I, O>
+    where
+        F: Fn(O, S, O) -> O + 'a,
+    {
+        Parser::new(move |input| {
+            let ParserState {
+                mut result,
+                mut index,
+            } = self.parse(input)?;
+            while let Ok(op_state) = op.parse_at(input, index) {
+                match self.parse_at(input, op_state.index) {
+                    Ok(term) => {
+                        result = combine(result, op_state.result, term.result);
+                        index = term.index;
+                    }
+                    Err(_) => break,
+                }
+            }
+            Ok(ParserState { result, index })
+        })
+    }
+
     pub fn one_of(parsers: Vec<Parser<I, O>>) -> Parser<I, O> {
         Parser::new(move |input| {
             let mut errors = Vec::with_capacity(parsers.len());
             for p in parsers.iter() {
-                match p.parse(&input) {
+                match p.parse(input) {
                     Err(err) => errors.push(err),
                     result @ Ok(_) => return result,
                 }
@@ -224,7 +433,7 @@ impl<'a, I: ?Sized + ParseInput, O> Parser<'a, I, O> {
 
 #[cfg(test)]
 mod tests {
-    use super::{ParseError, ParseResult, Parser, ParserState};
+    use super::{counted, length_prefixed, take, ParseError, ParseResult, Parser, ParserState};
 
     fn parse_char<'a>(ch: char) -> Parser<'a, str, char> {
         Parser::new(move |input: &str| match input.chars().next() {
@@ -310,6 +519,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn peek() {
+        assert_eq!(
+            parse_char('a').peek().parse("abc"),
+            Ok(ParserState {
+                index: 0,
+                result: 'a'
+            })
+        );
+        assert_eq!(
+            parse_char('a').peek().parse("bc"),
+            Err(ParseError::new(String::from("nope")))
+        );
+        assert_eq!(
+            parse_char('a').peek().right(parse_char('a')).parse("abc"),
+            Ok(ParserState {
+                index: 1,
+                result: 'a'
+            })
+        );
+    }
+
+    #[test]
+    fn optional() {
+        assert_eq!(
+            parse_char('a').optional().parse("abc"),
+            Ok(ParserState {
+                index: 1,
+                result: Some('a')
+            })
+        );
+        assert_eq!(
+            parse_char('a').optional().parse("bc"),
+            Ok(ParserState {
+                index: 0,
+                result: None
+            })
+        );
+        assert_eq!(
+            parse_char('a')
+                .optional()
+                .pair(parse_char('b'))
+                .parse_at("xbc", 1),
+            Ok(ParserState {
+                index: 2,
+                result: (None, 'b')
+            })
+        );
+    }
+
+    #[test]
+    fn not_followed_by() {
+        assert_eq!(
+            parse_char('a').not_followed_by(parse_char('b')).parse("ac"),
+            Ok(ParserState {
+                index: 1,
+                result: 'a'
+            })
+        );
+        assert_eq!(
+            parse_char('a').not_followed_by(parse_char('b')).parse("a"),
+            Ok(ParserState {
+                index: 1,
+                result: 'a'
+            })
+        );
+        assert_eq!(
+            parse_char('a')
+                .not_followed_by(parse_char('b'))
+                .parse("abc"),
+            Err(ParseError {
+                message: String::from("Unexpected input"),
+                index: 1
+            })
+        );
+    }
+
     #[test]
     fn zero_or_more() {
         assert_eq!(
@@ -343,6 +629,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sep_by() {
+        let list = parse_char('a').sep_by(parse_char(','));
+        assert_eq!(
+            list.parse("a,a,ab"),
+            Ok(ParserState {
+                index: 5,
+                result: vec!['a', 'a', 'a']
+            })
+        );
+        assert_eq!(
+            list.parse("a"),
+            Ok(ParserState {
+                index: 1,
+                result: vec!['a']
+            })
+        );
+        assert_eq!(
+            list.parse("b,a"),
+            Ok(ParserState {
+                index: 0,
+                result: vec![]
+            })
+        );
+        // The dangling separator stays for the caller
+        assert_eq!(
+            list.clone().left(parse_char(',')).parse("a,a,"),
+            Ok(ParserState {
+                index: 4,
+                result: vec!['a', 'a']
+            })
+        );
+        assert_eq!(
+            list.parse("a,,a"),
+            Ok(ParserState {
+                index: 1,
+                result: vec!['a']
+            })
+        );
+    }
+
     #[test]
     fn sequence_of() {
         assert_eq!(
@@ -381,6 +708,128 @@ mod tests {
         )
     }
 
+    #[test]
+    fn chainl1() {
+        let digit = Parser::new(|input: &str| match input.chars().next() {
+            Some(c) if c.is_ascii_digit() => Ok(ParserState {
+                index: 1,
+                result: c.to_string(),
+            }),
+            _ => Err(ParseError::new(String::from("nope"))),
+        });
+        let chain = digit.chainl1(parse_char('-'), |a, op, b| format!("({}{}{})", a, op, b));
+        assert_eq!(
+            chain.parse("1-2-3"),
+            Ok(ParserState {
+                index: 5,
+                result: "((1-2)-3)".to_string()
+            })
+        );
+        assert_eq!(
+            chain.parse("1-2-"),
+            Ok(ParserState {
+                index: 3,
+                result: "(1-2)".to_string()
+            })
+        );
+        assert_eq!(
+            chain.parse("-1"),
+            Err(ParseError::new(String::from("nope")))
+        );
+    }
+
+    #[test]
+    fn take_and_length_prefixed() {
+        assert_eq!(
+            take(2).parse("héllo"),
+            Err(ParseError::new(String::from("Cannot take 2 here")))
+        );
+        assert_eq!(
+            take(3).parse("héllo"),
+            Ok(ParserState {
+                index: 3,
+                result: "hé"
+            })
+        );
+        let digit = Parser::new(|input: &str| match input.chars().next() {
+            Some(c) if c.is_ascii_digit() => Ok(ParserState {
+                index: 1,
+                result: c as usize - '0' as usize,
+            }),
+            _ => Err(ParseError::new(String::from("nope"))),
+        });
+        let field = length_prefixed(digit.clone());
+        assert_eq!(
+            counted(digit, field.clone()).parse("23abc2de"),
+            Ok(ParserState {
+                index: 8,
+                result: vec!["abc", "de"]
+            })
+        );
+        assert_eq!(
+            field.clone().repeat(2).parse("3abc4de"),
+            Err(ParseError {
+                message: String::from("Unexpected end of input, 4 needed and 2 left"),
+                index: 5
+            })
+        );
+        assert_eq!(
+            field.parse_at("x3abc", 1).map(|state| state.result),
+            Ok("abc")
+        );
+    }
+
+    #[test]
+    fn pair() {
+        assert_eq!(
+            parse_char('a').pair(parse_char('b')).parse("abc"),
+            Ok(ParserState {
+                index: 2,
+                result: ('a', 'b')
+            })
+        );
+        assert_eq!(
+            parse_char('a').pair(parse_char('b')).parse("ac"),
+            Err(ParseError {
+                message: String::from("nope"),
+                index: 1
+            })
+        );
+    }
+
+    #[test]
+    fn many_till() {
+        let records = parse_char('a').many_till(parse_char(';'));
+        assert_eq!(
+            records.parse("aaa;b"),
+            Ok(ParserState {
+                index: 4,
+                result: vec!['a', 'a', 'a']
+            })
+        );
+        assert_eq!(
+            records.parse(";"),
+            Ok(ParserState {
+                index: 1,
+                result: vec![]
+            })
+        );
+        assert_eq!(
+            records.parse("aab;"),
+            Err(ParseError {
+                message: String::from("nope"),
+                index: 2
+            })
+        );
+        assert_eq!(
+            records.parse("aa"),
+            Err(ParseError {
+                message: String::from("nope"),
+                index: 2
+            })
+        );
+    }
+
     #[test]
     fn left() {
         assert_eq!(
@@ -1,15 +1,20 @@
 use super::core::{ParseError, Parser, ParserState};
 
+// Indices are byte offsets into the input, the parsers here only ever stop on a character
+// boundary so slicing at them is safe
 pub fn literal<'a>(expected: String) -> Parser<'a, str, String> {
-    Parser::new(move |input: &str| match input.get(0..expected.len()) {
-        Some(next) if next == expected => Ok(ParserState {
-            index: expected.len(),
-            result: expected.clone(),
-        }),
-        _ => Err(ParseError::new(format!(
-            "Could not match literal: \"{}\"",
-            expected
-        ))),
+    Parser::new(move |input: &str| {
+        if input.starts_with(expected.as_str()) {
+            Ok(ParserState {
+                index: expected.len(),
+                result: expected.clone(),
+            })
+        } else {
+            Err(ParseError::new(format!(
+                "Could not match literal: \"{}\"",
+                expected
+            )))
+        }
     })
 }
 
@@ -26,7 +31,7 @@ pub fn literal<'a>(expected: String) -> Parser<'a, str, String> {
 pub fn character<'a>(c: char) -> Parser<'a, str, String> {
     Parser::new(move |input: &str| match input.chars().next() {
         Some(ch) if ch == c => Ok(ParserState {
-            index: 1,
+            index: c.len_utf8(),
             result: c.to_string(),
         }),
         Some(ch) => Err(ParseError::new(format!("Expected '{}' found '{}'", c, ch))),
@@ -46,10 +51,22 @@ pub fn hexadecimal<'a>() -> Parser<'a, str, String> {
     .map(|v| v.iter().collect())
 }
 
+pub fn digits<'a>(radix: u32) -> Parser<'a, str, String> {
+    Parser::new(move |input: &str| match input.chars().next() {
+        Some(c) if c.is_digit(radix) => Ok(ParserState {
+            index: 1,
+            result: c,
+        }),
+        _ => Err(ParseError::new(format!("Not a base {} digit", radix))),
+    })
+    .one_or_more()
+    .map(|v| v.iter().collect())
+}
+
 pub fn alphabetic<'a>() -> Parser<'a, str, String> {
     Parser::new(|input: &str| match input.chars().next() {
         Some(c) if c.is_alphabetic() => Ok(ParserState {
-            index: 1,
+            index: c.len_utf8(),
             result: c,
         }),
         _ => Err(ParseError::new("Not an alphabetic character".to_string())),
@@ -63,9 +80,82 @@ pub fn upper_or_lower<'a>(s: String) -> Parser<'a, str, String> {
         .map(move |_| s.clone())
 }
 
+pub fn optional_whitespace<'a>() -> Parser<'a, str, String> {
+    character(' ').zero_or_more().map(|s| s.join(""))
+}
+
+// Spaces and tabs
+pub fn optional_blanks<'a>() -> Parser<'a, str, String> {
+    Parser::one_of(vec![character(' '), character('\t')])
+        .zero_or_more()
+        .map(|s| s.join(""))
+}
+
+pub fn whitespace<'a>() -> Parser<'a, str, String> {
+    character(' ').one_or_more().map(|s| s.join(""))
+}
+
+// A string between two `delimiter`s with the escapes resolved: \n, \t, \0, \\ and the
+// delimiter itself. An unterminated string fails at the opening delimiter, a bad escape at its
+// backslash.
+pub fn quoted<'a>(delimiter: char) -> Parser<'a, str, String> {
+    Parser::new(move |input: &str| {
+        let start = character(delimiter).parse(input)?.index;
+        let mut result = String::new();
+        let mut chars = input[start..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c == delimiter => {
+                    return Ok(ParserState {
+                        index: start + i + c.len_utf8(),
+                        result,
+                    })
+                }
+                '\\' => result.push(match chars.next() {
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    Some((_, '0')) => '\0',
+                    Some((_, '\\')) => '\\',
+                    Some((_, c)) if c == delimiter => c,
+                    Some((_, c)) => {
+                        return Err(ParseError {
+                            message: format!("Invalid escape '\\{}'", c),
+                            index: start + i,
+                        })
+                    }
+                    None => break,
+                }),
+                '\n' => break,
+                c => result.push(c),
+            }
+        }
+        Err(ParseError::new("Unterminated string".to_string()))
+    })
+}
+
+// Everything up to the first character matching `pred` or the end of the input, which may be
+// nothing. The matching character is not consumed.
+pub fn until<'a, F>(pred: F) -> Parser<'a, str, String>
+where
+    F: Fn(char) -> bool + 'a,
+{
+    Parser::new(move |input: &str| {
+        let index = input.find(&pred).unwrap_or(input.len());
+        Ok(ParserState {
+            index,
+            result: input[..index].to_string(),
+        })
+    })
+}
+
+// A `;` comment running to the end of the line, the result is the text after the `;`
+pub fn comment<'a>() -> Parser<'a, str, String> {
+    character(';').right(until(|c| c == '\n'))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{literal, upper_or_lower, ParseError, ParserState};
+    use super::{comment, literal, upper_or_lower, ParseError, ParserState};
 
     #[test]
     fn literal_parser() {
@@ -92,6 +182,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn multi_byte() {
+        assert_eq!(
+            literal(String::from("é")).parse("éa"),
+            Ok(ParserState {
+                index: 2,
+                result: String::from("é")
+            })
+        );
+        assert!(literal(String::from("a")).parse("é").is_err());
+        assert_eq!(
+            super::character('€').parse("€1"),
+            Ok(ParserState {
+                index: 3,
+                result: String::from("€")
+            })
+        );
+        assert_eq!(
+            super::alphabetic().parse("naïve!"),
+            Ok(ParserState {
+                index: 6,
+                result: String::from("naïve")
+            })
+        );
+    }
+
     #[test]
     fn upper_or_lower_test() {
         let parse_joe = upper_or_lower(String::from("joe!"));
@@ -125,12 +241,107 @@ mod tests {
             Err(ParseError::new("Could not match one or more".to_string()))
         )
     }
-}
 
-pub fn optional_whitespace<'a>() -> Parser<'a, str, String> {
-    character(' ').zero_or_more().map(|s| s.join(""))
-}
+    #[test]
+    fn quoted() {
+        assert_eq!(
+            super::quoted('"').parse("\"a\\n\\t\\0\\\\\\\"b\" rest"),
+            Ok(ParserState {
+                index: 14,
+                result: String::from("a\n\t\0\\\"b")
+            })
+        );
+        assert_eq!(
+            super::quoted('"').parse("\"\""),
+            Ok(ParserState {
+                index: 2,
+                result: String::new()
+            })
+        );
+        assert_eq!(
+            super::quoted('\'').parse("'it\\'s \"x\"'"),
+            Ok(ParserState {
+                index: 11,
+                result: String::from("it's \"x\"")
+            })
+        );
+        assert_eq!(
+            super::quoted('"').parse("\"ünï\""),
+            Ok(ParserState {
+                index: 7,
+                result: String::from("ünï")
+            })
+        );
+    }
 
-pub fn whitespace<'a>() -> Parser<'a, str, String> {
-    character(' ').one_or_more().map(|s| s.join(""))
+    #[test]
+    fn quoted_errors() {
+        assert_eq!(
+            super::quoted('"').parse_at("mov \"abc", 4),
+            Err(ParseError {
+                message: String::from("Unterminated string"),
+                index: 4
+            })
+        );
+        assert_eq!(
+            super::quoted('"').parse("\"abc\\\"\ndef\""),
+            Err(ParseError::new(String::from("Unterminated string")))
+        );
+        assert_eq!(
+            super::quoted('"').parse("\"ab\\q\""),
+            Err(ParseError {
+                message: String::from("Invalid escape '\\q'"),
+                index: 3
+            })
+        );
+        assert_eq!(
+            super::quoted('"').parse("abc"),
+            Err(ParseError::new(String::from("Expected '\"' found 'a'")))
+        );
+    }
+
+    #[test]
+    fn until() {
+        let comment = super::until(|c| c == '\n');
+        assert_eq!(
+            comment.parse("; note\nhlt"),
+            Ok(ParserState {
+                index: 6,
+                result: String::from("; note")
+            })
+        );
+        assert_eq!(
+            comment.parse("; last"),
+            Ok(ParserState {
+                index: 6,
+                result: String::from("; last")
+            })
+        );
+        assert_eq!(
+            comment.parse("\n"),
+            Ok(ParserState {
+                index: 0,
+                result: String::new()
+            })
+        );
+    }
+
+    #[test]
+    fn comment_parser() {
+        assert_eq!(
+            comment().parse("; mov $1 R1\nhlt"),
+            Ok(ParserState {
+                index: 11,
+                result: String::from(" mov $1 R1")
+            })
+        );
+        assert_eq!(
+            comment().parse(";"),
+            Ok(ParserState {
+                index: 1,
+                result: String::new()
+            })
+        );
+        assert!(comment().parse("mov ; no").is_err());
+    }
 }
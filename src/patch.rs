@@ -0,0 +1,187 @@
+// Changes a few bytes of a compiled program in place, written by `vm patch`. Addresses are
+// expressions like in the debugger, `!label` needs a container with debug info. Containers are
+// written back with fresh checksums.
+use crate::assembler::{self, SymbolTable};
+use crate::container::Container;
+use crate::cpu::instruction;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Patch {
+    Bytes(Vec<u8>),
+    Word(u16),
+    // A single instruction, it must be as long as the one it replaces. It can use the labels
+    // of a container with debug info.
+    Assemble(String),
+}
+
+impl Patch {
+    // `10 00 2a 04`
+    pub fn bytes(text: &str) -> Result<Patch, String> {
+        let bytes = text
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid byte: {}", byte)))
+            .collect::<Result<Vec<u8>, String>>()?;
+        if bytes.is_empty() {
+            return Err("Expected at least one byte".to_string());
+        }
+        Ok(Patch::Bytes(bytes))
+    }
+
+    // `0x2a`, `$2a` or `42`
+    pub fn word(text: &str) -> Result<Patch, String> {
+        let word = match text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => text.parse(),
+        };
+        word.map(Patch::Word)
+            .map_err(|_| format!("Invalid word: {}", text))
+    }
+
+    fn encode(
+        &self,
+        code: &[u8],
+        address: usize,
+        symbols: &SymbolTable,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            Patch::Bytes(bytes) => Ok(bytes.clone()),
+            Patch::Word(word) => Ok(word.to_be_bytes().to_vec()),
+            Patch::Assemble(source) => {
+                let bytes = assembler::assemble_one(source, address as u16, symbols)
+                    .map_err(|diagnostics| diagnostics.to_string())?;
+                let replaced = code
+                    .get(address)
+                    .and_then(|&opcode| {
+                        instruction::LIST
+                            .iter()
+                            .find(|instruction| instruction.opcode == opcode)
+                    })
+                    .ok_or_else(|| format!("No instruction at {:#06x} to replace", address))?;
+                if bytes.len() != replaced.size as usize {
+                    return Err(format!(
+                        "{} is {} bytes, the {} at {:#06x} is {} bytes",
+                        source,
+                        bytes.len(),
+                        replaced.mnemonic,
+                        address,
+                        replaced.size
+                    ));
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+// Returns the patched file, `bin` is a raw binary or a container
+pub fn apply(bin: &[u8], at: &str, patch: &Patch) -> Result<Vec<u8>, String> {
+    if !Container::is_container(bin) {
+        let mut code = bin.to_vec();
+        let symbols = SymbolTable::new();
+        write(&mut code, resolve(at, &symbols)?, patch, &symbols)?;
+        return Ok(code);
+    }
+    let mut container = Container::from_bytes(bin).map_err(|error| error.to_string())?;
+    let symbols = container
+        .debug
+        .as_ref()
+        .map(|debug| debug.symbols.iter().cloned().collect())
+        .unwrap_or_default();
+    write(&mut container.code, resolve(at, &symbols)?, patch, &symbols)?;
+    Ok(container.to_bytes())
+}
+
+fn resolve(at: &str, symbols: &SymbolTable) -> Result<u16, String> {
+    if symbols.is_empty() && at.contains('!') {
+        return Err(format!("{} needs a container with debug info", at));
+    }
+    assembler::evaluate_expression(&at.replace("0x", "$"), symbols)
+}
+
+fn write(
+    code: &mut [u8],
+    address: u16,
+    patch: &Patch,
+    symbols: &SymbolTable,
+) -> Result<(), String> {
+    let address = address as usize;
+    let bytes = patch.encode(code, address, symbols)?;
+    if address + bytes.len() > code.len() {
+        return Err(format!(
+            "Patch of {} bytes at {:#06x} ends past the {} bytes of code",
+            bytes.len(),
+            address,
+            code.len()
+        ));
+    }
+    code[address..address + bytes.len()].copy_from_slice(&bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Patch};
+    use crate::assembler;
+    use crate::container::Container;
+    use crate::cpu::register;
+    use crate::cpu::CPU;
+    use crate::device::memory::Memory;
+
+    const PROGRAM: &str = "mov $5 R1\nlimit:\nmov $10 R2\nadd R1 R2\nhlt\n";
+
+    fn fixture() -> Vec<u8> {
+        let assembly = assembler::assemble(PROGRAM, &assembler::Options::default()).unwrap();
+        Container {
+            code: assembly.bytes.clone(),
+            meta: vec![],
+            debug: Some(assembly.debug_info("prog.asm")),
+        }
+        .to_bytes()
+    }
+
+    fn acc(bin: &[u8]) -> u16 {
+        let code = Container::from_bytes(bin).unwrap().code;
+        let memory = Memory::with_contents(0x100, &code);
+        let mut cpu = CPU::new(Box::new(memory));
+        cpu.run().unwrap();
+        cpu.get_register(register::ACC)
+    }
+
+    #[test]
+    fn patches() {
+        let bin = fixture();
+        assert_eq!(acc(&bin), 0x15);
+        let patched = apply(&bin, "!limit + 0x1", &Patch::word("0x2a").unwrap()).unwrap();
+        assert_eq!(acc(&patched), 0x2f);
+        let patched = apply(&bin, "0x1", &Patch::bytes("00 07").unwrap()).unwrap();
+        assert_eq!(acc(&patched), 0x17);
+        let patched = apply(&bin, "!limit", &Patch::Assemble("mov $1 R2".to_string())).unwrap();
+        assert_eq!(acc(&patched), 0x6);
+        let patched = apply(&bin, "0x0", &Patch::Assemble("mov [!limit] R1".to_string())).unwrap();
+        assert_eq!(acc(&patched), 0x14);
+        let raw = Container::from_bytes(&bin).unwrap().code;
+        assert_eq!(
+            apply(&raw, "0x1", &Patch::Word(0x20)).unwrap()[1..3],
+            [0x00, 0x20]
+        );
+    }
+
+    #[test]
+    fn rejects() {
+        let bin = fixture();
+        let raw = Container::from_bytes(&bin).unwrap().code;
+        assert_eq!(
+            apply(&bin, "!limit", &Patch::Assemble("add R1 R2".to_string())),
+            Err("add R1 R2 is 3 bytes, the mov at 0x0004 is 4 bytes".to_string())
+        );
+        assert_eq!(
+            apply(&bin, "0xb", &Patch::Word(1)),
+            Err("Patch of 2 bytes at 0x000b ends past the 12 bytes of code".to_string())
+        );
+        assert_eq!(
+            apply(&raw, "!limit", &Patch::Word(1)),
+            Err("!limit needs a container with debug info".to_string())
+        );
+        assert_eq!(Patch::bytes("10 zz"), Err("Invalid byte: zz".to_string()));
+    }
+}
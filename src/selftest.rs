@@ -0,0 +1,153 @@
+// Runs a program that tests itself through the test harness device and summarizes its
+// assertions. Failures name the source line and the closest label before them when the program
+// has debug info.
+use std::cell::RefCell;
+
+use crate::container::DebugInfo;
+use crate::cpu::{register, CPU};
+use crate::device::test_harness::{Assertion, Results};
+use crate::inspect;
+
+#[derive(Debug)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: Vec<Assertion>,
+    // False when the program halted or faulted before writing DONE
+    pub done: bool,
+    pub fault: Option<String>,
+}
+
+impl Summary {
+    pub fn ok(&self) -> bool {
+        self.failed.is_empty() && self.done && self.fault.is_none()
+    }
+
+    pub fn render(&self, debug: Option<&DebugInfo>) -> String {
+        let mut res = vec![format!(
+            "{} passed, {} failed",
+            self.passed,
+            self.failed.len()
+        )];
+        for assertion in &self.failed {
+            res.push(format!(
+                "FAIL at {}: expected {:#06x}, got {:#06x}",
+                assertion.ip.map_or("?".to_string(), |ip| locate(ip, debug)),
+                assertion.expected,
+                assertion.actual
+            ));
+        }
+        if let Some(fault) = &self.fault {
+            res.push(format!("Program faulted: {}", fault));
+        } else if !self.done {
+            res.push("Program halted without writing DONE".to_string());
+        }
+        res.join("\n")
+    }
+}
+
+// `0x0012 arith.asm:7 (sub_tests+0x5)`
+fn locate(ip: u16, debug: Option<&DebugInfo>) -> String {
+    let mut res = format!("{:#06x}", ip);
+    if let Some(debug) = debug {
+        if let Some(location) = debug.location(ip) {
+            res.push_str(&format!(" {}", location));
+        }
+        if let Some(routine) = inspect::routine(&debug.symbols, ip) {
+            res.push_str(&format!(" ({})", routine));
+        }
+    }
+    res
+}
+
+pub fn run(cpu: &mut CPU, results: &RefCell<Results>) -> Summary {
+    let mut fault = None;
+    loop {
+        let ip = cpu.get_register(register::IP);
+        let step = cpu.step();
+        for assertion in results.borrow_mut().assertions.iter_mut() {
+            assertion.ip.get_or_insert(ip);
+        }
+        match step {
+            Ok(None) if !results.borrow().done => {}
+            Err(error) => {
+                fault = Some(cpu.fault_message(&error));
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    let results = results.borrow();
+    Summary {
+        passed: results.assertions.iter().filter(|a| a.passed()).count(),
+        failed: results
+            .assertions
+            .iter()
+            .filter(|a| !a.passed())
+            .cloned()
+            .collect(),
+        done: results.done,
+        fault,
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::run;
+    use crate::assembler;
+    use crate::device::memory::Memory;
+    use crate::device::test_harness::TestHarness;
+
+    use crate::machine::Builder;
+
+    const HARNESS: usize = 0xfde0;
+
+    fn selftest(code: &str) -> (bool, String) {
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        let memory = Memory::with_contents(0xfe00, &assembly.bytes);
+        let (harness, results) = TestHarness::new();
+        let mut cpu = Builder::new()
+            .map(Box::new(memory), 0x0000, 0xfdff, true)
+            .unwrap()
+            .map(Box::new(harness), HARNESS, HARNESS + 5, true)
+            .unwrap()
+            .build();
+        let summary = run(&mut cpu, &results);
+        (
+            summary.ok(),
+            summary.render(Some(&assembly.debug_info("test.asm"))),
+        )
+    }
+
+    #[test]
+    fn arithmetic() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/selftest/arithmetic.asm");
+        let (ok, report) = selftest(&fs::read_to_string(path).unwrap());
+        assert!(ok, "{}", report);
+        assert_eq!(report, "16 passed, 0 failed");
+    }
+
+    #[test]
+    fn reports_failures() {
+        assert_eq!(
+            selftest(
+                "mov $2 R1\n\
+                 check:\n\
+                 mov $3 &fde0\n\
+                 mov R1 &fde2\n\
+                 mov $3 &fde2\n\
+                 hlt\n"
+            ),
+            (
+                false,
+                "1 passed, 1 failed\n\
+                 FAIL at 0x0009 test.asm:4 (check+0x5): expected 0x0003, got 0x0002\n\
+                 Program halted without writing DONE"
+                    .to_string()
+            )
+        );
+    }
+}
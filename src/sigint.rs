@@ -0,0 +1,39 @@
+// Ctrl-C handling for `vm run` and `vm debug`. The first Ctrl-C only counts a press that the run
+// loop picks up, a second one before the press is cleared quits on the spot. Run loops take the
+// counter as a parameter so tests can press it without sending signals.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub static PRESSES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn pressed(presses: &AtomicUsize) -> bool {
+    presses.load(Ordering::Relaxed) > 0
+}
+
+// Called when the program resumes, so the next Ctrl-C pauses it again
+pub fn clear(presses: &AtomicUsize) {
+    presses.store(0, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+pub fn install() {
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+    const SIGINT: i32 = 2;
+
+    // Only async signal safe calls in here
+    extern "C" fn handle(_: i32) {
+        if PRESSES.fetch_add(1, Ordering::Relaxed) > 0 {
+            unsafe { _exit(130) }
+        }
+    }
+
+    unsafe {
+        signal(SIGINT, handle);
+    }
+}
+
+// Ctrl-C keeps its default behaviour elsewhere
+#[cfg(not(unix))]
+pub fn install() {}
@@ -0,0 +1,238 @@
+// Machine state written by `vm run --snapshot <file>` and the debugger's `snapshot` command:
+//
+//   magic "VMSS" | registers: one u16 per register::LIST entry | memory bytes
+//
+// Memory is everything the CPU can address through its devices, read with get_u8.
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use crate::cpu::register::{self, Register};
+use crate::cpu::CPU;
+use crate::error::VmError;
+use crate::inspect;
+
+pub const MAGIC: &[u8; 4] = b"VMSS";
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Snapshot {
+    pub registers: Vec<u16>,
+    pub memory: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum DiffEntry {
+    Register {
+        register: Register,
+        before: u16,
+        after: u16,
+    },
+    // Adjacent changed bytes are coalesced into one entry
+    Memory {
+        address: u16,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &CPU) -> Snapshot {
+        let memory = cpu.memory();
+        Snapshot {
+            registers: register::LIST
+                .iter()
+                .map(|&reg| cpu.get_register(reg))
+                .collect(),
+            memory: (0..memory.len()).map(|a| memory.get_u8(a)).collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = MAGIC.to_vec();
+        for value in &self.registers {
+            res.extend(value.to_be_bytes().iter());
+        }
+        res.extend(&self.memory);
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, VmError> {
+        let header = MAGIC.len() + register::LIST.len() * 2;
+        if !bytes.starts_with(MAGIC) || bytes.len() < header {
+            return Err(VmError::Config("Not a VM16 snapshot".to_string()));
+        }
+        Ok(Snapshot {
+            registers: bytes[MAGIC.len()..header]
+                .chunks(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                .collect(),
+            memory: bytes[header..].to_vec(),
+        })
+    }
+}
+
+// Changed registers first, then changed memory in address order. Bytes inside `exclude` are
+// ignored, bytes past the end of the shorter snapshot read as 0.
+pub fn diff(a: &Snapshot, b: &Snapshot, exclude: &[RangeInclusive<u16>]) -> Vec<DiffEntry> {
+    let mut res: Vec<DiffEntry> = register::LIST
+        .iter()
+        .zip(a.registers.iter().zip(b.registers.iter()))
+        .filter(|(_, (before, after))| before != after)
+        .map(|(&register, (&before, &after))| DiffEntry::Register {
+            register,
+            before,
+            after,
+        })
+        .collect();
+
+    let byte = |memory: &[u8], address: usize| memory.get(address).cloned().unwrap_or(0);
+    let mut current: Option<(u16, Vec<u8>, Vec<u8>)> = None;
+    for address in 0..a.memory.len().max(b.memory.len()).min(0x10000) {
+        let (before, after) = (byte(&a.memory, address), byte(&b.memory, address));
+        let excluded = exclude
+            .iter()
+            .any(|range| range.contains(&(address as u16)));
+        if before != after && !excluded {
+            let range = current.get_or_insert_with(|| (address as u16, vec![], vec![]));
+            range.1.push(before);
+            range.2.push(after);
+        } else if let Some((address, before, after)) = current.take() {
+            res.push(DiffEntry::Memory {
+                address,
+                before,
+                after,
+            });
+        }
+    }
+    if let Some((address, before, after)) = current {
+        res.push(DiffEntry::Memory {
+            address,
+            before,
+            after,
+        });
+    }
+    res
+}
+
+// Registers as `R1: 0x0000 -> 0x0005`, memory ranges as a header naming the nearest symbol at
+// or before them followed by the surrounding rows of both snapshots
+pub fn render(
+    entries: &[DiffEntry],
+    a: &Snapshot,
+    b: &Snapshot,
+    symbols: &[(String, u16)],
+) -> String {
+    let symbols: BTreeMap<u16, &str> = symbols
+        .iter()
+        .map(|(name, address)| (*address, name.as_str()))
+        .collect();
+    let mut res = String::new();
+    for entry in entries {
+        match entry {
+            DiffEntry::Register {
+                register,
+                before,
+                after,
+            } => res.push_str(&format!(
+                "{}: {:#06x} -> {:#06x}\n",
+                register::name(*register),
+                before,
+                after
+            )),
+            DiffEntry::Memory { address, after, .. } => {
+                let end = *address as usize + after.len() - 1;
+                res.push_str(&format!("{:#06x}-{:#06x}", address, end));
+                if let Some((start, name)) = symbols.range(..=address).next_back() {
+                    if start == address {
+                        res.push_str(&format!(" (!{})", name));
+                    } else {
+                        res.push_str(&format!(" (!{}+{:#x})", name, address - start));
+                    }
+                }
+                let plural = if after.len() == 1 { "" } else { "s" };
+                res.push_str(&format!(", {} byte{}\n", after.len(), plural));
+
+                let row = address & !7;
+                let length = ((end + 8) & !7) - row as usize;
+                for (sign, snapshot) in [("-", a), ("+", b)].iter() {
+                    for line in inspect::hexdump_bytes(&snapshot.memory, row, length as u16).lines()
+                    {
+                        res.push_str(&format!("{} {}\n", sign, line));
+                    }
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::{diff, render, DiffEntry, Snapshot};
+    use crate::assembler;
+    use crate::cpu::register;
+    use crate::cpu::CPU;
+    use crate::device::memory::Memory;
+
+    const PROGRAM: &str = "mov $105 R1\nmov $1234 &802\nmov R1 &804\nhlt\n";
+
+    fn snapshots(steps: usize) -> (Snapshot, Snapshot) {
+        let assembly = assembler::assemble(PROGRAM, &assembler::Options::default()).unwrap();
+        let memory = Memory::with_contents(0x1000, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        cpu.step().unwrap();
+        let before = Snapshot::capture(&cpu);
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        (before, Snapshot::capture(&cpu))
+    }
+
+    #[test]
+    fn store() {
+        let (a, b) = snapshots(1);
+        assert_eq!(Snapshot::from_bytes(&b.to_bytes()).unwrap(), b);
+        assert_eq!(
+            diff(&a, &b, &[]),
+            vec![
+                DiffEntry::Register {
+                    register: register::IP,
+                    before: 4,
+                    after: 9,
+                },
+                DiffEntry::Memory {
+                    address: 0x802,
+                    before: vec![0, 0],
+                    after: vec![0x12, 0x34],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn coalesce_and_exclude() {
+        let (a, b) = snapshots(2);
+        let entries = diff(&a, &b, &[]);
+        assert_eq!(
+            entries[1],
+            DiffEntry::Memory {
+                address: 0x802,
+                before: vec![0, 0, 0, 0],
+                after: vec![0x12, 0x34, 1, 5],
+            }
+        );
+        assert_eq!(
+            render(&entries, &a, &b, &[("data".to_string(), 0x800)]),
+            "IP: 0x0004 -> 0x000d\n0x0802-0x0805 (!data+0x2), 4 bytes\n\
+             - 0x0800: 00 00 00 00 00 00 00 00\n+ 0x0800: 00 00 12 34 01 05 00 00\n"
+        );
+
+        assert_eq!(
+            diff(&a, &b, &[0x800..=0x803])[1],
+            DiffEntry::Memory {
+                address: 0x804,
+                before: vec![0, 0],
+                after: vec![1, 5],
+            }
+        );
+    }
+}
@@ -0,0 +1,445 @@
+// Filters for `vm run --trace`. An instruction is traced when IP is inside the range and its
+// opcode belongs to one of the selected mnemonics. Of those, the first `skip` are dropped and at
+// most `limit` printed after them, so untraced instructions cost a few comparisons.
+//
+// `--trace-format jsonl` and `csv` write one row per traced instruction once it has run, for
+// analysis outside the VM. The fields are the same in both:
+//   step      instructions executed before this one, from 0
+//   ip        address of the instruction
+//   opcode    its first byte
+//   mnemonic  null or empty for opcodes that aren't built-in instructions
+//   operands  as the disassembler writes them, e.g. ["$42", "&800"], in CSV separated by spaces
+//   changes   registers other than IP the instruction changed, with their new value, e.g.
+//             {"R1": 66}, in CSV `R1=66` separated by spaces
+//   source    `file:line` from the debug info, null or empty without it
+// Numbers are decimal. CSV starts with a header row and quotes fields holding a comma or quote.
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::container::DebugInfo;
+use crate::cpu::instruction;
+use crate::cpu::register;
+use crate::cpu::CPU;
+use crate::disassembler;
+use crate::isa::json_string;
+
+pub struct Filter {
+    range: RangeInclusive<u16>,
+    // Indexed by opcode, everything is traced without `--trace-only`
+    opcodes: Vec<bool>,
+    skip: u64,
+    limit: u64,
+    matched: u64,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter {
+            range: 0..=0xffff,
+            opcodes: vec![true; 256],
+            skip: 0,
+            limit: u64::MAX,
+            matched: 0,
+        }
+    }
+
+    // Inclusive hex range like `0x100..0x1ff`
+    pub fn range(mut self, range: &str) -> Result<Filter, String> {
+        let invalid = || format!("Invalid trace range: {}, expected <start>..<end>", range);
+        let index = range.find("..").ok_or_else(invalid)?;
+        let hex = |s: &str| {
+            u16::from_str_radix(s.trim().trim_start_matches("0x"), 16).map_err(|_| invalid())
+        };
+        self.range = hex(&range[..index])?..=hex(&range[index + 2..])?;
+        Ok(self)
+    }
+
+    // Comma separated mnemonics like `cal,ret,int`, each selects all of its forms
+    pub fn only(mut self, mnemonics: &str) -> Result<Filter, String> {
+        self.opcodes = vec![false; 256];
+        for mnemonic in mnemonics.split(',').map(str::trim) {
+            let forms: Vec<u8> = instruction::LIST
+                .iter()
+                .filter(|instruction| instruction.mnemonic == mnemonic)
+                .map(|instruction| instruction.opcode)
+                .collect();
+            if forms.is_empty() {
+                return Err(format!("Unknown mnemonic in trace filter: {}", mnemonic));
+            }
+            for opcode in forms {
+                self.opcodes[opcode as usize] = true;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn skip(mut self, skip: u64) -> Filter {
+        self.skip = skip;
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Filter {
+        self.limit = limit;
+        self
+    }
+
+    // Whether the instruction at IP is traced, called once before it runs
+    pub fn select(&mut self, cpu: &CPU) -> bool {
+        let ip = cpu.get_register(register::IP);
+        if !self.range.contains(&ip) {
+            return false;
+        }
+        let opcode = cpu.memory().get_u8(ip as usize);
+        if !self.opcodes[opcode as usize] {
+            return false;
+        }
+        self.matched += 1;
+        self.matched > self.skip && self.matched - self.skip <= self.limit
+    }
+
+    // The trace line for the instruction at IP, called before it runs
+    pub fn trace(&mut self, cpu: &CPU, debug: Option<&DebugInfo>) -> Option<String> {
+        if !self.select(cpu) {
+            return None;
+        }
+        let ip = cpu.get_register(register::IP);
+        let opcode = cpu.memory().get_u8(ip as usize);
+        let location = debug.and_then(|debug| debug.location(ip));
+        Some(format!(
+            "{:04x}: {:02x} {}",
+            ip,
+            opcode,
+            location.unwrap_or_default()
+        ))
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::new()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Format {
+    Text,
+    Jsonl,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "text" => Ok(Format::Text),
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!(
+                "Unknown trace format: {}, expected text, jsonl or csv",
+                name
+            )),
+        }
+    }
+}
+
+// The instruction about to run, completed with the registers it changed once it has
+struct Step {
+    step: u64,
+    ip: u16,
+    opcode: u8,
+    text: Option<String>,
+    source: Option<String>,
+    registers: Vec<u16>,
+}
+
+// Writes the trace of a run, call `before` and `after` around every step
+pub struct Tracer {
+    filter: Filter,
+    format: Format,
+    out: Box<dyn Write>,
+    pending: Option<Step>,
+    header: bool,
+}
+
+impl Tracer {
+    pub fn new(filter: Filter, format: Format, out: Box<dyn Write>) -> Tracer {
+        Tracer {
+            filter,
+            format,
+            out,
+            pending: None,
+            header: format == Format::Csv,
+        }
+    }
+
+    pub fn before(&mut self, cpu: &CPU, debug: Option<&DebugInfo>) -> io::Result<()> {
+        if self.format == Format::Text {
+            return match self.filter.trace(cpu, debug) {
+                Some(line) => writeln!(self.out, "{}", line),
+                None => Ok(()),
+            };
+        }
+        if !self.filter.select(cpu) {
+            return Ok(());
+        }
+        let ip = cpu.get_register(register::IP);
+        let code: Vec<u8> = (0..5)
+            .map(|offset| cpu.memory().get_u8(ip.wrapping_add(offset) as usize))
+            .collect();
+        self.pending = Some(Step {
+            step: cpu.instructions(),
+            ip,
+            opcode: code[0],
+            text: disassembler::decode(&code, 0).map(|(text, _)| text),
+            source: debug.and_then(|debug| debug.location(ip)),
+            registers: register::LIST
+                .iter()
+                .map(|&r| cpu.get_register(r))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    pub fn after(&mut self, cpu: &CPU) -> io::Result<()> {
+        let step = match self.pending.take() {
+            Some(step) => step,
+            None => return Ok(()),
+        };
+        let mut words = step.text.as_deref().unwrap_or_default().split(' ');
+        let mnemonic = words.next().filter(|mnemonic| !mnemonic.is_empty());
+        let operands: Vec<&str> = words.collect();
+        let changes: Vec<(&str, u16)> = register::LIST
+            .iter()
+            .zip(&step.registers)
+            .filter(|&(&r, &before)| r != register::IP && cpu.get_register(r) != before)
+            .map(|(&r, _)| (register::name(r), cpu.get_register(r)))
+            .collect();
+        if self.format == Format::Jsonl {
+            let operands: Vec<String> = operands.iter().map(|o| json_string(o)).collect();
+            let changes: Vec<String> = changes
+                .iter()
+                .map(|(name, value)| format!("{}: {}", json_string(name), value))
+                .collect();
+            let optional = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+            return writeln!(
+                self.out,
+                "{{\"step\": {}, \"ip\": {}, \"opcode\": {}, \"mnemonic\": {}, \"operands\": [{}], \"changes\": {{{}}}, \"source\": {}}}",
+                step.step,
+                step.ip,
+                step.opcode,
+                optional(mnemonic),
+                operands.join(", "),
+                changes.join(", "),
+                optional(step.source.as_deref())
+            );
+        }
+        if self.header {
+            writeln!(self.out, "step,ip,opcode,mnemonic,operands,changes,source")?;
+            self.header = false;
+        }
+        let changes: Vec<String> = changes
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{}",
+            step.step,
+            step.ip,
+            step.opcode,
+            mnemonic.unwrap_or_default(),
+            operands.join(" "),
+            changes.join(" "),
+            csv_field(step.source.as_deref().unwrap_or_default())
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::{Filter, Format, Tracer};
+    use crate::assembler;
+    use crate::cpu::CPU;
+    use crate::device::memory::Memory;
+
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const PROGRAM: &str = "mov $3 R1\n\
+                           loop:\n\
+                           psh $0\n\
+                           cal [!body]\n\
+                           dec R1\n\
+                           mov R1 ACC\n\
+                           jne $0 &[!loop]\n\
+                           hlt\n\
+                           body:\n\
+                           inc R2\n\
+                           ret\n";
+
+    fn trace(filter: Filter) -> Vec<String> {
+        let assembly = assembler::assemble(PROGRAM, &assembler::Options::default()).unwrap();
+        let debug = assembly.debug_info("loop.asm");
+        let memory = Memory::with_contents(0x1000, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        let mut filter = filter;
+        let mut lines = vec![];
+        loop {
+            lines.extend(filter.trace(&cpu, Some(&debug)));
+            if cpu.step() != Ok(None) {
+                return lines;
+            }
+        }
+    }
+
+    #[test]
+    fn everything() {
+        let lines = trace(Filter::new());
+        assert_eq!(lines.len(), 1 + 3 * 7 + 1);
+        assert_eq!(lines[0], "0000: 10 loop.asm:1");
+        assert_eq!(lines[1], "0004: 16 loop.asm:3");
+        assert_eq!(lines[2], "0007: 19 loop.asm:4");
+    }
+
+    #[test]
+    fn combined() {
+        assert_eq!(
+            trace(Filter::new().only("cal, ret").unwrap().skip(1).limit(3)),
+            vec![
+                "0017: 1b loop.asm:11",
+                "0007: 19 loop.asm:4",
+                "0017: 1b loop.asm:11"
+            ]
+        );
+        // inc is outside the range
+        assert_eq!(
+            trace(
+                Filter::new()
+                    .range("0xa..0x14")
+                    .unwrap()
+                    .only("jne,hlt,dec,inc")
+                    .unwrap()
+                    .skip(3)
+            ),
+            vec![
+                "000f: 50 loop.asm:7",
+                "000a: 37 loop.asm:5",
+                "000f: 50 loop.asm:7",
+                "0014: ff loop.asm:8"
+            ]
+        );
+    }
+
+    fn run(code: &str, filter: Filter, format: Format) -> String {
+        let assembly = assembler::assemble(code, &assembler::Options::default()).unwrap();
+        let debug = assembly.debug_info("store.asm");
+        let memory = Memory::with_contents(0x1000, &assembly.bytes);
+        let mut cpu = CPU::new(Box::new(memory));
+        let output = SharedOutput(Rc::new(RefCell::new(vec![])));
+        let mut tracer = Tracer::new(filter, format, Box::new(output.clone()));
+        loop {
+            tracer.before(&cpu, Some(&debug)).unwrap();
+            let result = cpu.step();
+            tracer.after(&cpu).unwrap();
+            if result != Ok(None) {
+                break;
+            }
+        }
+        let text = output.0.borrow().clone();
+        String::from_utf8(text).unwrap()
+    }
+
+    // The raw JSON of a field, enough for the flat objects of the trace
+    fn field<'a>(line: &'a str, key: &str) -> &'a str {
+        let start = line.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+        let rest = &line[start..];
+        let end = match rest.chars().next() {
+            Some('[') => rest.find(']').unwrap() + 1,
+            Some('{') => rest.find('}').unwrap() + 1,
+            _ => rest.find([',', '}']).unwrap(),
+        };
+        &rest[..end]
+    }
+
+    const STORE: &str = "mov $42 R1\nmov R1 &800\n.opcode $e0\n";
+
+    #[test]
+    fn jsonl() {
+        let trace = run(STORE, Filter::new(), Format::Jsonl);
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines
+            .iter()
+            .all(|line| line.starts_with('{') && line.ends_with('}')));
+
+        assert_eq!(field(lines[0], "step"), "0");
+        assert_eq!(field(lines[0], "mnemonic"), "\"mov\"");
+        assert_eq!(field(lines[0], "operands"), "[\"$42\", \"R1\"]");
+        assert_eq!(field(lines[0], "changes"), "{\"R1\": 66}");
+        assert_eq!(field(lines[0], "source"), "\"store.asm:1\"");
+
+        // Writes memory and no register
+        assert_eq!(field(lines[1], "ip"), "4");
+        assert_eq!(field(lines[1], "opcode"), "18");
+        assert_eq!(field(lines[1], "operands"), "[\"R1\", \"&800\"]");
+        assert_eq!(field(lines[1], "changes"), "{}");
+
+        // Not a built-in instruction, the run ends with a fault on it
+        assert_eq!(field(lines[2], "opcode"), "224");
+        assert_eq!(field(lines[2], "mnemonic"), "null");
+        assert_eq!(field(lines[2], "operands"), "[]");
+        assert_eq!(field(lines[2], "ip"), "8");
+    }
+
+    #[test]
+    fn csv() {
+        let trace = run(
+            "mov $42 R1\nmov R1 &800\ninc R1\nhlt\n",
+            Filter::new().only("mov").unwrap(),
+            Format::Csv,
+        );
+        assert_eq!(
+            trace,
+            "step,ip,opcode,mnemonic,operands,changes,source\n\
+             0,0,16,mov,$42 R1,R1=66,store.asm:1\n\
+             1,4,18,mov,R1 &800,,store.asm:2\n"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            Filter::new().only("cal,jump").err(),
+            Some("Unknown mnemonic in trace filter: jump".to_string())
+        );
+        assert_eq!(
+            Filter::new().range("100-1ff").err(),
+            Some("Invalid trace range: 100-1ff, expected <start>..<end>".to_string())
+        );
+    }
+}
@@ -0,0 +1,309 @@
+// Runs the allocator in runtime/alloc.asm on the standard machine and checks the heap from the
+// host after every call
+use vm::assembler::{self, Options};
+use vm::machine::{Builder, HEAP_START_ADDRESS};
+use vm::CPU;
+
+const RUNTIME: &str = include_str!("../runtime/alloc.asm");
+const HEAP_END: u16 = 0xf000;
+const SLOTS: u16 = 8;
+
+struct Program {
+    cpu: CPU,
+    slots: u16,
+    freelist: u16,
+}
+
+fn load(code: &str) -> Program {
+    let assembly = assembler::assemble(&format!("{}{}", code, RUNTIME), &Options::default())
+        .unwrap_or_else(|error| panic!("{}", error));
+    let symbol = |name: &str| {
+        assembly
+            .symbols
+            .iter()
+            .find(|(symbol, _)| symbol == name)
+            .map_or(0, |&(_, address)| address)
+    };
+    Program {
+        cpu: Builder::standard(&assembly.bytes).unwrap().build(),
+        slots: symbol("slots"),
+        freelist: symbol("freelist"),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Alloc { slot: u16, size: u16 },
+    Free { slot: u16 },
+}
+
+// Every op stops at a hlt of its own. Allocations tag the first word of their block with the
+// slot and op number, which has to survive every later call.
+fn ops_program(ops: &[Op]) -> String {
+    let mut code = String::new();
+    for (index, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Alloc { slot, size } => code.push_str(&format!(
+                "mov ${:x} R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + ${:x}]\n\
+                 mov ${:x} R1\nmov R1 $0 ACC\nhlt\n",
+                size,
+                slot * 2,
+                tag(slot, index)
+            )),
+            Op::Free { slot } => code.push_str(&format!(
+                "mov &[!slots + ${0:x}] R1\npsh $0\ncal [!free]\nmov $0 &[!slots + ${0:x}]\nhlt\n",
+                slot * 2
+            )),
+        }
+    }
+    code.push_str("slots:\n.word");
+    code.push_str(&" $0".repeat(SLOTS as usize));
+    code.push('\n');
+    code
+}
+
+fn tag(slot: u16, index: usize) -> u16 {
+    0xa000 | slot << 8 | index as u16 & 0xff
+}
+
+#[derive(Debug, PartialEq)]
+struct Heap {
+    // Address and size of every block, in address order
+    blocks: Vec<(u16, u16)>,
+    free: Vec<u16>,
+}
+
+// Walks the blocks from the heap start and the free list, failing on anything the allocator
+// should never leave behind
+fn walk(program: &Program) -> Result<Heap, String> {
+    let memory = program.cpu.memory();
+    let start = (memory.get_u16(HEAP_START_ADDRESS) + 1) & 0xfffe;
+    let mut blocks = vec![];
+    let mut address = start;
+    while address < HEAP_END {
+        let size = memory.get_u16(address as usize);
+        if size < 4 || !size.is_multiple_of(2) || size > HEAP_END - address {
+            return Err(format!("Block {:#06x} has size {:#06x}", address, size));
+        }
+        blocks.push((address, size));
+        address += size;
+    }
+    let mut free: Vec<(u16, u16)> = vec![];
+    let mut next = memory.get_u16(program.freelist as usize);
+    while next != 0 {
+        let &(block, size) = blocks
+            .iter()
+            .find(|&&(block, _)| block == next)
+            .ok_or_else(|| format!("Free block {:#06x} is not a block", next))?;
+        match free.last() {
+            Some(&(last, _)) if last >= block => {
+                return Err(format!(
+                    "Free block {:#06x} comes after {:#06x}",
+                    block, last
+                ))
+            }
+            Some(&(last, last_size)) if last + last_size == block => {
+                return Err(format!(
+                    "Free blocks {:#06x} and {:#06x} were not merged",
+                    last, block
+                ))
+            }
+            _ => {}
+        }
+        free.push((block, size));
+        next = memory.get_u16(block as usize + 2);
+    }
+    let free = free.into_iter().map(|(block, _)| block).collect();
+    Ok(Heap { blocks, free })
+}
+
+fn slot(program: &Program, slot: u16) -> u16 {
+    program
+        .cpu
+        .memory()
+        .get_u16((program.slots + slot * 2) as usize)
+}
+
+// Runs the ops one by one. After each the heap has to walk, and the blocks that aren't free have
+// to be exactly the live allocations, big enough and with their tags intact.
+fn run(ops: &[Op]) -> Program {
+    let mut program = load(&ops_program(ops));
+    let mut live: Vec<Option<(u16, u16)>> = vec![None; SLOTS as usize];
+    for (index, op) in ops.iter().enumerate() {
+        program.cpu.run().unwrap();
+        match *op {
+            Op::Alloc { slot: s, size } => {
+                let pointer = slot(&program, s);
+                assert_ne!(pointer, 0, "op {}: alloc {:#x} failed", index, size);
+                live[s as usize] = Some((size, tag(s, index)));
+            }
+            Op::Free { slot: s } => live[s as usize] = None,
+        }
+        let heap = walk(&program).unwrap_or_else(|error| panic!("op {}: {}", index, error));
+        let mut used: Vec<u16> = heap
+            .blocks
+            .iter()
+            .map(|&(block, _)| block)
+            .filter(|block| !heap.free.contains(block))
+            .collect();
+        for (s, allocation) in live.iter().enumerate() {
+            let (size, tag) = match allocation {
+                Some(allocation) => *allocation,
+                None => continue,
+            };
+            let pointer = slot(&program, s as u16);
+            let position = used
+                .iter()
+                .position(|&block| block + 2 == pointer)
+                .unwrap_or_else(|| panic!("op {}: {:#06x} is not a used block", index, pointer));
+            let block = used.remove(position);
+            let (_, block_size) = heap.blocks.iter().find(|&&(b, _)| b == block).unwrap();
+            assert!(block_size - 2 >= size, "op {}: block too small", index);
+            assert_eq!(
+                program.cpu.memory().get_u16(pointer as usize),
+                tag,
+                "op {}: tag of slot {} overwritten",
+                index,
+                s
+            );
+        }
+        assert_eq!(used, vec![], "op {}: leaked blocks", index);
+    }
+    program
+}
+
+#[test]
+fn alloc_and_free() {
+    let program = run(&[
+        Op::Alloc { slot: 0, size: 10 },
+        Op::Alloc { slot: 1, size: 1 },
+        Op::Alloc { slot: 2, size: 0 },
+        Op::Free { slot: 1 },
+    ]);
+    assert_eq!(
+        walk(&program),
+        Ok(Heap {
+            blocks: vec![
+                (0x1020, 12),
+                (0x102c, 4),
+                (0x1030, 4),
+                (0x1034, HEAP_END - 0x1034)
+            ],
+            free: vec![0x102c, 0x1034],
+        })
+    );
+
+    // Freeing merges with the block after, then with the block before
+    let program = run(&[
+        Op::Alloc { slot: 0, size: 10 },
+        Op::Alloc { slot: 1, size: 1 },
+        Op::Alloc { slot: 2, size: 0 },
+        Op::Free { slot: 1 },
+        Op::Free { slot: 0 },
+        Op::Free { slot: 2 },
+    ]);
+    assert_eq!(
+        walk(&program),
+        Ok(Heap {
+            blocks: vec![(0x1020, HEAP_END - 0x1020)],
+            free: vec![0x1020],
+        })
+    );
+}
+
+#[test]
+fn first_fit_and_realloc() {
+    // Growing the first allocation moves it to the end, the next small one takes its old place
+    // and the copied words survive
+    let mut program = load(
+        "mov $4 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots]\n\
+         mov ACC R2\nmov $1111 R3\nmov R3 $0 R2\nmov $2222 R3\nmov R3 $2 R2\n\
+         mov $4 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $2]\n\
+         mov $14 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $4]\n\
+         mov &[!slots] R2\nmov &[!slots + $4] R3\nmov $2 R4\n\
+         copy:\n\
+         mov &R2 R5\nmov R5 $0 R3\n\
+         add $2 R2\nmov ACC R2\nadd $2 R3\nmov ACC R3\n\
+         dec R4\nmov R4 ACC\njne $0 &[!copy]\n\
+         mov &[!slots] R1\npsh $0\ncal [!free]\n\
+         mov $3 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $6]\n\
+         hlt\n\
+         slots:\n.word $0 $0 $0 $0\n",
+    );
+    program.cpu.run().unwrap();
+    assert_eq!(slot(&program, 0), 0x1022);
+    assert_eq!(slot(&program, 1), 0x1028);
+    assert_eq!(slot(&program, 2), 0x102e);
+    assert_eq!(slot(&program, 3), 0x1022);
+    assert_eq!(program.cpu.memory().get_u16(0x102e), 0x1111);
+    assert_eq!(program.cpu.memory().get_u16(0x1030), 0x2222);
+    assert_eq!(
+        walk(&program).unwrap().blocks[..4],
+        [
+            (0x1020, 6),
+            (0x1026, 6),
+            (0x102c, 22),
+            (0x1042, HEAP_END - 0x1042)
+        ]
+    );
+}
+
+#[test]
+fn exhaustion() {
+    let mut program = load(
+        "mov $f000 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots]\n\
+         mov $dfe0 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $2]\n\
+         mov $dfde R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $4]\n\
+         mov $1 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $6]\n\
+         hlt\n\
+         mov $0 R1\npsh $0\ncal [!free]\n\
+         mov &[!slots + $4] R1\npsh $0\ncal [!free]\n\
+         mov $1 R1\npsh $0\ncal [!alloc]\nmov ACC &[!slots + $6]\n\
+         hlt\n\
+         slots:\n.word $0 $0 $0 $0\n",
+    );
+    program.cpu.run().unwrap();
+    assert_eq!(slot(&program, 0), 0);
+    assert_eq!(slot(&program, 1), 0);
+    assert_eq!(slot(&program, 2), 0x1022);
+    assert_eq!(slot(&program, 3), 0);
+    assert_eq!(walk(&program).unwrap().free, vec![]);
+
+    program.cpu.run().unwrap();
+    assert_eq!(slot(&program, 3), 0x1022);
+}
+
+#[test]
+fn random_ops() {
+    // A fixed linear congruential sequence, so failures reproduce
+    let mut state: u32 = 0x2545;
+    let mut next = |range: u32| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) % range
+    };
+    let mut used = [false; SLOTS as usize];
+    let mut ops = vec![];
+    while ops.len() < 150 {
+        let slot = next(SLOTS as u32) as u16;
+        if used[slot as usize] {
+            ops.push(Op::Free { slot });
+        } else {
+            let size = match next(4) {
+                0 => next(4),
+                1 => next(0x20),
+                _ => next(0x200),
+            } as u16;
+            ops.push(Op::Alloc { slot, size });
+        }
+        used[slot as usize] = !used[slot as usize];
+    }
+    for slot in 0..SLOTS {
+        if used[slot as usize] {
+            ops.push(Op::Free { slot });
+        }
+    }
+    let program = run(&ops);
+    let heap = walk(&program).unwrap();
+    assert_eq!(heap.blocks.len(), 1);
+    assert_eq!(heap.free, vec![0x1020]);
+}
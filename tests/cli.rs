@@ -0,0 +1,92 @@
+// Runs the `vm` binary on images built with `vm compile` and corrupted on disk
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use vm::checksum;
+
+const PROGRAM: &str = "mov $5 R1\nloop:\ndec R1\njne $0 &[!loop]\nhlt\n";
+
+fn vm(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_vm"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// A fresh directory with the program in prog.asm
+fn workspace(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("vm_cli_test_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("prog.asm"), PROGRAM).unwrap();
+    dir
+}
+
+fn compile(dir: &Path, flags: &[&str], output: &str) -> Vec<u8> {
+    let source = dir.join("prog.asm");
+    let binary = dir.join(output);
+    let mut args = vec!["compile"];
+    args.extend(flags);
+    args.extend([source.to_str().unwrap(), binary.to_str().unwrap()]);
+    let compiled = vm(&args);
+    assert!(compiled.status.success(), "{:?}", compiled);
+    fs::read(binary).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn corrupted_container() {
+    let dir = workspace("container");
+    let code = compile(&dir, &[], "raw.bin");
+    let mut image = compile(&dir, &["-g"], "prog.bin");
+    let binary = dir.join("prog.bin");
+    let run = vm(&["run", binary.to_str().unwrap()]);
+    assert!(run.status.success(), "{:?}", run);
+
+    // The dec R1 in the code section
+    let at = image
+        .windows(code.len())
+        .position(|window| window == code.as_slice())
+        .unwrap();
+    image[at + 4] ^= 0x01;
+    fs::write(&binary, &image).unwrap();
+    let run = vm(&["run", binary.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(
+        stderr(&run),
+        "Checksum mismatch in code section, the file is corrupted\n"
+    );
+}
+
+#[test]
+fn raw_binary_with_expected_crc32() {
+    let dir = workspace("raw");
+    let mut image = compile(&dir, &[], "prog.bin");
+    let binary = dir.join("prog.bin");
+    let crc = format!("{:08x}", checksum::crc32(&image));
+    let run = vm(&["run", "--expect-crc32", &crc, binary.to_str().unwrap()]);
+    assert!(run.status.success(), "{:?}", run);
+
+    image[4] ^= 0x01;
+    fs::write(&binary, &image).unwrap();
+    let run = vm(&["run", "--expect-crc32", &crc, binary.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(
+        stderr(&run),
+        format!(
+            "{} has CRC32 {:08x}, expected {}\n",
+            binary.display(),
+            checksum::crc32(&image),
+            crc
+        )
+    );
+}
@@ -0,0 +1,72 @@
+// Drives the VM through the library API only, the way an embedding project would
+use vm::assembler::Options;
+use vm::device::memory::Memory;
+use vm::device::screen::Screen;
+use vm::machine::Builder;
+use vm::{register, VmError, CPU};
+
+fn load(source: &str) -> CPU {
+    let bytes = vm::compile(source, &Options::default()).unwrap();
+    let memory = Memory::with_contents(0x1000, &bytes);
+    CPU::new(Box::new(memory))
+}
+
+#[test]
+fn runs_assembled_program() {
+    let mut cpu = load(
+        "mov $0 R1\n\
+         mov $5 R2\n\
+         loop:\n\
+         add R1 R2\n\
+         mov ACC R1\n\
+         dec R2\n\
+         mov R2 ACC\n\
+         jne $0 &[!loop]\n\
+         mov R1 &800\n\
+         hlt\n",
+    );
+    let halt = cpu.run().unwrap();
+    assert_eq!(cpu.get_register(register::R1), 15);
+    assert_eq!(cpu.get_register(register::R2), 0);
+    assert_eq!(halt.acc, 0);
+    assert_eq!(cpu.memory().get_u16(0x800), 15);
+}
+
+#[test]
+fn steps_with_host_registers() {
+    let mut cpu = load("add R1 R2\nhlt\n");
+    cpu.set_register(register::R1, 0x1200);
+    cpu.set_register(register::R2, 0x0034);
+    assert_eq!(cpu.step(), Ok(None));
+    assert_eq!(cpu.get_register(register::ACC), 0x1234);
+    let halt = cpu.step().unwrap().unwrap();
+    assert_eq!((halt.ip, halt.acc), (3, 0x1234));
+}
+
+// Assembling, building the machine and running all fail with VmError, so one `?` each does
+fn run_r1(source: &str) -> Result<u16, VmError> {
+    let bytes = vm::compile(source, &Options::default())?;
+    let memory = Memory::with_contents(0x1000, &bytes);
+    let mut cpu = Builder::new()
+        .map(Box::new(memory), 0x0000, 0x0fff, true)?
+        .screen(Screen::headless(16, 16), 0x1000, 0x10ff)?
+        .build();
+    cpu.run()?;
+    Ok(cpu.get_register(register::R1))
+}
+
+#[test]
+fn errors_convert_to_vm_error() {
+    assert_eq!(run_r1("mov $5 R1\nhlt\n").unwrap(), 5);
+    assert!(matches!(run_r1("mov $5\nhlt\n"), Err(VmError::Parse(_))));
+    match run_r1("mov $5 R1\n.word $ee00\n") {
+        Err(error @ VmError::Fault(_)) => {
+            assert_eq!(error.to_string(), "Illegal opcode 0xee at 0x0004")
+        }
+        other => panic!("Expected a fault, got {:?}", other),
+    }
+    assert!(matches!(
+        Builder::new().screen(Screen::headless(16, 16), 0x1000, 0x1080),
+        Err(VmError::Config(_))
+    ));
+}
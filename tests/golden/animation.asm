@@ -0,0 +1,38 @@
+mov $30 R1
+loop:
+mov R1 &fe88
+inc R1
+mov R1 ACC
+jne $3a &[!loop]
+mov $2a &fec0
+mov $2d &fec0
+mov $2a &fec1
+mov $2d &fec1
+mov $2a &fec2
+mov $2d &fec2
+mov $2a &fec3
+mov $2d &fec3
+mov $2a &fec4
+mov $2d &fec4
+mov $2a &fec5
+mov $2d &fec5
+mov $2a &fec6
+mov $2d &fec6
+mov $2a &fec7
+mov $2d &fec7
+mov $2a &fec8
+mov $2d &fec8
+mov $2a &fec9
+mov $2d &fec9
+mov $2a &feca
+mov $2d &feca
+mov $2a &fecb
+mov $2d &fecb
+mov $2a &fecc
+mov $2d &fecc
+mov $2a &fecd
+mov $2d &fecd
+mov $2a &fece
+mov $2d &fece
+mov $2a &fecf
+hlt
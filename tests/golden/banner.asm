@@ -0,0 +1,46 @@
+mov $ff00 &fe00
+mov $3d &fe10
+mov $3d &fe11
+mov $3d &fe12
+mov $3d &fe13
+mov $3d &fe14
+mov $3d &fe15
+mov $3d &fe16
+mov $3d &fe17
+mov $3d &fe18
+mov $3d &fe19
+mov $3d &fe1a
+mov $3d &fe1b
+mov $3d &fe1c
+mov $3d &fe1d
+mov $3d &fe1e
+mov $3d &fe1f
+mov $48 &fe22
+mov $45 &fe23
+mov $4c &fe24
+mov $4c &fe25
+mov $4f &fe26
+mov $2c &fe27
+mov $20 &fe28
+mov $56 &fe29
+mov $4d &fe2a
+mov $31 &fe2b
+mov $36 &fe2c
+mov $21 &fe2d
+mov $3d &fe30
+mov $3d &fe31
+mov $3d &fe32
+mov $3d &fe33
+mov $3d &fe34
+mov $3d &fe35
+mov $3d &fe36
+mov $3d &fe37
+mov $3d &fe38
+mov $3d &fe39
+mov $3d &fe3a
+mov $3d &fe3b
+mov $3d &fe3c
+mov $3d &fe3d
+mov $3d &fe3e
+mov $3d &fe3f
+hlt
@@ -0,0 +1,15 @@
+mov $fdf0 SP
+mov $fdf0 FP
+mov $3 R1
+psh $abcd
+cal [!countdown]
+hlt
+countdown:
+psh R1
+dec R1
+mov R1 ACC
+jeq $0 &[!done]
+jne $0 &[!countdown]
+done:
+mov $7 R2
+hlt
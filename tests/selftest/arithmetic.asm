@@ -0,0 +1,58 @@
+mov $5 R1
+mov $7 R2
+addition:
+add R1 R2
+mov $c &fde0
+mov ACC &fde2
+add $10 R1
+mov $15 &fde0
+mov ACC &fde2
+add $100 R2
+mov $107 &fde0
+mov ACC &fde2
+subtraction:
+sub $10 R2
+mov $9 &fde0
+mov ACC &fde2
+sub R1 $1
+mov $4 &fde0
+mov ACC &fde2
+sub R2 R1
+mov $2 &fde0
+mov ACC &fde2
+multiplication:
+mul $3 R2
+mov $15 &fde0
+mov ACC &fde2
+mul R2 R2
+mov $31 &fde0
+mov ACC &fde2
+increment:
+inc R2
+mov $8 &fde0
+mov R2 &fde2
+dec R1
+mov $4 &fde0
+mov R1 &fde2
+bitwise:
+and R2 $c
+mov $8 &fde0
+mov ACC &fde2
+or R2 $f0
+mov $f8 &fde0
+mov ACC &fde2
+xor R2 R1
+mov $c &fde0
+mov ACC &fde2
+not R2
+mov $fff7 &fde0
+mov ACC &fde2
+shifts:
+lsf R2 $2
+mov $20 &fde0
+mov R2 &fde2
+rsf R2 $1
+mov $10 &fde0
+mov R2 &fde2
+mov $1 &fde4
+hlt